@@ -0,0 +1,90 @@
+//! Compares the throughput of the accept modes: one acceptor with the shared pool, acceptors with `SO_REUSEPORT`
+//! sockets queueing for pools of their own, and acceptors answering connections directly.
+//!
+//! Every request uses a new connection, so the accept path dominates. Run it with:
+//!
+//! ```bash
+//! cargo bench --bench accept
+//! ```
+
+extern crate webserver;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use webserver::Config;
+use webserver::listen::{AcceptMode, AcceptorsConfig};
+use webserver::server::Server;
+
+/// Number of concurrent clients.
+const CLIENTS: usize = 32;
+/// Requests sent by each client.
+const REQUESTS: usize = 500;
+/// Worker threads of the server.
+const THREADS: usize = 8;
+/// Acceptor threads per listener in the `SO_REUSEPORT` modes.
+const ACCEPTORS: usize = 4;
+
+fn main() {
+    run("shared pool", None);
+    run("reuseport queue", Some(AcceptorsConfig::new(ACCEPTORS).unwrap()));
+    run("reuseport direct", Some(AcceptorsConfig::new(ACCEPTORS).unwrap().with_mode(AcceptMode::Direct)));
+}
+
+fn run(name: &str, acceptors: Option<AcceptorsConfig>) {
+    let port = free_port();
+    let threads = match acceptors {
+        Some(ref acceptors) if *acceptors.mode() == AcceptMode::Direct => *acceptors.count(),
+        _ => THREADS,
+    };
+    let config = Config::new(String::from("127.0.0.1"), port, threads, String::from("test/web_dir"),
+        String::from("error"), String::from("target/bench-logs")).unwrap()
+        .with_acceptors(acceptors).unwrap();
+    let server = Arc::new(Server::new(config));
+
+    thread::spawn(move || server.bind().unwrap());
+    wait_for(port);
+
+    let started = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| thread::spawn(move || (0..REQUESTS).for_each(|_| request(port))))
+        .collect();
+
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    let elapsed = started.elapsed();
+    let requests = CLIENTS * REQUESTS;
+    println!("{:<18} {:>7} requests in {:>6.2?}, {:>9.0} requests/s",
+        name, requests, elapsed, requests as f64 / elapsed.as_secs_f64());
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn wait_for(port: u16) {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)) {
+            return get(stream);
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    panic!("Server didn't start on port {}!", port);
+}
+
+fn request(port: u16) {
+    get(TcpStream::connect(("127.0.0.1", port)).unwrap());
+}
+
+fn get(mut stream: TcpStream) {
+    stream.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200"));
+}
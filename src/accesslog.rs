@@ -0,0 +1,628 @@
+///! This module provides the access log.
+///!
+///! Each answered request is written as one line to a dedicated file under `log_dir`. The line format
+///! is either `common` (Common Log Format), `combined` (Combined Log Format), `json` or a custom format
+///! string with placeholders in braces:
+///!
+///! | Placeholder        | Value                                            |
+///! |--------------------|--------------------------------------------------|
+///! | `{remote_addr}`    | IP address of the client                         |
+///! | `{user}`           | User authenticated by HTTP Basic authentication  |
+///! | `{time}`           | Local time like `10/Oct/2000:13:55:36 +0200`     |
+///! | `{request}`        | Request line like `GET /index.html HTTP/1.1`     |
+///! | `{method}`         | Request method                                   |
+///! | `{path}`           | Decoded request path                             |
+///! | `{query}`          | Query string                                     |
+///! | `{protocol}`       | Protocol like `HTTP/1.1`                         |
+///! | `{status}`         | Response status code                             |
+///! | `{bytes}`          | Size of the response body, `-` if empty          |
+///! | `{referer}`        | Value of the `Referer` header                    |
+///! | `{user_agent}`     | Value of the `User-Agent` header                 |
+///! | `{duration_ms}`    | Time to answer the request in milliseconds       |
+///! | `{duration_us}`    | Time to answer the request in microseconds       |
+///! | `{vhost}`          | Name of the virtual host                         |
+///! | `{request_id}`     | Unique id of the request                         |
+///! | `{client_subject}` | Subject of the verified client certificate       |
+///! | `{client_sans}`    | Alternative names of the client certificate      |
+///!
+///! Missing values are written as `-`. The `json` format writes one object per line with the fields
+///! `timestamp` (RFC 3339, UTC), `request_id`, `remote_addr` (`null` if unknown), `method`, `path`,
+///! `query`, `status`, `bytes`, `duration_us`, `user_agent` and `vhost`, followed by `client_subject` and
+///! `client_sans` if the client authenticated with a certificate. Writes are buffered and flushed every second,
+///! also while the server is idle, and when the log is dropped.
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use time;
+use escape;
+use http::Request;
+
+/// Format string of the Common Log Format.
+pub static COMMON_FORMAT: &'static str = "{remote_addr} - {user} [{time}] \"{request}\" {status} {bytes}";
+/// Format string of the Combined Log Format.
+pub static COMBINED_FORMAT: &'static str =
+    "{remote_addr} - {user} [{time}] \"{request}\" {status} {bytes} \"{referer}\" \"{user_agent}\"";
+
+fn default_file() -> String {
+    String::from("access.log")
+}
+
+fn default_format() -> String {
+    String::from("combined")
+}
+
+fn default_buffer_size() -> usize {
+    8192
+}
+
+/// Configuration of the access log.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct AccessLogConfig {
+    /// Name of the log file, relative to `log_dir`.
+    #[serde(default = "default_file")]
+    file: String,
+    /// `common`, `combined`, `json` or a custom format string.
+    #[serde(default = "default_format")]
+    format: String,
+    /// Number of bytes buffered before they are written to the file.
+    #[serde(default = "default_buffer_size")]
+    buffer_size: usize,
+    #[serde(skip)]
+    layout: Layout,
+}
+
+impl AccessLogConfig {
+    /// Creates a new access log configuration.
+    pub fn new(file: String, format: String, buffer_size: usize) -> Result<AccessLogConfig, &'static str> {
+        if file.is_empty() {
+            return Err("Config value 'access_log.file' must not be empty!");
+        }
+
+        let path = Path::new(&file);
+        if path.is_absolute() || path.components().any(|component| component == Component::ParentDir) {
+            return Err("Config value 'access_log.file' must be a relative path inside log_dir!");
+        }
+
+        let layout = match format.as_str() {
+            "common" => Layout::Text(parse_format(COMMON_FORMAT)?),
+            "combined" => Layout::Text(parse_format(COMBINED_FORMAT)?),
+            "json" => Layout::Json,
+            custom => Layout::Text(parse_format(custom)?),
+        };
+
+        Ok(AccessLogConfig { file, format, buffer_size, layout })
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<AccessLogConfig, &'static str> {
+        AccessLogConfig::new(self.file, self.format, self.buffer_size)
+    }
+
+    /// Get the name of the log file, relative to `log_dir`.
+    pub fn file(&self) -> &String {
+        &self.file
+    }
+
+    /// Get the format.
+    pub fn format(&self) -> &String {
+        &self.format
+    }
+
+    /// Get the number of buffered bytes.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    RemoteAddr,
+    User,
+    Time,
+    Request,
+    Method,
+    Path,
+    Query,
+    Protocol,
+    Status,
+    Bytes,
+    Referer,
+    UserAgent,
+    DurationMs,
+    DurationUs,
+    Vhost,
+    RequestId,
+    ClientSubject,
+    ClientSans,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    Field(Field),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Layout {
+    Text(Vec<Token>),
+    Json,
+}
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout::Text(Vec::new())
+    }
+}
+
+fn parse_format(format: &str) -> Result<Vec<Token>, &'static str> {
+    let mut tokens = Vec::new();
+    let mut rest = format;
+
+    while let Some(open) = rest.find('{') {
+        if open > 0 {
+            tokens.push(Token::Literal(rest[..open].to_string()));
+        }
+
+        let close = rest[open..].find('}')
+            .ok_or("Config value 'access_log.format' contains an unclosed placeholder!")?;
+        let field = match &rest[open + 1..open + close] {
+            "remote_addr" => Field::RemoteAddr,
+            "user" => Field::User,
+            "time" => Field::Time,
+            "request" => Field::Request,
+            "method" => Field::Method,
+            "path" => Field::Path,
+            "query" => Field::Query,
+            "protocol" => Field::Protocol,
+            "status" => Field::Status,
+            "bytes" => Field::Bytes,
+            "referer" => Field::Referer,
+            "user_agent" => Field::UserAgent,
+            "duration_ms" => Field::DurationMs,
+            "duration_us" => Field::DurationUs,
+            "vhost" => Field::Vhost,
+            "request_id" => Field::RequestId,
+            "client_subject" => Field::ClientSubject,
+            "client_sans" => Field::ClientSans,
+            _ => return Err("Config value 'access_log.format' contains an unknown placeholder!"),
+        };
+
+        tokens.push(Token::Field(field));
+        rest = &rest[open + close + 1..];
+    }
+
+    if !rest.is_empty() {
+        tokens.push(Token::Literal(rest.to_string()));
+    }
+
+    Ok(tokens)
+}
+
+/// Everything logged about an answered request.
+#[derive(Debug, Clone)]
+pub struct Entry<'a> {
+    request: &'a Request,
+    status: u16,
+    bytes: usize,
+    duration: Duration,
+    vhost: &'a str,
+    user: Option<&'a str>,
+    time: time::Tm,
+}
+
+impl<'a> Entry<'a> {
+    /// Creates an entry for a request answered now.
+    pub fn new(request: &'a Request, status: u16, bytes: usize, duration: Duration, vhost: &'a str) -> Entry<'a> {
+        Entry { request, status, bytes, duration, vhost, user: None, time: time::now() }
+    }
+
+    /// Sets the user authenticated for the request.
+    pub fn with_user(mut self, user: Option<&'a str>) -> Entry<'a> {
+        self.user = user;
+        self
+    }
+
+    /// Sets the time the request was answered.
+    pub fn with_time(mut self, time: time::Tm) -> Entry<'a> {
+        self.time = time;
+        self
+    }
+
+    fn value(&self, field: Field) -> String {
+        let request = self.request;
+
+        match field {
+            Field::RemoteAddr => request.remote_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default(),
+            Field::User => self.user.unwrap_or_default().to_string(),
+            Field::Time => format_time(&self.time),
+            Field::Request => format!("{} {} HTTP/{}", request.method(), request.url(), request.version()),
+            Field::Method => request.method().clone(),
+            Field::Path => request.path(),
+            Field::Query => request.query().to_string(),
+            Field::Protocol => format!("HTTP/{}", request.version()),
+            Field::Status => self.status.to_string(),
+            Field::Bytes if self.bytes == 0 => String::new(),
+            Field::Bytes => self.bytes.to_string(),
+            Field::Referer => request.referer().clone(),
+            Field::UserAgent => request.user_agent().clone(),
+            Field::DurationMs => (self.duration.as_secs() * 1000 + u64::from(self.duration.subsec_millis())).to_string(),
+            Field::DurationUs => self.duration_us().to_string(),
+            Field::Vhost => self.vhost.to_string(),
+            Field::RequestId => request.id().clone(),
+            Field::ClientSubject => request.client_cert().as_ref()
+                .map(|cert| cert.subject().clone())
+                .unwrap_or_default(),
+            Field::ClientSans => request.client_cert().as_ref()
+                .map(|cert| cert.sans().join(","))
+                .unwrap_or_default(),
+        }
+    }
+
+    fn duration_us(&self) -> u64 {
+        self.duration.as_secs() * 1_000_000 + u64::from(self.duration.subsec_micros())
+    }
+}
+
+/// Formats the time like `10/Oct/2000:13:55:36 +0200`. `%z` of the time crate writes UTC as `-0000`.
+fn format_time(tm: &time::Tm) -> String {
+    let offset = tm.tm_utcoff.abs();
+    let sign = if tm.tm_utcoff < 0 { '-' } else { '+' };
+
+    format!(
+        "{} {}{:02}{:02}",
+        time::strftime("%d/%b/%Y:%H:%M:%S", tm).unwrap_or_default(),
+        sign,
+        offset / 3600,
+        offset % 3600 / 60)
+}
+
+/// Formats the time like `2000-10-10T13:55:36.000000Z` as described in [RFC 3339](https://tools.ietf.org/html/rfc3339).
+pub fn format_rfc3339(tm: &time::Tm) -> String {
+    let utc = tm.to_utc();
+
+    format!(
+        "{}.{:06}Z",
+        time::strftime("%Y-%m-%dT%H:%M:%S", &utc).unwrap_or_default(),
+        utc.tm_nsec / 1000)
+}
+
+fn format_entry(layout: &Layout, entry: &Entry) -> String {
+    match *layout {
+        Layout::Text(ref tokens) => format_text(tokens, entry),
+        Layout::Json => format_json(entry),
+    }
+}
+
+fn format_json(entry: &Entry) -> String {
+    let request = entry.request;
+    let remote_addr = request.remote_addr()
+        .map(|addr| escape::json(&addr.ip().to_string()))
+        .unwrap_or_else(|| String::from("null"));
+    let client_cert = request.client_cert().as_ref()
+        .map(|cert| format!(
+            ",\"client_subject\":{},\"client_sans\":[{}]",
+            escape::json(cert.subject()),
+            cert.sans().iter().map(|san| escape::json(san)).collect::<Vec<_>>().join(",")))
+        .unwrap_or_default();
+
+    format!(
+        "{{\"timestamp\":{},\"request_id\":{},\"remote_addr\":{},\"method\":{},\"path\":{},\"query\":{},\
+         \"status\":{},\"bytes\":{},\"duration_us\":{},\"user_agent\":{},\"vhost\":{}{}}}",
+        escape::json(&format_rfc3339(&entry.time)),
+        escape::json(request.id()),
+        remote_addr,
+        escape::json(request.method()),
+        escape::json(&request.path()),
+        escape::json(request.query()),
+        entry.status,
+        entry.bytes,
+        entry.duration_us(),
+        escape::json(request.user_agent()),
+        escape::json(entry.vhost),
+        client_cert)
+}
+
+fn format_text(tokens: &[Token], entry: &Entry) -> String {
+    let mut line = String::new();
+
+    for token in tokens {
+        match *token {
+            Token::Literal(ref literal) => line.push_str(literal),
+            Token::Field(field) => {
+                let value = entry.value(field);
+
+                if value.is_empty() {
+                    line.push('-');
+                } else {
+                    escape_value(&value, &mut line);
+                }
+            },
+        }
+    }
+
+    line
+}
+
+/// Escapes quotes, backslashes and control characters like Apache does, so every entry stays one line.
+fn escape_value(value: &str, line: &mut String) {
+    for ch in value.chars() {
+        match ch {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            ch if ch.is_control() => line.push_str(&format!("\\x{:02x}", ch as u32)),
+            ch => line.push(ch),
+        }
+    }
+}
+
+/// Time after which buffered entries are written to the file.
+static FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct Writer {
+    file: BufWriter<File>,
+    last_flush: Instant,
+}
+
+/// The access log file shared by all workers.
+#[derive(Debug)]
+pub struct AccessLog {
+    layout: Layout,
+    writer: Mutex<Writer>,
+}
+
+impl AccessLog {
+    /// Opens the log file under the log directory for appending, creating both if necessary.
+    pub fn open(log_dir: &str, config: &AccessLogConfig) -> io::Result<AccessLog> {
+        fs::create_dir_all(log_dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Path::new(log_dir).join(&config.file))?;
+        let writer = Writer { file: BufWriter::with_capacity(config.buffer_size, file), last_flush: Instant::now() };
+
+        Ok(AccessLog { layout: config.layout.clone(), writer: Mutex::new(writer) })
+    }
+
+    /// Writes the entry to the log.
+    pub fn log(&self, entry: &Entry) {
+        let line = format_entry(&self.layout, entry);
+        let mut writer = self.writer.lock().unwrap();
+        let mut result = writeln!(writer.file, "{}", line);
+
+        if result.is_ok() && writer.last_flush.elapsed() >= FLUSH_INTERVAL {
+            result = writer.file.flush();
+            writer.last_flush = Instant::now();
+        }
+
+        if let Err(err) = result {
+            error!("Can't write access log: {}", err);
+        }
+    }
+
+    /// Starts a thread flushing the log every second, so entries don't stay buffered while no requests come in.
+    ///
+    /// The thread ends when the log is dropped.
+    pub fn flush_periodically(log: &Arc<AccessLog>) {
+        let log: Weak<AccessLog> = Arc::downgrade(log);
+
+        thread::spawn(move || {
+            loop {
+                thread::sleep(FLUSH_INTERVAL);
+
+                match log.upgrade() {
+                    Some(log) => log.flush_if_due(),
+                    None => break,
+                }
+            }
+        });
+    }
+
+    fn flush_if_due(&self) {
+        if self.writer.lock().unwrap().last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Writes all buffered entries to the file.
+    pub fn flush(&self) {
+        let mut writer = self.writer.lock().unwrap();
+
+        if let Err(err) = writer.file.flush() {
+            error!("Can't flush access log: {}", err);
+        }
+
+        writer.last_flush = Instant::now();
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+    use http;
+    use tls::ClientCertificate;
+
+    fn request() -> Request {
+        http::parse_request(
+            "GET /a%20b.html?x=1 HTTP/1.1\r\nHost: localhost\r\nUser-Agent: curl/7.58 \"quoted\"\r\nReferer: http://example.com/\r\n")
+            .with_remote_addr(Some("192.168.1.2:4711".parse().unwrap()))
+            .with_id(String::from("5b2d8e1c-2a"))
+    }
+
+    fn entry(request: &Request) -> Entry<'_> {
+        let time = time::at_utc(time::Timespec::new(971_186_136, 0));
+
+        Entry::new(request, 200, 2326, Duration::from_micros(1500), "example.com").with_time(time)
+    }
+
+    #[test]
+    fn new_validates_format() {
+        assert_that!(
+            AccessLogConfig::new(String::from("access.log"), String::from("{remote_addr} {nope}"), 1024),
+            is(equal_to(Err("Config value 'access_log.format' contains an unknown placeholder!"))));
+        assert_that!(
+            AccessLogConfig::new(String::from("access.log"), String::from("{status"), 1024),
+            is(equal_to(Err("Config value 'access_log.format' contains an unclosed placeholder!"))));
+    }
+
+    #[test]
+    fn new_refuses_file_outside_log_dir() {
+        for file in ["/var/log/access.log", "../access.log", "logs/../../access.log"].iter() {
+            assert_that!(
+                AccessLogConfig::new(file.to_string(), String::from("common"), 1024),
+                is(equal_to(Err("Config value 'access_log.file' must be a relative path inside log_dir!"))));
+        }
+
+        assert_that!(
+            AccessLogConfig::new(String::from("logs/access.log"), String::from("common"), 1024).is_ok(),
+            is(true));
+    }
+
+    #[test]
+    fn format_common() {
+        let request = request();
+        let config = AccessLogConfig::new(String::from("access.log"), String::from("common"), 1024).unwrap();
+
+        assert_that!(
+            format_entry(&config.layout, &entry(&request)),
+            is(equal_to(String::from(
+                "192.168.1.2 - - [10/Oct/2000:13:55:36 +0000] \"GET /a%20b.html?x=1 HTTP/1.1\" 200 2326"))));
+    }
+
+    #[test]
+    fn format_common_with_user() {
+        let request = request();
+        let config = AccessLogConfig::new(String::from("access.log"), String::from("common"), 1024).unwrap();
+
+        assert_that!(
+            format_entry(&config.layout, &entry(&request).with_user(Some("alice"))),
+            is(equal_to(String::from(
+                "192.168.1.2 - alice [10/Oct/2000:13:55:36 +0000] \"GET /a%20b.html?x=1 HTTP/1.1\" 200 2326"))));
+    }
+
+    #[test]
+    fn format_combined_escapes_quotes() {
+        let request = request();
+        let config = AccessLogConfig::new(String::from("access.log"), String::from("combined"), 1024).unwrap();
+
+        assert_that!(
+            format_entry(&config.layout, &entry(&request)).ends_with(
+                "200 2326 \"http://example.com/\" \"curl/7.58 \\\"quoted\\\"\""),
+            is(true));
+    }
+
+    #[test]
+    fn format_custom() {
+        let request = http::parse_request("HEAD /index.html HTTP/1.0\r\n");
+        let config = AccessLogConfig::new(
+            String::from("access.log"),
+            String::from("{vhost} {request_id} {method} {path} {query} {status} {bytes} {duration_ms}ms {duration_us}us"),
+            1024).unwrap();
+
+        assert_that!(
+            format_entry(&config.layout, &Entry::new(&request, 404, 0, Duration::from_micros(1500), "default")),
+            is(equal_to(String::from("default - HEAD /index.html - 404 - 1ms 1500us"))));
+    }
+
+    #[test]
+    fn format_json() {
+        let request = request();
+        let config = AccessLogConfig::new(String::from("access.log"), String::from("json"), 1024).unwrap();
+
+        assert_that!(
+            format_entry(&config.layout, &entry(&request)),
+            is(equal_to(String::from(
+                "{\"timestamp\":\"2000-10-10T13:55:36.000000Z\",\"request_id\":\"5b2d8e1c-2a\",\
+                 \"remote_addr\":\"192.168.1.2\",\"method\":\"GET\",\"path\":\"/a b.html\",\"query\":\"x=1\",\
+                 \"status\":200,\"bytes\":2326,\"duration_us\":1500,\
+                 \"user_agent\":\"curl/7.58 \\\"quoted\\\"\",\"vhost\":\"example.com\"}"))));
+    }
+
+    #[test]
+    fn format_json_without_remote_addr() {
+        let request = http::parse_request("GET / HTTP/1.1\r\nHost: localhost\r\n");
+        let config = AccessLogConfig::new(String::from("access.log"), String::from("json"), 1024).unwrap();
+        let line = format_entry(&config.layout, &Entry::new(&request, 200, 0, Duration::from_secs(0), "default"));
+
+        assert_that!(line.contains("\"remote_addr\":null,"), is(true));
+    }
+
+    #[test]
+    fn format_client_certificate() {
+        let request = http::parse_request("GET / HTTP/1.1\r\nHost: localhost\r\n")
+            .with_client_cert(Some(ClientCertificate::new(
+                String::from("O=Example, CN=alice"),
+                vec!(String::from("email:alice@example.com"), String::from("DNS:alice.example.com")))));
+        let text = AccessLogConfig::new(String::from("access.log"), String::from("{client_subject} {client_sans}"), 1024).unwrap();
+        let json = AccessLogConfig::new(String::from("access.log"), String::from("json"), 1024).unwrap();
+        let entry = Entry::new(&request, 200, 0, Duration::from_secs(0), "default");
+
+        assert_that!(
+            format_entry(&text.layout, &entry),
+            is(equal_to(String::from("O=Example, CN=alice email:alice@example.com,DNS:alice.example.com"))));
+        assert_that!(
+            format_entry(&json.layout, &entry).ends_with(
+                ",\"client_subject\":\"O=Example, CN=alice\",\
+                 \"client_sans\":[\"email:alice@example.com\",\"DNS:alice.example.com\"]}"),
+            is(true));
+    }
+
+    #[test]
+    fn test_format_rfc3339() {
+        let timespec = time::Timespec::new(971_186_136, 123_456_789);
+
+        assert_that!(format_rfc3339(&time::at_utc(timespec)), is(equal_to(String::from("2000-10-10T13:55:36.123456Z"))));
+        assert_that!(format_rfc3339(&time::at(timespec)), is(equal_to(String::from("2000-10-10T13:55:36.123456Z"))));
+    }
+
+    #[test]
+    fn log_appends_lines_to_file() {
+        let log_dir = ::std::env::temp_dir().join(format!("webserver-access-log-{}", ::std::process::id()));
+        let config = AccessLogConfig::new(String::from("access.log"), String::from("{status}"), 1024).unwrap();
+        let request = request();
+
+        {
+            let sut = AccessLog::open(log_dir.to_str().unwrap(), &config).unwrap();
+            sut.log(&entry(&request));
+            sut.log(&Entry::new(&request, 404, 0, Duration::from_secs(0), "default"));
+        }
+
+        let content = fs::read_to_string(log_dir.join("access.log")).unwrap();
+        fs::remove_dir_all(&log_dir).unwrap();
+
+        assert_that!(content, is(equal_to(String::from("200\n404\n"))));
+    }
+
+    #[test]
+    fn flush_periodically_writes_entries_while_idle() {
+        let log_dir = ::std::env::temp_dir().join(format!("webserver-access-log-idle-{}", ::std::process::id()));
+        let config = AccessLogConfig::new(String::from("access.log"), String::from("{status}"), 1024).unwrap();
+        let sut = Arc::new(AccessLog::open(log_dir.to_str().unwrap(), &config).unwrap());
+        AccessLog::flush_periodically(&sut);
+
+        sut.log(&entry(&request()));
+        thread::sleep(FLUSH_INTERVAL * 3);
+
+        let content = fs::read_to_string(log_dir.join("access.log")).unwrap();
+        drop(sut);
+        fs::remove_dir_all(&log_dir).unwrap();
+
+        assert_that!(content, is(equal_to(String::from("200\n"))));
+    }
+}
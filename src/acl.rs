@@ -0,0 +1,200 @@
+///! This module provides access control by client address.
+///!
+///! Rules are written like `allow 10.0.0.0/8`, `deny 2001:db8::/32` or `deny all` and evaluated in
+///! order. The first rule matching the client address decides. If no rule matches, access is allowed.
+///! IPv4 addresses mapped into IPv6 (`::ffff:10.0.0.1`) are matched against IPv4 ranges.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// What happens to requests denied by an access rule.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DenyAction {
+    /// Answer with `403 FORBIDDEN`.
+    #[default]
+    Forbidden,
+    /// Close the connection without answering.
+    Drop,
+}
+
+/// A range of IPv4 or IPv6 addresses in CIDR notation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Parses a range like `192.168.0.0/16` or `::1`. An address without prefix length matches only itself.
+    pub fn parse(cidr: &str) -> Result<Cidr, &'static str> {
+        let (address, prefix_len) = match cidr.find('/') {
+            Some(slash) => (&cidr[..slash], Some(&cidr[slash + 1..])),
+            None => (cidr, None),
+        };
+        let network: IpAddr = address.parse()
+            .map_err(|_| "Config value 'location.access' must contain valid IP addresses!")?;
+        let max_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or("Config value 'location.access' must contain valid CIDR prefix lengths!")?,
+            None => max_len,
+        };
+
+        Ok(Cidr { network: mask(network, prefix_len), prefix_len })
+    }
+
+    /// Whether the address is inside this range.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+            IpAddr::V4(_) => address,
+        };
+
+        match (self.network, address) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) =>
+                mask(address, self.prefix_len) == self.network,
+            _ => false,
+        }
+    }
+}
+
+fn mask(address: IpAddr, prefix_len: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4);
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(bits & mask))
+        },
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6);
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix_len)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(bits & mask))
+        },
+    }
+}
+
+/// A single allow or deny rule.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessRule {
+    /// Allows addresses in the range, or all addresses if `None`.
+    Allow(Option<Cidr>),
+    /// Denies addresses in the range, or all addresses if `None`.
+    Deny(Option<Cidr>),
+}
+
+impl AccessRule {
+    /// Parses a rule like `allow 10.0.0.0/8` or `deny all`.
+    pub fn parse(rule: &str) -> Result<AccessRule, &'static str> {
+        let mut parts = rule.split_whitespace();
+        let verb = parts.next().unwrap_or("");
+        let range = match parts.next() {
+            Some("all") => None,
+            Some(cidr) => Some(Cidr::parse(cidr)?),
+            None => return Err("Config value 'location.access' must contain rules like 'allow 10.0.0.0/8' or 'deny all'!"),
+        };
+
+        if parts.next().is_some() {
+            return Err("Config value 'location.access' must contain rules like 'allow 10.0.0.0/8' or 'deny all'!");
+        }
+
+        match verb {
+            "allow" => Ok(AccessRule::Allow(range)),
+            "deny" => Ok(AccessRule::Deny(range)),
+            _ => Err("Config value 'location.access' must contain rules like 'allow 10.0.0.0/8' or 'deny all'!"),
+        }
+    }
+
+    /// Whether the rule applies to the address. Rules for `all` also apply to clients without IP address.
+    fn matches(&self, address: Option<IpAddr>) -> bool {
+        let range = match *self {
+            AccessRule::Allow(range) | AccessRule::Deny(range) => range,
+        };
+
+        match (range, address) {
+            (None, _) => true,
+            (Some(range), Some(address)) => range.contains(address),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Whether the rules allow access for the client address.
+pub fn is_allowed(rules: &[AccessRule], address: Option<IpAddr>) -> bool {
+    !matches!(rules.iter().find(|rule| rule.matches(address)), Some(&AccessRule::Deny(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    fn rules(rules: &[&str]) -> Vec<AccessRule> {
+        rules.iter().map(|rule| AccessRule::parse(rule).unwrap()).collect()
+    }
+
+    #[test]
+    fn cidr_contains_ipv4() {
+        let sut = Cidr::parse("192.168.1.17/24").unwrap();
+
+        assert_that!(sut.contains("192.168.1.1".parse().unwrap()), is(true));
+        assert_that!(sut.contains("192.168.2.1".parse().unwrap()), is(false));
+        assert_that!(sut.contains("::ffff:192.168.1.200".parse().unwrap()), is(true));
+        assert_that!(sut.contains("::1".parse().unwrap()), is(false));
+        assert_that!(Cidr::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()), is(true));
+    }
+
+    #[test]
+    fn cidr_contains_ipv6() {
+        let sut = Cidr::parse("2001:db8::/32").unwrap();
+
+        assert_that!(sut.contains("2001:db8:1::1".parse().unwrap()), is(true));
+        assert_that!(sut.contains("2001:db9::1".parse().unwrap()), is(false));
+        assert_that!(Cidr::parse("::1").unwrap().contains("::1".parse().unwrap()), is(true));
+    }
+
+    #[test]
+    fn cidr_parse_validates() {
+        assert_that!(
+            Cidr::parse("10.0.0.0/33"),
+            is(equal_to(Err("Config value 'location.access' must contain valid CIDR prefix lengths!"))));
+        assert_that!(
+            Cidr::parse("example.com"),
+            is(equal_to(Err("Config value 'location.access' must contain valid IP addresses!"))));
+    }
+
+    #[test]
+    fn access_rule_parse_validates() {
+        let expected = "Config value 'location.access' must contain rules like 'allow 10.0.0.0/8' or 'deny all'!";
+
+        assert_that!(AccessRule::parse("permit all"), is(equal_to(Err(expected))));
+        assert_that!(AccessRule::parse("allow"), is(equal_to(Err(expected))));
+        assert_that!(AccessRule::parse("deny all now"), is(equal_to(Err(expected))));
+        assert_that!(AccessRule::parse("deny all"), is(equal_to(Ok(AccessRule::Deny(None)))));
+    }
+
+    #[test]
+    fn is_allowed_uses_first_matching_rule() {
+        let sut = rules(&["deny 10.0.0.13", "allow 10.0.0.0/8", "allow ::1", "deny all"]);
+
+        assert_that!(is_allowed(&sut, ip("10.1.2.3")), is(true));
+        assert_that!(is_allowed(&sut, ip("10.0.0.13")), is(false));
+        assert_that!(is_allowed(&sut, ip("::1")), is(true));
+        assert_that!(is_allowed(&sut, ip("192.168.0.1")), is(false));
+        assert_that!(is_allowed(&sut, None), is(false));
+    }
+
+    #[test]
+    fn is_allowed_without_matching_rule() {
+        assert_that!(is_allowed(&[], ip("10.1.2.3")), is(true));
+        assert_that!(is_allowed(&rules(&["deny 10.0.0.0/8"]), ip("192.168.0.1")), is(true));
+    }
+}
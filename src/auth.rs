@@ -0,0 +1,333 @@
+///! This module provides HTTP Basic authentication as described in [RFC 7617](https://tools.ietf.org/html/rfc7617).
+///!
+///! Path prefixes are protected by a realm whose users are read from a htpasswd file. The file is
+///! read once and again when its modification time or size changes, or the configuration is reloaded, so
+///! changes apply without restart. Only bcrypt (`$2a$`, `$2b$`, `$2y$`) and SHA-crypt (`$5$`, `$6$`) hashes
+///! are accepted.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use pwhash;
+
+/// Configuration of a protected path prefix.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct AuthConfig {
+    /// Path prefix to protect, e.g. `/reports/`.
+    path: String,
+    /// Name of the protection space shown by the client.
+    realm: String,
+    /// Location of the htpasswd file with the users allowed to access the path.
+    htpasswd: String,
+    #[serde(skip)]
+    users: UserCache,
+}
+
+/// Hash of a password no user has, verified for unknown users so they take as long to refuse as known ones.
+static DUMMY_HASH: &str = "$2b$10$YAtEIGwOmRw6pk9etipo4.L/AAJgSOjTJa1J7ULvEc2KJj0ydCLBK";
+
+/// The users read from the htpasswd file, shared by all clones of the configuration.
+#[derive(Clone, Default)]
+struct UserCache(Arc<Mutex<Option<Users>>>);
+
+/// The users of a htpasswd file with its modification time and size when it was read.
+struct Users {
+    modified: Option<SystemTime>,
+    len: u64,
+    hashes: Arc<HashMap<String, String>>,
+}
+
+impl fmt::Debug for UserCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("UserCache")
+    }
+}
+
+impl PartialEq for UserCache {
+    fn eq(&self, _other: &UserCache) -> bool {
+        true
+    }
+}
+
+impl AuthConfig {
+    /// Creates a new protected path prefix.
+    pub fn new(path: String, realm: String, htpasswd: String) -> Result<AuthConfig, &'static str> {
+        if !path.starts_with('/') {
+            return Err("Config value 'auth.path' must start with '/'!");
+        }
+
+        if realm.is_empty() || realm.contains('"') || realm.contains('\\') {
+            return Err("Config value 'auth.realm' must not be empty or contain quotes or backslashes!");
+        }
+
+        if htpasswd.is_empty() {
+            return Err("Config value 'auth.htpasswd' must not be empty!");
+        }
+
+        Ok(AuthConfig { path, realm, htpasswd, users: UserCache::default() })
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<AuthConfig, &'static str> {
+        AuthConfig::new(self.path, self.realm, self.htpasswd)
+    }
+
+    /// Get the protected path prefix.
+    pub fn path(&self) -> &String {
+        &self.path
+    }
+
+    /// Get the realm.
+    pub fn realm(&self) -> &String {
+        &self.realm
+    }
+
+    /// Get the location of the htpasswd file.
+    pub fn htpasswd(&self) -> &String {
+        &self.htpasswd
+    }
+
+    /// Get the value of the `WWW-Authenticate` header challenging the client.
+    pub fn challenge(&self) -> String {
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm)
+    }
+
+    /// Authenticates the credentials given in the `Authorization` header.
+    ///
+    /// Returns the name of the authenticated user.
+    pub fn authenticate(&self, authorization: &str) -> Result<String, AuthError> {
+        let (user, password) = parse_basic_credentials(authorization)
+            .ok_or(AuthError::MissingCredentials)?;
+        let hashes = self.load_users()?;
+
+        match hashes.get(&user) {
+            Some(hash) if verify(&password, hash) => Ok(user),
+            Some(_) => Err(AuthError::InvalidCredentials(user)),
+            None => {
+                pwhash::unix::verify(&password, DUMMY_HASH);
+                Err(AuthError::InvalidCredentials(user))
+            },
+        }
+    }
+
+    /// Returns the users of the htpasswd file, reading it again if it changed since it was read last.
+    fn load_users(&self) -> Result<Arc<HashMap<String, String>>, AuthError> {
+        let metadata = fs::metadata(&self.htpasswd)
+            .map_err(|err| AuthError::UnreadableHtpasswd(format!("{}: {}", self.htpasswd, err)))?;
+        let modified = metadata.modified().ok();
+        let mut cached = self.users.0.lock().unwrap();
+
+        match *cached {
+            Some(ref users) if users.modified == modified && users.len == metadata.len() => {
+                return Ok(users.hashes.clone());
+            },
+            _ => (),
+        }
+
+        let hashes = Arc::new(parse_htpasswd(&read_htpasswd(&self.htpasswd)?));
+        *cached = Some(Users { modified, len: metadata.len(), hashes: hashes.clone() });
+        Ok(hashes)
+    }
+}
+
+/// Reasons why authentication failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthError {
+    /// The request has no or malformed Basic credentials.
+    MissingCredentials,
+    /// The user is unknown or the password wrong.
+    InvalidCredentials(String),
+    /// The htpasswd file can't be read.
+    UnreadableHtpasswd(String),
+}
+
+/// Selects the protected path prefix with the longest match for the given request path.
+pub fn select<'a>(auths: &'a [AuthConfig], path: &str) -> Option<&'a AuthConfig> {
+    auths.iter()
+        .filter(|auth| path.starts_with(auth.path.as_str()))
+        .fold(None, |best: Option<&AuthConfig>, candidate| match best {
+            Some(best) if best.path.len() >= candidate.path.len() => Some(best),
+            _ => Some(candidate),
+        })
+}
+
+/// Parses user and password from the value of an `Authorization` header using the Basic scheme.
+pub fn parse_basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let mut parts = authorization.trim().splitn(2, ' ');
+    let scheme = parts.next()?;
+
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+
+    let decoded = STANDARD.decode(parts.next()?.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let colon = decoded.find(':')?;
+
+    Some((decoded[..colon].to_string(), decoded[colon + 1..].to_string()))
+}
+
+fn read_htpasswd(file_name: &str) -> Result<String, AuthError> {
+    let mut content = String::new();
+
+    File::open(file_name)
+        .and_then(|mut file| file.read_to_string(&mut content))
+        .map_err(|err| AuthError::UnreadableHtpasswd(format!("{}: {}", file_name, err)))?;
+
+    Ok(content)
+}
+
+/// Maps the users in the content of a htpasswd file to their password hashes, keeping the first entry of each.
+fn parse_htpasswd(htpasswd: &str) -> HashMap<String, String> {
+    let mut hashes = HashMap::new();
+
+    for line in htpasswd.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        if let Some(colon) = line.find(':') {
+            hashes.entry(line[..colon].to_string()).or_insert_with(|| line[colon + 1..].to_string());
+        }
+    }
+
+    hashes
+}
+
+/// Verifies the password against a bcrypt or SHA-crypt hash.
+fn verify(password: &str, hash: &str) -> bool {
+    let supported = ["$2a$", "$2b$", "$2y$", "$5$", "$6$"].iter()
+        .any(|prefix| hash.starts_with(prefix));
+
+    if !supported {
+        warn!("Ignoring htpasswd entry with unsupported hash algorithm.");
+        return false;
+    }
+
+    pwhash::unix::verify(password, hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+
+    fn auth() -> AuthConfig {
+        AuthConfig::new(
+            String::from("/reports/"),
+            String::from("Reports"),
+            String::from("test/fixtures/htpasswd")).unwrap()
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", STANDARD.encode(credentials))
+    }
+
+    #[test]
+    fn new_validates_realm() {
+        assert_that!(
+            AuthConfig::new(String::from("/"), String::from("Say \"hi\""), String::from("htpasswd")),
+            is(equal_to(Err("Config value 'auth.realm' must not be empty or contain quotes or backslashes!"))));
+    }
+
+    #[test]
+    fn new_validates_path() {
+        assert_that!(
+            AuthConfig::new(String::from("reports"), String::from("Reports"), String::from("htpasswd")),
+            is(equal_to(Err("Config value 'auth.path' must start with '/'!"))));
+    }
+
+    #[test]
+    fn test_challenge() {
+        assert_that!(auth().challenge(), is(equal_to(String::from("Basic realm=\"Reports\", charset=\"UTF-8\""))));
+    }
+
+    #[test]
+    fn test_parse_basic_credentials() {
+        assert_that!(
+            parse_basic_credentials(&basic("alice:pass:word")),
+            is(equal_to(Some((String::from("alice"), String::from("pass:word"))))));
+        assert_that!(
+            parse_basic_credentials("basic YWxpY2U6c2VjcmV0"),
+            is(equal_to(Some((String::from("alice"), String::from("secret"))))));
+        assert_that!(parse_basic_credentials("Bearer abc"), is(equal_to(None)));
+        assert_that!(parse_basic_credentials("Basic !!!"), is(equal_to(None)));
+        assert_that!(parse_basic_credentials(&basic("nocolon")), is(equal_to(None)));
+        assert_that!(parse_basic_credentials(""), is(equal_to(None)));
+    }
+
+    #[test]
+    fn authenticate_accepts_bcrypt_and_sha_crypt() {
+        let sut = auth();
+
+        assert_that!(sut.authenticate(&basic("alice:secret")), is(equal_to(Ok(String::from("alice")))));
+        assert_that!(sut.authenticate(&basic("bob:secret")), is(equal_to(Ok(String::from("bob")))));
+        assert_that!(sut.authenticate(&basic("carol:secret")), is(equal_to(Ok(String::from("carol")))));
+    }
+
+    #[test]
+    fn authenticate_rejects_invalid_credentials() {
+        let sut = auth();
+
+        assert_that!(
+            sut.authenticate(&basic("alice:wrong")),
+            is(equal_to(Err(AuthError::InvalidCredentials(String::from("alice"))))));
+        assert_that!(
+            sut.authenticate(&basic("eve:secret")),
+            is(equal_to(Err(AuthError::InvalidCredentials(String::from("eve"))))));
+        assert_that!(
+            sut.authenticate(&basic("mallory:secret")),
+            is(equal_to(Err(AuthError::InvalidCredentials(String::from("mallory"))))));
+        assert_that!(sut.authenticate(""), is(equal_to(Err(AuthError::MissingCredentials))));
+    }
+
+    #[test]
+    fn authenticate_reports_missing_htpasswd() {
+        let sut = AuthConfig::new(String::from("/"), String::from("Reports"), String::from("does/not/exist")).unwrap();
+
+        assert_that!(
+            match sut.authenticate(&basic("alice:secret")) {
+                Err(AuthError::UnreadableHtpasswd(_)) => true,
+                _ => false,
+            },
+            is(true));
+    }
+
+    #[test]
+    fn authenticate_reads_htpasswd_again_when_changed() {
+        let file = ::std::env::temp_dir().join(format!("webserver-auth-{}.htpasswd", ::std::process::id()));
+        let alice = "alice:$2b$10$yWMxIw6YSqkjzxmgTaGj4.pqym4GEm.oX/22.E4rAspvQTVmg8eu2\n";
+        fs::write(&file, alice).unwrap();
+        let sut = AuthConfig::new(
+            String::from("/"), String::from("Reports"), file.to_string_lossy().into_owned()).unwrap();
+
+        assert_that!(sut.authenticate(&basic("alice:secret")), is(equal_to(Ok(String::from("alice")))));
+        assert_that!(
+            sut.clone().authenticate(&basic("bob:secret")),
+            is(equal_to(Err(AuthError::InvalidCredentials(String::from("bob"))))));
+
+        fs::write(&file, format!("{}bob:$5$tb7XaBJeVZ7f1wQ3$41zf6YvqV8ARcA4f3ZO4cY8hMs.yIdkD4/hSIuQmin5\n", alice))
+            .unwrap();
+
+        assert_that!(sut.authenticate(&basic("bob:secret")), is(equal_to(Ok(String::from("bob")))));
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn dummy_hash_is_a_bcrypt_hash() {
+        assert_that!(pwhash::unix::verify("no user has this password", DUMMY_HASH), is(true));
+    }
+
+    #[test]
+    fn select_prefers_longest_prefix() {
+        let auths = vec!(
+            auth(),
+            AuthConfig::new(String::from("/reports/2018/"), String::from("2018"), String::from("htpasswd")).unwrap());
+
+        assert_that!(select(&auths, "/reports/2018/a.html").unwrap().realm().as_str(), is(equal_to("2018")));
+        assert_that!(select(&auths, "/reports/a.html").unwrap().realm().as_str(), is(equal_to("Reports")));
+        assert_that!(select(&auths, "/index.html"), is(equal_to(None)));
+    }
+}
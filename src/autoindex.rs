@@ -0,0 +1,266 @@
+///! This module renders directory listings as HTML or JSON.
+///!
+///! The HTML listing can be sorted by name, size or modification time with the query parameters
+///! `C` (`N`, `S` or `M`) and `O` (`A` for ascending or `D` for descending), like Apache does.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use time;
+use escape;
+
+/// A file or directory in a listing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl Entry {
+    /// Creates a new entry.
+    pub fn new(name: String, is_dir: bool, size: u64, modified: SystemTime) -> Entry {
+        Entry { name, is_dir, size, modified }
+    }
+}
+
+/// Column to sort the listing by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+/// Order of a sorted listing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sort {
+    key: SortKey,
+    descending: bool,
+}
+
+impl Sort {
+    /// Parses the sort order from a query string like `C=S&O=D`. Defaults to ascending by name.
+    pub fn from_query(query: &str) -> Sort {
+        let mut sort = Sort { key: SortKey::Name, descending: false };
+
+        for pair in query.split('&') {
+            match pair {
+                "C=N" => sort.key = SortKey::Name,
+                "C=S" => sort.key = SortKey::Size,
+                "C=M" => sort.key = SortKey::Modified,
+                "O=A" => sort.descending = false,
+                "O=D" => sort.descending = true,
+                _ => (),
+            }
+        }
+
+        sort
+    }
+
+    fn compare(&self, left: &Entry, right: &Entry) -> Ordering {
+        let ordering = match self.key {
+            SortKey::Name => left.name.cmp(&right.name),
+            SortKey::Size => left.size.cmp(&right.size).then_with(|| left.name.cmp(&right.name)),
+            SortKey::Modified => left.modified.cmp(&right.modified).then_with(|| left.name.cmp(&right.name)),
+        };
+
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+
+    /// Get the query string selecting the given column, toggling the order if it is the current one.
+    fn query_for(&self, key: SortKey) -> String {
+        let column = match key {
+            SortKey::Name => "N",
+            SortKey::Size => "S",
+            SortKey::Modified => "M",
+        };
+        let order = if self.key == key && !self.descending { "D" } else { "A" };
+
+        format!("?C={}&amp;O={}", column, order)
+    }
+}
+
+/// Reads the entries of a directory. Dotfiles are skipped unless `show_hidden` is set.
+pub fn read_entries(dir: &Path, show_hidden: bool) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+
+    for dir_entry in fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name().to_string_lossy().into_owned();
+
+        if name.starts_with('.') && !show_hidden {
+            continue;
+        }
+
+        // Follows symlinks so linked directories are listed as directories.
+        let metadata = match fs::metadata(dir_entry.path()) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                debug!("Skipping {:?} in listing: {}", dir_entry.path(), err);
+                continue;
+            },
+        };
+
+        entries.push(Entry::new(
+            name,
+            metadata.is_dir(),
+            metadata.len(),
+            metadata.modified().unwrap_or(UNIX_EPOCH)));
+    }
+
+    Ok(entries)
+}
+
+/// Sorts the entries with directories first.
+pub fn sort_entries(entries: &mut [Entry], sort: &Sort) {
+    entries.sort_by(|left, right| {
+        right.is_dir.cmp(&left.is_dir).then_with(|| sort.compare(left, right))
+    });
+}
+
+/// Renders the entries as HTML page for the given URL path of the directory.
+///
+/// The URL path must be decoded and end with a slash.
+pub fn render_html(url_path: &str, entries: &[Entry], sort: &Sort) -> String {
+    let title = escape::html(&format!("Index of {}", url_path));
+    let base = escape::url_encode_path(url_path);
+    let mut html = String::new();
+
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n", title, title));
+    html.push_str("<table>\n<tr>");
+    html.push_str(&format!("<th><a href=\"{}\">Name</a></th>", sort.query_for(SortKey::Name)));
+    html.push_str(&format!("<th><a href=\"{}\">Last modified</a></th>", sort.query_for(SortKey::Modified)));
+    html.push_str(&format!("<th><a href=\"{}\">Size</a></th>", sort.query_for(SortKey::Size)));
+    html.push_str("</tr>\n");
+
+    if url_path != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir { String::from("-") } else { entry.size.to_string() };
+
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            base,
+            escape::html(&escape::url_encode(&entry.name)),
+            suffix,
+            escape::html(&entry.name),
+            suffix,
+            format_time(entry.modified, "%Y-%m-%d %H:%M"),
+            size));
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Renders the entries as JSON array for the given URL path of the directory.
+pub fn render_json(url_path: &str, entries: &[Entry]) -> String {
+    let base = escape::url_encode_path(url_path);
+    let items: Vec<String> = entries.iter()
+        .map(|entry| {
+            let suffix = if entry.is_dir { "/" } else { "" };
+
+            format!(
+                "{{\"name\":{},\"type\":{},\"size\":{},\"mtime\":{},\"href\":{}}}",
+                escape::json(&entry.name),
+                escape::json(if entry.is_dir { "directory" } else { "file" }),
+                entry.size,
+                escape::json(&format_time(entry.modified, "%Y-%m-%dT%H:%M:%SZ")),
+                escape::json(&format!("{}{}{}", base, escape::url_encode(&entry.name), suffix)))
+        })
+        .collect();
+
+    format!("[{}]", items.join(","))
+}
+
+fn format_time(modified: SystemTime, format: &str) -> String {
+    let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let tm = time::at_utc(time::Timespec::new(since_epoch.as_secs() as i64, 0));
+
+    time::strftime(format, &tm).expect("Can't format date!")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+    use std::time::Duration;
+
+    fn entries() -> Vec<Entry> {
+        vec!(
+            Entry::new(String::from("b.txt"), false, 10, UNIX_EPOCH + Duration::from_secs(200)),
+            Entry::new(String::from("a <b>.txt"), false, 20, UNIX_EPOCH + Duration::from_secs(100)),
+            Entry::new(String::from("css"), true, 4096, UNIX_EPOCH + Duration::from_secs(300)))
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_sort_from_query() {
+        assert_that!(Sort::from_query(""), is(equal_to(Sort { key: SortKey::Name, descending: false })));
+        assert_that!(Sort::from_query("C=S&O=D"), is(equal_to(Sort { key: SortKey::Size, descending: true })));
+        assert_that!(Sort::from_query("O=A&C=M"), is(equal_to(Sort { key: SortKey::Modified, descending: false })));
+    }
+
+    #[test]
+    fn sort_entries_lists_directories_first() {
+        let mut sut = entries();
+
+        sort_entries(&mut sut, &Sort::from_query("C=N"));
+        assert_that!(names(&sut), is(equal_to(vec!("css", "a <b>.txt", "b.txt"))));
+
+        sort_entries(&mut sut, &Sort::from_query("C=M&O=D"));
+        assert_that!(names(&sut), is(equal_to(vec!("css", "b.txt", "a <b>.txt"))));
+
+        sort_entries(&mut sut, &Sort::from_query("C=S&O=D"));
+        assert_that!(names(&sut), is(equal_to(vec!("css", "a <b>.txt", "b.txt"))));
+    }
+
+    #[test]
+    fn read_entries_hides_dotfiles() {
+        let hidden = read_entries(Path::new("test/fixtures/autoindex"), false).unwrap();
+        let shown = read_entries(Path::new("test/fixtures/autoindex"), true).unwrap();
+
+        assert_that!(hidden.iter().any(|entry| entry.name.starts_with('.')), is(false));
+        assert_that!(shown.iter().any(|entry| entry.name == ".hidden"), is(true));
+        assert_that!(hidden.iter().any(|entry| entry.name == "sub" && entry.is_dir), is(true));
+        assert_that!(hidden.iter().any(|entry| entry.name == "a file.txt" && !entry.is_dir), is(true));
+    }
+
+    #[test]
+    fn render_html_escapes_names_and_encodes_links() {
+        let html = render_html("/my files/", &entries(), &Sort::from_query(""));
+
+        assert_that!(html.contains("<title>Index of /my files/</title>"), is(true));
+        assert_that!(
+            html.contains("<a href=\"/my%20files/a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a>"),
+            is(true));
+        assert_that!(html.contains("<a href=\"/my%20files/css/\">css/</a>"), is(true));
+        assert_that!(html.contains("<a href=\"?C=N&amp;O=D\">Name</a>"), is(true));
+        assert_that!(html.contains("<a href=\"../\">../</a>"), is(true));
+    }
+
+    #[test]
+    fn test_render_json() {
+        let json = render_json("/", &entries()[2..]);
+
+        assert_that!(
+            json,
+            is(equal_to(String::from(
+                "[{\"name\":\"css\",\"type\":\"directory\",\"size\":4096,\"mtime\":\"1970-01-01T00:05:00Z\",\"href\":\"/css/\"}]"))));
+    }
+}
@@ -0,0 +1,334 @@
+///! This module provides an in-memory cache for static files.
+///!
+///! The cache is keyed by the resource path resolved from the request URL and holds the file
+///! content together with its metadata, MIME type and ETag. It is bounded by a memory budget and
+///! evicts the least recently used entries first. Each lookup revalidates the entry against the
+///! modification time and size of the file on disk, so changed files are never served stale.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Configuration of the static file cache.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct CacheConfig {
+    /// Maximum number of bytes of file content held in memory.
+    /// Must not be zero.
+    max_bytes: usize,
+}
+
+impl CacheConfig {
+    /// Creates a new cache configuration.
+    pub fn new(max_bytes: usize) -> Result<CacheConfig, &'static str> {
+        if max_bytes < 1 {
+            return Err("Config value 'cache.max_bytes' must be grater than 0!");
+        }
+
+        Ok(CacheConfig { max_bytes })
+    }
+
+    /// Get the memory budget in bytes.
+    pub fn max_bytes(&self) -> &usize {
+        &self.max_bytes
+    }
+}
+
+/// A file held by the cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedFile {
+    /// The file on disk the content was read from.
+    path: PathBuf,
+    /// The content of the file.
+    content: Arc<Vec<u8>>,
+    /// Modification time of the file when it was read.
+    modified: SystemTime,
+    /// Size of the file when it was read.
+    len: u64,
+    /// MIME type used for the `Content-Type` header.
+    content_type: String,
+    /// Entity tag derived from size and modification time.
+    etag: String,
+}
+
+impl CachedFile {
+    /// Creates a cache entry for a file already read from disk.
+    pub fn new(path: PathBuf, content: Vec<u8>, modified: SystemTime, content_type: String) -> CachedFile {
+        let len = content.len() as u64;
+        let etag = create_etag(len, modified);
+
+        CachedFile { path, content: Arc::new(content), modified, len, content_type, etag }
+    }
+
+    /// Get the file on disk the content was read from.
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Get the content of the file, shared with the cache.
+    pub fn content(&self) -> &Arc<Vec<u8>> {
+        &self.content
+    }
+
+    /// Get the modification time of the file.
+    pub fn modified(&self) -> &SystemTime {
+        &self.modified
+    }
+
+    /// Get the MIME type of the file.
+    pub fn content_type(&self) -> &String {
+        &self.content_type
+    }
+
+    /// Get the entity tag of the file.
+    pub fn etag(&self) -> &String {
+        &self.etag
+    }
+
+    fn is_fresh(&self) -> bool {
+        match fs::metadata(&self.path) {
+            Ok(metadata) => {
+                metadata.is_file()
+                    && metadata.len() == self.len
+                    && metadata.modified().ok() == Some(self.modified)
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+/// Creates an entity tag from the size and modification time of a file.
+pub fn create_etag(len: u64, modified: SystemTime) -> String {
+    let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("\"{:x}-{:x}-{:x}\"", len, since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+struct Entry {
+    file: CachedFile,
+    last_used: u64,
+}
+
+struct Entries {
+    map: HashMap<PathBuf, Entry>,
+    /// Keys of the entries by their last use, least recently used first.
+    order: BTreeMap<u64, PathBuf>,
+    size: usize,
+    clock: u64,
+}
+
+impl Entries {
+    /// Removes the entry for the given key, if any.
+    fn remove(&mut self, key: &Path) {
+        if let Some(old) = self.map.remove(key) {
+            self.order.remove(&old.last_used);
+            self.size -= old.file.content.len();
+        }
+    }
+}
+
+/// Size bounded cache of static files with LRU eviction.
+pub struct FileCache {
+    max_bytes: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl FileCache {
+    /// Creates an empty cache which holds at most `max_bytes` of file content.
+    pub fn new(max_bytes: usize) -> FileCache {
+        FileCache {
+            max_bytes,
+            entries: Mutex::new(Entries { map: HashMap::new(), order: BTreeMap::new(), size: 0, clock: 0 }),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Looks up the file cached for the given resource path.
+    ///
+    /// Entries whose file changed on disk since they were read are dropped and reported as miss.
+    pub fn get(&self, key: &Path) -> Option<CachedFile> {
+        let file = {
+            let mut entries = self.entries.lock().unwrap();
+            let entries = &mut *entries;
+            entries.clock += 1;
+            let clock = entries.clock;
+
+            match entries.map.get_mut(key) {
+                Some(entry) => {
+                    entries.order.remove(&entry.last_used);
+                    entries.order.insert(clock, key.to_path_buf());
+                    entry.last_used = clock;
+                    Some(entry.file.clone())
+                },
+                None => None,
+            }
+        };
+
+        match file {
+            Some(ref file) if file.is_fresh() => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                debug!("Cache hit for {:?}.", key);
+                Some(file.clone())
+            },
+            Some(_) => {
+                debug!("Cache entry for {:?} is stale.", key);
+                self.remove(key);
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            },
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            },
+        }
+    }
+
+    /// Stores a file for the given resource path, evicting least recently used entries if necessary.
+    ///
+    /// Files larger than the whole memory budget are not cached at all.
+    pub fn insert(&self, key: PathBuf, file: CachedFile) {
+        let file_size = file.content.len();
+
+        if file_size > self.max_bytes {
+            debug!("File {:?} is too big to be cached.", file.path);
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+
+        while entries.size + file_size > self.max_bytes {
+            match entries.order.first_key_value().map(|(_, oldest)| oldest.clone()) {
+                Some(oldest) => {
+                    debug!("Evicting {:?} from cache.", oldest);
+                    entries.remove(&oldest);
+                },
+                None => break,
+            }
+        }
+
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries.size += file_size;
+        entries.order.insert(last_used, key.clone());
+        entries.map.insert(key, Entry { file, last_used });
+    }
+
+    /// Removes the entry for the given resource path.
+    pub fn remove(&self, key: &Path) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    /// Get the number of lookups served from the cache.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of lookups not served from the cache.
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of bytes of file content currently held.
+    pub fn size(&self) -> usize {
+        self.entries.lock().unwrap().size
+    }
+
+    /// Get the number of cached files.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    /// Whether the cache holds no files.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+
+    /// Writes a file with the given content into a directory of the test and reads it like the server does.
+    fn fixture(test: &str, content: &str) -> CachedFile {
+        let dir = fixture_dir(test);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(content);
+        fs::write(&path, content).unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+
+        CachedFile::new(path, content.as_bytes().to_vec(), modified, String::from("text/plain"))
+    }
+
+    fn fixture_dir(test: &str) -> PathBuf {
+        ::std::env::temp_dir().join(format!("webserver-cache-{}-{}", ::std::process::id(), test))
+    }
+
+    #[test]
+    fn new_config_validates_max_bytes_not_less_than_one() {
+        assert_that!(CacheConfig::new(0), is(equal_to(Err("Config value 'cache.max_bytes' must be grater than 0!"))));
+    }
+
+    #[test]
+    fn get_counts_hits_and_misses() {
+        let sut = FileCache::new(100);
+
+        assert_that!(sut.get(Path::new("/hello.txt")), is(equal_to(None)));
+        sut.insert(PathBuf::from("/hello.txt"), fixture("hits", "hello"));
+        assert_that!(sut.get(Path::new("/hello.txt")).is_some(), is(true));
+
+        assert_that!(sut.hits(), is(equal_to(1)));
+        assert_that!(sut.misses(), is(equal_to(1)));
+        fs::remove_dir_all(fixture_dir("hits")).unwrap();
+    }
+
+    #[test]
+    fn insert_evicts_least_recently_used() {
+        let sut = FileCache::new(10);
+        sut.insert(PathBuf::from("/a"), fixture("lru", "aaaa"));
+        sut.insert(PathBuf::from("/b"), fixture("lru", "bbbb"));
+        sut.get(Path::new("/a"));
+
+        sut.insert(PathBuf::from("/c"), fixture("lru", "cccc"));
+
+        assert_that!(sut.len(), is(equal_to(2)));
+        assert_that!(sut.size(), is(equal_to(8)));
+        assert_that!(sut.get(Path::new("/a")).is_some(), is(true));
+        assert_that!(sut.get(Path::new("/b")), is(equal_to(None)));
+        assert_that!(sut.get(Path::new("/c")).is_some(), is(true));
+        fs::remove_dir_all(fixture_dir("lru")).unwrap();
+    }
+
+    #[test]
+    fn insert_ignores_files_bigger_than_budget() {
+        let sut = FileCache::new(3);
+
+        sut.insert(PathBuf::from("/a"), fixture("big", "aaaa"));
+
+        assert_that!(sut.is_empty(), is(true));
+        fs::remove_dir_all(fixture_dir("big")).unwrap();
+    }
+
+    #[test]
+    fn get_drops_stale_entries() {
+        let sut = FileCache::new(100);
+        let mut file = fixture("stale", "hello");
+        file.modified = UNIX_EPOCH;
+        sut.insert(PathBuf::from("/hello.txt"), file);
+
+        assert_that!(sut.get(Path::new("/hello.txt")), is(equal_to(None)));
+        assert_that!(sut.is_empty(), is(true));
+        fs::remove_dir_all(fixture_dir("stale")).unwrap();
+    }
+
+    #[test]
+    fn test_create_etag() {
+        assert_that!(create_etag(13, UNIX_EPOCH), is(equal_to(String::from("\"d-0-0\""))));
+    }
+}
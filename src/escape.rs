@@ -0,0 +1,155 @@
+///! This module provides helpers to escape strings for HTML, JSON and URLs.
+
+/// Escapes a string to be embedded in HTML text or attribute values.
+pub fn html(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+
+    for ch in input.chars() {
+        match ch {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&#39;"),
+            _ => output.push(ch),
+        }
+    }
+
+    output
+}
+
+/// Renders a string as quoted JSON string literal.
+pub fn json(input: &str) -> String {
+    let mut output = String::with_capacity(input.len() + 2);
+    output.push('"');
+
+    for ch in input.chars() {
+        match ch {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", ch as u32)),
+            _ => output.push(ch),
+        }
+    }
+
+    output.push('"');
+    output
+}
+
+/// Percent encodes a single path segment as described in [RFC 3986](https://tools.ietf.org/html/rfc3986#section-2.1).
+///
+/// Only unreserved characters are kept as they are, so slashes are encoded too.
+pub fn url_encode(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => output.push(byte as char),
+            _ => output.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    output
+}
+
+/// Percent encodes each segment of a slash separated path.
+pub fn url_encode_path(input: &str) -> String {
+    input.split('/')
+        .map(url_encode)
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Percent encodes all characters not allowed in a URL, but keeps reserved characters like `/`, `:` or `?`.
+///
+/// The percent sign itself is encoded as well, so the input is expected to be decoded.
+pub fn url_escape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => output.push(byte as char),
+            b':' | b'/' | b'?' | b'#' | b'[' | b']' | b'@' => output.push(byte as char),
+            b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' => output.push(byte as char),
+            _ => output.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    output
+}
+
+/// Decodes percent encoded characters. Invalid escape sequences are kept as they are.
+pub fn url_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut output: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let high = (bytes[i + 1] as char).to_digit(16);
+            let low = (bytes[i + 2] as char).to_digit(16);
+
+            if let (Some(high), Some(low)) = (high, low) {
+                output.push((high * 16 + low) as u8);
+                i += 3;
+                continue;
+            }
+        }
+
+        output.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+
+    #[test]
+    fn test_html() {
+        assert_that!(
+            html("<a href=\"x\">Tom & Jerry's</a>"),
+            is(equal_to(String::from("&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"))));
+    }
+
+    #[test]
+    fn test_json() {
+        assert_that!(json("foo"), is(equal_to(String::from("\"foo\""))));
+        assert_that!(
+            json("say \"hi\"\\\n\t\u{1}"),
+            is(equal_to(String::from("\"say \\\"hi\\\"\\\\\\n\\t\\u0001\""))));
+    }
+
+    #[test]
+    fn test_url_encode() {
+        assert_that!(url_encode("hello world.html"), is(equal_to(String::from("hello%20world.html"))));
+        assert_that!(url_encode("a/b?c#d%"), is(equal_to(String::from("a%2Fb%3Fc%23d%25"))));
+        assert_that!(url_encode("ä"), is(equal_to(String::from("%C3%A4"))));
+    }
+
+    #[test]
+    fn test_url_encode_path() {
+        assert_that!(url_encode_path("/foo bar/baz/"), is(equal_to(String::from("/foo%20bar/baz/"))));
+    }
+
+    #[test]
+    fn test_url_escape() {
+        assert_that!(
+            url_escape("https://example.com/a b/100%/ä?x=1&y=2"),
+            is(equal_to(String::from("https://example.com/a%20b/100%25/%C3%A4?x=1&y=2"))));
+    }
+
+    #[test]
+    fn test_url_decode() {
+        assert_that!(url_decode("hello%20world.html"), is(equal_to(String::from("hello world.html"))));
+        assert_that!(url_decode("%C3%A4%2f"), is(equal_to(String::from("ä/"))));
+        assert_that!(url_decode("100%"), is(equal_to(String::from("100%"))));
+        assert_that!(url_decode("%zz%4"), is(equal_to(String::from("%zz%4"))));
+    }
+}
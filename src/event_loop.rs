@@ -0,0 +1,742 @@
+///! This module provides an event loop holding plain HTTP connections without occupying workers.
+///!
+///! With an `[event_loop]` section, the acceptors hand plain HTTP connections to one thread waiting for all of
+///! them with epoll (kqueue on BSD). It reads without blocking until a request is complete and only then
+///! hands it to a worker, which answers it into a buffer. The event loop writes the response without blocking
+///! and keeps the connection open for the next request if the client wants it and the response tells its
+///! length. So idle keep-alive and slow clients cost a few bytes instead of a worker each. HTTPS connections
+///! are still answered by a worker each. Every quarter of a second the event loop closes the connections which
+///! ran into one of the `[timeouts]`.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{Cursor, Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::unix::SourceFd;
+use listen::Stream;
+use metrics::Metrics;
+use timeout;
+use timeout::{Timed, Timeout, TimeoutsConfig, Timer};
+
+/// Maximum size of a request, like a worker reads at once.
+pub static MAX_REQUEST: usize = 4096;
+
+/// Token of the waker, connections are numbered from 1 on.
+const WAKER: Token = Token(0);
+
+/// How often the connections are checked for expired timeouts.
+const TICK: Duration = Duration::from_millis(250);
+
+fn default_max_connections() -> usize {
+    10_000
+}
+
+fn default_keep_alive_requests() -> usize {
+    100
+}
+
+/// Configuration of the event loop.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct EventLoopConfig {
+    /// Maximum number of open connections, further ones are closed right away.
+    #[serde(default = "default_max_connections")]
+    max_connections: usize,
+    /// Maximum number of requests answered on one connection before it's closed.
+    #[serde(default = "default_keep_alive_requests")]
+    keep_alive_requests: usize,
+}
+
+impl EventLoopConfig {
+    /// Creates a new configuration with the given maximum of open connections.
+    pub fn new(max_connections: usize) -> Result<EventLoopConfig, &'static str> {
+        if max_connections < 1 {
+            return Err("Config value 'event_loop.max_connections' must be greater than 0!");
+        }
+
+        Ok(EventLoopConfig { max_connections, keep_alive_requests: default_keep_alive_requests() })
+    }
+
+    /// Sets the maximum number of requests answered on one connection.
+    pub fn with_keep_alive_requests(mut self, keep_alive_requests: usize) -> Result<EventLoopConfig, &'static str> {
+        if keep_alive_requests < 1 {
+            return Err("Config value 'event_loop.keep_alive_requests' must be greater than 0!");
+        }
+
+        self.keep_alive_requests = keep_alive_requests;
+        Ok(self)
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<EventLoopConfig, &'static str> {
+        EventLoopConfig::new(self.max_connections)?.with_keep_alive_requests(self.keep_alive_requests)
+    }
+
+    /// Get the maximum number of open connections.
+    pub fn max_connections(&self) -> &usize {
+        &self.max_connections
+    }
+
+    /// Get the maximum number of requests answered on one connection.
+    pub fn keep_alive_requests(&self) -> &usize {
+        &self.keep_alive_requests
+    }
+}
+
+/// Answers a request: gets its bytes, the address of the client and whether the connection may stay open.
+/// Returns the bytes of the response and whether the connection stays open.
+pub type Handler = Arc<dyn Fn(Vec<u8>, Option<SocketAddr>, bool) -> (Vec<u8>, bool) + Send + Sync>;
+
+/// A job run in the background.
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Runs jobs in the background, e.g. on a worker pool. If it can't, it gives back the response to send instead,
+/// which is empty to just close the connection.
+pub type Executor = Arc<dyn Fn(Job) -> Result<(), Vec<u8>> + Send + Sync>;
+
+enum Command {
+    Register(Stream, Handler),
+    Completed(usize, Vec<u8>, bool),
+    Shutdown,
+}
+
+/// Handle of the event loop thread, shared by the acceptors.
+#[derive(Clone)]
+pub struct EventLoop {
+    sender: mpsc::Sender<Command>,
+    waker: Arc<Waker>,
+    /// Number of open connections.
+    open: Arc<AtomicUsize>,
+    thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+}
+
+impl EventLoop {
+    /// Starts the event loop thread, answering requests by jobs given to the executor. Expired timeouts are
+    /// counted by the metrics.
+    pub fn start(config: EventLoopConfig, timeouts: TimeoutsConfig, metrics: Arc<Metrics>, executor: Executor)
+        -> io::Result<EventLoop> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (sender, receiver) = mpsc::channel();
+        let open = Arc::new(AtomicUsize::new(0));
+        let reactor = Reactor {
+            poll,
+            config,
+            timeouts,
+            metrics,
+            executor,
+            sender: sender.clone(),
+            receiver,
+            waker: Arc::clone(&waker),
+            connections: HashMap::new(),
+            next_token: 1,
+            shutting_down: false,
+            open: Arc::clone(&open),
+            next_expiry: Instant::now() + TICK,
+        };
+        let thread = thread::Builder::new()
+            .name(String::from("event-loop"))
+            .spawn(move || reactor.run())?;
+
+        Ok(EventLoop { sender, waker, open, thread: Arc::new(Mutex::new(Some(thread))) })
+    }
+
+    /// Hands a connection to the event loop, answering its requests by the handler.
+    pub fn register(&self, stream: Stream, handler: Handler) {
+        self.send(Command::Register(stream, handler));
+    }
+
+    /// Get the number of open connections.
+    pub fn open_connections(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    /// Closes idle connections, finishes the others without keeping them open and waits until all are closed.
+    pub fn shutdown(&self) {
+        self.send(Command::Shutdown);
+
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+
+    fn send(&self, command: Command) {
+        if self.sender.send(command).is_err() {
+            warn!("Event loop is stopped.");
+            return;
+        }
+
+        if let Err(err) = self.waker.wake() {
+            error!("Can't wake event loop: {}", err);
+        }
+    }
+}
+
+/// What a connection waits for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// The rest of a request.
+    Reading,
+    /// A worker answering the request.
+    Answering,
+    /// The client to take the response.
+    Writing,
+}
+
+struct Connection {
+    stream: Stream,
+    handler: Handler,
+    remote_addr: Option<SocketAddr>,
+    state: State,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    /// Whether the connection stays open after the response.
+    keep_alive: bool,
+    /// Whether the client closed its side.
+    eof: bool,
+    /// Number of requests answered.
+    requests: usize,
+    /// What the connection waits for and until when, none while a worker answers.
+    timer: Option<Timer>,
+}
+
+/// The event loop thread.
+struct Reactor {
+    poll: Poll,
+    config: EventLoopConfig,
+    timeouts: TimeoutsConfig,
+    metrics: Arc<Metrics>,
+    executor: Executor,
+    sender: mpsc::Sender<Command>,
+    receiver: mpsc::Receiver<Command>,
+    waker: Arc<Waker>,
+    connections: HashMap<usize, Connection>,
+    next_token: usize,
+    shutting_down: bool,
+    open: Arc<AtomicUsize>,
+    /// When the connections are checked for expired timeouts next.
+    next_expiry: Instant,
+}
+
+impl Reactor {
+    fn run(mut self) {
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            let wait = self.next_expiry.saturating_duration_since(Instant::now());
+
+            if let Err(err) = self.poll.poll(&mut events, Some(wait)) {
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+
+                error!("Event loop failed: {}", err);
+                return;
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => self.receive(),
+                    Token(token) => {
+                        if event.is_readable() || event.is_read_closed() {
+                            self.read(token);
+                        }
+
+                        if event.is_writable() || event.is_write_closed() {
+                            self.write(token);
+                        }
+                    },
+                }
+            }
+
+            let now = Instant::now();
+
+            if now >= self.next_expiry {
+                self.expire(now);
+                self.next_expiry = now + TICK;
+            }
+
+            if self.shutting_down && self.connections.is_empty() {
+                info!("Event loop closed all connections.");
+                return;
+            }
+        }
+    }
+
+    fn receive(&mut self) {
+        while let Ok(command) = self.receiver.try_recv() {
+            match command {
+                Command::Register(stream, handler) => self.register(stream, handler),
+                Command::Completed(token, output, keep_alive) => {
+                    if let Some(connection) = self.connections.get_mut(&token) {
+                        connection.state = State::Writing;
+                        connection.output = output;
+                        connection.written = 0;
+                        connection.keep_alive = keep_alive;
+                        connection.timer = Some(Timer::write(&self.timeouts, Instant::now()));
+                    }
+
+                    self.write(token);
+                },
+                Command::Shutdown => {
+                    self.shutting_down = true;
+                    let idle: Vec<usize> = self.connections.iter()
+                        .filter(|(_, connection)| connection.state == State::Reading)
+                        .map(|(token, _)| *token)
+                        .collect();
+
+                    for token in idle {
+                        self.close(token);
+                    }
+                },
+            }
+        }
+    }
+
+    fn register(&mut self, stream: Stream, handler: Handler) {
+        if self.shutting_down {
+            return;
+        }
+
+        if self.connections.len() >= self.config.max_connections {
+            warn!("Closing connection from {:?}, {} connections are open.", stream.peer_addr(), self.connections.len());
+            return;
+        }
+
+        let token = self.next_token;
+        self.next_token += 1;
+        let registered = stream.set_nonblocking(true).and_then(|_| {
+            self.poll.registry().register(&mut SourceFd(&stream.as_raw_fd()), Token(token),
+                                          Interest::READABLE | Interest::WRITABLE)
+        });
+
+        if let Err(err) = registered {
+            warn!("Can't register connection from {:?}: {}", stream.peer_addr(), err);
+            return;
+        }
+
+        self.connections.insert(token, Connection {
+            remote_addr: stream.peer_addr(),
+            stream,
+            handler,
+            state: State::Reading,
+            input: Vec::new(),
+            output: Vec::new(),
+            written: 0,
+            keep_alive: false,
+            eof: false,
+            requests: 0,
+            timer: Some(Timer::header(&self.timeouts, Instant::now())),
+        });
+        self.open.store(self.connections.len(), Ordering::SeqCst);
+        self.read(token);
+    }
+
+    /// Reads what the client sent until it would block, then answers a complete request.
+    fn read(&mut self, token: usize) {
+        let timeouts = &self.timeouts;
+        let failed = match self.connections.get_mut(&token) {
+            Some(connection) if connection.state == State::Reading => {
+                let before = connection.input.len();
+                let failed = read_available(connection).err();
+                track_read(connection, before, timeouts, Instant::now());
+                failed
+            },
+            _ => return,
+        };
+
+        match failed {
+            Some(err) => {
+                debug!("Can't read from connection: {}", err);
+                self.close(token);
+            },
+            None => self.answer(token),
+        }
+    }
+
+    /// Hands a complete request to a worker. Closes the connection if the client closed it without one.
+    fn answer(&mut self, token: usize) {
+        let shutting_down = self.shutting_down;
+        let keep_alive_requests = self.config.keep_alive_requests;
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        let (request, complete) = match request_len(&connection.input) {
+            Some(len) if len <= connection.input.len() => (connection.input.drain(..len).collect(), true),
+            _ if connection.input.len() >= MAX_REQUEST || (connection.eof && !connection.input.is_empty()) =>
+                (mem::take(&mut connection.input), false),
+            _ if connection.eof => return self.close(token),
+            _ => return,
+        };
+        let keep_alive = complete && !connection.eof && !shutting_down && connection.requests + 1 < keep_alive_requests;
+        let handler = Arc::clone(&connection.handler);
+        let remote_addr = connection.remote_addr;
+        let sender = self.sender.clone();
+        let waker = Arc::clone(&self.waker);
+        connection.state = State::Answering;
+        connection.timer = None;
+
+        let executed = (self.executor)(Box::new(move || {
+            let (output, keep_alive) = panic::catch_unwind(AssertUnwindSafe(|| handler(request, remote_addr, keep_alive)))
+                .unwrap_or_else(|_| {
+                    error!("Answering a request from {:?} panicked.", remote_addr);
+                    (Vec::new(), false)
+                });
+
+            if sender.send(Command::Completed(token, output, keep_alive)).is_ok() {
+                let _ = waker.wake();
+            }
+        }));
+
+        // The workers are overloaded, so the connection gets the response given back and is closed.
+        if let Err(output) = executed {
+            connection.state = State::Writing;
+            connection.output = output;
+            connection.written = 0;
+            connection.keep_alive = false;
+            connection.timer = Some(Timer::write(&self.timeouts, Instant::now()));
+            self.write(token);
+        }
+    }
+
+    /// Writes the response until it would block. Once written, waits for the next request or closes.
+    fn write(&mut self, token: usize) {
+        let shutting_down = self.shutting_down;
+        let timeouts = &self.timeouts;
+        let written = match self.connections.get_mut(&token) {
+            Some(connection) if connection.state == State::Writing => write_available(connection),
+            _ => return,
+        };
+
+        match written {
+            Ok(false) => (),
+            Ok(true) => {
+                let connection = self.connections.get_mut(&token).expect("Connection was written to!");
+
+                if connection.keep_alive && !shutting_down {
+                    let now = Instant::now();
+                    connection.state = State::Reading;
+                    connection.output = Vec::new();
+                    connection.requests += 1;
+                    connection.timer = Some(if connection.input.is_empty() {
+                        Timer::keep_alive(timeouts, now)
+                    } else {
+                        Timer::header(timeouts, now)
+                    });
+                    self.read(token);
+                } else {
+                    self.close(token);
+                }
+            },
+            Err(err) => {
+                debug!("Can't write to connection: {}", err);
+                self.close(token);
+            },
+        }
+    }
+
+    /// Closes the connections which ran into a timeout.
+    fn expire(&mut self, now: Instant) {
+        let expired: Vec<(usize, Timeout)> = self.connections.iter()
+            .filter_map(|(token, connection)| connection.timer.as_ref().map(|timer| (*token, timer.deadline())))
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(token, (_, timeout))| (token, timeout))
+            .collect();
+
+        for (token, timeout) in expired {
+            self.time_out(token, timeout, now);
+        }
+    }
+
+    /// Answers a partly received request by `408 Request Timeout`, closes the connection otherwise.
+    fn time_out(&mut self, token: usize, timeout: Timeout, now: Instant) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        timeout::record(&self.metrics, timeout, connection.remote_addr);
+
+        if connection.state == State::Reading && !connection.input.is_empty() {
+            connection.state = State::Writing;
+            connection.output = timeout::request_timeout_response();
+            connection.written = 0;
+            connection.keep_alive = false;
+            connection.timer = Some(Timer::write(&self.timeouts, now));
+            self.write(token);
+        } else {
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: usize) {
+        if let Some(connection) = self.connections.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut SourceFd(&connection.stream.as_raw_fd()));
+        }
+
+        self.open.store(self.connections.len(), Ordering::SeqCst);
+    }
+}
+
+/// Reads into the input of the connection until it would block, the client closed it or a request is at most
+/// this long.
+fn read_available(connection: &mut Connection) -> io::Result<()> {
+    let mut chunk = [0; 4096];
+
+    while connection.input.len() < MAX_REQUEST && !connection.eof {
+        let max = MAX_REQUEST - connection.input.len();
+
+        match connection.stream.read(&mut chunk[..max]) {
+            Ok(0) => connection.eof = true,
+            Ok(len) => connection.input.extend_from_slice(&chunk[..len]),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Restarts the timer of a connection for the header once the next request begins, or for the body once the
+/// header is complete, and records the bytes read.
+fn track_read(connection: &mut Connection, before: usize, timeouts: &TimeoutsConfig, now: Instant) {
+    let read = connection.input.len() - before;
+    let timer = match connection.timer {
+        Some(ref mut timer) if read > 0 => timer,
+        _ => return,
+    };
+
+    if timer.timeout() == Timeout::KeepAlive {
+        *timer = Timer::header(timeouts, now);
+    }
+
+    if timer.timeout() == Timeout::Header && request_len(&connection.input).is_some() {
+        *timer = Timer::body(timeouts, now);
+    } else {
+        timer.progress(read, now);
+    }
+}
+
+/// Writes the output of the connection until it would block. Returns whether all is written.
+fn write_available(connection: &mut Connection) -> io::Result<bool> {
+    while connection.written < connection.output.len() {
+        match connection.stream.write(&connection.output[connection.written..]) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+            Ok(len) => {
+                connection.written += len;
+
+                if let Some(ref mut timer) = connection.timer {
+                    timer.progress(len, Instant::now());
+                }
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Get the length of the first request in the input, header and body as told by `Content-Length`, once the
+/// header is complete.
+pub fn request_len(input: &[u8]) -> Option<usize> {
+    let header_len = input.windows(4).position(|window| window == b"\r\n\r\n")? + 4;
+    let header = String::from_utf8_lossy(&input[..header_len]);
+    let content_length = header.lines()
+        .filter_map(|line| line.find(':').map(|colon| (&line[..colon], &line[colon + 1..])))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+
+    Some(header_len + content_length)
+}
+
+/// A request read by the event loop and the response written by a worker, both in memory.
+pub struct BufferedStream {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl BufferedStream {
+    /// Creates a stream reading the given request.
+    pub fn new(input: Vec<u8>) -> BufferedStream {
+        BufferedStream { input: Cursor::new(input), output: Vec::new() }
+    }
+
+    /// Get what was written.
+    pub fn into_output(self) -> Vec<u8> {
+        self.output
+    }
+}
+
+/// Reading and writing memory doesn't block.
+impl Timed for BufferedStream {
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for BufferedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for BufferedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+    use std::net::{TcpListener, TcpStream};
+    use threads::ThreadPool;
+
+    fn start(max_connections: usize, keep_alive_requests: usize) -> EventLoop {
+        start_with_metrics(max_connections, keep_alive_requests, TimeoutsConfig::default(), Arc::new(Metrics::new()))
+    }
+
+    fn start_with_metrics(max_connections: usize, keep_alive_requests: usize, timeouts: TimeoutsConfig,
+                          metrics: Arc<Metrics>) -> EventLoop {
+        let pool = Arc::new(ThreadPool::new(1));
+        let config = EventLoopConfig::new(max_connections).unwrap()
+            .with_keep_alive_requests(keep_alive_requests).unwrap();
+
+        let executor = Arc::new(move |job: Job| pool.execute(job).map_err(|_| b"HTTP/1.1 503\r\n\r\n".to_vec()));
+
+        EventLoop::start(config, timeouts, metrics, executor).unwrap()
+    }
+
+    fn echo() -> Handler {
+        Arc::new(|request: Vec<u8>, _, keep_alive| {
+            let body = String::from_utf8_lossy(&request).lines().next().unwrap_or("").to_string();
+            (format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).into_bytes(), keep_alive)
+        })
+    }
+
+    fn connect(sut: &EventLoop, listener: &TcpListener) -> TcpStream {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        sut.register(Stream::Tcp(listener.accept().unwrap().0), echo());
+        client
+    }
+
+    fn read_response(client: &mut TcpStream, body: &str) -> String {
+        let expected = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let mut buf = vec!(0; expected.len());
+        client.read_exact(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn request_len_includes_body() {
+        assert_that!(request_len(b"GET / HTTP/1.1\r\nHost: a"), is(equal_to(None)));
+        assert_that!(request_len(b"GET / HTTP/1.1\r\nHost: a\r\n\r\nGET"), is(equal_to(Some(27))));
+        assert_that!(request_len(b"POST / HTTP/1.1\r\ncontent-length: 3\r\n\r\nab"), is(equal_to(Some(41))));
+    }
+
+    #[test]
+    fn config_validates_limits() {
+        assert_that!(EventLoopConfig::new(0),
+            is(equal_to(Err("Config value 'event_loop.max_connections' must be greater than 0!"))));
+        assert_that!(EventLoopConfig::new(1).unwrap().with_keep_alive_requests(0),
+            is(equal_to(Err("Config value 'event_loop.keep_alive_requests' must be greater than 0!"))));
+    }
+
+    #[test]
+    fn answers_pipelined_requests_on_kept_connection() {
+        let sut = start(10, 100);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = connect(&sut, &listener);
+
+        client.write_all(b"GET /a HTTP/1.1\r\nHo").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(b"st: a\r\n\r\nGET /b HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_that!(read_response(&mut client, "GET /a HTTP/1.1").ends_with("GET /a HTTP/1.1"), is(true));
+        assert_that!(read_response(&mut client, "GET /b HTTP/1.1").ends_with("GET /b HTTP/1.1"), is(true));
+        sut.shutdown();
+    }
+
+    #[test]
+    fn holds_idle_connections_without_workers() {
+        let sut = start(100, 100);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let idle: Vec<TcpStream> = (0..50).map(|_| connect(&sut, &listener)).collect();
+        let mut client = connect(&sut, &listener);
+
+        client.write_all(b"GET /c HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_that!(read_response(&mut client, "GET /c HTTP/1.1").ends_with("GET /c HTTP/1.1"), is(true));
+        assert_that!(sut.open_connections(), is(equal_to(51)));
+        drop(idle);
+        sut.shutdown();
+    }
+
+    #[test]
+    fn closes_after_keep_alive_requests_and_on_shutdown() {
+        let sut = start(10, 1);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = connect(&sut, &listener);
+        let mut idle = connect(&sut, &listener);
+        let mut rest = Vec::new();
+
+        client.write_all(b"GET /d HTTP/1.1\r\n\r\n").unwrap();
+        read_response(&mut client, "GET /d HTTP/1.1");
+        client.read_to_end(&mut rest).unwrap();
+        assert_that!(rest.is_empty(), is(true));
+
+        sut.shutdown();
+        idle.read_to_end(&mut rest).unwrap();
+        assert_that!(rest.is_empty(), is(true));
+        assert_that!(sut.open_connections(), is(equal_to(0)));
+    }
+
+    #[test]
+    fn closes_idle_connections_and_answers_incomplete_requests_after_timeouts() {
+        let metrics = Arc::new(Metrics::new());
+        let timeouts = TimeoutsConfig::new(1, 1, 1, 1).unwrap();
+        let sut = start_with_metrics(10, 100, timeouts, Arc::clone(&metrics));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut kept = connect(&sut, &listener);
+        let mut slow = connect(&sut, &listener);
+        let mut rest = Vec::new();
+
+        kept.write_all(b"GET /e HTTP/1.1\r\n\r\n").unwrap();
+        read_response(&mut kept, "GET /e HTTP/1.1");
+        slow.write_all(b"GET /f HTTP/1.1\r\nHo").unwrap();
+
+        kept.read_to_end(&mut rest).unwrap();
+        assert_that!(rest.is_empty(), is(true));
+        slow.read_to_end(&mut rest).unwrap();
+        assert_that!(String::from_utf8(rest).unwrap().starts_with("HTTP/1.1 408 REQUEST TIMEOUT\r\n"), is(true));
+        assert_that!(metrics.timeouts(Timeout::KeepAlive), is(equal_to(1)));
+        assert_that!(metrics.timeouts(Timeout::Header), is(equal_to(1)));
+        sut.shutdown();
+    }
+}
@@ -1,6 +1,10 @@
 ///! This module provides abstractions to deal with HTTP requests and responses.
 
 use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use escape;
+use tls::ClientCertificate;
 
 /// Used HTTP version.
 pub static VERSION: &'static str = "1.1";
@@ -26,6 +30,15 @@ pub struct Request {
     connection: String,
     referer: String,
     cache_control: String,
+    authorization: String,
+    /// Address of the client, if connected via IP.
+    remote_addr: Option<SocketAddr>,
+    /// Unique id of the request used to correlate log entries.
+    id: String,
+    /// Server name the client asked for by TLS SNI, if received via TLS.
+    server_name: Option<String>,
+    /// Verified certificate the client authenticated with via TLS.
+    client_cert: Option<ClientCertificate>,
 }
 
 impl Request {
@@ -38,6 +51,146 @@ impl Request {
     pub fn url(&self) -> &String {
         &self.url
     }
+
+    /// Get the decoded path of the requested URL without query string, resolved by `resolve_path`.
+    pub fn path(&self) -> String {
+        match self.url.find('?') {
+            Some(question_mark) => resolve_path(&self.url[..question_mark]),
+            None => resolve_path(&self.url),
+        }
+    }
+
+    /// Get the query string of the requested URL without leading question mark.
+    pub fn query(&self) -> &str {
+        match self.url.find('?') {
+            Some(question_mark) => &self.url[question_mark + 1..],
+            None => "",
+        }
+    }
+
+    /// Creates a copy of this request with the URL replaced, e.g. by a rewrite rule.
+    pub fn with_url(&self, url: String) -> Request {
+        let mut request = self.clone();
+        request.url = url;
+        request
+    }
+
+    /// Sets the address of the client the request was received from.
+    pub fn with_remote_addr(mut self, remote_addr: Option<SocketAddr>) -> Request {
+        self.remote_addr = remote_addr;
+        self
+    }
+
+    /// Get the address of the client, if connected via IP.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// Sets the server name the client asked for by TLS SNI.
+    pub fn with_server_name(mut self, server_name: Option<String>) -> Request {
+        self.server_name = server_name;
+        self
+    }
+
+    /// Get the server name the client asked for by TLS SNI, if any.
+    pub fn server_name(&self) -> &Option<String> {
+        &self.server_name
+    }
+
+    /// Sets the verified certificate the client authenticated with.
+    pub fn with_client_cert(mut self, client_cert: Option<ClientCertificate>) -> Request {
+        self.client_cert = client_cert;
+        self
+    }
+
+    /// Get the verified certificate the client authenticated with, if any.
+    pub fn client_cert(&self) -> &Option<ClientCertificate> {
+        &self.client_cert
+    }
+
+    /// Sets the unique id of the request.
+    pub fn with_id(mut self, id: String) -> Request {
+        self.id = id;
+        self
+    }
+
+    /// Get the unique id of the request, empty if none was assigned.
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    /// Get the HTTP version the client speaks, e.g. `1.1`.
+    pub fn version(&self) -> &String {
+        &self.version
+    }
+
+    /// Get the value of the `Host` header.
+    pub fn host(&self) -> &String {
+        &self.host
+    }
+
+    /// Get the value of the `Connection` header.
+    pub fn connection(&self) -> &String {
+        &self.connection
+    }
+
+    /// Whether the client wants to send further requests on the connection: by default since HTTP/1.1,
+    /// only with `Connection: keep-alive` before.
+    pub fn keeps_alive(&self) -> bool {
+        let connection = self.connection.to_lowercase();
+
+        if self.version == "1.1" {
+            !connection.split(',').any(|option| option.trim() == "close")
+        } else {
+            connection.split(',').any(|option| option.trim() == "keep-alive")
+        }
+    }
+
+    /// Get the value of the `Accept` header.
+    pub fn accept(&self) -> &String {
+        &self.accept
+    }
+
+    /// Get the value of the `User-Agent` header.
+    pub fn user_agent(&self) -> &String {
+        &self.user_agent
+    }
+
+    /// Get the value of the `Referer` header.
+    pub fn referer(&self) -> &String {
+        &self.referer
+    }
+
+    /// Get the value of the `Authorization` header.
+    pub fn authorization(&self) -> &String {
+        &self.authorization
+    }
+}
+
+/// Decodes the path of a URL and resolves it.
+///
+/// Consecutive slashes and `.` segments are removed, so `//reports/./a.html` is matched against path
+/// prefixes like `/reports/a.html` and served as that file. `..` segments are kept, the server refuses
+/// such paths, see `is_safe_path`.
+pub fn resolve_path(path: &str) -> String {
+    let decoded = escape::url_decode(path);
+    let mut resolved = String::with_capacity(decoded.len());
+
+    for segment in decoded.split('/').filter(|segment| !segment.is_empty() && *segment != ".") {
+        resolved.push('/');
+        resolved.push_str(segment);
+    }
+
+    if resolved.is_empty() || decoded.ends_with('/') || decoded.ends_with("/.") {
+        resolved.push('/');
+    }
+
+    resolved
+}
+
+/// Whether the decoded request path stays inside the web root.
+pub fn is_safe_path(path: &str) -> bool {
+    !path.split(['/', '\\']).any(|segment| segment == "..")
 }
 
 #[derive(Debug)]
@@ -55,6 +208,7 @@ struct RequestBuilder {
     connection: String,
     referer: String,
     cache_control: String,
+    authorization: String,
 }
 
 impl RequestBuilder {
@@ -73,6 +227,7 @@ impl RequestBuilder {
             connection: String::from(""),
             referer: String::from(""),
             cache_control: String::from(""),
+            authorization: String::from(""),
         }
     }
 
@@ -91,6 +246,11 @@ impl RequestBuilder {
             connection: self.connection.clone(),
             referer: self.referer.clone(),
             cache_control: self.cache_control.clone(),
+            authorization: self.authorization.clone(),
+            remote_addr: None,
+            id: String::from(""),
+            server_name: None,
+            client_cert: None,
         }
     }
 
@@ -145,6 +305,10 @@ impl RequestBuilder {
     fn with_cache_control(&mut self, new_cache_control: &String) {
         self.cache_control = new_cache_control.clone();
     }
+
+    fn with_authorization(&mut self, new_authorization: &String) {
+        self.authorization = new_authorization.clone();
+    }
 }
 
 /// Represents a HTTP response.
@@ -153,44 +317,76 @@ pub struct Response {
     version: String,
     status: Status,
     headers: Vec<ResponseHeader>,
-    body: Vec<u8>,
+    /// Shared, so a cached file is answered without copying it.
+    body: Arc<Vec<u8>>,
+    /// User authenticated for the request, written to the access log.
+    user: Option<String>,
 }
 
 impl Response {
     pub fn new(version: String, status: Status, body: Vec<u8>) -> Response {
-        Response { version, status, headers: Vec::new(), body }
+        Response::with_shared_body(version, status, Arc::new(body))
+    }
+
+    /// Creates a response with a body shared with others, e.g. the content of a cached file.
+    pub fn with_shared_body(version: String, status: Status, body: Arc<Vec<u8>>) -> Response {
+        Response { version, status, headers: Vec::new(), body, user: None }
     }
 
     /// Renders the response into a byte vector to be written to  stream.
     pub fn render(&self) -> Vec<u8> {
-        let mut buffer: Vec<u8> = Vec::new();
-        let first_line = format!("HTTP/{} {}\r\n", self.version, self.status);
+        let mut buffer = self.render_head();
+        buffer.extend_from_slice(&self.body);
+        buffer
+    }
 
-        for byte in first_line.as_bytes() {
-            buffer.push(*byte);
-        }
+    /// Renders the status line and the headers, to be written to a stream before the body.
+    pub fn render_head(&self) -> Vec<u8> {
+        let mut buffer = format!("HTTP/{} {}\r\n", self.version, self.status);
 
         for header in self.headers.iter() {
-            let header = format!("{}\r\n", header);
-
-            for byte in header.as_bytes() {
-                buffer.push(*byte);
-            }
+            buffer.push_str(&format!("{}\r\n", header));
         }
 
-        buffer.push('\r' as u8);
-        buffer.push('\n' as u8);
+        buffer.push_str("\r\n");
+        buffer.into_bytes()
+    }
 
-        for byte in &self.body {
-            buffer.push(*byte);
-        }
+    /// Get the body of the response.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
 
-        buffer
+    /// Sets the user authenticated for the request.
+    pub fn with_user(mut self, user: Option<String>) -> Response {
+        self.user = user;
+        self
+    }
+
+    /// Get the user authenticated for the request, if any.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
     }
 
     pub fn add_header(&mut self, header: ResponseHeader) {
         self.headers.push(header);
     }
+
+    /// Whether the response tells the length of its body, so the client finds its end without the connection
+    /// being closed.
+    pub fn has_content_length(&self) -> bool {
+        self.headers.iter().any(|header| matches!(*header, ResponseHeader::ContentLength(_)))
+    }
+
+    /// Get the status of the response.
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+
+    /// Get the size of the response body in bytes.
+    pub fn body_len(&self) -> usize {
+        self.body.len()
+    }
 }
 
 /// This enum declares some [HTTP response headers](https://www.w3.org/Protocols/rfc2616/rfc2616-sec14.html).
@@ -206,6 +402,22 @@ pub enum ResponseHeader {
     ContentLength(usize),
     // Date: Wed, 14 Feb 2018 11:27:44 GMT
     Date(String),
+    // ETag: "3f80f-1b6-3e1cb03b"
+    ETag(String),
+    // Location: /css/
+    Location(String),
+    // WWW-Authenticate: Basic realm="Reports", charset="UTF-8"
+    WwwAuthenticate(String),
+    // Retry-After: 120
+    RetryAfter(u64),
+    // RateLimit-Limit: 20
+    RateLimitLimit(u32),
+    // RateLimit-Remaining: 19
+    RateLimitRemaining(u32),
+    // RateLimit-Reset: 1
+    RateLimitReset(u64),
+    /// Any other header given by name and value.
+    Custom(String, String),
 }
 
 impl fmt::Display for ResponseHeader {
@@ -217,30 +429,99 @@ impl fmt::Display for ResponseHeader {
             ResponseHeader::ContentType(ref value) => format!("Content-Type: {}", value),
             ResponseHeader::ContentLength(ref value) => format!("Content-Length: {}", value),
             ResponseHeader::Date(ref value) => format!("Date: {}", value),
+            ResponseHeader::ETag(ref value) => format!("ETag: {}", value),
+            ResponseHeader::Location(ref value) => format!("Location: {}", value),
+            ResponseHeader::WwwAuthenticate(ref value) => format!("WWW-Authenticate: {}", value),
+            ResponseHeader::RetryAfter(ref value) => format!("Retry-After: {}", value),
+            ResponseHeader::RateLimitLimit(ref value) => format!("RateLimit-Limit: {}", value),
+            ResponseHeader::RateLimitRemaining(ref value) => format!("RateLimit-Remaining: {}", value),
+            ResponseHeader::RateLimitReset(ref value) => format!("RateLimit-Reset: {}", value),
+            ResponseHeader::Custom(ref name, ref value) => format!("{}: {}", name, value),
         };
         write!(f, "{}", printable)
     }
 }
 
 /// This enum declares some [HTTP stats codes](https://en.wikipedia.org/wiki/List_of_HTTP_status_codes).
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Status {
     // Success 200 - 299:
     /// Standard response for successful HTTP requests.
     Ok,
+    // Redirection 300 - 399:
+    /// The resource has been moved to the URL given in the `Location` header.
+    MovedPermanently,
+    /// The resource resides temporarily under the URL given in the `Location` header.
+    Found,
+    /// Like `Found`, but the client must not change the request method.
+    TemporaryRedirect,
+    /// Like `MovedPermanently`, but the client must not change the request method.
+    PermanentRedirect,
     // Client errors 400 - 499:
+    /// The request is malformed, e.g. a HTTP/1.1 request without `Host` header.
+    BadRequest,
+    /// The request lacks valid credentials for the requested resource.
+    Unauthorized,
+    /// The client is not allowed to access the requested resource.
+    Forbidden,
     /// The requested resource could not be found.
     NotFound,
     /// A request method is not supported for the requested resource.
     MethodNotAllowed,
+    /// The client didn't send its request in time.
+    RequestTimeout,
+    /// The client sent too many requests in a given amount of time.
+    TooManyRequests,
+    /// The request was sent on a connection for another host, e.g. the TLS server name differs from `Host`.
+    MisdirectedRequest,
+    // Server errors 500 - 599:
+    /// An unexpected condition prevented the server from fulfilling the request.
+    InternalServerError,
+    /// The server is overloaded, the client may retry after the time given in the `Retry-After` header.
+    ServiceUnavailable,
+}
+
+impl Status {
+    /// Get the numeric status code.
+    pub fn code(&self) -> u16 {
+        match *self {
+            Status::Ok => 200,
+            Status::MovedPermanently => 301,
+            Status::Found => 302,
+            Status::TemporaryRedirect => 307,
+            Status::PermanentRedirect => 308,
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::RequestTimeout => 408,
+            Status::MisdirectedRequest => 421,
+            Status::TooManyRequests => 429,
+            Status::InternalServerError => 500,
+            Status::ServiceUnavailable => 503,
+        }
+    }
 }
 
 impl fmt::Display for Status {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let printable = match *self {
             Status::Ok => "200 OK",
+            Status::MovedPermanently => "301 MOVED PERMANENTLY",
+            Status::Found => "302 FOUND",
+            Status::TemporaryRedirect => "307 TEMPORARY REDIRECT",
+            Status::PermanentRedirect => "308 PERMANENT REDIRECT",
+            Status::BadRequest => "400 BAD REQUEST",
+            Status::Unauthorized => "401 UNAUTHORIZED",
+            Status::Forbidden => "403 FORBIDDEN",
             Status::NotFound => "404 NOT FOUND",
             Status::MethodNotAllowed => "405 METHOD NOT ALLOWED",
+            Status::RequestTimeout => "408 REQUEST TIMEOUT",
+            Status::MisdirectedRequest => "421 MISDIRECTED REQUEST",
+            Status::TooManyRequests => "429 TOO MANY REQUESTS",
+            Status::InternalServerError => "500 INTERNAL SERVER ERROR",
+            Status::ServiceUnavailable => "503 SERVICE UNAVAILABLE",
         };
         write!(f, "{}", printable)
     }
@@ -290,6 +571,7 @@ pub fn parse_request(request: &str) -> Request {
                         "Upgrade-Insecure-Requests" => builder.with_upgrade_insecure_requests(&value.clone()),
                         "Referer" => builder.with_referer(&value.clone()),
                         "Cache-Control" => builder.with_cache_control(&value.clone()),
+                        "Authorization" => builder.with_authorization(&value.clone()),
                         _ => debug!("Unexpected header name '{}'!", name),
                     }
                 }
@@ -477,6 +759,11 @@ mod tests {
                     connection: String::from(""),
                     referer: String::from(""),
                     cache_control: String::from(""),
+                    authorization: String::from(""),
+                    remote_addr: None,
+                    id: String::from(""),
+                    server_name: None,
+                    client_cert: None,
                 }
             ))
         );
@@ -503,11 +790,72 @@ mod tests {
                     connection: String::from("keep-alive"),
                     referer: String::from("http://localhost:8080/index.html"),
                     cache_control: String::from("max-age=0"),
+                    authorization: String::from(""),
+                    remote_addr: None,
+                    id: String::from(""),
+                    server_name: None,
+                    client_cert: None,
                 }
             ))
         );
     }
 
+    #[test]
+    fn request_path_and_query() {
+        let sut = parse_request("GET /my%20files/?C=S&O=D HTTP/1.1\r\nHost: localhost\r\n");
+
+        assert_that!(sut.path(), is(equal_to(String::from("/my files/"))));
+        assert_that!(sut.query(), is(equal_to("C=S&O=D")));
+
+        let sut = parse_request("GET /index.html HTTP/1.1\r\nHost: localhost\r\n");
+
+        assert_that!(sut.path(), is(equal_to(String::from("/index.html"))));
+        assert_that!(sut.query(), is(equal_to("")));
+
+        let sut = parse_request("GET //reports/%2F/a.html HTTP/1.1\r\nHost: localhost\r\n");
+
+        assert_that!(sut.path(), is(equal_to(String::from("/reports/a.html"))));
+    }
+
+    #[test]
+    fn request_path_resolves_dot_segments() {
+        let sut = parse_request("GET /./index.html HTTP/1.1\r\nHost: localhost\r\n");
+        assert_that!(sut.path(), is(equal_to(String::from("/index.html"))));
+
+        let sut = parse_request("GET /%2e/admin/%2E/x HTTP/1.1\r\nHost: localhost\r\n");
+        assert_that!(sut.path(), is(equal_to(String::from("/admin/x"))));
+
+        let sut = parse_request("GET /docs/. HTTP/1.1\r\nHost: localhost\r\n");
+        assert_that!(sut.path(), is(equal_to(String::from("/docs/"))));
+
+        let sut = parse_request("GET /docs/../admin/ HTTP/1.1\r\nHost: localhost\r\n");
+        assert_that!(sut.path(), is(equal_to(String::from("/docs/../admin/"))));
+    }
+
+    #[test]
+    fn test_is_safe_path() {
+        assert_that!(is_safe_path("/foo/bar.html"), is(true));
+        assert_that!(is_safe_path("/foo..bar/"), is(true));
+        assert_that!(is_safe_path("/../etc/passwd"), is(false));
+        assert_that!(is_safe_path("/foo/..\\..\\bar"), is(false));
+    }
+
+    #[test]
+    fn request_with_remote_addr() {
+        let addr: SocketAddr = "[::1]:4711".parse().unwrap();
+        let sut = parse_request("GET / HTTP/1.1\r\nHost: localhost\r\n");
+
+        assert_that!(sut.remote_addr(), is(equal_to(None)));
+        assert_that!(sut.with_remote_addr(Some(addr)).remote_addr(), is(equal_to(Some(addr))));
+    }
+
+    #[test]
+    fn parse_request_with_authorization() {
+        let sut = parse_request("GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic YWxpY2U6c2VjcmV0\r\n");
+
+        assert_that!(sut.authorization().as_str(), is(equal_to("Basic YWxpY2U6c2VjcmV0")));
+    }
+
     #[test]
     fn test_render_response_without_headers() {
         let sut = Response::new(
@@ -553,6 +901,23 @@ mod tests {
         assert_that!(
             format!("{}", Status::MethodNotAllowed).as_str(),
             is(equal_to("405 METHOD NOT ALLOWED")));
+        assert_that!(
+            format!("{}", Status::BadRequest).as_str(),
+            is(equal_to("400 BAD REQUEST")));
+    }
+
+    #[test]
+    fn status_code() {
+        assert_that!(Status::Ok.code(), is(equal_to(200)));
+        assert_that!(Status::MovedPermanently.code(), is(equal_to(301)));
+        assert_that!(Status::BadRequest.code(), is(equal_to(400)));
+        assert_that!(Status::Unauthorized.code(), is(equal_to(401)));
+        assert_that!(Status::Forbidden.code(), is(equal_to(403)));
+        assert_that!(Status::NotFound.code(), is(equal_to(404)));
+        assert_that!(Status::MethodNotAllowed.code(), is(equal_to(405)));
+        assert_that!(Status::MisdirectedRequest.code(), is(equal_to(421)));
+        assert_that!(Status::TooManyRequests.code(), is(equal_to(429)));
+        assert_that!(Status::InternalServerError.code(), is(equal_to(500)));
     }
 
     #[test]
@@ -560,5 +925,28 @@ mod tests {
         assert_that!(
             format!("{}", ResponseHeader::Allow(String::from("GET, POST, HEAD"))).as_str(),
             is(equal_to("Allow: GET, POST, HEAD")));
+        assert_that!(
+            format!("{}", ResponseHeader::Custom(String::from("X-Frame-Options"), String::from("DENY"))).as_str(),
+            is(equal_to("X-Frame-Options: DENY")));
+        assert_that!(
+            format!("{}", ResponseHeader::WwwAuthenticate(String::from("Basic realm=\"Reports\""))).as_str(),
+            is(equal_to("WWW-Authenticate: Basic realm=\"Reports\"")));
+    }
+
+    #[test]
+    fn request_keeps_alive_by_version_and_connection_header() {
+        assert_that!(parse_request("GET / HTTP/1.1\r\nHost: localhost\r\n").keeps_alive(), is(true));
+        assert_that!(parse_request("GET / HTTP/1.1\r\nConnection: Close\r\n").keeps_alive(), is(false));
+        assert_that!(parse_request("GET / HTTP/1.0\r\nHost: localhost\r\n").keeps_alive(), is(false));
+        assert_that!(parse_request("GET / HTTP/1.0\r\nConnection: keep-alive\r\n").keeps_alive(), is(true));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn response_has_content_length() {
+        let mut response = Response::new(String::from("1.1"), Status::Ok, Vec::new());
+        assert_that!(response.has_content_length(), is(false));
+
+        response.add_header(ResponseHeader::ContentLength(0));
+        assert_that!(response.has_content_length(), is(true));
+    }
+}
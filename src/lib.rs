@@ -14,13 +14,56 @@ extern crate time;
 extern crate serde_derive;
 extern crate serde;
 extern crate toml;
+extern crate regex;
+extern crate base64;
+extern crate pwhash;
+extern crate signal_hook;
+extern crate rustls;
+extern crate x509_parser;
+extern crate socket2;
+extern crate libc;
+extern crate mio;
 
+use std::fs;
 use std::path::PathBuf;
+use auth::AuthConfig;
+use accesslog::AccessLogConfig;
+use cache::CacheConfig;
+use ratelimit::RateLimitConfig;
+use metrics::MetricsConfig;
+use status::StatusConfig;
+use tls::TlsConfig;
+use listen::{AcceptMode, AcceptorsConfig, ListenConfig};
+use event_loop::EventLoopConfig;
+use timeout::TimeoutsConfig;
+use threads::QueueConfig;
+use location::Location;
+use rewrite::RewriteRule;
+use vhost::VirtualHost;
 
+pub mod accesslog;
+pub mod acl;
+pub mod auth;
+pub mod autoindex;
+pub mod cache;
+pub mod escape;
+pub mod event_loop;
 pub mod file;
 pub mod http;
+pub mod listen;
+pub mod location;
+pub mod metrics;
+pub mod ratelimit;
+pub mod reload;
+pub mod rewrite;
 pub mod threads;
+pub mod tls;
 pub mod server;
+pub mod status;
+pub mod systemd;
+pub mod timeout;
+pub mod upgrade;
+pub mod vhost;
 
 /// Name of the application
 pub static APPLICATION_NAME: &'static str = "webserver";
@@ -29,6 +72,14 @@ pub static APPLICATION_DESCRIPTION: &'static str = "Weltraumschaf's Webserver";
 /// Version of the application.
 pub static APPLICATION_VERSION: &'static str = "1.0.0";
 
+fn default_log_format() -> String {
+    String::from("text")
+}
+
+fn default_index_files() -> Vec<String> {
+    vec!(String::from("index.html"), String::from("index.htm"))
+}
+
 /// Configuration of the server.
 #[derive(Debug, Clone, Deserialize, PartialEq)]
 pub struct Config {
@@ -42,10 +93,54 @@ pub struct Config {
     threads: usize,
     /// Directory with the content to serve.
     web_dir: String,
+    /// Directory with custom error pages named by status code, e.g. `404.html`.
+    error_dir: Option<String>,
+    /// Files served for requested directories, in order of preference.
+    #[serde(default = "default_index_files")]
+    index_files: Vec<String>,
     /// Defines which messages to log.
     log_level: String,
+    /// Format of the application log: `text` or `json` (one object per line).
+    #[serde(default = "default_log_format")]
+    log_format: String,
     /// Location to store log files.
     log_dir: String,
+    /// Optional HTTPS listener.
+    tls: Option<TlsConfig>,
+    /// Further plain HTTP or HTTPS listeners.
+    #[serde(default)]
+    listen: Vec<ListenConfig>,
+    /// Optional acceptor threads per listener with sockets of their own.
+    acceptors: Option<AcceptorsConfig>,
+    /// Optional event loop holding plain HTTP connections between requests.
+    event_loop: Option<EventLoopConfig>,
+    /// Timeouts for reading requests and writing responses.
+    #[serde(default)]
+    timeouts: TimeoutsConfig,
+    /// Optional bound of the connections waiting for a free worker.
+    queue: Option<QueueConfig>,
+    /// Optional access log written to a file under `log_dir`.
+    access_log: Option<AccessLogConfig>,
+    /// Optional in-memory cache for static files.
+    cache: Option<CacheConfig>,
+    /// Optional per-client rate limiting.
+    rate_limit: Option<RateLimitConfig>,
+    /// Optional Prometheus metrics endpoint.
+    metrics: Option<MetricsConfig>,
+    /// Optional server status page.
+    status: Option<StatusConfig>,
+    /// Per location settings for requests not served by a virtual host.
+    #[serde(default, rename = "location")]
+    locations: Vec<Location>,
+    /// URL rewrite and redirect rules for requests not served by a virtual host.
+    #[serde(default, rename = "rewrite")]
+    rewrites: Vec<RewriteRule>,
+    /// Path prefixes protected by HTTP Basic authentication for requests not served by a virtual host.
+    #[serde(default, rename = "auth")]
+    auths: Vec<AuthConfig>,
+    /// Name based virtual hosts.
+    #[serde(default, rename = "vhost")]
+    vhosts: Vec<VirtualHost>,
 }
 
 impl Config {
@@ -58,11 +153,110 @@ impl Config {
     /// port = 8080
     /// threads = 4
     /// dir = 'target/doc'
+    /// index_files = ['index.html', 'index.htm']
     /// log_level = 'debug'
+    /// log_format = 'text'
     /// log_dir = 'logs/'
+    ///
+    /// [tls]
+    /// port = 8443
+    /// cert = 'etc/tls/cert.pem'
+    /// key = 'etc/tls/key.pem'
+    /// min_version = '1.2'
+    ///
+    /// [[listen]]
+    /// address = '::'
+    /// port = 8080
+    /// ipv6_only = true
+    ///
+    /// [[listen]]
+    /// address = '::'
+    /// port = 8443
+    /// tls = true
+    /// default_vhost = 'example.com'
+    ///
+    /// [acceptors]
+    /// count = 4
+    /// mode = 'queue'
+    ///
+    /// [event_loop]
+    /// max_connections = 10000
+    /// keep_alive_requests = 100
+    ///
+    /// [queue]
+    /// capacity = 1000
+    /// retry_after = 5
+    /// full_action = 'reject'
+    ///
+    /// [timeouts]
+    /// header = 20
+    /// body = 20
+    /// keep_alive = 5
+    /// write = 30
+    /// min_rate = 500
+    ///
+    /// [access_log]
+    /// file = 'access.log'
+    /// format = 'combined'
+    ///
+    /// [cache]
+    /// max_bytes = 10485760
+    ///
+    /// [metrics]
+    /// path = '/metrics'
+    /// listen = '127.0.0.1:9090'
+    ///
+    /// [status]
+    /// path = '/server-status'
+    /// access = ['allow 127.0.0.1', 'allow ::1', 'deny all']
+    ///
+    /// [rate_limit]
+    /// requests_per_second = 10.0
+    /// burst = 20
+    ///
+    /// [[rate_limit.path]]
+    /// path = '/api/'
+    /// requests_per_second = 1.0
+    /// burst = 5
+    ///
+    /// [[rewrite]]
+    /// pattern = '^/blog/(\d+)/(.*)$'
+    /// replacement = '/posts/$2-$1.html'
+    ///
+    /// [[rewrite]]
+    /// pattern = '^/old/(.*)$'
+    /// replacement = '/new/$1'
+    /// redirect = 301
+    /// methods = ['GET', 'HEAD']
+    /// file_exists = false
+    ///
+    /// [[auth]]
+    /// path = '/reports/'
+    /// realm = 'Internal reports'
+    /// htpasswd = 'etc/htpasswd'
+    ///
+    /// [[vhost]]
+    /// server_names = ['example.com', '*.example.com']
+    /// web_dir = 'sites/example.com'
+    /// error_dir = 'sites/errors'
+    /// index_files = ['index.html', 'default.htm']
+    /// log_level = 'info'
+    /// headers = { X-Frame-Options = 'DENY' }
+    ///
+    /// [[vhost.location]]
+    /// path = '/downloads/'
+    /// autoindex = true
+    ///
+    /// [[vhost.location]]
+    /// path = '/admin/'
+    /// access = ['allow 10.0.0.0/8', 'allow ::1', 'deny all']
+    /// deny_action = 'drop'
     /// ```
     pub fn from_file(file_name: &PathBuf) -> Result<Config, &'static str> {
-        let config = file::read_string(&file_name);
+        let config = fs::read_to_string(file_name).map_err(|err| {
+            error!("Can't read config file {:?}: {}", file_name, err);
+            "Can't read config file!"
+        })?;
 
         match toml::from_str::<Config>(config.as_ref()) {
             // Make a copy here to invoke the constructor which validates some fields.
@@ -73,10 +267,27 @@ impl Config {
                 config.web_dir,
                 config.log_level,
                 config.log_dir
-            ),
+            )?.with_error_dir(config.error_dir)?
+                .with_index_files(config.index_files)?
+                .with_log_format(config.log_format)?
+                .with_tls(config.tls)?
+                .with_access_log(config.access_log)?
+                .with_cache(config.cache)?
+                .with_rate_limit(config.rate_limit)?
+                .with_metrics(config.metrics)?
+                .with_status(config.status)?
+                .with_locations(config.locations)?
+                .with_rewrites(config.rewrites)?
+                .with_auths(config.auths)?
+                .with_vhosts(config.vhosts)?
+                .with_listen(config.listen)?
+                .with_acceptors(config.acceptors)?
+                .with_event_loop(config.event_loop)?
+                .with_queue(config.queue)?
+                .with_timeouts(config.timeouts),
             Err(err) => {
-                // FIXME Return appropriate error result.
-                panic!("{}", err);
+                error!("Can't parse config file {:?}: {}", file_name, err);
+                Err("Can't parse config file!")
             },
         }
     }
@@ -109,7 +320,96 @@ impl Config {
             return Err("Config value 'log_dir' must not be empty!");
         }
 
-        Ok(Config { address, port, threads, web_dir: web_dir, log_level, log_dir })
+        Ok(Config { address, port, threads, web_dir: web_dir, log_level, log_format: default_log_format(), log_dir, tls: None, listen: Vec::new(), acceptors: None, event_loop: None, timeouts: TimeoutsConfig::default(), queue: None, error_dir: None, access_log: None, index_files: default_index_files(), cache: None, rate_limit: None, metrics: None, status: None, locations: Vec::new(), rewrites: Vec::new(), auths: Vec::new(), vhosts: Vec::new() })
+    }
+
+    /// Sets the directory with custom error pages.
+    pub fn with_error_dir(mut self, error_dir: Option<String>) -> Result<Config, &'static str> {
+        if let Some(ref error_dir) = error_dir {
+            if error_dir.is_empty() {
+                return Err("Config value 'error_dir' must not be empty!");
+            }
+        }
+
+        self.error_dir = error_dir;
+        Ok(self)
+    }
+
+    /// Sets the files served for requested directories, in order of preference.
+    pub fn with_index_files(mut self, index_files: Vec<String>) -> Result<Config, &'static str> {
+        vhost::validate_index_files(&index_files)?;
+        self.index_files = index_files;
+        Ok(self)
+    }
+
+    /// Sets the format of the application log: `text` or `json`.
+    pub fn with_log_format(mut self, log_format: String) -> Result<Config, &'static str> {
+        if log_format != "text" && log_format != "json" {
+            return Err("Config value 'log_format' must be 'text' or 'json'!");
+        }
+
+        self.log_format = log_format;
+        Ok(self)
+    }
+
+    /// Enables the access log, or disables it if `None` is given.
+    pub fn with_access_log(mut self, access_log: Option<AccessLogConfig>) -> Result<Config, &'static str> {
+        self.access_log = match access_log {
+            Some(access_log) => Some(access_log.validate()?),
+            None => None,
+        };
+
+        Ok(self)
+    }
+
+    /// Enables the static file cache, or disables it if `None` is given.
+    pub fn with_cache(mut self, cache: Option<CacheConfig>) -> Result<Config, &'static str> {
+        self.cache = match cache {
+            Some(cache) => Some(CacheConfig::new(*cache.max_bytes())?),
+            None => None,
+        };
+
+        Ok(self)
+    }
+
+    /// Enables per-client rate limiting, or disables it if `None` is given.
+    pub fn with_rate_limit(mut self, rate_limit: Option<RateLimitConfig>) -> Result<Config, &'static str> {
+        self.rate_limit = match rate_limit {
+            Some(rate_limit) => Some(rate_limit.validate()?),
+            None => None,
+        };
+
+        Ok(self)
+    }
+
+    /// Enables HTTPS, or disables it if `None` is given.
+    pub fn with_tls(mut self, tls: Option<TlsConfig>) -> Result<Config, &'static str> {
+        self.tls = match tls {
+            Some(tls) => Some(tls.validate()?),
+            None => None,
+        };
+
+        Ok(self)
+    }
+
+    /// Enables the metrics endpoint, or disables it if `None` is given.
+    pub fn with_metrics(mut self, metrics: Option<MetricsConfig>) -> Result<Config, &'static str> {
+        self.metrics = match metrics {
+            Some(metrics) => Some(metrics.validate()?),
+            None => None,
+        };
+
+        Ok(self)
+    }
+
+    /// Enables the server status page, or disables it if `None` is given.
+    pub fn with_status(mut self, status: Option<StatusConfig>) -> Result<Config, &'static str> {
+        self.status = match status {
+            Some(status) => Some(status.validate()?),
+            None => None,
+        };
+
+        Ok(self)
     }
 
     /// Get the IP address to listen.
@@ -127,26 +427,306 @@ impl Config {
         &self.threads
     }
 
+    /// Sets the per location settings for requests not served by a virtual host.
+    pub fn with_locations(mut self, locations: Vec<Location>) -> Result<Config, &'static str> {
+        let mut validated = Vec::with_capacity(locations.len());
+
+        for location in locations {
+            validated.push(location.validate()?);
+        }
+
+        self.locations = validated;
+        Ok(self)
+    }
+
+    /// Sets the rewrite rules for requests not served by a virtual host.
+    pub fn with_rewrites(mut self, rewrites: Vec<RewriteRule>) -> Result<Config, &'static str> {
+        let mut validated = Vec::with_capacity(rewrites.len());
+
+        for rule in rewrites {
+            validated.push(rule.validate()?);
+        }
+
+        self.rewrites = validated;
+        Ok(self)
+    }
+
+    /// Sets the path prefixes protected by HTTP Basic authentication for requests not served by a virtual host.
+    pub fn with_auths(mut self, auths: Vec<AuthConfig>) -> Result<Config, &'static str> {
+        let mut validated = Vec::with_capacity(auths.len());
+
+        for auth in auths {
+            validated.push(auth.validate()?);
+        }
+
+        self.auths = validated;
+        Ok(self)
+    }
+
+    /// Sets the virtual hosts.
+    ///
+    /// At most one of them may be marked as default.
+    pub fn with_vhosts(mut self, vhosts: Vec<VirtualHost>) -> Result<Config, &'static str> {
+        let mut validated = Vec::with_capacity(vhosts.len());
+
+        for vhost in vhosts {
+            validated.push(vhost.validate()?);
+        }
+
+        if validated.iter().filter(|vhost| vhost.is_default()).count() > 1 {
+            return Err("Config value 'vhost.default' must not be set for more than one virtual host!");
+        }
+
+        self.vhosts = validated;
+        Ok(self)
+    }
+
+    /// Sets further listeners.
+    ///
+    /// HTTPS listeners need the `[tls]` section, default virtual hosts must be configured.
+    pub fn with_listen(mut self, listen: Vec<ListenConfig>) -> Result<Config, &'static str> {
+        let mut validated = Vec::with_capacity(listen.len());
+
+        for listen in listen {
+            let listen = listen.validate()?;
+
+            if listen.tls() && self.tls.is_none() {
+                return Err("Config value 'listen.tls' requires the 'tls' section!");
+            }
+
+            if let Some(ref name) = *listen.default_vhost() {
+                if vhost::select(&self.vhosts, name).is_none() {
+                    return Err("Config value 'listen.default_vhost' must be a server name of a virtual host!");
+                }
+            }
+
+            validated.push(listen);
+        }
+
+        self.listen = validated;
+        Ok(self)
+    }
+
+    /// Enables acceptor threads per listener with sockets of their own, or a single one if `None` is given.
+    ///
+    /// Acceptors answering connections directly can't hand them to the event loop and have no queue.
+    pub fn with_acceptors(mut self, acceptors: Option<AcceptorsConfig>) -> Result<Config, &'static str> {
+        let direct = acceptors.as_ref().is_some_and(|acceptors| *acceptors.mode() == AcceptMode::Direct);
+
+        if direct && self.event_loop.is_some() {
+            return Err("Config value 'event_loop' requires acceptors in 'queue' mode!");
+        }
+
+        if direct && self.queue.is_some() {
+            return Err("Config value 'queue' requires acceptors in 'queue' mode!");
+        }
+
+        self.acceptors = match acceptors {
+            Some(acceptors) => Some(acceptors.validate()?),
+            None => None,
+        };
+
+        Ok(self)
+    }
+
+    /// Enables the event loop holding plain HTTP connections between requests, or disables it if `None` is given.
+    ///
+    /// Acceptors answering connections directly can't hand them to the event loop.
+    pub fn with_event_loop(mut self, event_loop: Option<EventLoopConfig>) -> Result<Config, &'static str> {
+        let direct = self.acceptors.as_ref().is_some_and(|acceptors| *acceptors.mode() == AcceptMode::Direct);
+
+        self.event_loop = match event_loop {
+            Some(_) if direct => return Err("Config value 'event_loop' requires acceptors in 'queue' mode!"),
+            Some(event_loop) => Some(event_loop.validate()?),
+            None => None,
+        };
+
+        Ok(self)
+    }
+
+    /// Bounds the queue of connections waiting for a free worker, or leaves it unbounded if `None` is given.
+    ///
+    /// Acceptors answering connections directly have no queue.
+    pub fn with_queue(mut self, queue: Option<QueueConfig>) -> Result<Config, &'static str> {
+        let direct = self.acceptors.as_ref().is_some_and(|acceptors| *acceptors.mode() == AcceptMode::Direct);
+
+        self.queue = match queue {
+            Some(_) if direct => return Err("Config value 'queue' requires acceptors in 'queue' mode!"),
+            Some(queue) => Some(queue.validate()?),
+            None => None,
+        };
+
+        Ok(self)
+    }
+
+    /// Sets the timeouts for reading requests and writing responses.
+    pub fn with_timeouts(mut self, timeouts: TimeoutsConfig) -> Result<Config, &'static str> {
+        self.timeouts = timeouts.validate()?;
+        Ok(self)
+    }
+
     /// Get the web root directory.
     pub fn web_dir(&self) -> &String {
         &self.web_dir
     }
 
+    /// Get the directory with custom error pages.
+    pub fn error_dir(&self) -> &Option<String> {
+        &self.error_dir
+    }
+
+    /// Get the files served for requested directories, in order of preference.
+    pub fn index_files(&self) -> &Vec<String> {
+        &self.index_files
+    }
+
     /// Get the log level.
     pub fn log_level(&self) -> &String {
         &self.log_level
     }
 
+    /// Get the format of the application log.
+    pub fn log_format(&self) -> &String {
+        &self.log_format
+    }
+
     /// Get the location of the log files.
     pub fn log_dir(&self) -> &String {
         &self.log_dir
     }
+
+    /// Get the access log configuration, if the access log is enabled.
+    pub fn access_log(&self) -> &Option<AccessLogConfig> {
+        &self.access_log
+    }
+
+    /// Get the further listeners.
+    pub fn listen(&self) -> &Vec<ListenConfig> {
+        &self.listen
+    }
+
+    /// Get all listeners: the top level address and port, the HTTPS port if enabled and the further ones.
+    pub fn listeners(&self) -> Vec<ListenConfig> {
+        let mut listeners = vec!(ListenConfig::new(self.address.clone(), self.port)
+            .expect("Top level config is validated!"));
+
+        if let Some(ref tls) = self.tls {
+            listeners.push(ListenConfig::new(self.address.clone(), *tls.port())
+                .expect("Top level config is validated!")
+                .with_tls(true)
+                .expect("Top level config is validated!"));
+        }
+
+        listeners.extend(self.listen.iter().cloned());
+        listeners
+    }
+
+    /// Get the acceptor configuration, if listeners have several acceptors.
+    pub fn acceptors(&self) -> &Option<AcceptorsConfig> {
+        &self.acceptors
+    }
+
+    /// Get the event loop configuration, if enabled.
+    pub fn event_loop(&self) -> &Option<EventLoopConfig> {
+        &self.event_loop
+    }
+
+    /// Get the bound of the queue of connections waiting for a free worker, if any.
+    pub fn queue(&self) -> &Option<QueueConfig> {
+        &self.queue
+    }
+
+    /// Get the timeouts for reading requests and writing responses.
+    pub fn timeouts(&self) -> &TimeoutsConfig {
+        &self.timeouts
+    }
+
+    /// Get the HTTPS configuration, if HTTPS is enabled.
+    pub fn tls(&self) -> &Option<TlsConfig> {
+        &self.tls
+    }
+
+    /// Get the static file cache configuration, if the cache is enabled.
+    pub fn cache(&self) -> &Option<CacheConfig> {
+        &self.cache
+    }
+
+    /// Get the metrics endpoint configuration, if the endpoint is enabled.
+    pub fn metrics(&self) -> &Option<MetricsConfig> {
+        &self.metrics
+    }
+
+    /// Get the server status page configuration, if the page is enabled.
+    pub fn status(&self) -> &Option<StatusConfig> {
+        &self.status
+    }
+
+    /// Get the rate limit configuration, if rate limiting is enabled.
+    pub fn rate_limit(&self) -> &Option<RateLimitConfig> {
+        &self.rate_limit
+    }
+
+    /// Get the per location settings for requests not served by a virtual host.
+    pub fn locations(&self) -> &Vec<Location> {
+        &self.locations
+    }
+
+    /// Get the rewrite rules for requests not served by a virtual host.
+    pub fn rewrites(&self) -> &Vec<RewriteRule> {
+        &self.rewrites
+    }
+
+    /// Get the path prefixes protected by HTTP Basic authentication for requests not served by a virtual host.
+    pub fn auths(&self) -> &Vec<AuthConfig> {
+        &self.auths
+    }
+
+    /// Get the configured virtual hosts.
+    pub fn vhosts(&self) -> &Vec<VirtualHost> {
+        &self.vhosts
+    }
+
+    /// Selects the virtual host serving the given value of the `Host` header.
+    ///
+    /// If no virtual host matches, the one marked as default is used. Without such a virtual host the
+    /// top level `web_dir`, `error_dir`, locations, rewrite rules and authentication settings are served.
+    /// Virtual hosts without own index files inherit the top level ones.
+    pub fn virtual_host(&self, host: &str) -> VirtualHost {
+        self.virtual_host_or(host, &None)
+    }
+
+    /// Selects the virtual host serving the given value of the `Host` header, like `virtual_host`.
+    ///
+    /// If no virtual host matches, the one with the given server name is used before the one marked as default.
+    pub fn virtual_host_or(&self, host: &str, default_vhost: &Option<String>) -> VirtualHost {
+        let vhost = vhost::select(&self.vhosts, host)
+            .or_else(|| default_vhost.as_ref().and_then(|name| vhost::select(&self.vhosts, name)))
+            .or_else(|| self.vhosts.iter().find(|vhost| vhost.is_default()))
+            .cloned()
+            .unwrap_or_else(|| {
+                VirtualHost::new(Vec::new(), self.web_dir.clone())
+                    .and_then(|vhost| vhost.with_error_dir(self.error_dir.clone()))
+                    .and_then(|vhost| vhost.with_locations(self.locations.clone()))
+                    .and_then(|vhost| vhost.with_rewrites(self.rewrites.clone()))
+                    .and_then(|vhost| vhost.with_auths(self.auths.clone()))
+                    .expect("Top level config is validated!")
+                    .with_default(true)
+            });
+
+        if vhost.index_files().is_empty() {
+            vhost.with_index_files(self.index_files.clone())
+                .expect("Top level config is validated!")
+        } else {
+            vhost
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use hamcrest::prelude::*;
+    use threads::FullAction;
 
     #[test]
     fn read_config_from_from_file() {
@@ -161,6 +741,243 @@ mod tests {
         assert_eq!(config.web_dir(), "web_dir");
         assert_eq!(config.log_level(), "debug");
         assert_eq!(config.log_dir(), "logs");
+        assert_eq!(config.cache(), &None);
+        assert_eq!(config.index_files(), &vec!(String::from("index.html"), String::from("index.htm")));
+        assert_eq!(config.timeouts(), &TimeoutsConfig::default());
+    }
+
+    #[test]
+    fn read_config_with_timeouts_from_file() {
+        let config = Config::from_file(&PathBuf::from("test/fixtures/config_with_timeouts.toml"))
+            .expect("Can't read config fixture file!");
+
+        assert_eq!(config.event_loop(), &Some(EventLoopConfig::new(2000).unwrap()));
+        assert_eq!(config.timeouts(), &TimeoutsConfig::new(10, 20, 15, 30).unwrap().with_min_rate(0));
+    }
+
+    #[test]
+    fn read_config_with_cache_from_file() {
+        let config = Config::from_file(&PathBuf::from("test/fixtures/config_with_cache.toml"))
+            .expect("Can't read config fixture file!");
+
+        assert_eq!(config.cache(), &Some(CacheConfig::new(1024).unwrap()));
+    }
+
+    #[test]
+    fn read_config_with_access_log_from_file() {
+        let config = Config::from_file(&PathBuf::from("test/fixtures/config_with_access_log.toml"))
+            .expect("Can't read config fixture file!");
+        let access_log = config.access_log().as_ref().expect("Access log expected!");
+
+        assert_eq!(access_log.file(), "access.log");
+        assert_eq!(access_log.format(), "{remote_addr} {status} {duration_ms}");
+        assert_eq!(access_log.buffer_size(), 8192);
+        assert_eq!(config.log_format(), "json");
+    }
+
+    #[test]
+    fn with_log_format_validates_format() {
+        let config = Config::new(
+            String::from("127.0.0.1"), 8080, 4, String::from("web_dir"), String::from("info"), String::from("logs"))
+            .unwrap();
+
+        assert_eq!(config.log_format(), "text");
+        assert_eq!(
+            config.with_log_format(String::from("xml")).err(),
+            Some("Config value 'log_format' must be 'text' or 'json'!"));
+    }
+
+    #[test]
+    fn read_config_with_rate_limit_from_file() {
+        let config = Config::from_file(&PathBuf::from("test/fixtures/config_with_rate_limit.toml"))
+            .expect("Can't read config fixture file!");
+        let rate_limit = config.rate_limit().as_ref().expect("Rate limit expected!");
+
+        assert_eq!(rate_limit.burst(), 5);
+        assert_eq!(rate_limit.max_clients(), 10_000);
+        assert_eq!(rate_limit.idle_timeout(), 60);
+        assert_eq!(rate_limit.paths().len(), 1);
+    }
+
+    #[test]
+    fn read_config_with_metrics_from_file() {
+        let config = Config::from_file(&PathBuf::from("test/fixtures/config_with_metrics.toml"))
+            .expect("Can't read config fixture file!");
+        let metrics = config.metrics().as_ref().expect("Metrics expected!");
+
+        assert_eq!(metrics.path(), "/metrics");
+        assert_eq!(metrics.listen(), &Some(String::from("127.0.0.1:9090")));
+    }
+
+    #[test]
+    fn read_config_with_status_from_file() {
+        let config = Config::from_file(&PathBuf::from("test/fixtures/config_with_status.toml"))
+            .expect("Can't read config fixture file!");
+        let status = config.status().as_ref().expect("Status expected!");
+
+        assert_eq!(status.path(), "/server-status");
+        assert!(status.is_allowed(Some("10.0.0.1:4711".parse().unwrap())));
+        assert!(!status.is_allowed(Some("192.168.0.1:4711".parse().unwrap())));
+    }
+
+    #[test]
+    fn read_config_with_tls_from_file() {
+        let config = Config::from_file(&PathBuf::from("test/fixtures/config_with_tls.toml"))
+            .expect("Can't read config fixture file!");
+        let tls = config.tls().as_ref().expect("TLS expected!");
+
+        assert_eq!(*tls.port(), 8443);
+        assert_eq!(tls.cert(), "test/fixtures/tls/localhost.pem");
+        assert_eq!(tls.min_version(), "1.3");
+        assert_eq!(tls.ciphers(), &vec!(String::from("TLS13_AES_256_GCM_SHA384")));
+        assert_eq!(tls.certificates()[0].server_names(), &vec!(String::from("*.example.com")));
+        assert_eq!(tls.client_ca(), &Some(String::from("test/fixtures/tls/client-ca.pem")));
+        assert_eq!(tls.client_auth_mode("/admin/users"), tls::ClientAuthMode::Required);
+        assert_eq!(tls.redirect().as_ref().map(|redirect| *redirect.status()), Some(301));
+        assert_eq!(tls.hsts().as_ref().map(|hsts| hsts.header_value()), Some(String::from("max-age=31536000; includeSubDomains")));
+        assert_eq!(config.vhosts()[0].tls_cert(), &Some(String::from("test/fixtures/tls/example.org.pem")));
+    }
+
+    #[test]
+    fn read_config_with_vhosts_from_file() {
+        let config = Config::from_file(&PathBuf::from("test/fixtures/config_with_vhosts.toml"))
+            .expect("Can't read config fixture file!");
+
+        assert_eq!(config.error_dir(), &Some(String::from("error_dir")));
+        assert_eq!(config.vhosts().len(), 2);
+        assert_eq!(config.virtual_host("www.example.com:8080").web_dir(), "example.com");
+        assert_eq!(config.virtual_host("foo.example.org").web_dir(), "example.org");
+        assert_eq!(config.virtual_host("example.net").web_dir(), "example.org");
+        assert_eq!(
+            config.virtual_host("www.example.com").headers().get("X-Frame-Options"),
+            Some(&String::from("DENY")));
+        assert_eq!(config.virtual_host("www.example.com").index_files(), &vec!(String::from("default.htm")));
+        assert_eq!(config.virtual_host("foo.example.org").index_files(), &vec!(String::from("index.html")));
+
+        let admin = config.virtual_host("foo.example.org").location("/admin/").cloned().unwrap();
+        assert_eq!(admin.deny_action(), acl::DenyAction::Drop);
+        assert_eq!(admin.is_allowed(Some("10.1.2.3".parse().unwrap())), true);
+        assert_eq!(admin.is_allowed(Some("192.168.1.1".parse().unwrap())), false);
+    }
+
+    #[test]
+    fn read_config_with_listen_from_file() {
+        let config = Config::from_file(&PathBuf::from("test/fixtures/config_with_listen.toml"))
+            .expect("Can't read config fixture file!");
+        let urls: Vec<String> = config.listeners().iter().map(|listen| listen.url()).collect();
+
+        assert_eq!(urls, vec!("http://[::1]:8080/", "https://[::1]:8443/", "http://[::]:9080/", "https://0.0.0.0:9443/",
+            "unix:/run/webserver.sock"));
+        assert_eq!(config.listen()[1].name(), &Some(String::from("https")));
+        assert_eq!(config.listen()[2].mode(), &Some(String::from("0660")));
+        assert_eq!(config.listen()[0].ipv6_only(), &Some(true));
+        assert_eq!(config.acceptors(), &Some(AcceptorsConfig::new(4).unwrap().with_mode(AcceptMode::Direct)));
+        assert_eq!(config.virtual_host_or("example.net", config.listen()[0].default_vhost()).web_dir(), "example.com");
+        assert_eq!(config.virtual_host_or("example.net", config.listen()[1].default_vhost()).web_dir(), "example.org");
+    }
+
+    #[test]
+    fn with_listen_validates_tls_and_default_vhost() {
+        let config = Config::new(String::from("127.0.0.1"), 8080, 4, String::from("dir"),
+            String::from("debug"), String::from("logs")).unwrap();
+        let listen = ListenConfig::new(String::from("::"), 8443).unwrap();
+
+        assert_that!(
+            config.clone().with_listen(vec!(listen.clone().with_tls(true).unwrap())),
+            is(equal_to(Err("Config value 'listen.tls' requires the 'tls' section!"))));
+        assert_that!(
+            config.with_listen(vec!(listen.with_default_vhost(Some(String::from("example.com"))).unwrap())),
+            is(equal_to(Err("Config value 'listen.default_vhost' must be a server name of a virtual host!"))));
+    }
+
+    #[test]
+    fn with_event_loop_requires_queueing_acceptors() {
+        let config = Config::new(String::from("127.0.0.1"), 8080, 4, String::from("dir"),
+            String::from("debug"), String::from("logs")).unwrap();
+        let direct = AcceptorsConfig::new(2).unwrap().with_mode(AcceptMode::Direct);
+
+        assert_that!(
+            config.clone().with_event_loop(Some(EventLoopConfig::new(100).unwrap())).unwrap().event_loop().clone(),
+            is(equal_to(Some(EventLoopConfig::new(100).unwrap()))));
+        assert_that!(
+            config.clone().with_acceptors(Some(direct.clone())).unwrap()
+                .with_event_loop(Some(EventLoopConfig::new(100).unwrap())),
+            is(equal_to(Err("Config value 'event_loop' requires acceptors in 'queue' mode!"))));
+        assert_that!(
+            config.with_event_loop(Some(EventLoopConfig::new(100).unwrap())).unwrap().with_acceptors(Some(direct)),
+            is(equal_to(Err("Config value 'event_loop' requires acceptors in 'queue' mode!"))));
+    }
+
+    #[test]
+    fn read_config_with_queue_from_file() {
+        let config = Config::from_file(&PathBuf::from("test/fixtures/config_with_queue.toml"))
+            .expect("Can't read config fixture file!");
+
+        assert_eq!(config.queue(), &Some(QueueConfig::new(500).unwrap().with_retry_after(10)
+            .with_full_action(FullAction::Drop)));
+    }
+
+    #[test]
+    fn with_queue_requires_queueing_acceptors() {
+        let config = Config::new(String::from("127.0.0.1"), 8080, 4, String::from("dir"),
+            String::from("debug"), String::from("logs")).unwrap();
+        let direct = AcceptorsConfig::new(2).unwrap().with_mode(AcceptMode::Direct);
+
+        assert_that!(
+            config.clone().with_acceptors(Some(direct.clone())).unwrap().with_queue(Some(QueueConfig::new(10).unwrap())),
+            is(equal_to(Err("Config value 'queue' requires acceptors in 'queue' mode!"))));
+        assert_that!(
+            config.with_queue(Some(QueueConfig::new(10).unwrap())).unwrap().with_acceptors(Some(direct)),
+            is(equal_to(Err("Config value 'queue' requires acceptors in 'queue' mode!"))));
+    }
+
+    #[test]
+    fn read_config_with_rewrites_from_file() {
+        let config = Config::from_file(&PathBuf::from("test/fixtures/config_with_rewrites.toml"))
+            .expect("Can't read config fixture file!");
+
+        assert_eq!(config.rewrites().len(), 2);
+        assert_eq!(config.rewrites()[1].redirect(), &Some(301));
+        assert_eq!(config.virtual_host("localhost").rewrites(), config.rewrites());
+    }
+
+    #[test]
+    fn read_config_with_auths_from_file() {
+        let config = Config::from_file(&PathBuf::from("test/fixtures/config_with_auths.toml"))
+            .expect("Can't read config fixture file!");
+
+        assert_eq!(config.auths().len(), 1);
+        assert_eq!(config.auths()[0].realm(), "Internal reports");
+        assert_eq!(config.virtual_host("localhost").auths(), config.auths());
+    }
+
+    #[test]
+    fn virtual_host_falls_back_to_top_level_config() {
+        let config = Config::from_file(&PathBuf::from("test/fixtures/config_with_error_dir.toml"))
+            .expect("Can't read config fixture file!");
+        let vhost = config.virtual_host("localhost");
+
+        assert_eq!(vhost.web_dir(), "web_dir");
+        assert_eq!(vhost.error_dir(), &Some(String::from("error_dir")));
+        assert_eq!(vhost.is_default(), true);
+    }
+
+    #[test]
+    fn with_vhosts_validates_single_default() {
+        let vhost = VirtualHost::new(vec!(String::from("example.com")), String::from("dir"))
+            .unwrap()
+            .with_default(true);
+        let config = Config::new(
+            String::from("127.0.0.1"),
+            8080,
+            4,
+            String::from("dir"),
+            String::from("debug"),
+            String::from("logs")).unwrap();
+
+        assert_that!(
+            config.with_vhosts(vec!(vhost.clone(), vhost)),
+            is(equal_to(Err("Config value 'vhost.default' must not be set for more than one virtual host!"))));
     }
 
     #[test]
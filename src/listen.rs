@@ -0,0 +1,767 @@
+///! This module provides the addresses the server listens on.
+///!
+///! Besides the top level `address` and `port`, and the `port` of the `[tls]` section, further listeners
+///! are configured by `[[listen]]` sections. Each one serves plain HTTP or, with `tls = true`, HTTPS with
+///! the certificates of the `[tls]` section. A listener may name the virtual host serving requests whose
+///! `Host` header matches none. All listeners hand their connections to the same worker pool.
+///!
+///! IPv6 addresses are written with or without brackets like `::1`. Whether a listener on an IPv6 address also
+///! accepts IPv4 connections is decided by the operating system unless `ipv6_only` is set.
+///!
+///! An address like `unix:/run/webserver.sock` listens on a Unix domain socket instead of a TCP port, e.g.
+///! behind a local reverse proxy. Permissions are set by `mode` like `0660` and `owner` like `www-data` or
+///! `www-data:proxy`. A stale socket file left by a crashed server is removed on start, the socket is
+///! removed again on shutdown. Unix sockets serve plain HTTP only.
+///!
+///! By default one thread accepts the connections of a listener and hands them to the worker pool. With an
+///! `[acceptors]` section, each listener gets `count` acceptor threads instead. For TCP each one has a socket
+///! of its own bound with `SO_REUSEPORT`, so the kernel spreads the connections among them, the acceptors of
+///! Unix and passed sockets share them. In `queue` mode each acceptor hands its connections to a pool of its
+///! own with a share of the `threads`, in `direct` mode it answers them itself. Neither takes the lock of one
+///! queue shared by all acceptors.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+use libc;
+use socket2::{Domain, SockAddr, SockRef, Socket, Type};
+use timeout::Timed;
+
+/// Prefix of addresses naming a Unix domain socket.
+pub static UNIX_PREFIX: &'static str = "unix:";
+
+/// Number of pending connections the operating system queues before refusing new ones.
+static BACKLOG: i32 = 128;
+
+/// Where an acceptor puts the connections it accepted.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AcceptMode {
+    /// Into the queue of a worker pool of its own.
+    #[default]
+    Queue,
+    /// Nowhere, the acceptor answers the connection itself.
+    Direct,
+}
+
+/// Configuration of the acceptor threads per listener.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct AcceptorsConfig {
+    /// Number of acceptor threads per TCP listener, each one with a socket of its own.
+    count: usize,
+    /// Whether the acceptors queue connections for their workers or answer them.
+    #[serde(default)]
+    mode: AcceptMode,
+}
+
+impl AcceptorsConfig {
+    /// Creates a new configuration with the given number of acceptors per listener, queueing connections.
+    pub fn new(count: usize) -> Result<AcceptorsConfig, &'static str> {
+        if count < 1 {
+            return Err("Config value 'acceptors.count' must be greater than 0!");
+        }
+
+        Ok(AcceptorsConfig { count, mode: AcceptMode::default() })
+    }
+
+    /// Sets where the acceptors put their connections.
+    pub fn with_mode(mut self, mode: AcceptMode) -> AcceptorsConfig {
+        self.mode = mode;
+        self
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<AcceptorsConfig, &'static str> {
+        Ok(AcceptorsConfig::new(self.count)?.with_mode(self.mode))
+    }
+
+    /// Get the number of acceptor threads per TCP listener.
+    pub fn count(&self) -> &usize {
+        &self.count
+    }
+
+    /// Get where the acceptors put their connections.
+    pub fn mode(&self) -> &AcceptMode {
+        &self.mode
+    }
+}
+
+/// Configuration of a listener.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ListenConfig {
+    /// IP address or host name to listen on.
+    address: String,
+    /// TCP port to listen on, not used for Unix sockets.
+    #[serde(default)]
+    port: u16,
+    /// Whether to serve HTTPS with the certificates of the `[tls]` section.
+    #[serde(default)]
+    tls: bool,
+    /// Whether a listener on an IPv6 address accepts IPv6 connections only. The system default if unset.
+    ipv6_only: Option<bool>,
+    /// Server name of the virtual host serving requests matching no virtual host.
+    default_vhost: Option<String>,
+    /// Octal permissions of a Unix socket like `0660`.
+    mode: Option<String>,
+    /// User and optional group owning a Unix socket like `www-data:proxy`.
+    owner: Option<String>,
+    /// Name of the socket passed by systemd to use instead of binding, see `FileDescriptorName`.
+    name: Option<String>,
+}
+
+impl ListenConfig {
+    /// Creates a new plain HTTP listener. The port is not used for Unix sockets.
+    pub fn new(address: String, port: u16) -> Result<ListenConfig, &'static str> {
+        if address.is_empty() {
+            return Err("Config value 'listen.address' must not be empty!");
+        }
+
+        if address.starts_with(UNIX_PREFIX) && !address[UNIX_PREFIX.len()..].starts_with('/') {
+            return Err("Config value 'listen.address' must be an absolute path like 'unix:/run/webserver.sock'!");
+        }
+
+        if port == 0 && !address.starts_with(UNIX_PREFIX) {
+            return Err("Config value 'listen.port' must not be 0!");
+        }
+
+        Ok(ListenConfig { address, port, tls: false, ipv6_only: None, default_vhost: None, mode: None, owner: None, name: None })
+    }
+
+    /// Serves HTTPS instead of plain HTTP.
+    pub fn with_tls(mut self, tls: bool) -> Result<ListenConfig, &'static str> {
+        if tls && self.unix_path().is_some() {
+            return Err("Config value 'listen.tls' is not supported for Unix sockets!");
+        }
+
+        self.tls = tls;
+        Ok(self)
+    }
+
+    /// Sets the permissions and the owner of a Unix socket.
+    pub fn with_permissions(mut self, mode: Option<String>, owner: Option<String>) -> Result<ListenConfig, &'static str> {
+        if (mode.is_some() || owner.is_some()) && self.unix_path().is_none() {
+            return Err("Config value 'listen.mode' and 'listen.owner' are only allowed for Unix sockets!");
+        }
+
+        if let Some(ref mode) = mode {
+            parse_mode(mode)?;
+        }
+
+        if let Some(ref owner) = owner {
+            if owner.is_empty() || owner.starts_with(':') || owner.ends_with(':') {
+                return Err("Config value 'listen.owner' must be a user and optional group like 'www-data:proxy'!");
+            }
+        }
+
+        self.mode = mode;
+        self.owner = owner;
+        Ok(self)
+    }
+
+    /// Accepts IPv6 connections only, or IPv4 ones too.
+    pub fn with_ipv6_only(mut self, ipv6_only: Option<bool>) -> ListenConfig {
+        self.ipv6_only = ipv6_only;
+        self
+    }
+
+    /// Sets the server name of the virtual host serving requests matching no virtual host.
+    pub fn with_default_vhost(mut self, default_vhost: Option<String>) -> Result<ListenConfig, &'static str> {
+        if default_vhost.as_ref().is_some_and(|name| name.is_empty()) {
+            return Err("Config value 'listen.default_vhost' must not be empty!");
+        }
+
+        self.default_vhost = default_vhost;
+        Ok(self)
+    }
+
+    /// Sets the name of the socket passed by systemd to use instead of binding.
+    pub fn with_name(mut self, name: Option<String>) -> Result<ListenConfig, &'static str> {
+        if name.as_ref().is_some_and(|name| name.is_empty() || name.contains(':')) {
+            return Err("Config value 'listen.name' must not be empty or contain ':'!");
+        }
+
+        self.name = name;
+        Ok(self)
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<ListenConfig, &'static str> {
+        ListenConfig::new(self.address, self.port)?
+            .with_tls(self.tls)?
+            .with_ipv6_only(self.ipv6_only)
+            .with_default_vhost(self.default_vhost)?
+            .with_permissions(self.mode, self.owner)?
+            .with_name(self.name)
+    }
+
+    /// Get the IP address or host name to listen on.
+    pub fn address(&self) -> &String {
+        &self.address
+    }
+
+    /// Get the TCP port to listen on.
+    pub fn port(&self) -> &u16 {
+        &self.port
+    }
+
+    /// Whether HTTPS is served.
+    pub fn tls(&self) -> bool {
+        self.tls
+    }
+
+    /// Get whether IPv6 connections are accepted only, if configured.
+    pub fn ipv6_only(&self) -> &Option<bool> {
+        &self.ipv6_only
+    }
+
+    /// Get the server name of the virtual host serving requests matching no virtual host, if any.
+    pub fn default_vhost(&self) -> &Option<String> {
+        &self.default_vhost
+    }
+
+    /// Get the octal permissions of a Unix socket, if configured.
+    pub fn mode(&self) -> &Option<String> {
+        &self.mode
+    }
+
+    /// Get the owner of a Unix socket, if configured.
+    pub fn owner(&self) -> &Option<String> {
+        &self.owner
+    }
+
+    /// Get the name of the socket passed by systemd, if configured.
+    pub fn name(&self) -> &Option<String> {
+        &self.name
+    }
+
+    /// Get the path of the Unix socket, if the address names one.
+    pub fn unix_path(&self) -> Option<&str> {
+        if self.address.starts_with(UNIX_PREFIX) {
+            Some(&self.address[UNIX_PREFIX.len()..])
+        } else {
+            None
+        }
+    }
+
+    /// Get the address and port like `127.0.0.1:8080` or `[::1]:8080`, or the address of a Unix socket.
+    pub fn socket_address(&self) -> String {
+        match self.unix_path() {
+            Some(_) => self.address.clone(),
+            None => socket_address(&self.address, self.port),
+        }
+    }
+
+    /// Get the URL of the listener like `https://[::1]:8443/`, or the address of a Unix socket.
+    pub fn url(&self) -> String {
+        match self.unix_path() {
+            Some(_) => self.address.clone(),
+            None => format!("{}://{}/", if self.tls { "https" } else { "http" }, self.socket_address()),
+        }
+    }
+
+    /// Binds a TCP listener to the address and port, or a listener to the Unix socket.
+    pub fn bind(&self) -> io::Result<Listener> {
+        self.bind_reusable(false)
+    }
+
+    /// Binds like `bind`, allowing further TCP sockets on the same address and port with `SO_REUSEPORT` if
+    /// asked to.
+    pub fn bind_reusable(&self, reuse_port: bool) -> io::Result<Listener> {
+        if let Some(path) = self.unix_path() {
+            let owner = match self.owner {
+                Some(ref owner) => Some(lookup_owner(owner)?),
+                None => None,
+            };
+            let mode = self.mode.as_ref().map(|mode| parse_mode(mode).expect("Mode is validated!"));
+
+            return bind_unix(Path::new(path), mode, owner).map(Listener::Unix);
+        }
+
+        let addr = self.socket_address().to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "address resolves to nothing"))?;
+
+        bind_socket(addr, self.ipv6_only, reuse_port).map(Listener::Tcp)
+    }
+}
+
+/// A bound TCP or Unix socket listener.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Get a plain HTTP listener configuration for the address the listener is bound to.
+    pub fn local_config(&self) -> io::Result<ListenConfig> {
+        let listen = match *self {
+            Listener::Tcp(ref listener) => {
+                let addr = listener.local_addr()?;
+                ListenConfig::new(addr.ip().to_string(), addr.port())
+            },
+            Listener::Unix(ref listener) => match listener.local_addr()?.as_pathname() {
+                Some(path) => ListenConfig::new(format!("{}{}", UNIX_PREFIX, path.display()), 0),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "socket has no path")),
+            },
+        };
+
+        listen.map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+    }
+
+    /// Waits for the next connection. The connection blocks on reads and writes, even if the listener doesn't.
+    pub fn accept(&self) -> io::Result<Stream> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                Ok(Stream::Tcp(stream))
+            }),
+            Listener::Unix(ref listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                Ok(Stream::Unix(stream))
+            }),
+        }
+    }
+
+    /// Opens another listener for the same address: a TCP socket of its own if this one uses `SO_REUSEPORT`,
+    /// otherwise a handle of the same socket.
+    pub fn sibling(&self) -> io::Result<Listener> {
+        match *self {
+            Listener::Tcp(ref listener) => {
+                let socket = SockRef::from(listener);
+
+                if !socket.reuse_port()? {
+                    return listener.try_clone().map(Listener::Tcp);
+                }
+
+                let addr = listener.local_addr()?;
+                let ipv6_only = if addr.is_ipv6() { Some(socket.only_v6()?) } else { None };
+                bind_socket(addr, ipv6_only, true).map(Listener::Tcp)
+            },
+            Listener::Unix(ref listener) => listener.try_clone().map(Listener::Unix),
+        }
+    }
+
+    /// Moves the listener into or out of non-blocking mode, where `accept` fails with `WouldBlock` if no
+    /// connection is pending.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.set_nonblocking(nonblocking),
+            Listener::Unix(ref listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Waits at most the timeout for a pending connection. Returns whether there is one.
+    ///
+    /// Another process sharing the socket may still take the connection before it's accepted.
+    pub fn wait(&self, timeout: Duration) -> io::Result<bool> {
+        let mut poll_fd = libc::pollfd { fd: self.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+
+        match unsafe { libc::poll(&mut poll_fd, 1, timeout) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(err) }
+            },
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Listener::Tcp(ref listener) => listener.as_raw_fd(),
+            Listener::Unix(ref listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// A connection accepted on a TCP or Unix socket.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Get the address of the client, unknown for Unix sockets.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.peer_addr().ok(),
+            Stream::Unix(_) => None,
+        }
+    }
+
+    /// Moves the connection into or out of non-blocking mode.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.set_nonblocking(nonblocking),
+            Stream::Unix(ref stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Creates another handle of the same connection.
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Unix(ref stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+}
+
+impl Timed for Stream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(ref stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.set_write_timeout(timeout),
+            Stream::Unix(ref stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Stream::Tcp(ref stream) => stream.as_raw_fd(),
+            Stream::Unix(ref stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            Stream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            Stream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            Stream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<u32, &'static str> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err("Config value 'listen.mode' must be octal permissions like '0660'!"),
+    }
+}
+
+/// Resolves a user and optional group like `www-data:proxy` to their ids. Numeric ids are taken as is.
+fn lookup_owner(owner: &str) -> io::Result<(Option<u32>, Option<u32>)> {
+    let mut parts = owner.splitn(2, ':');
+    let user = parts.next().unwrap_or_default();
+    let uid = match user.parse() {
+        Ok(uid) => uid,
+        Err(_) => {
+            let name = CString::new(user).map_err(io::Error::other)?;
+            // Only called while starting, before other threads may use the passwd database.
+            let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+
+            if passwd.is_null() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown user '{}'", user)));
+            }
+
+            unsafe { (*passwd).pw_uid }
+        },
+    };
+    let gid = match parts.next() {
+        Some(group) => Some(match group.parse() {
+            Ok(gid) => gid,
+            Err(_) => {
+                let name = CString::new(group).map_err(io::Error::other)?;
+                // Only called while starting, before other threads may use the group database.
+                let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+
+                if entry.is_null() {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown group '{}'", group)));
+                }
+
+                unsafe { (*entry).gr_gid }
+            },
+        }),
+        None => None,
+    };
+
+    Ok((Some(uid), gid))
+}
+
+/// Binds a listener to the Unix socket, replacing a stale socket file, and sets permissions and owner.
+///
+/// With a mode, the socket is created accessible by the owner only and gets the owner and then the mode
+/// afterwards.
+pub fn bind_unix(path: &Path, mode: Option<u32>, owner: Option<(Option<u32>, Option<u32>)>) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file exists and is no socket"));
+        }
+
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "socket is in use by another process"));
+        }
+
+        info!("Removing stale socket {:?}", path);
+        fs::remove_file(path)?;
+    }
+
+    let listener = match mode {
+        Some(_) => bind_owner_only(path)?,
+        None => UnixListener::bind(path)?,
+    };
+
+    // The owner comes first, so the group of the server can't connect before the configured group is set.
+    if let Some((uid, gid)) = owner {
+        ::std::os::unix::fs::chown(path, uid, gid)?;
+    }
+
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(listener)
+}
+
+/// Binds a listener to the Unix socket with a socket file accessible by the owner only.
+///
+/// Linux creates the socket file with the mode of the socket before binding, so unlike changing the umask this
+/// doesn't affect files created by other threads meanwhile.
+fn bind_owner_only(path: &Path) -> io::Result<UnixListener> {
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+
+    if unsafe { libc::fchmod(socket.as_raw_fd(), 0o600) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    socket.bind(&SockAddr::unix(path)?)?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+/// Removes the socket file of a Unix socket listener on shutdown.
+pub fn remove_socket(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(_) => info!("Removed socket {:?}", path),
+        Err(err) => warn!("Can't remove socket {:?}: {}", path, err),
+    }
+}
+
+/// Formats address and port like `127.0.0.1:8080`, putting IPv6 addresses in brackets like `[::1]:8080`.
+pub fn socket_address(address: &str, port: u16) -> String {
+    if address.contains(':') && !address.starts_with('[') {
+        format!("[{}]:{}", address, port)
+    } else {
+        format!("{}:{}", address, port)
+    }
+}
+
+/// Binds a TCP listener, setting `IPV6_V6ONLY` for IPv6 addresses if given.
+pub fn bind(addr: SocketAddr, ipv6_only: Option<bool>) -> io::Result<TcpListener> {
+    bind_socket(addr, ipv6_only, false)
+}
+
+/// Binds a TCP listener like `bind`, with `SO_REUSEPORT` if asked to.
+fn bind_socket(addr: SocketAddr, ipv6_only: Option<bool>, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+
+    if let (SocketAddr::V6(_), Some(ipv6_only)) = (addr, ipv6_only) {
+        socket.set_only_v6(ipv6_only)?;
+    }
+
+    if cfg!(unix) {
+        socket.set_reuse_address(true)?;
+    }
+
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+
+    #[test]
+    fn socket_address_puts_ipv6_in_brackets() {
+        assert_that!(socket_address("127.0.0.1", 8080), is(equal_to(String::from("127.0.0.1:8080"))));
+        assert_that!(socket_address("localhost", 8080), is(equal_to(String::from("localhost:8080"))));
+        assert_that!(socket_address("::1", 8080), is(equal_to(String::from("[::1]:8080"))));
+        assert_that!(socket_address("[::1]", 8080), is(equal_to(String::from("[::1]:8080"))));
+    }
+
+    #[test]
+    fn new_validates_address_and_port() {
+        assert_that!(
+            ListenConfig::new(String::new(), 8080),
+            is(equal_to(Err("Config value 'listen.address' must not be empty!"))));
+        assert_that!(ListenConfig::new(String::from("::1"), 0), is(equal_to(Err("Config value 'listen.port' must not be 0!"))));
+        assert_that!(
+            ListenConfig::new(String::from("::"), 8443).unwrap().with_tls(true).unwrap().url(),
+            is(equal_to(String::from("https://[::]:8443/"))));
+    }
+
+    #[test]
+    fn unix_socket_address() {
+        let sut = ListenConfig::new(String::from("unix:/run/webserver.sock"), 0).unwrap();
+
+        assert_that!(sut.unix_path(), is(equal_to(Some("/run/webserver.sock"))));
+        assert_that!(sut.url(), is(equal_to(String::from("unix:/run/webserver.sock"))));
+        assert_that!(
+            ListenConfig::new(String::from("unix:webserver.sock"), 0),
+            is(equal_to(Err("Config value 'listen.address' must be an absolute path like 'unix:/run/webserver.sock'!"))));
+        assert_that!(
+            sut.clone().with_tls(true),
+            is(equal_to(Err("Config value 'listen.tls' is not supported for Unix sockets!"))));
+        assert_that!(
+            sut.clone().with_permissions(Some(String::from("0960")), None),
+            is(equal_to(Err("Config value 'listen.mode' must be octal permissions like '0660'!"))));
+        assert_that!(
+            ListenConfig::new(String::from("::1"), 8080).unwrap().with_permissions(Some(String::from("0660")), None),
+            is(equal_to(Err("Config value 'listen.mode' and 'listen.owner' are only allowed for Unix sockets!"))));
+        assert_that!(
+            sut.with_permissions(Some(String::from("0660")), Some(String::from("www-data:"))),
+            is(equal_to(Err("Config value 'listen.owner' must be a user and optional group like 'www-data:proxy'!"))));
+    }
+
+    #[test]
+    fn bind_unix_replaces_stale_socket() {
+        let path = ::std::env::temp_dir().join(format!("webserver-listen-{}.sock", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        drop(UnixListener::bind(&path).unwrap());
+
+        let listener = Listener::Unix(bind_unix(&path, Some(0o600), Some((None, None))).unwrap());
+
+        assert_that!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, is(equal_to(0o600)));
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        listener.accept().unwrap().read_exact(&mut buf).unwrap();
+        assert_that!(&buf, is(equal_to(b"ping")));
+        assert_that!(bind_unix(&path, None, None).unwrap_err().kind(), is(equal_to(io::ErrorKind::AddrInUse)));
+
+        drop(listener);
+        remove_socket(&path);
+        assert_that!(path.exists(), is(false));
+        fs::write(&path, "no socket").unwrap();
+        assert_that!(bind_unix(&path, None, None).unwrap_err().kind(), is(equal_to(io::ErrorKind::AlreadyExists)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bind_unix_sets_mode_wider_than_while_binding() {
+        let path = ::std::env::temp_dir().join(format!("webserver-listen-mode-{}.sock", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let listener = bind_unix(&path, Some(0o666), None).unwrap();
+
+        assert_that!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, is(equal_to(0o666)));
+        drop(listener);
+        remove_socket(&path);
+    }
+
+    #[test]
+    fn bind_owner_only_creates_socket_for_owner() {
+        let path = ::std::env::temp_dir().join(format!("webserver-listen-owner-{}.sock", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let listener = bind_owner_only(&path).unwrap();
+
+        assert_that!(fs::metadata(&path).unwrap().permissions().mode() & 0o077, is(equal_to(0)));
+        UnixStream::connect(&path).unwrap();
+        drop(listener);
+        remove_socket(&path);
+    }
+
+    #[test]
+    fn wait_returns_whether_connection_is_pending() {
+        let listener = Listener::Tcp(bind("127.0.0.1:0".parse().unwrap(), None).unwrap());
+        let port = *listener.local_config().unwrap().port();
+        listener.set_nonblocking(true).unwrap();
+
+        assert_that!(listener.wait(Duration::from_millis(10)).unwrap(), is(false));
+        assert_that!(listener.accept().unwrap_err().kind(), is(equal_to(io::ErrorKind::WouldBlock)));
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(b"ping").unwrap();
+        assert_that!(listener.wait(Duration::from_secs(5)).unwrap(), is(true));
+        let mut buf = [0; 4];
+        listener.accept().unwrap().read_exact(&mut buf).unwrap();
+        assert_that!(&buf, is(equal_to(b"ping")));
+    }
+
+    #[test]
+    fn sibling_binds_own_socket_with_reuse_port() {
+        let listener = Listener::Tcp(bind_socket("127.0.0.1:0".parse().unwrap(), None, true).unwrap());
+        let port = *listener.local_config().unwrap().port();
+        let sibling = listener.sibling().unwrap();
+
+        assert_that!(*sibling.local_config().unwrap().port(), is(equal_to(port)));
+        assert_that!(sibling.as_raw_fd(), is(not(equal_to(listener.as_raw_fd()))));
+        assert_that!(bind(format!("127.0.0.1:{}", port).parse().unwrap(), None).is_err(), is(true));
+
+        drop(listener);
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        sibling.accept().unwrap().read_exact(&mut buf).unwrap();
+        assert_that!(&buf, is(equal_to(b"ping")));
+    }
+
+    #[test]
+    fn acceptors_count_must_be_positive() {
+        assert_that!(AcceptorsConfig::new(0), is(equal_to(Err("Config value 'acceptors.count' must be greater than 0!"))));
+        assert_that!(*AcceptorsConfig::new(4).unwrap().mode(), is(equal_to(AcceptMode::Queue)));
+    }
+
+    #[test]
+    fn lookup_owner_resolves_names_and_ids() {
+        assert_that!(lookup_owner("root:root").unwrap(), is(equal_to((Some(0), Some(0)))));
+        assert_that!(lookup_owner("1000").unwrap(), is(equal_to((Some(1000), None))));
+        assert_that!(lookup_owner("no-such-user").is_err(), is(true));
+    }
+
+    #[test]
+    fn bind_sets_ipv6_only() {
+        let listener = match bind("[::1]:0".parse().unwrap(), Some(true)) {
+            Ok(listener) => listener,
+            // The test system has no IPv6.
+            Err(_) => return,
+        };
+
+        assert_that!(listener.local_addr().unwrap().is_ipv6(), is(true));
+        assert_that!(Socket::from(listener).only_v6().unwrap(), is(true));
+    }
+}
@@ -0,0 +1,167 @@
+///! This module provides per location settings.
+///!
+///! A location applies to all request paths starting with its path prefix. If several locations
+///! match, the one with the longest prefix wins.
+
+use std::net::IpAddr;
+use acl;
+use acl::{AccessRule, DenyAction};
+
+/// Configuration of a location.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct Location {
+    /// Path prefix of the location, e.g. `/downloads/`.
+    path: String,
+    /// Whether directories without index file are listed.
+    #[serde(default)]
+    autoindex: bool,
+    /// Whether files starting with a dot are included in directory listings.
+    #[serde(default)]
+    show_hidden: bool,
+    /// Allow and deny rules for client addresses, evaluated in order, e.g. `['allow 10.0.0.0/8', 'deny all']`.
+    #[serde(default)]
+    access: Vec<String>,
+    /// Whether denied clients get `403 FORBIDDEN` (`forbidden`) or no answer at all (`drop`).
+    #[serde(default)]
+    deny_action: DenyAction,
+    #[serde(skip)]
+    access_rules: Vec<AccessRule>,
+}
+
+impl Location {
+    /// Creates a new location for the given path prefix.
+    pub fn new(path: String) -> Result<Location, &'static str> {
+        if !path.starts_with('/') {
+            return Err("Config value 'location.path' must start with '/'!");
+        }
+
+        Ok(Location {
+            path,
+            autoindex: false,
+            show_hidden: false,
+            access: Vec::new(),
+            deny_action: DenyAction::Forbidden,
+            access_rules: Vec::new(),
+        })
+    }
+
+    /// Enables or disables directory listings.
+    pub fn with_autoindex(mut self, autoindex: bool, show_hidden: bool) -> Location {
+        self.autoindex = autoindex;
+        self.show_hidden = show_hidden;
+        self
+    }
+
+    /// Sets the access rules for client addresses and what happens to denied clients.
+    pub fn with_access(mut self, access: Vec<String>, deny_action: DenyAction) -> Result<Location, &'static str> {
+        let mut access_rules = Vec::with_capacity(access.len());
+
+        for rule in access.iter() {
+            access_rules.push(AccessRule::parse(rule)?);
+        }
+
+        self.access = access;
+        self.deny_action = deny_action;
+        self.access_rules = access_rules;
+        Ok(self)
+    }
+
+    /// Validates a deserialized location by running it through the constructor.
+    pub fn validate(self) -> Result<Location, &'static str> {
+        let autoindex = self.autoindex;
+        let show_hidden = self.show_hidden;
+        let deny_action = self.deny_action;
+
+        Location::new(self.path)?
+            .with_autoindex(autoindex, show_hidden)
+            .with_access(self.access, deny_action)
+    }
+
+    /// Get the path prefix.
+    pub fn path(&self) -> &String {
+        &self.path
+    }
+
+    /// Whether directories without index file are listed.
+    pub fn autoindex(&self) -> bool {
+        self.autoindex
+    }
+
+    /// Whether dotfiles are included in directory listings.
+    pub fn show_hidden(&self) -> bool {
+        self.show_hidden
+    }
+
+    /// Get what happens to denied clients.
+    pub fn deny_action(&self) -> DenyAction {
+        self.deny_action
+    }
+
+    /// Whether the access rules allow the client address.
+    pub fn is_allowed(&self, address: Option<IpAddr>) -> bool {
+        acl::is_allowed(&self.access_rules, address)
+    }
+}
+
+/// Selects the location with the longest path prefix matching the given request path.
+pub fn select<'a>(locations: &'a [Location], path: &str) -> Option<&'a Location> {
+    locations.iter()
+        .filter(|location| path.starts_with(location.path.as_str()))
+        .fold(None, |best: Option<&Location>, candidate| match best {
+            Some(best) if best.path.len() >= candidate.path.len() => Some(best),
+            _ => Some(candidate),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+
+    #[test]
+    fn new_validates_path_is_absolute() {
+        assert_that!(
+            Location::new(String::from("downloads")),
+            is(equal_to(Err("Config value 'location.path' must start with '/'!"))));
+    }
+
+    #[test]
+    fn with_access_validates_rules() {
+        assert_that!(
+            Location::new(String::from("/")).unwrap()
+                .with_access(vec!(String::from("allow everybody")), DenyAction::Drop),
+            is(equal_to(Err("Config value 'location.access' must contain valid IP addresses!"))));
+    }
+
+    #[test]
+    fn is_allowed_evaluates_access_rules() {
+        let sut = Location::new(String::from("/admin/")).unwrap()
+            .with_access(vec!(String::from("allow 127.0.0.1"), String::from("deny all")), DenyAction::Drop)
+            .unwrap();
+
+        assert_that!(sut.is_allowed(Some("127.0.0.1".parse().unwrap())), is(true));
+        assert_that!(sut.is_allowed(Some("10.0.0.1".parse().unwrap())), is(false));
+        assert_that!(sut.deny_action(), is(equal_to(DenyAction::Drop)));
+    }
+
+    #[test]
+    fn select_prefers_longest_prefix() {
+        let locations = vec!(
+            Location::new(String::from("/")).unwrap(),
+            Location::new(String::from("/downloads/")).unwrap(),
+            Location::new(String::from("/downloads/private/")).unwrap());
+
+        assert_that!(select(&locations, "/index.html").unwrap().path().as_str(), is(equal_to("/")));
+        assert_that!(select(&locations, "/downloads/a.zip").unwrap().path().as_str(), is(equal_to("/downloads/")));
+        assert_that!(
+            select(&locations, "/downloads/private/b.zip").unwrap().path().as_str(),
+            is(equal_to("/downloads/private/")));
+    }
+
+    #[test]
+    fn select_returns_none_if_nothing_matches() {
+        let locations = vec!(Location::new(String::from("/downloads/")).unwrap());
+
+        assert_that!(select(&locations, "/index.html"), is(equal_to(None)));
+    }
+}
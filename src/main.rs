@@ -2,39 +2,61 @@ extern crate webserver;
 extern crate flexi_logger;
 #[macro_use]
 extern crate log;
+extern crate time;
 
 use flexi_logger::{Logger, opt_format};
+use log::Record;
 
 extern crate clap;
 
 use std::process;
-use clap::{Arg, App};
+use clap::{Arg, App, ArgMatches};
 use std::path::{PathBuf};
 use flexi_logger::FlexiLoggerError;
 use webserver::Config;
 use webserver::server::Server;
+use webserver::rewrite;
+use webserver::rewrite::{Outcome, RewriteRequest};
+use webserver::accesslog::format_rfc3339;
+use webserver::escape;
+use webserver::http;
+use webserver::systemd::ListenFds;
 
 fn main() {
-    let config = read_config().unwrap_or_else(|err| {
+    let matches = parse_arguments();
+    let config = read_config(&matches).unwrap_or_else(|err| {
         println!("Problem reading config file: {}", err);
         process::exit(1);
     });
 
+    if let Some(url) = matches.value_of("test-rewrite") {
+        let host = matches.value_of("host").unwrap_or("localhost");
+        let method = matches.value_of("method").unwrap_or("GET");
+        test_rewrite(&config, url, host, method);
+        return;
+    }
+
     let _ = setup_logging(&config).unwrap_or_else(|err| {
         println!("Logger initialization failed with {}", err);
         process::exit(2);
     });
 
     info!("Starting web server ...");
-    let server = Server::new(config);
+    let listen_fds = ListenFds::from_env().unwrap_or_else(|err| {
+        println!("{}", err);
+        process::exit(3);
+    });
+    let server = Server::new(config)
+        .with_config_file(config_file(&matches))
+        .with_listen_fds(listen_fds);
     server.bind().unwrap_or_else(|err| {
         println!("{}", err);
         process::exit(3);
     });
 }
 
-fn read_config() -> Result<Config, &'static str> {
-    let matches = App::new(webserver::APPLICATION_DESCRIPTION)
+fn parse_arguments() -> ArgMatches<'static> {
+    App::new(webserver::APPLICATION_DESCRIPTION)
         .version(webserver::APPLICATION_VERSION)
         .author("Sven Strittmatter <ich@weltraumschaf.de>")
         .about("A minimalistic HTTP server.")
@@ -44,11 +66,62 @@ fn read_config() -> Result<Config, &'static str> {
             .takes_value(true)
             .help("Location of configuration file in TOML format.")
             .required(true))
-        .get_matches();
+        .arg(Arg::with_name("test-rewrite")
+            .long("test-rewrite")
+            .takes_value(true)
+            .value_name("URL")
+            .help("Prints which rewrite rules match the given URL and exits."))
+        .arg(Arg::with_name("host")
+            .long("host")
+            .takes_value(true)
+            .requires("test-rewrite")
+            .help("Host header used with --test-rewrite (default: localhost)."))
+        .arg(Arg::with_name("method")
+            .long("method")
+            .takes_value(true)
+            .requires("test-rewrite")
+            .help("HTTP method used with --test-rewrite (default: GET)."))
+        .get_matches()
+}
 
-    let config_file = matches.value_of("config").expect("No config file given!");
-    let config_file = PathBuf::from(config_file);
-    Config::from_file(&config_file)
+fn config_file(matches: &ArgMatches) -> PathBuf {
+    PathBuf::from(matches.value_of("config").expect("No config file given!"))
+}
+
+fn read_config(matches: &ArgMatches) -> Result<Config, &'static str> {
+    Config::from_file(&config_file(matches))
+}
+
+fn test_rewrite(config: &Config, url: &str, host: &str, method: &str) {
+    let vhost = config.virtual_host(host);
+    let (path, query) = match url.find('?') {
+        Some(question_mark) => (&url[..question_mark], &url[question_mark + 1..]),
+        None => (url, ""),
+    };
+    let request = RewriteRequest::new(
+        http::resolve_path(path),
+        query.to_string(),
+        host,
+        method);
+    let exists = |path: &str| rewrite::exists_in_web_root(vhost.web_dir(), path);
+    let mut trace = Vec::new();
+    let outcome = rewrite::apply(vhost.rewrites(), request, &exists, &mut trace);
+
+    println!("Virtual host: {}", vhost.name());
+
+    for step in trace {
+        println!("{}", step);
+    }
+
+    match outcome {
+        Ok(Outcome::Unchanged) => println!("No rule matches {}", url),
+        Ok(Outcome::Rewritten(url)) => println!("Serves {}", url),
+        Ok(Outcome::Redirect(status, location)) => println!("Redirects with {} to {}", status, location),
+        Err(err) => {
+            println!("{}", err);
+            process::exit(4);
+        },
+    }
 }
 
 fn setup_logging(config: &Config) -> Result<(), FlexiLoggerError> {
@@ -58,11 +131,28 @@ fn setup_logging(config: &Config) -> Result<(), FlexiLoggerError> {
         webserver::APPLICATION_NAME, level,
         webserver::APPLICATION_NAME, level);
     println!("Use log config: {}", log_config);
+    let format = match config.log_format().as_str() {
+        "json" => json_format,
+        _ => opt_format,
+    };
     Logger::with_str(log_config.as_str())
         .log_to_file()
         .print_message()
         .directory(config.log_dir().clone())
         .duplicate_error()
-        .format(opt_format)
+        .format(format)
         .start()
 }
+
+/// Formats a log record as one JSON object with the fields `timestamp` (RFC 3339, UTC), `level`,
+/// `target`, `file`, `line` and `message`.
+fn json_format(record: &Record) -> String {
+    format!(
+        "{{\"timestamp\":{},\"level\":{},\"target\":{},\"file\":{},\"line\":{},\"message\":{}}}",
+        escape::json(&format_rfc3339(&time::now_utc())),
+        escape::json(&record.level().to_string()),
+        escape::json(record.target()),
+        record.file().map(escape::json).unwrap_or_else(|| String::from("null")),
+        record.line().map(|line| line.to_string()).unwrap_or_else(|| String::from("null")),
+        escape::json(&record.args().to_string()))
+}
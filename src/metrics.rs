@@ -0,0 +1,379 @@
+///! This module collects metrics and renders them in the
+///! [Prometheus text format](https://prometheus.io/docs/instrumenting/exposition_formats/).
+///!
+///! The metrics are served at a configurable path, either on the main listener or on a separate
+///! admin listener which keeps working while all workers are busy.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use acl;
+use acl::AccessRule;
+use cache::FileCache;
+use threads::PoolStats;
+use timeout::Timeout;
+
+/// Upper bounds of the request duration histogram buckets in seconds.
+pub static DURATION_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Methods counted by name, all others are counted as `OTHER` to bound the number of series.
+static KNOWN_METHODS: [&'static str; 9] = ["GET", "HEAD", "POST", "PUT", "DELETE", "OPTIONS", "PATCH", "CONNECT", "TRACE"];
+
+fn default_path() -> String {
+    String::from("/metrics")
+}
+
+fn default_reload_access() -> Vec<String> {
+    vec!(String::from("allow 127.0.0.1"), String::from("allow ::1"), String::from("deny all"))
+}
+
+/// Configuration of the metrics endpoint.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct MetricsConfig {
+    /// Path the metrics are served at.
+    #[serde(default = "default_path")]
+    path: String,
+    /// Address like `127.0.0.1:9090` of a separate admin listener. Served on the main listener if not set.
+    #[serde(default)]
+    listen: Option<String>,
+    /// Allow and deny rules for clients reloading the configuration on the admin listener. Only localhost by
+    /// default.
+    #[serde(default = "default_reload_access")]
+    reload_access: Vec<String>,
+    #[serde(skip)]
+    reload_access_rules: Vec<AccessRule>,
+}
+
+impl MetricsConfig {
+    /// Creates a new metrics configuration.
+    pub fn new(path: String, listen: Option<String>) -> Result<MetricsConfig, &'static str> {
+        if !path.starts_with('/') {
+            return Err("Config value 'metrics.path' must start with '/'!");
+        }
+
+        if let Some(ref listen) = listen {
+            if listen.is_empty() {
+                return Err("Config value 'metrics.listen' must not be empty!");
+            }
+        }
+
+        MetricsConfig { path, listen, reload_access: Vec::new(), reload_access_rules: Vec::new() }
+            .with_reload_access(default_reload_access())
+    }
+
+    /// Sets the allow and deny rules for clients reloading the configuration.
+    pub fn with_reload_access(mut self, reload_access: Vec<String>) -> Result<MetricsConfig, &'static str> {
+        let mut reload_access_rules = Vec::with_capacity(reload_access.len());
+
+        for rule in reload_access.iter() {
+            reload_access_rules.push(AccessRule::parse(rule)
+                .map_err(|_| "Config value 'metrics.reload_access' must contain rules like 'allow 127.0.0.1' or 'deny all'!")?);
+        }
+
+        self.reload_access = reload_access;
+        self.reload_access_rules = reload_access_rules;
+        Ok(self)
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<MetricsConfig, &'static str> {
+        MetricsConfig::new(self.path, self.listen)?.with_reload_access(self.reload_access)
+    }
+
+    /// Get the path the metrics are served at.
+    pub fn path(&self) -> &String {
+        &self.path
+    }
+
+    /// Get the address of the admin listener, if any.
+    pub fn listen(&self) -> &Option<String> {
+        &self.listen
+    }
+
+    /// Whether the client may reload the configuration on the admin listener.
+    pub fn is_reload_allowed(&self, client: Option<SocketAddr>) -> bool {
+        acl::is_allowed(&self.reload_access_rules, client.map(|addr| addr.ip()))
+    }
+}
+
+/// Metrics shared by all workers.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Answered requests by method, status and virtual host.
+    requests: Mutex<BTreeMap<(String, u16, String), u64>>,
+    /// Requests per duration bucket, not cumulative. The last bucket counts requests above all bounds.
+    duration_buckets: [AtomicU64; 12],
+    /// Sum of all request durations in microseconds.
+    duration_sum_us: AtomicU64,
+    /// Bytes of response bodies sent.
+    bytes_sent: AtomicU64,
+    /// Connections currently handled.
+    in_flight: AtomicUsize,
+    /// Connections which ran into a timeout, by timeout.
+    timeouts: Mutex<BTreeMap<Timeout, u64>>,
+}
+
+impl Metrics {
+    /// Creates empty metrics.
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Records an answered request.
+    pub fn observe(&self, method: &str, status: u16, vhost: &str, bytes: usize, duration: Duration) {
+        let method = if KNOWN_METHODS.contains(&method) { method } else { "OTHER" };
+        let seconds = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+        let bucket = DURATION_BUCKETS.iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+
+        *self.requests.lock().unwrap()
+            .entry((method.to_string(), status, vhost.to_string()))
+            .or_insert(0) += 1;
+        self.duration_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.duration_sum_us.fetch_add(
+            duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros()),
+            Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records a connection which ran into the timeout.
+    pub fn timeout(&self, timeout: Timeout) {
+        *self.timeouts.lock().unwrap().entry(timeout).or_insert(0) += 1;
+    }
+
+    /// Get the number of connections which ran into the timeout.
+    pub fn timeouts(&self, timeout: Timeout) -> u64 {
+        self.timeouts.lock().unwrap().get(&timeout).cloned().unwrap_or(0)
+    }
+
+    /// Marks a connection as handled until the returned guard is dropped.
+    pub fn connection(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight { metrics: self }
+    }
+
+    /// Get the number of connections currently handled.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Get the total number of answered requests.
+    pub fn requests_total(&self) -> u64 {
+        self.requests.lock().unwrap().values().sum()
+    }
+
+    /// Get the number of answered requests by status code.
+    pub fn requests_by_status(&self) -> BTreeMap<u16, u64> {
+        let mut by_status = BTreeMap::new();
+
+        for (&(_, status, _), count) in self.requests.lock().unwrap().iter() {
+            *by_status.entry(status).or_insert(0) += *count;
+        }
+
+        by_status
+    }
+
+    /// Get the bytes of response bodies sent.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Renders all metrics in the Prometheus text format.
+    pub fn render(&self, pool: Option<&PoolStats>, cache: Option<&FileCache>) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP webserver_requests_total Answered requests.\n");
+        out.push_str("# TYPE webserver_requests_total counter\n");
+
+        for (&(ref method, status, ref vhost), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "webserver_requests_total{{method=\"{}\",status=\"{}\",vhost=\"{}\"}} {}",
+                escape_label(method), status, escape_label(vhost), count);
+        }
+
+        out.push_str("# HELP webserver_request_duration_seconds Time to answer requests.\n");
+        out.push_str("# TYPE webserver_request_duration_seconds histogram\n");
+        let mut cumulative = 0;
+
+        for (bound, bucket) in DURATION_BUCKETS.iter().zip(self.duration_buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "webserver_request_duration_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative);
+        }
+
+        cumulative += self.duration_buckets[DURATION_BUCKETS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "webserver_request_duration_seconds_bucket{{le=\"+Inf\"}} {}", cumulative);
+        let _ = writeln!(
+            out,
+            "webserver_request_duration_seconds_sum {}",
+            self.duration_sum_us.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "webserver_request_duration_seconds_count {}", cumulative);
+
+        write_metric(&mut out, "webserver_response_bytes_total", "counter", "Bytes of response bodies sent.",
+            self.bytes_sent() as usize);
+        write_metric(&mut out, "webserver_connections_in_flight", "gauge", "Connections currently handled.",
+            self.in_flight());
+
+        out.push_str("# HELP webserver_timeouts_total Connections which ran into a timeout.\n");
+        out.push_str("# TYPE webserver_timeouts_total counter\n");
+
+        for (timeout, count) in self.timeouts.lock().unwrap().iter() {
+            let _ = writeln!(out, "webserver_timeouts_total{{timeout=\"{}\"}} {}", timeout.name(), count);
+        }
+
+        if let Some(pool) = pool {
+            write_metric(&mut out, "webserver_pool_workers", "gauge", "Workers of the thread pool.",
+                pool.size());
+            write_metric(&mut out, "webserver_pool_busy_workers", "gauge", "Workers executing a job.",
+                pool.busy_workers());
+            write_metric(&mut out, "webserver_pool_queue_depth", "gauge", "Jobs waiting for a free worker.",
+                pool.queue_depth());
+
+            if let Some(capacity) = pool.capacity() {
+                write_metric(&mut out, "webserver_pool_queue_capacity", "gauge", "Jobs which may wait for a free worker.",
+                    capacity);
+            }
+
+            write_metric(&mut out, "webserver_pool_rejected_total", "counter",
+                "Connections rejected because the queue was full.", pool.rejected());
+        }
+
+        if let Some(cache) = cache {
+            write_metric(&mut out, "webserver_cache_hits_total", "counter", "Requests served from the file cache.",
+                cache.hits());
+            write_metric(&mut out, "webserver_cache_misses_total", "counter", "Requests missing the file cache.",
+                cache.misses());
+            write_metric(&mut out, "webserver_cache_bytes", "gauge", "Bytes of file content held by the cache.",
+                cache.size());
+            write_metric(&mut out, "webserver_cache_entries", "gauge", "Files held by the cache.",
+                cache.len());
+        }
+
+        out
+    }
+}
+
+/// Decrements the in-flight connections when dropped.
+#[derive(Debug)]
+pub struct InFlight<'a> {
+    metrics: &'a Metrics,
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.metrics.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: usize) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+
+    #[test]
+    fn new_validates_path() {
+        assert_that!(
+            MetricsConfig::new(String::from("metrics"), None),
+            is(equal_to(Err("Config value 'metrics.path' must start with '/'!"))));
+    }
+
+    #[test]
+    fn reload_is_allowed_from_localhost_by_default() {
+        let sut = MetricsConfig::new(String::from("/metrics"), Some(String::from("127.0.0.1:9090"))).unwrap();
+
+        assert_that!(sut.is_reload_allowed(Some("127.0.0.1:4711".parse().unwrap())), is(true));
+        assert_that!(sut.is_reload_allowed(Some("[::1]:4711".parse().unwrap())), is(true));
+        assert_that!(sut.is_reload_allowed(Some("10.0.0.1:4711".parse().unwrap())), is(false));
+        assert_that!(sut.is_reload_allowed(None), is(false));
+
+        let sut = sut.with_reload_access(vec!(String::from("allow 10.0.0.0/8"))).unwrap();
+        assert_that!(sut.is_reload_allowed(Some("10.0.0.1:4711".parse().unwrap())), is(true));
+        assert_that!(
+            sut.with_reload_access(vec!(String::from("permit all"))),
+            is(equal_to(Err("Config value 'metrics.reload_access' must contain rules like 'allow 127.0.0.1' or 'deny all'!"))));
+    }
+
+    #[test]
+    fn render_requests_and_histogram() {
+        let sut = Metrics::new();
+
+        sut.observe("GET", 200, "example.com", 100, Duration::from_millis(3));
+        sut.observe("GET", 200, "example.com", 50, Duration::from_millis(30));
+        sut.observe("BREW", 405, "a\"b", 0, Duration::from_secs(20));
+
+        let out = sut.render(None, None);
+
+        assert_that!(out.contains("webserver_requests_total{method=\"GET\",status=\"200\",vhost=\"example.com\"} 2\n"), is(true));
+        assert_that!(out.contains("webserver_requests_total{method=\"OTHER\",status=\"405\",vhost=\"a\\\"b\"} 1\n"), is(true));
+        assert_that!(out.contains("webserver_request_duration_seconds_bucket{le=\"0.005\"} 1\n"), is(true));
+        assert_that!(out.contains("webserver_request_duration_seconds_bucket{le=\"0.05\"} 2\n"), is(true));
+        assert_that!(out.contains("webserver_request_duration_seconds_bucket{le=\"10\"} 2\n"), is(true));
+        assert_that!(out.contains("webserver_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"), is(true));
+        assert_that!(out.contains("webserver_request_duration_seconds_sum 20.033\n"), is(true));
+        assert_that!(out.contains("webserver_request_duration_seconds_count 3\n"), is(true));
+        assert_that!(out.contains("webserver_response_bytes_total 150\n"), is(true));
+        assert_that!(out.contains("webserver_pool_workers"), is(false));
+        assert_that!(out.contains("webserver_pool_rejected_total"), is(false));
+        assert_that!(sut.requests_total(), is(equal_to(3)));
+        assert_that!(sut.requests_by_status().get(&200), is(equal_to(Some(&2))));
+    }
+
+    #[test]
+    fn connection_guard_counts_in_flight() {
+        let sut = Metrics::new();
+
+        {
+            let _first = sut.connection();
+            let _second = sut.connection();
+            assert_that!(sut.in_flight(), is(equal_to(2)));
+        }
+
+        assert_that!(sut.in_flight(), is(equal_to(0)));
+        assert_that!(sut.render(None, None).contains("webserver_connections_in_flight 0\n"), is(true));
+    }
+
+    #[test]
+    fn render_timeouts() {
+        let sut = Metrics::new();
+
+        sut.timeout(Timeout::Header);
+        sut.timeout(Timeout::Header);
+        sut.timeout(Timeout::MinRate);
+
+        let out = sut.render(None, None);
+
+        assert_that!(out.contains("webserver_timeouts_total{timeout=\"header\"} 2\n"), is(true));
+        assert_that!(out.contains("webserver_timeouts_total{timeout=\"min_rate\"} 1\n"), is(true));
+        assert_that!(sut.timeouts(Timeout::Write), is(equal_to(0)));
+    }
+
+    #[test]
+    fn render_bounded_pool() {
+        let pool = PoolStats::new(4).with_capacity(100);
+        let out = Metrics::new().render(Some(&pool), None);
+
+        assert_that!(out.contains("webserver_pool_queue_capacity 100\n"), is(true));
+        assert_that!(out.contains("webserver_pool_rejected_total 0\n"), is(true));
+    }
+
+    #[test]
+    fn render_cache() {
+        let cache = FileCache::new(1024);
+
+        assert_that!(
+            Metrics::new().render(None, Some(&cache)).contains("webserver_cache_hits_total 0\n"),
+            is(true));
+    }
+}
@@ -0,0 +1,412 @@
+///! This module provides per-client rate limiting with token buckets.
+///!
+///! Each client IP gets a bucket holding up to `burst` tokens, refilled with `requests_per_second`
+///! tokens per second. A request takes one token; if the bucket is empty the request is limited.
+///! Path prefixes may have their own, separately tracked limits. The number of tracked buckets is
+///! bounded by `max_clients`: buckets idle for longer than `idle_timeout` seconds are expired, and if
+///! all buckets are in use the least recently used one is evicted.
+///!
+///! IPv6 clients are tracked by their /64 network, since a single host usually gets a whole /64 and
+///! could otherwise evade the limit by rotating its addresses.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+fn default_max_clients() -> usize {
+    10_000
+}
+
+fn default_idle_timeout() -> u64 {
+    300
+}
+
+/// Configuration of the rate limiter.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct RateLimitConfig {
+    /// Tokens refilled per second, i.e. the sustained request rate.
+    requests_per_second: f64,
+    /// Size of the bucket, i.e. the number of requests allowed in a burst.
+    burst: u32,
+    /// Maximum number of tracked buckets.
+    #[serde(default = "default_max_clients")]
+    max_clients: usize,
+    /// Seconds after which the bucket of an idle client is forgotten.
+    #[serde(default = "default_idle_timeout")]
+    idle_timeout: u64,
+    /// Separate limits for path prefixes.
+    #[serde(default, rename = "path")]
+    paths: Vec<PathLimit>,
+}
+
+impl RateLimitConfig {
+    /// Creates a new rate limit configuration.
+    pub fn new(requests_per_second: f64, burst: u32) -> Result<RateLimitConfig, &'static str> {
+        validate_limit(requests_per_second, burst)?;
+
+        Ok(RateLimitConfig {
+            requests_per_second,
+            burst,
+            max_clients: default_max_clients(),
+            idle_timeout: default_idle_timeout(),
+            paths: Vec::new(),
+        })
+    }
+
+    /// Sets the bounds of the tracked state.
+    pub fn with_bounds(mut self, max_clients: usize, idle_timeout: u64) -> Result<RateLimitConfig, &'static str> {
+        if max_clients < 1 {
+            return Err("Config value 'rate_limit.max_clients' must be grater than 0!");
+        }
+
+        if idle_timeout < 1 {
+            return Err("Config value 'rate_limit.idle_timeout' must be grater than 0!");
+        }
+
+        self.max_clients = max_clients;
+        self.idle_timeout = idle_timeout;
+        Ok(self)
+    }
+
+    /// Sets separate limits for path prefixes.
+    pub fn with_paths(mut self, paths: Vec<PathLimit>) -> Result<RateLimitConfig, &'static str> {
+        let mut validated = Vec::with_capacity(paths.len());
+
+        for path in paths {
+            validated.push(PathLimit::new(path.path, path.requests_per_second, path.burst)?);
+        }
+
+        self.paths = validated;
+        Ok(self)
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<RateLimitConfig, &'static str> {
+        RateLimitConfig::new(self.requests_per_second, self.burst)?
+            .with_bounds(self.max_clients, self.idle_timeout)?
+            .with_paths(self.paths)
+    }
+
+    /// Get the sustained request rate.
+    pub fn requests_per_second(&self) -> f64 {
+        self.requests_per_second
+    }
+
+    /// Get the number of requests allowed in a burst.
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    /// Get the maximum number of tracked buckets.
+    pub fn max_clients(&self) -> usize {
+        self.max_clients
+    }
+
+    /// Get the seconds after which idle buckets are forgotten.
+    pub fn idle_timeout(&self) -> u64 {
+        self.idle_timeout
+    }
+
+    /// Get the separate limits for path prefixes.
+    pub fn paths(&self) -> &Vec<PathLimit> {
+        &self.paths
+    }
+}
+
+/// A separate limit for a path prefix.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct PathLimit {
+    /// Path prefix the limit applies to, e.g. `/api/`.
+    path: String,
+    /// Tokens refilled per second.
+    requests_per_second: f64,
+    /// Size of the bucket.
+    burst: u32,
+}
+
+impl PathLimit {
+    /// Creates a new limit for the path prefix.
+    pub fn new(path: String, requests_per_second: f64, burst: u32) -> Result<PathLimit, &'static str> {
+        if !path.starts_with('/') {
+            return Err("Config value 'rate_limit.path.path' must start with '/'!");
+        }
+
+        validate_limit(requests_per_second, burst)?;
+        Ok(PathLimit { path, requests_per_second, burst })
+    }
+}
+
+fn validate_limit(requests_per_second: f64, burst: u32) -> Result<(), &'static str> {
+    if !(requests_per_second > 0.0 && requests_per_second.is_finite()) {
+        return Err("Config value 'rate_limit.requests_per_second' must be grater than 0!");
+    }
+
+    if burst < 1 {
+        return Err("Config value 'rate_limit.burst' must be grater than 0!");
+    }
+
+    Ok(())
+}
+
+/// Outcome of checking a request against the rate limiter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    /// Whether the request is allowed.
+    allowed: bool,
+    /// Size of the bucket.
+    limit: u32,
+    /// Whole tokens left in the bucket.
+    remaining: u32,
+    /// Seconds until the bucket is full again.
+    reset: u64,
+    /// Seconds until the next request is allowed, zero if allowed now.
+    retry_after: u64,
+}
+
+impl Decision {
+    /// Whether the request is allowed.
+    pub fn is_allowed(&self) -> bool {
+        self.allowed
+    }
+
+    /// Get the number of requests allowed in a burst.
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    /// Get the number of requests left in the current burst.
+    pub fn remaining(&self) -> u32 {
+        self.remaining
+    }
+
+    /// Get the seconds until the full burst is available again.
+    pub fn reset(&self) -> u64 {
+        self.reset
+    }
+
+    /// Get the seconds until the next request is allowed.
+    pub fn retry_after(&self) -> u64 {
+        self.retry_after
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Position in the order of use.
+    used: u64,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    map: HashMap<(IpAddr, String), Bucket>,
+    /// Keys of the buckets by their last use, least recently used first.
+    order: BTreeMap<u64, (IpAddr, String)>,
+    clock: u64,
+}
+
+/// Token bucket rate limiter shared by all workers.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Creates a new rate limiter.
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        let buckets = Buckets { map: HashMap::new(), order: BTreeMap::new(), clock: 0 };
+
+        RateLimiter { config, buckets: Mutex::new(buckets) }
+    }
+
+    /// Checks a request of the client to the decoded path and takes a token if it is allowed.
+    pub fn check(&self, client: IpAddr, path: &str) -> Decision {
+        self.check_at(client, path, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, path: &str, now: Instant) -> Decision {
+        let (prefix, rate, burst) = self.limit_for(path);
+        let idle_timeout = Duration::from_secs(self.config.idle_timeout);
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets = &mut *buckets;
+
+        while buckets.order.first_key_value()
+            .is_some_and(|(_, key)| now.duration_since(buckets.map[key].updated) >= idle_timeout) {
+            if let Some((_, key)) = buckets.order.pop_first() {
+                buckets.map.remove(&key);
+            }
+        }
+
+        let key = (client_key(client), prefix);
+
+        if !buckets.map.contains_key(&key) && buckets.map.len() >= self.config.max_clients {
+            if let Some((_, oldest)) = buckets.order.pop_first() {
+                debug!("Rate limiter is full, forgetting client {}.", oldest.0);
+                buckets.map.remove(&oldest);
+            }
+        }
+
+        buckets.clock += 1;
+        let used = buckets.clock;
+        let bucket = buckets.map.entry(key.clone())
+            .or_insert(Bucket { tokens: f64::from(burst), updated: now, used });
+        buckets.order.remove(&bucket.used);
+        bucket.used = used;
+        buckets.order.insert(used, key);
+        let elapsed = now.duration_since(bucket.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(f64::from(burst));
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        Decision {
+            allowed,
+            limit: burst,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((f64::from(burst) - bucket.tokens) / rate).ceil() as u64,
+            retry_after: if allowed { 0 } else { ((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64 },
+        }
+    }
+
+    /// Selects the limit with the longest path prefix matching, or the global limit.
+    fn limit_for(&self, path: &str) -> (String, f64, u32) {
+        self.config.paths.iter()
+            .filter(|limit| path.starts_with(limit.path.as_str()))
+            .max_by_key(|limit| limit.path.len())
+            .map(|limit| (limit.path.clone(), limit.requests_per_second, limit.burst))
+            .unwrap_or((String::new(), self.config.requests_per_second, self.config.burst))
+    }
+
+    /// Get the number of tracked buckets.
+    pub fn len(&self) -> usize {
+        self.buckets.lock().unwrap().map.len()
+    }
+
+    /// Whether no bucket is tracked.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Get the address a client is tracked by, the /64 network for IPv6 clients.
+fn client_key(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V6(address) => match address.to_ipv4_mapped() {
+            Some(address) => IpAddr::V4(address),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(address) & (u128::MAX << 64))),
+        },
+        IpAddr::V4(_) => client,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn new_validates_limit() {
+        assert_that!(
+            RateLimitConfig::new(0.0, 10),
+            is(equal_to(Err("Config value 'rate_limit.requests_per_second' must be grater than 0!"))));
+        assert_that!(
+            RateLimitConfig::new(1.0, 0),
+            is(equal_to(Err("Config value 'rate_limit.burst' must be grater than 0!"))));
+        assert_that!(
+            PathLimit::new(String::from("api"), 1.0, 1),
+            is(equal_to(Err("Config value 'rate_limit.path.path' must start with '/'!"))));
+    }
+
+    #[test]
+    fn check_limits_bursts_and_refills() {
+        let sut = RateLimiter::new(RateLimitConfig::new(2.0, 3).unwrap());
+        let start = Instant::now();
+
+        for remaining in (0..3).rev() {
+            let decision = sut.check_at(ip("10.0.0.1"), "/", start);
+            assert_that!(decision.is_allowed(), is(true));
+            assert_that!(decision.remaining(), is(equal_to(remaining)));
+        }
+
+        let decision = sut.check_at(ip("10.0.0.1"), "/", start);
+        assert_that!(decision.is_allowed(), is(false));
+        assert_that!(decision.retry_after(), is(equal_to(1)));
+        assert_that!(decision.reset(), is(equal_to(2)));
+
+        assert_that!(sut.check_at(ip("10.0.0.2"), "/", start).is_allowed(), is(true));
+        assert_that!(sut.check_at(ip("10.0.0.1"), "/", start + Duration::from_millis(500)).is_allowed(), is(true));
+        assert_that!(sut.check_at(ip("10.0.0.1"), "/", start + Duration::from_millis(500)).is_allowed(), is(false));
+    }
+
+    #[test]
+    fn check_tracks_path_prefixes_separately() {
+        let config = RateLimitConfig::new(10.0, 10).unwrap()
+            .with_paths(vec!(PathLimit::new(String::from("/api/"), 1.0, 1).unwrap()))
+            .unwrap();
+        let sut = RateLimiter::new(config);
+        let start = Instant::now();
+
+        assert_that!(sut.check_at(ip("::1"), "/api/users", start).is_allowed(), is(true));
+        assert_that!(sut.check_at(ip("::1"), "/api/users", start).is_allowed(), is(false));
+        assert_that!(sut.check_at(ip("::1"), "/index.html", start).limit(), is(equal_to(10)));
+        assert_that!(sut.check_at(ip("::1"), "/index.html", start).is_allowed(), is(true));
+    }
+
+    #[test]
+    fn check_bounds_tracked_clients() {
+        let config = RateLimitConfig::new(1.0, 1).unwrap().with_bounds(2, 60).unwrap();
+        let sut = RateLimiter::new(config);
+        let start = Instant::now();
+
+        sut.check_at(ip("10.0.0.1"), "/", start);
+        sut.check_at(ip("10.0.0.2"), "/", start + Duration::from_secs(1));
+        sut.check_at(ip("10.0.0.3"), "/", start + Duration::from_secs(2));
+        assert_that!(sut.len(), is(equal_to(2)));
+        // The oldest client was forgotten, so it starts with a full bucket again.
+        assert_that!(sut.check_at(ip("10.0.0.1"), "/", start + Duration::from_secs(2)).is_allowed(), is(true));
+
+        sut.check_at(ip("10.0.0.3"), "/", start + Duration::from_secs(120));
+        assert_that!(sut.len(), is(equal_to(1)));
+    }
+
+    #[test]
+    fn check_evicts_least_recently_used_client() {
+        let config = RateLimitConfig::new(1.0, 1).unwrap().with_bounds(2, 60).unwrap();
+        let sut = RateLimiter::new(config);
+        let start = Instant::now();
+
+        sut.check_at(ip("10.0.0.1"), "/", start);
+        sut.check_at(ip("10.0.0.2"), "/", start);
+        assert_that!(sut.check_at(ip("10.0.0.1"), "/", start).is_allowed(), is(false));
+        sut.check_at(ip("10.0.0.3"), "/", start);
+
+        // 10.0.0.1 was used more recently than 10.0.0.2, so it is still limited.
+        assert_that!(sut.check_at(ip("10.0.0.1"), "/", start).is_allowed(), is(false));
+        assert_that!(sut.len(), is(equal_to(2)));
+    }
+
+    #[test]
+    fn check_tracks_ipv6_clients_by_network() {
+        let sut = RateLimiter::new(RateLimitConfig::new(1.0, 1).unwrap());
+        let start = Instant::now();
+
+        assert_that!(sut.check_at(ip("2001:db8::1"), "/", start).is_allowed(), is(true));
+        assert_that!(sut.check_at(ip("2001:db8::2:3"), "/", start).is_allowed(), is(false));
+        assert_that!(sut.check_at(ip("2001:db8:0:1::1"), "/", start).is_allowed(), is(true));
+        assert_that!(sut.check_at(ip("::ffff:10.0.0.1"), "/", start).is_allowed(), is(true));
+        assert_that!(sut.check_at(ip("10.0.0.1"), "/", start).is_allowed(), is(false));
+        assert_that!(sut.len(), is(equal_to(3)));
+    }
+}
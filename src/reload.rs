@@ -0,0 +1,252 @@
+///! This module provides reloading the configuration while the server is running.
+///!
+///! The configuration is re-read from its file on `SIGHUP` or by `POST /-/reload` on the admin listener.
+///! If the new configuration is valid it is swapped in atomically: connections accepted afterwards use the
+///! new configuration, connections already in progress finish with the old one. An invalid configuration
+///! is rejected and the old one stays active.
+///!
+///! Some settings are only used when the server starts, e.g. the listeners or the number of workers.
+///! Changing them is reported by `reload` and takes effect after a restart. Until then the reloaded
+///! configuration keeps their values from the start, so answering requests matches what is running.
+
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use Config;
+
+/// Holds the active configuration and the file it was read from.
+#[derive(Debug)]
+pub struct ConfigHandle {
+    /// File to re-read on reload. Reloading is not possible without.
+    file: Option<PathBuf>,
+    /// Configuration the server was started with.
+    initial: Arc<Config>,
+    current: RwLock<Arc<Config>>,
+}
+
+impl ConfigHandle {
+    /// Creates a handle for the configuration read from the given file.
+    pub fn new(config: Config, file: Option<PathBuf>) -> ConfigHandle {
+        let config = Arc::new(config);
+        ConfigHandle { file, initial: config.clone(), current: RwLock::new(config) }
+    }
+
+    /// Get the active configuration.
+    pub fn current(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Re-reads the configuration file and activates it if it is valid.
+    ///
+    /// Returns the names of changed settings which only take effect after a restart.
+    pub fn reload(&self) -> Result<Vec<&'static str>, &'static str> {
+        let file = self.file.as_ref().ok_or("No config file to reload from!")?;
+        let config = Config::from_file(file)?;
+
+        Ok(self.replace(config))
+    }
+
+    /// Activates the given configuration.
+    ///
+    /// Returns the settings which differ from the ones the server was started with and require a restart.
+    /// These keep the values the server was started with.
+    pub fn replace(&self, config: Config) -> Vec<&'static str> {
+        let changes = restart_required(&self.initial, &config);
+        let config = keep_startup_settings(&self.initial, config);
+        *self.current.write().unwrap() = Arc::new(config);
+        changes
+    }
+}
+
+/// Get the names of settings which differ and are only used when the server starts.
+pub fn restart_required(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut changes = Vec::new();
+
+    if old.address() != new.address() {
+        changes.push("address");
+    }
+
+    if old.port() != new.port() {
+        changes.push("port");
+    }
+
+    if old.listen() != new.listen() {
+        changes.push("listen");
+    }
+
+    if old.acceptors() != new.acceptors() {
+        changes.push("acceptors");
+    }
+
+    if old.event_loop() != new.event_loop() {
+        changes.push("event_loop");
+    }
+
+    if old.queue().as_ref().map(|queue| queue.capacity()) != new.queue().as_ref().map(|queue| queue.capacity()) {
+        changes.push("queue");
+    }
+
+    // The event loop keeps the timeouts it was started with.
+    if old.event_loop().is_some() && old.timeouts() != new.timeouts() {
+        changes.push("timeouts");
+    }
+
+    if old.threads() != new.threads() {
+        changes.push("threads");
+    }
+
+    if old.log_level() != new.log_level() || old.log_format() != new.log_format() || old.log_dir() != new.log_dir() {
+        changes.push("log");
+    }
+
+    if old.tls() != new.tls() {
+        changes.push("tls");
+    }
+
+    // The certificates are loaded by server name when the server starts.
+    if vhost_certificates(old) != vhost_certificates(new) {
+        changes.push("vhost.tls_cert");
+    }
+
+    if old.access_log() != new.access_log() {
+        changes.push("access_log");
+    }
+
+    if old.cache() != new.cache() {
+        changes.push("cache");
+    }
+
+    if old.rate_limit() != new.rate_limit() {
+        changes.push("rate_limit");
+    }
+
+    if old.metrics().as_ref().map(|metrics| metrics.listen()) != new.metrics().as_ref().map(|metrics| metrics.listen()) {
+        changes.push("metrics.listen");
+    }
+
+    changes
+}
+
+/// Takes the settings only used when the server starts from the initial configuration.
+fn keep_startup_settings(initial: &Config, mut config: Config) -> Config {
+    config.address = initial.address.clone();
+    config.port = initial.port;
+    config.listen = initial.listen.clone();
+    config.acceptors = initial.acceptors.clone();
+    config.event_loop = initial.event_loop.clone();
+    config.threads = initial.threads;
+    config.log_level = initial.log_level.clone();
+    config.log_format = initial.log_format.clone();
+    config.log_dir = initial.log_dir.clone();
+    config.tls = initial.tls.clone();
+    config.access_log = initial.access_log.clone();
+    config.cache = initial.cache.clone();
+    config.rate_limit = initial.rate_limit.clone();
+
+    if config.queue.as_ref().map(|queue| queue.capacity()) != initial.queue.as_ref().map(|queue| queue.capacity()) {
+        config.queue = initial.queue.clone();
+    }
+
+    if initial.event_loop.is_some() {
+        config.timeouts = initial.timeouts.clone();
+    }
+
+    if config.metrics.as_ref().map(|metrics| metrics.listen()) != initial.metrics.as_ref().map(|metrics| metrics.listen()) {
+        config.metrics = initial.metrics.clone();
+    }
+
+    config
+}
+
+/// Get the server names, certificate and key files of the virtual hosts with their own certificate.
+fn vhost_certificates(config: &Config) -> Vec<(&Vec<String>, &String, &String)> {
+    config.vhosts().iter()
+        .filter_map(|vhost| match (vhost.tls_cert(), vhost.tls_key()) {
+            (Some(cert), Some(key)) => Some((vhost.server_names(), cert, key)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+    use vhost::VirtualHost;
+
+    fn config(port: u16, threads: usize, web_dir: &str) -> Config {
+        Config::new(String::from("127.0.0.1"), port, threads, String::from(web_dir),
+            String::from("debug"), String::from("logs")).unwrap()
+    }
+
+    #[test]
+    fn replace_swaps_config_and_reports_restart_required() {
+        let sut = ConfigHandle::new(config(8080, 4, "web_dir"), None);
+        let old = sut.current();
+
+        assert_that!(sut.replace(config(8080, 4, "other_dir")), is(equal_to(Vec::new())));
+        assert_that!(sut.replace(config(8081, 8, "other_dir")), is(equal_to(vec!("port", "threads"))));
+        assert_that!(*sut.current().port(), is(equal_to(8080)));
+        assert_that!(sut.replace(config(8081, 4, "web_dir")), is(equal_to(vec!("port"))));
+        assert_that!(old.web_dir(), is(equal_to("web_dir")));
+    }
+
+    #[test]
+    fn restart_required_reports_vhost_certificates() {
+        let vhost = |cert: &str| VirtualHost::new(vec!(String::from("example.org")), String::from("web_dir"))
+            .and_then(|vhost| vhost.with_tls_certificate(Some(String::from(cert)), Some(String::from("example.org.key"))))
+            .unwrap();
+        let old = config(8080, 4, "web_dir").with_vhosts(vec!(vhost("example.org.pem"))).unwrap();
+
+        assert_that!(restart_required(&old, &old.clone()), is(equal_to(Vec::new())));
+
+        let new = config(8080, 4, "web_dir").with_vhosts(vec!(vhost("renewed.pem"))).unwrap();
+        assert_that!(restart_required(&old, &new), is(equal_to(vec!("vhost.tls_cert"))));
+
+        let new = config(8080, 4, "web_dir");
+        assert_that!(restart_required(&old, &new), is(equal_to(vec!("vhost.tls_cert"))));
+    }
+
+    #[test]
+    fn replace_keeps_startup_settings() {
+        let tls = ::tls::TlsConfig::new(8443, String::from("cert.pem"), String::from("key.pem")).unwrap();
+        let metrics = ::metrics::MetricsConfig::new(String::from("/metrics"), Some(String::from("127.0.0.1:9090")))
+            .unwrap();
+        let initial = config(8080, 4, "web_dir")
+            .with_tls(Some(tls.clone()))
+            .and_then(|config| config.with_metrics(Some(metrics.clone())))
+            .unwrap();
+        let sut = ConfigHandle::new(initial, None);
+
+        let changes = sut.replace(config(8081, 4, "other_dir"));
+
+        assert_that!(changes, is(equal_to(vec!("port", "tls", "metrics.listen"))));
+        assert_that!(*sut.current().port(), is(equal_to(8080)));
+        let current = sut.current();
+        assert_that!(current.tls().clone(), is(equal_to(Some(tls))));
+        assert_that!(current.metrics().clone(), is(equal_to(Some(metrics))));
+        assert_that!(current.web_dir(), is(equal_to("other_dir")));
+    }
+
+    #[test]
+    fn reload_keeps_config_if_file_is_invalid() {
+        let sut = ConfigHandle::new(config(8080, 4, "web_dir"), Some(PathBuf::from("test/fixtures/config_invalid.toml")));
+
+        assert_that!(sut.reload(), is(equal_to(Err("Can't parse config file!"))));
+        assert_that!(*sut.current().port(), is(equal_to(8080)));
+    }
+
+    #[test]
+    fn reload_reads_file() {
+        let sut = ConfigHandle::new(config(8080, 4, "web_dir"), Some(PathBuf::from("test/fixtures/config.toml")));
+
+        assert_that!(sut.reload().is_ok(), is(true));
+        assert_that!(sut.current().web_dir(), is(equal_to("web_dir")));
+    }
+
+    #[test]
+    fn reload_without_file() {
+        let sut = ConfigHandle::new(config(8080, 4, "web_dir"), None);
+
+        assert_that!(sut.reload(), is(equal_to(Err("No config file to reload from!"))));
+    }
+}
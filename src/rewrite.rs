@@ -0,0 +1,429 @@
+///! This module provides URL rewrite and redirect rules.
+///!
+///! Rules are evaluated in order against the decoded request path. The first matching rule
+///! substitutes its captures (`$1`, `${name}`) into the replacement. Redirect rules answer the
+///! request with the given status and the replacement as `Location`. Internal rewrites change the
+///! URL used to look up the resource and restart the evaluation with the new URL, unless the rule
+///! sets `stop`. To guard against loops, a request is rewritten at most `MAX_REWRITES` times.
+
+use std::path::Path;
+use regex::{Regex, RegexBuilder};
+use escape;
+use http;
+use vhost;
+
+/// Maximum number of internal rewrites of a single request.
+pub static MAX_REWRITES: usize = 10;
+
+/// Configuration of a rewrite rule.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RewriteRule {
+    /// Regular expression matched against the decoded request path.
+    pattern: String,
+    /// URL to rewrite or redirect to. May contain captures like `$1`.
+    replacement: String,
+    /// Status of an external redirect: 301, 302, 307 or 308. Internal rewrite if not set.
+    #[serde(default)]
+    redirect: Option<u16>,
+    /// Whether no further rules are evaluated after an internal rewrite by this rule.
+    #[serde(default)]
+    stop: bool,
+    /// Condition: regular expression the host name must match, ignoring case.
+    #[serde(default)]
+    host: Option<String>,
+    /// Condition: HTTP methods the rule applies to. All methods if empty.
+    #[serde(default)]
+    methods: Vec<String>,
+    /// Condition: whether the requested path must (not) exist in the web root.
+    #[serde(default)]
+    file_exists: Option<bool>,
+    #[serde(skip)]
+    compiled: Option<Compiled>,
+}
+
+#[derive(Debug, Clone)]
+struct Compiled {
+    pattern: Regex,
+    host: Option<Regex>,
+}
+
+impl PartialEq for RewriteRule {
+    fn eq(&self, other: &RewriteRule) -> bool {
+        self.pattern == other.pattern
+            && self.replacement == other.replacement
+            && self.redirect == other.redirect
+            && self.stop == other.stop
+            && self.host == other.host
+            && self.methods == other.methods
+            && self.file_exists == other.file_exists
+    }
+}
+
+impl RewriteRule {
+    /// Creates a new rule rewriting paths matching the pattern internally.
+    pub fn new(pattern: String, replacement: String) -> Result<RewriteRule, &'static str> {
+        let compiled = Regex::new(&pattern)
+            .map_err(|_| "Config value 'rewrite.pattern' must be a valid regular expression!")?;
+
+        if replacement.is_empty() {
+            return Err("Config value 'rewrite.replacement' must not be empty!");
+        }
+
+        Ok(RewriteRule {
+            pattern,
+            replacement,
+            redirect: None,
+            stop: false,
+            host: None,
+            methods: Vec::new(),
+            file_exists: None,
+            compiled: Some(Compiled { pattern: compiled, host: None }),
+        })
+    }
+
+    /// Turns the rule into an external redirect with the given status, or back into an internal rewrite.
+    pub fn with_redirect(mut self, redirect: Option<u16>) -> Result<RewriteRule, &'static str> {
+        match redirect {
+            None | Some(301) | Some(302) | Some(307) | Some(308) => {
+                self.redirect = redirect;
+                Ok(self)
+            },
+            _ => Err("Config value 'rewrite.redirect' must be one of 301, 302, 307 or 308!"),
+        }
+    }
+
+    /// Stops evaluating further rules after an internal rewrite by this rule.
+    pub fn with_stop(mut self, stop: bool) -> RewriteRule {
+        self.stop = stop;
+        self
+    }
+
+    /// Sets the conditions of the rule.
+    pub fn with_conditions(mut self, host: Option<String>, methods: Vec<String>, file_exists: Option<bool>) -> Result<RewriteRule, &'static str> {
+        let host_pattern = match host {
+            Some(ref host) => Some(RegexBuilder::new(host)
+                .case_insensitive(true)
+                .build()
+                .map_err(|_| "Config value 'rewrite.host' must be a valid regular expression!")?),
+            None => None,
+        };
+
+        if let Some(ref mut compiled) = self.compiled {
+            compiled.host = host_pattern;
+        }
+
+        self.host = host;
+        self.methods = methods.iter().map(|method| method.to_uppercase()).collect();
+        self.file_exists = file_exists;
+        Ok(self)
+    }
+
+    /// Validates a deserialized rule by running it through the constructor.
+    pub fn validate(self) -> Result<RewriteRule, &'static str> {
+        let stop = self.stop;
+
+        RewriteRule::new(self.pattern, self.replacement)?
+            .with_redirect(self.redirect)?
+            .with_conditions(self.host, self.methods, self.file_exists)
+            .map(|rule| rule.with_stop(stop))
+    }
+
+    /// Get the regular expression matched against the request path.
+    pub fn pattern(&self) -> &String {
+        &self.pattern
+    }
+
+    /// Get the replacement.
+    pub fn replacement(&self) -> &String {
+        &self.replacement
+    }
+
+    /// Get the status of an external redirect.
+    pub fn redirect(&self) -> &Option<u16> {
+        &self.redirect
+    }
+
+    /// Returns the substituted replacement if this rule applies to the request.
+    fn apply(&self, request: &RewriteRequest, exists: &dyn Fn(&str) -> bool) -> Option<String> {
+        let compiled = self.compiled.as_ref()
+            .expect("Rewrite rules must be validated before use!");
+
+        if !self.methods.is_empty() && !self.methods.iter().any(|method| method == request.method) {
+            return None;
+        }
+
+        if let Some(ref host) = compiled.host {
+            if !host.is_match(vhost::strip_port(request.host)) {
+                return None;
+            }
+        }
+
+        let captures = compiled.pattern.captures(&request.path)?;
+
+        if let Some(file_exists) = self.file_exists {
+            if exists(&request.path) != file_exists {
+                return None;
+            }
+        }
+
+        let mut replacement = String::new();
+        captures.expand(&self.replacement, &mut replacement);
+        Some(replacement)
+    }
+}
+
+/// The parts of a request rewrite rules look at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RewriteRequest<'a> {
+    /// Decoded request path.
+    path: String,
+    /// Query string without question mark.
+    query: String,
+    /// Value of the `Host` header.
+    host: &'a str,
+    /// HTTP method.
+    method: &'a str,
+}
+
+impl<'a> RewriteRequest<'a> {
+    /// Creates a new request to rewrite.
+    pub fn new(path: String, query: String, host: &'a str, method: &'a str) -> RewriteRequest<'a> {
+        RewriteRequest { path, query, host, method }
+    }
+
+    /// Get the URL made of the encoded path and the query.
+    pub fn url(&self) -> String {
+        if self.query.is_empty() {
+            escape::url_escape(&self.path)
+        } else {
+            format!("{}?{}", escape::url_escape(&self.path), self.query)
+        }
+    }
+
+    /// Replaces path and query by the given replacement. The query is kept if the replacement has none.
+    fn rewrite(&mut self, replacement: &str) {
+        match replacement.find('?') {
+            Some(question_mark) => {
+                self.path = replacement[..question_mark].to_string();
+                self.query = replacement[question_mark + 1..].to_string();
+            },
+            None => self.path = replacement.to_string(),
+        }
+    }
+}
+
+/// Result of applying rewrite rules to a request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// No internal rewrite or redirect applied.
+    Unchanged,
+    /// The request was rewritten internally to the given URL.
+    Rewritten(String),
+    /// The request must be redirected with given status to the given location.
+    Redirect(u16, String),
+}
+
+/// Whether a decoded path exists in the web root, the check of the `file_exists` condition.
+///
+/// Paths leaving the web root never exist, as the server refuses them.
+pub fn exists_in_web_root(web_dir: &str, path: &str) -> bool {
+    http::is_safe_path(path) && Path::new(web_dir).join(path.trim_start_matches('/')).exists()
+}
+
+/// Applies the rules to the request.
+///
+/// The closure `exists` tells whether a decoded path exists in the web root. Each applied rule is
+/// described in `trace`. Returns an error if the request is rewritten more than `MAX_REWRITES` times.
+pub fn apply(rules: &[RewriteRule], mut request: RewriteRequest, exists: &dyn Fn(&str) -> bool, trace: &mut Vec<String>) -> Result<Outcome, &'static str> {
+    let mut rewrites = 0;
+
+    'restart: loop {
+        for (index, rule) in rules.iter().enumerate() {
+            let replacement = match rule.apply(&request, exists) {
+                Some(replacement) => replacement,
+                None => continue,
+            };
+
+            if let Some(status) = rule.redirect {
+                let location = if replacement.contains('?') || request.query.is_empty() {
+                    escape::url_escape(&replacement)
+                } else {
+                    format!("{}?{}", escape::url_escape(&replacement), request.query)
+                };
+                trace.push(format!(
+                    "Rule #{} '{}' redirects {} with {} to {}",
+                    index + 1, rule.pattern, request.url(), status, location));
+                return Ok(Outcome::Redirect(status, location));
+            }
+
+            let before = request.url();
+            request.rewrite(&replacement);
+            rewrites += 1;
+            trace.push(format!("Rule #{} '{}' rewrites {} to {}", index + 1, rule.pattern, before, request.url()));
+
+            if rewrites > MAX_REWRITES {
+                return Err("Too many rewrites, the rules probably contain a loop!");
+            }
+
+            if rule.stop || before == request.url() {
+                return Ok(Outcome::Rewritten(request.url()));
+            }
+
+            continue 'restart;
+        }
+
+        return Ok(if rewrites == 0 {
+            Outcome::Unchanged
+        } else {
+            Outcome::Rewritten(request.url())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+
+    fn rule(pattern: &str, replacement: &str) -> RewriteRule {
+        RewriteRule::new(String::from(pattern), String::from(replacement)).unwrap()
+    }
+
+    fn request(url: &str) -> RewriteRequest<'static> {
+        match url.find('?') {
+            Some(question_mark) => RewriteRequest::new(
+                url[..question_mark].to_string(),
+                url[question_mark + 1..].to_string(),
+                "www.example.com:8080",
+                "GET"),
+            None => RewriteRequest::new(url.to_string(), String::new(), "www.example.com:8080", "GET"),
+        }
+    }
+
+    fn never(_: &str) -> bool {
+        false
+    }
+
+    fn run(rules: &[RewriteRule], url: &str) -> Result<Outcome, &'static str> {
+        apply(rules, request(url), &never, &mut Vec::new())
+    }
+
+    #[test]
+    fn new_validates_pattern() {
+        assert_that!(
+            RewriteRule::new(String::from("^/(unclosed$"), String::from("/")),
+            is(equal_to(Err("Config value 'rewrite.pattern' must be a valid regular expression!"))));
+    }
+
+    #[test]
+    fn with_redirect_validates_status() {
+        assert_that!(
+            rule("^/old$", "/new").with_redirect(Some(303)),
+            is(equal_to(Err("Config value 'rewrite.redirect' must be one of 301, 302, 307 or 308!"))));
+    }
+
+    #[test]
+    fn apply_substitutes_captures() {
+        let rules = vec!(rule("^/blog/(\\d+)/(?P<slug>[^/]+)$", "/posts/${slug}-$1.html"));
+
+        assert_that!(
+            run(&rules, "/blog/42/hello?x=1"),
+            is(equal_to(Ok(Outcome::Rewritten(String::from("/posts/hello-42.html?x=1"))))));
+        assert_that!(run(&rules, "/about.html"), is(equal_to(Ok(Outcome::Unchanged))));
+    }
+
+    #[test]
+    fn apply_redirects() {
+        let rules = vec!(
+            rule("^/old/(.*)$", "/new/$1").with_redirect(Some(308)).unwrap(),
+            rule("^/legacy$", "https://example.org/?from=legacy").with_redirect(Some(302)).unwrap());
+
+        assert_that!(
+            run(&rules, "/old/a.html?x=1"),
+            is(equal_to(Ok(Outcome::Redirect(308, String::from("/new/a.html?x=1"))))));
+        assert_that!(
+            run(&rules, "/legacy?x=1"),
+            is(equal_to(Ok(Outcome::Redirect(302, String::from("https://example.org/?from=legacy"))))));
+    }
+
+    #[test]
+    fn apply_encodes_urls() {
+        let rules = vec!(
+            rule("^/old/(.*)$", "/new/$1").with_redirect(Some(301)).unwrap(),
+            rule("^/docs/(.*)$", "/documents/$1"));
+
+        assert_that!(
+            run(&rules, "/old/a b.html"),
+            is(equal_to(Ok(Outcome::Redirect(301, String::from("/new/a%20b.html"))))));
+        assert_that!(
+            run(&rules, "/docs/100%.html"),
+            is(equal_to(Ok(Outcome::Rewritten(String::from("/documents/100%25.html"))))));
+    }
+
+    #[test]
+    fn apply_restarts_after_internal_rewrite() {
+        let rules = vec!(
+            rule("^/v2/(.*)$", "/current/$1").with_redirect(Some(301)).unwrap(),
+            rule("^/v1/(.*)$", "/v2/$1"));
+
+        assert_that!(
+            run(&rules, "/v1/a.html"),
+            is(equal_to(Ok(Outcome::Redirect(301, String::from("/current/a.html"))))));
+    }
+
+    #[test]
+    fn apply_stops_evaluation() {
+        let rules = vec!(
+            rule("^/v2/(.*)$", "/current/$1").with_redirect(Some(301)).unwrap(),
+            rule("^/v1/(.*)$", "/v2/$1").with_stop(true));
+
+        assert_that!(run(&rules, "/v1/a.html"), is(equal_to(Ok(Outcome::Rewritten(String::from("/v2/a.html"))))));
+    }
+
+    #[test]
+    fn apply_guards_against_loops() {
+        let rules = vec!(rule("^/a/(.*)$", "/b/$1"), rule("^/b/(.*)$", "/a/$1"));
+
+        assert_that!(run(&rules, "/a/x"), is(equal_to(Err("Too many rewrites, the rules probably contain a loop!"))));
+    }
+
+    #[test]
+    fn apply_checks_conditions() {
+        let rules = vec!(
+            rule("^/admin", "/denied.html")
+                .with_conditions(Some(String::from("^WWW\\.example\\.com$")), vec!(String::from("post")), None)
+                .unwrap()
+                .with_stop(true),
+            rule("^/(.*)$", "/index.html")
+                .with_conditions(None, Vec::new(), Some(false))
+                .unwrap());
+        let exists = |path: &str| path == "/index.html" || path == "/style.css";
+
+        let mut trace = Vec::new();
+        let post = RewriteRequest::new(String::from("/admin"), String::new(), "www.example.com", "POST");
+        assert_that!(
+            apply(&rules, post, &exists, &mut trace),
+            is(equal_to(Ok(Outcome::Rewritten(String::from("/denied.html"))))));
+        assert_that!(trace, is(equal_to(vec!(String::from("Rule #1 '^/admin' rewrites /admin to /denied.html")))));
+
+        let get = RewriteRequest::new(String::from("/admin"), String::new(), "www.example.com", "GET");
+        assert_that!(
+            apply(&rules, get, &exists, &mut Vec::new()),
+            is(equal_to(Ok(Outcome::Rewritten(String::from("/index.html"))))));
+
+        let other_host = RewriteRequest::new(String::from("/admin"), String::new(), "example.org", "POST");
+        assert_that!(
+            apply(&rules, other_host, &exists, &mut Vec::new()),
+            is(equal_to(Ok(Outcome::Rewritten(String::from("/index.html"))))));
+
+        let existing = RewriteRequest::new(String::from("/style.css"), String::new(), "example.org", "GET");
+        assert_that!(apply(&rules, existing, &exists, &mut Vec::new()), is(equal_to(Ok(Outcome::Unchanged))));
+    }
+
+    #[test]
+    fn exists_in_web_root_refuses_paths_leaving_it() {
+        assert_that!(exists_in_web_root("test/web_dir", "/index.html"), is(true));
+        assert_that!(exists_in_web_root("test/web_dir", "/missing.html"), is(false));
+        assert_that!(exists_in_web_root("test/web_dir", "/../fixtures/hello.txt"), is(false));
+    }
+}
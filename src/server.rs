@@ -25,144 +25,1410 @@
 ///! });
 ///! ```
 
+use std::fs;
+use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::net::TcpListener;
 use std::net::TcpStream;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::process;
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use time;
 use Config;
+use accesslog;
+use accesslog::AccessLog;
+use cache;
+use cache::{CachedFile, FileCache};
+use ratelimit::{Decision, RateLimiter};
+use vhost;
+use vhost::VirtualHost;
+use autoindex;
+use rewrite;
+use rewrite::{Outcome, RewriteRequest};
+use auth::AuthError;
+use acl::DenyAction;
+use location::Location;
 use file;
-use threads::ThreadPool;
+use threads::{Busy, ExecuteError, FullAction, PoolStats, ThreadPool};
+use metrics::Metrics;
+use status::Scoreboard;
+use reload::ConfigHandle;
+use systemd;
+use systemd::ListenFds;
+use upgrade;
+use tls;
+use listen;
+use listen::{AcceptMode, ListenConfig, Listener, Stream};
+use event_loop::{BufferedStream, EventLoop, EventLoopConfig, Handler, Job};
+use timeout;
+use timeout::{Timed, Timeout};
+use tls::{ClientAuthMode, ClientCertificate, TlsStream};
+use rustls::ServerConfig;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR2};
+use signal_hook::iterator::Signals;
 use http;
-use http::{Request, Response, ResponseHeader, Status};
+use http::{is_safe_path, Request, Response, ResponseHeader, Status};
+
+/// How long an acceptor waits for a connection before checking whether the server is draining.
+static ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 /// Represents the HTTP server.
 pub struct Server {
     /// Configuration of the server,
     config: Config,
+    /// Active configuration, replaced on reload.
+    configs: Arc<ConfigHandle>,
+    /// Static file cache shared by all workers, if enabled.
+    cache: Option<Arc<FileCache>>,
+    /// Per-client rate limiter shared by all workers, if enabled.
+    limiter: Option<Arc<RateLimiter>>,
+    /// Listening sockets passed by systemd, taken when binding.
+    listen_fds: Mutex<ListenFds>,
 }
 
 impl Server {
     /// Creates a new server.
     pub fn new(config: Config) -> Server {
-        Server { config }
+        let cache = config.cache().as_ref()
+            .map(|cache| Arc::new(FileCache::new(*cache.max_bytes())));
+        let limiter = config.rate_limit().as_ref()
+            .map(|rate_limit| Arc::new(RateLimiter::new(rate_limit.clone())));
+        let configs = Arc::new(ConfigHandle::new(config.clone(), None));
+        Server { config, configs, cache, limiter, listen_fds: Mutex::new(ListenFds::default()) }
+    }
+
+    /// Enables reloading the configuration from the given file on `SIGHUP` or by the admin listener.
+    pub fn with_config_file(mut self, file: PathBuf) -> Server {
+        self.configs = Arc::new(ConfigHandle::new(self.config.clone(), Some(file)));
+        self
+    }
+
+    /// Takes over the given listening sockets instead of binding, e.g. the ones passed by systemd.
+    pub fn with_listen_fds(mut self, listen_fds: ListenFds) -> Server {
+        self.listen_fds = Mutex::new(listen_fds);
+        self
+    }
+
+    /// Get the static file cache, if enabled.
+    pub fn cache(&self) -> Option<Arc<FileCache>> {
+        self.cache.clone()
+    }
+
+    /// Opens the access log, if enabled.
+    fn open_access_log(&self) -> Result<Option<Arc<AccessLog>>, &'static str> {
+        match *self.config.access_log() {
+            Some(ref access_log) => AccessLog::open(self.config.log_dir(), access_log)
+                .map(|access_log| {
+                    let access_log = Arc::new(access_log);
+                    AccessLog::flush_periodically(&access_log);
+                    Some(access_log)
+                })
+                .map_err(|err| {
+                    error!("Can't open access log: {}", err);
+                    "Can't open access log file!"
+                }),
+            None => Ok(None),
+        }
     }
 
     /// Bind the server to the configured IP and port and start listening. It returns an error describing the problem if it had failed for any reason.
     pub fn bind(&self) -> Result<(), &'static str> {
-        let addr = format!("{}:{}", self.config.address, self.config.port);
-        info!("Bind to {}", addr);
+        let mut shared = Shared {
+            config: self.configs.current(),
+            configs: self.configs.clone(),
+            cache: self.cache.clone(),
+            limiter: self.limiter.clone(),
+            access_log: self.open_access_log()?,
+            id_prefix: request_id_prefix(),
+            metrics: Arc::new(Metrics::new()),
+            scoreboard: Arc::new(Scoreboard::new()),
+            pool: None,
+            default_vhost: None,
+        };
+        let admin_listener = self.open_admin_listener()?;
+        let listeners = self.open_listeners()?;
+        let draining = Arc::new(AtomicBool::new(false));
 
-        let listener = if let Ok(listener) = TcpListener::bind(addr.clone()) {
-            listener
-        } else {
-            return Err("Can't bind TCP listener on address!");
+        let server_config = match *self.config.tls() {
+            Some(ref tls) => Some(tls::server_config(tls, self.config.vhosts())?),
+            None => None,
         };
 
-        info!("Serving with {} threads.", self.config.threads);
-        let pool = ThreadPool::new(self.config.threads);
-        format!("Listening on http://{}:{}/", self.config.address, self.config.port);
+        let count = self.config.acceptors().as_ref().map(|acceptors| *acceptors.count()).unwrap_or(1);
+        let dispatches = self.dispatches(count * listeners.len());
+        shared.pool = Some(dispatches[0].stats());
+        let context = AcceptContext {
+            server_config,
+            event_loop: match *self.config.event_loop() {
+                Some(ref event_loop) => Some(Server::start_event_loop(event_loop, &shared, &dispatches)?),
+                None => None,
+            },
+            draining,
+        };
+        Server::start_upgrade_on_user_signal(&listeners, &admin_listener, &context.draining)?;
+        Server::start_admin_listener(admin_listener, &shared);
+        Server::start_reload_on_hangup(&shared)?;
+        Server::start_remove_sockets_on_exit(&listeners, &context.draining)?;
+
+        let mut acceptors = Vec::with_capacity(count * listeners.len());
+
+        for (listen, listener, _) in listeners {
+            let mut siblings = Vec::with_capacity(count);
+
+            for _ in 1..count {
+                siblings.push(listener.sibling().map_err(|err| {
+                    error!("Can't bind further acceptor to {}: {}", listen.socket_address(), err);
+                    "Can't bind TCP listener on address!"
+                })?);
+            }
+
+            for (index, listener) in Some(listener).into_iter().chain(siblings).enumerate() {
+                let dispatch = dispatches[index % dispatches.len()].clone();
+                acceptors.push(Server::start_acceptor(listen.clone(), listener, index, &shared, dispatch, context.clone()));
+            }
+        }
+
+        if let Err(err) = systemd::notify("READY=1") {
+            warn!("Can't notify service manager about readiness: {}", err);
+        }
+
+        if let Some(ref socket) = *self.listen_fds.lock().unwrap().upgrade_socket() {
+            if let Err(err) = upgrade::notify_ready(socket) {
+                warn!("Can't notify upgrading server about readiness: {}", err);
+            }
+        }
+
+        for acceptor in acceptors {
+            let _ = acceptor.join();
+        }
+
+        if let Some(ref event_loop) = context.event_loop {
+            event_loop.shutdown();
+        }
+
+        // Dropping the last reference to the pools lets the workers finish the queued connections.
+        info!("Stopped accepting connections, finishing the ones in flight.");
+        Ok(())
+    }
+
+    /// Creates where the acceptors put their connections: one pool for all, a pool of their own with a share of
+    /// the threads per acceptor, or the acceptors themselves for the given number of acceptor threads.
+    ///
+    /// All pools share one set of counters. A bounded queue is shared out among the pools like the threads.
+    fn dispatches(&self, acceptor_threads: usize) -> Vec<Dispatch> {
+        let threads = self.config.threads;
+        let capacity = self.config.queue().as_ref().map(|queue| *queue.capacity());
+
+        match *self.config.acceptors() {
+            None => {
+                info!("Serving with {} threads.", threads);
+                vec!(Dispatch::Pool(Arc::new(pool(threads, capacity, Arc::new(pool_stats(threads, capacity))))))
+            },
+            Some(ref acceptors) if *acceptors.mode() == AcceptMode::Queue => {
+                let count = *acceptors.count();
+                let size = ::std::cmp::max(1, threads / count);
+                let capacity = capacity.map(|capacity| ::std::cmp::max(1, capacity / count));
+                let stats = Arc::new(pool_stats(size * count, capacity.map(|capacity| capacity * count)));
+                info!("Serving with {} pools of {} threads.", count, size);
+
+                (0..count)
+                    .map(|_| Dispatch::Pool(Arc::new(pool(size, capacity, Arc::clone(&stats)))))
+                    .collect()
+            },
+            Some(_) => {
+                info!("Serving with {} acceptor threads.", acceptor_threads);
+                vec!(Dispatch::Direct(Arc::new(PoolStats::new(acceptor_threads))))
+            },
+        }
+    }
+
+    /// Starts the event loop, handing the requests to the pools in turn. Requests the pools can't take are
+    /// rejected like connections.
+    fn start_event_loop(config: &EventLoopConfig, shared: &Shared, dispatches: &[Dispatch])
+        -> Result<EventLoop, &'static str> {
+        let dispatches = dispatches.to_vec();
+        let next = AtomicUsize::new(0);
+        let rejecting = shared.clone();
+        let executor = Arc::new(move |job: Job| {
+            dispatches[next.fetch_add(1, Ordering::Relaxed) % dispatches.len()].execute(job).map_err(|err| {
+                let shared = rejecting.reloaded();
+
+                match rejection(&shared, err) {
+                    Some(response) => response.render(),
+                    None => Vec::new(),
+                }
+            })
+        });
+        info!("Holding plain HTTP connections in the event loop, at most {}.", config.max_connections());
+
+        let timeouts = shared.config.timeouts().clone();
+
+        EventLoop::start(config.clone(), timeouts, Arc::clone(&shared.metrics), executor).map_err(|err| {
+            error!("Can't start event loop: {}", err);
+            "Can't start event loop!"
+        })
+    }
+
+    /// Takes over the admin listener passed by systemd or binds it, if one is configured.
+    fn open_admin_listener(&self) -> Result<Option<TcpListener>, &'static str> {
+        let addr = match *self.config.metrics() {
+            Some(ref metrics) => match *metrics.listen() {
+                Some(ref listen) => listen.clone(),
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        let socket_addr = addr.to_socket_addrs().ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or("Can't bind admin listener on address!")?;
+        let listen = ListenConfig::new(socket_addr.ip().to_string(), socket_addr.port())?;
+        let passed = self.listen_fds.lock().unwrap().take(&listen).map_err(|err| {
+            error!("Can't use socket passed for admin listener {}: {}", addr, err);
+            "Can't use socket passed by systemd!"
+        })?;
+
+        match passed {
+            Some(Listener::Tcp(listener)) => {
+                info!("Using passed socket for admin listener {}", addr);
+                Ok(Some(listener))
+            },
+            Some(Listener::Unix(_)) => Err("Can't use Unix socket passed by systemd for admin listener!"),
+            None => {
+                info!("Bind admin listener to {}", addr);
+                TcpListener::bind(socket_addr)
+                    .map(Some)
+                    .map_err(|_| "Can't bind admin listener on address!")
+            },
+        }
+    }
+
+    /// Takes over the listening sockets passed by systemd and binds the others.
+    ///
+    /// Returns each listener with its configuration and whether the server bound it. Passed sockets matching
+    /// no listener serve plain HTTP.
+    fn open_listeners(&self) -> Result<Vec<(ListenConfig, Listener, bool)>, &'static str> {
+        let mut listen_fds = self.listen_fds.lock().unwrap();
+        let mut listeners = Vec::new();
+
+        for listen in listeners_of(&self.config) {
+            let passed = listen_fds.take(&listen).map_err(|err| {
+                error!("Can't use socket passed for {}: {}", listen.socket_address(), err);
+                "Can't use socket passed by systemd!"
+            })?;
+
+            match passed {
+                Some(listener) => {
+                    info!("Using passed socket for {}", listen.socket_address());
+                    listeners.push((listen, listener, false));
+                },
+                None => {
+                    info!("Bind to {}", listen.socket_address());
+                    let listener = listen.bind_reusable(self.config.acceptors().is_some()).map_err(|err| {
+                        error!("Can't bind to {}: {}", listen.socket_address(), err);
+                        "Can't bind TCP listener on address!"
+                    })?;
+                    listeners.push((listen, listener, true));
+                },
+            }
+        }
+
+        let passed = listen_fds.take_all().map_err(|err| {
+            error!("Can't use socket passed by systemd: {}", err);
+            "Can't use socket passed by systemd!"
+        })?;
+
+        for (name, listener) in passed {
+            let listen = listener.local_config().map_err(|err| {
+                error!("Can't get address of socket '{}' passed by systemd: {}", name, err);
+                "Can't use socket passed by systemd!"
+            })?;
+            info!("Serving plain HTTP on socket '{}' passed by systemd for {}", name, listen.socket_address());
+            listeners.push((listen, listener, false));
+        }
+
+        Ok(listeners)
+    }
+
+    /// Starts a thread accepting connections on the listener and dispatching them. The index numbers the
+    /// acceptors of the same listener.
+    ///
+    /// Plain HTTP connections go to the event loop if there is one. The thread ends once the server is draining
+    /// after an upgrade.
+    fn start_acceptor(listen: ListenConfig, listener: Listener, index: usize, shared: &Shared, dispatch: Dispatch,
+                      context: AcceptContext) -> thread::JoinHandle<()> {
+        let mut shared = shared.clone();
+        shared.default_vhost = listen.default_vhost().clone();
+        let transport = if redirects(&shared.config, &listen) { Transport::Redirect } else { Transport::Plain };
+        let name = match index {
+            0 => format!("accept-{}", listen.socket_address()),
+            index => format!("accept-{}-{}", listen.socket_address(), index),
+        };
+
+        let handler: Handler = {
+            let shared = shared.clone();
+            let transport = transport.clone();
+
+            Arc::new(move |input, remote_addr, keep_alive| {
+                Server::handle_buffered_request(input, remote_addr, keep_alive, transport.clone(), shared.reloaded())
+            })
+        };
+
+        if index == 0 {
+            info!("Listening on {}", listen.url());
+        }
+
+        // A new server sharing the socket after an upgrade may take a connection the poll reported.
+        if let Err(err) = listener.set_nonblocking(true) {
+            warn!("Can't make listener on {} non-blocking: {}", listen.socket_address(), err);
+        }
+
+        thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                let handle = |stream: Stream| {
+                    let shared = shared.reloaded();
+
+                    match (&context.server_config, &context.event_loop, stream) {
+                        (Some(server_config), _, Stream::Tcp(stream)) if listen.tls() => {
+                            let server_config = server_config.clone();
+                            let rejecting = shared.clone();
+
+                            // Answering needs a TLS handshake, so a rejected connection is just closed.
+                            if let Err(err) = dispatch.execute(move || {
+                                Server::handle_tls_connection(stream, server_config, shared);
+                            }) {
+                                rejection(&rejecting, err);
+                            }
+                        },
+                        (_, Some(event_loop), stream) => event_loop.register(stream, handler.clone()),
+                        (_, _, stream) => {
+                            let transport = transport.clone();
+                            let rejecting = shared.clone();
+                            // The job owns the stream, so a rejected connection is answered on a copy.
+                            let spare = if dispatch.is_bounded() { stream.try_clone().ok() } else { None };
+
+                            if let Err(err) = dispatch.execute(move || {
+                                Server::handle_connection_new(stream, transport, shared);
+                            }) {
+                                if let (Some(response), Some(spare)) = (rejection(&rejecting, err), spare) {
+                                    reject_connection(spare, &response);
+                                }
+                            }
+                        },
+                    }
+                };
+
+                while !context.draining.load(Ordering::SeqCst) {
+                    match listener.wait(ACCEPT_POLL_INTERVAL) {
+                        Ok(true) => (),
+                        Ok(false) => continue,
+                        Err(err) => {
+                            warn!("Can't wait for connection on {}: {}", listen.socket_address(), err);
+                            continue;
+                        },
+                    }
+
+                    match listener.accept() {
+                        Ok(stream) => handle(stream),
+                        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                        Err(err) => warn!("Can't accept connection on {}: {}", listen.socket_address(), err),
+                    }
+                }
+
+                // A socket of its own drops the connections still queued once it's closed.
+                while let Ok(stream) = listener.accept() {
+                    handle(stream);
+                }
+            })
+            .expect("Can't spawn acceptor thread!")
+    }
+
+    /// Starts a thread serving the metrics and the status page on the admin listener, if one is configured.
+    ///
+    /// The admin listener answers in its own threads, so metrics are available while all workers are busy. Each
+    /// connection gets a short-lived thread, so a client not sending its request doesn't block the others.
+    fn start_admin_listener(listener: Option<TcpListener>, shared: &Shared) {
+        let listener = match listener {
+            Some(listener) => listener,
+            None => return,
+        };
+        let shared = shared.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Can't accept admin connection: {}", err);
+                        continue;
+                    },
+                };
+                let shared = shared.clone();
+                let spawned = thread::Builder::new()
+                    .name(String::from("admin"))
+                    .spawn(move || Server::handle_admin_connection(stream, &shared));
+
+                if let Err(err) = spawned {
+                    warn!("Can't spawn thread for admin connection: {}", err);
+                }
+            }
+        });
+    }
+
+    /// Starts a thread upgrading to a new binary on `SIGUSR2`, which takes over the listeners.
+    ///
+    /// Once the new server accepts connections, this one starts draining: the acceptors stop and the workers
+    /// finish the connections in flight.
+    fn start_upgrade_on_user_signal(listeners: &[(ListenConfig, Listener, bool)], admin_listener: &Option<TcpListener>,
+                                    draining: &Arc<AtomicBool>) -> Result<(), &'static str> {
+        let mut signals = Signals::new([SIGUSR2])
+            .map_err(|_| "Can't register handler for SIGUSR2!")?;
+        // The descriptors stay open until the acceptors stop, which happens only after a successful upgrade.
+        let mut fds: Vec<(RawFd, String)> = listeners.iter()
+            .map(|(listen, listener, _)| (listener.as_raw_fd(), listen.name().clone().unwrap_or_else(|| String::from("unknown"))))
+            .collect();
+        fds.extend(admin_listener.iter().map(|listener| (listener.as_raw_fd(), String::from("admin"))));
+        let draining = draining.clone();
+
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                info!("Got SIGUSR2, upgrading to a new process.");
 
-        for stream in listener.incoming() {
-            let stream = stream.expect("Cn't open TCP stream!");
-            let config = self.config.clone();
+                match upgrade::upgrade(&fds) {
+                    Ok(pid) => {
+                        info!("New process {} accepts connections, draining.", pid);
 
-            pool.execute(|| {
-                Server::handle_connection_new(stream, config);
-            });
+                        if let Err(err) = systemd::notify(&format!("MAINPID={}", pid)) {
+                            warn!("Can't notify service manager about new main process: {}", err);
+                        }
+
+                        draining.store(true, Ordering::SeqCst);
+                        break;
+                    },
+                    Err(err) => error!("Upgrade failed, keep serving: {}", err),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Starts a thread removing the files of Unix sockets bound by the server and exiting on `SIGINT` or `SIGTERM`.
+    ///
+    /// While draining, the sockets belong to the new server and are kept.
+    fn start_remove_sockets_on_exit(listeners: &[(ListenConfig, Listener, bool)], draining: &Arc<AtomicBool>)
+        -> Result<(), &'static str> {
+        let paths: Vec<PathBuf> = listeners.iter()
+            .filter(|(_, _, bound)| *bound)
+            .filter_map(|(listen, _, _)| listen.unix_path().map(PathBuf::from))
+            .collect();
+
+        if paths.is_empty() {
+            return Ok(());
         }
 
+        let mut signals = Signals::new([SIGINT, SIGTERM])
+            .map_err(|_| "Can't register handler for SIGINT and SIGTERM!")?;
+        let draining = draining.clone();
+
+        thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                info!("Got signal {}, shutting down.", signal);
+
+                if !draining.load(Ordering::SeqCst) {
+                    for path in paths.iter() {
+                        listen::remove_socket(path);
+                    }
+                }
+
+                process::exit(0);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Starts a thread reloading the configuration on `SIGHUP`.
+    fn start_reload_on_hangup(shared: &Shared) -> Result<(), &'static str> {
+        let mut signals = Signals::new([SIGHUP])
+            .map_err(|_| "Can't register handler for SIGHUP!")?;
+        let configs = shared.configs.clone();
+
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                info!("Got SIGHUP, reloading configuration.");
+                let _ = reload_config(&configs);
+            }
+        });
+
         Ok(())
     }
 
-    fn handle_connection_new(mut stream: TcpStream, config: Config) {
-        let mut buffer = [0; 4096];
-        stream.read(&mut buffer)
-            .expect("Can't read from TCP stream!");
-        let request = byte_array_to_string(buffer);
-        debug!("Received data: {:?}", request);
+    fn handle_admin_connection(mut stream: TcpStream, shared: &Shared) {
+        let shared = &shared.reloaded();
+        let remote_addr = stream.peer_addr().ok();
+        let request = match read_request(&mut stream, remote_addr, shared) {
+            Some(request) => request,
+            None => return,
+        };
+        let response = match *shared.config.metrics() {
+            Some(ref metrics) if request.path() == *metrics.path() => metrics_response(shared),
+            _ if is_status_request(shared, &request) => status_response(shared, &request),
+            _ if request.path() == RELOAD_PATH => reload_response(shared, &request),
+            _ => {
+                let mut response = Response::new(http::VERSION.to_string(), Status::NotFound, Vec::new());
+                response.add_header(ResponseHeader::ContentLength(0));
+                response
+            },
+        };
+        debug!("Admin request \"{} {}\" {}", request.method(), request.url(), response.status().code());
+
+        if let Err(err) = stream.write_all(&response.render()).and_then(|_| stream.flush()) {
+            warn!("Can't answer admin request: {}", err);
+        }
+    }
+
+    fn handle_connection_new(mut stream: Stream, transport: Transport, shared: Shared) {
+        let remote_addr = stream.peer_addr();
+        Server::handle_connection(&mut stream, remote_addr, transport, shared, false);
+    }
+
+    /// Answers a request read by the event loop into a buffer.
+    ///
+    /// Returns the response and whether the connection stays open for further requests.
+    fn handle_buffered_request(input: Vec<u8>, remote_addr: Option<SocketAddr>, keep_alive: bool, transport: Transport,
+                               shared: Shared) -> (Vec<u8>, bool) {
+        let mut stream = BufferedStream::new(input);
+        let keep_alive = Server::handle_connection(&mut stream, remote_addr, transport, shared, keep_alive);
+        (stream.into_output(), keep_alive)
+    }
+
+    fn handle_tls_connection(stream: TcpStream, server_config: Arc<ServerConfig>, shared: Shared) {
+        let remote_addr = stream.peer_addr().ok();
+        // The handshake is part of receiving the request header.
+        let header = Some(Duration::from_secs(*shared.config.timeouts().header()));
+
+        if let Err(err) = stream.set_read_timeout(header).and_then(|_| stream.set_write_timeout(header)) {
+            debug!("Can't set timeouts of connection from {:?}: {}", remote_addr, err);
+            return;
+        }
+
+        let mut stream = match TlsStream::accept(server_config, stream) {
+            Ok(stream) => stream,
+            Err(ref err) if timeout::is_timeout(err) => {
+                timeout::record(&shared.metrics, Timeout::Header, remote_addr);
+                return;
+            },
+            Err(err) => {
+                debug!("TLS handshake with {:?} failed: {}", remote_addr, err);
+                return;
+            },
+        };
+
+        let transport = Transport::Tls {
+            server_name: stream.server_name().map(String::from),
+            client_cert: stream.client_certificate(),
+        };
+        Server::handle_connection(&mut stream, remote_addr, transport, shared, false);
+
+        if let Err(err) = stream.close() {
+            debug!("Can't close TLS connection with {:?}: {}", remote_addr, err);
+        }
+    }
+
+    /// Answers a request on a plain or TLS stream.
+    ///
+    /// If the connection may stay open, it does so if the client wants it and the response tells its length.
+    /// Returns whether it stays open.
+    fn handle_connection<S: Read + Write + Timed>(stream: &mut S, remote_addr: Option<SocketAddr>,
+                                                  transport: Transport, shared: Shared, keep_alive: bool) -> bool {
+        let _in_flight = shared.metrics.connection();
+        let started = Instant::now();
+        let request = match read_request(stream, remote_addr, &shared) {
+            Some(request) => request,
+            None => return false,
+        };
+        let client_auth = client_auth_mode(&shared, &request);
+        let request = match transport {
+            Transport::Tls { ref server_name, ref client_cert } => {
+                let request = request.with_server_name(server_name.clone());
+
+                match client_auth {
+                    ClientAuthMode::Off => request,
+                    _ => request.with_client_cert(client_cert.clone()),
+                }
+            },
+            _ => request,
+        };
+        let _busy = shared.scoreboard.begin(&request);
+        let vhost = shared.config.virtual_host_or(request.host(), &shared.default_vhost);
+
+        if let Some(location) = denying_location(&vhost, &request) {
+            if location.deny_action() == DenyAction::Drop {
+                log!(vhost.log_level(), "[{}] {} \"{} {}\" dropped",
+                    vhost.name(), remote_ip(&request), request.method(), request.url());
+                return false;
+            }
+        }
+
+        let cache = shared.cache.clone();
+        let decision = shared.limiter.as_ref().and_then(|limiter| check_rate_limit(limiter, &request));
+        let mut response = match decision {
+            _ if transport == Transport::Redirect && !is_redirect_exception(&shared, &request) =>
+                https_redirect(&shared, &vhost, &request),
+            _ if is_misdirected(&request) => {
+                let mut response = error_response(&vhost, Status::MisdirectedRequest, "Misdirected request!");
+                add_vhost_headers(&vhost, &mut response);
+                response
+            },
+            _ if client_auth == ClientAuthMode::Required && request.client_cert().is_none() => {
+                let mut response = error_response(&vhost, Status::Forbidden, "Client certificate required!");
+                add_vhost_headers(&vhost, &mut response);
+                response
+            },
+            _ if is_status_request(&shared, &request) => status_response(&shared, &request),
+            Some(ref decision) if !decision.is_allowed() => {
+                let mut response = too_many_requests(&vhost, decision);
+                add_vhost_headers(&vhost, &mut response);
+                response
+            },
+            _ if is_metrics_request(&shared, &request) => protected_metrics_response(&shared, &vhost, &request),
+            Some(ref decision) => {
+                let mut response = build_response(&vhost, &request, cache);
+                add_rate_limit_headers(&mut response, decision);
+                response
+            },
+            None => build_response(&vhost, &request, cache),
+        };
+        response.add_header(ResponseHeader::Custom(String::from("X-Request-Id"), request.id().clone()));
+
+        if let Transport::Tls { .. } = transport {
+            add_hsts_header(&shared, &mut response);
+        }
+
+        let mut keep_alive = keep_alive && request.keeps_alive() && response.has_content_length();
+
+        if keep_alive {
+            response.add_header(ResponseHeader::Custom(String::from("Connection"), String::from("keep-alive")));
+        } else if request.keeps_alive() {
+            response.add_header(ResponseHeader::Custom(String::from("Connection"), String::from("close")));
+        }
+
+        log!(vhost.log_level(), "[{}] {} \"{} {}\" {}",
+            vhost.name(), remote_ip(&request), request.method(), request.url(), response.status().code());
+
+        if let Err(err) = timeout::write_response(stream, &response, shared.config.timeouts()) {
+            connection_failed(&shared, remote_addr, err);
+            keep_alive = false;
+        }
+
+        shared.scoreboard.record_status(response.status().code());
+        shared.metrics.observe(
+            request.method(), response.status().code(), vhost.name(), response.body_len(), started.elapsed());
+
+        if let Some(ref access_log) = shared.access_log {
+            access_log.log(&accesslog::Entry::new(
+                &request, response.status().code(), response.body_len(), started.elapsed(), vhost.name())
+                .with_user(response.user()));
+        }
+
+        keep_alive
+    }
+}
+
+/// How a connection was accepted.
+#[derive(Debug, Clone, PartialEq)]
+enum Transport {
+    /// Plain HTTP.
+    Plain,
+    /// Plain HTTP on the listener redirecting to HTTPS.
+    Redirect,
+    /// HTTPS with the server name requested by SNI and the verified client certificate, if any.
+    Tls { server_name: Option<String>, client_cert: Option<ClientCertificate> },
+}
+
+/// What the acceptors of all listeners share.
+#[derive(Clone)]
+struct AcceptContext {
+    /// TLS configuration for listeners serving HTTPS.
+    server_config: Option<Arc<ServerConfig>>,
+    /// Event loop holding plain HTTP connections, if enabled.
+    event_loop: Option<EventLoop>,
+    /// Whether the server stops accepting after an upgrade.
+    draining: Arc<AtomicBool>,
+}
+
+/// Where an acceptor puts the connections it accepted.
+#[derive(Clone)]
+enum Dispatch {
+    /// Into the queue of a worker pool.
+    Pool(Arc<ThreadPool>),
+    /// Nowhere, the acceptor answers it, counted as busy worker.
+    Direct(Arc<PoolStats>),
+}
+
+impl Dispatch {
+    /// Answers the connection by the given closure. Fails if the queue of the pool is full.
+    fn execute<F>(&self, job: F) -> Result<(), ExecuteError>
+        where
+            F: FnOnce() + Send + 'static
+    {
+        match *self {
+            Dispatch::Pool(ref pool) => pool.execute(job),
+            Dispatch::Direct(ref stats) => {
+                let _busy = Busy::new(stats);
+
+                // Like a pool worker, the acceptor must survive a panicking connection.
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    error!("Answering a connection panicked.");
+                }
+
+                Ok(())
+            },
+        }
+    }
+
+    /// Whether connections may be rejected because the queue is full.
+    fn is_bounded(&self) -> bool {
+        self.stats().capacity().is_some()
+    }
+
+    /// Get the counters of the workers.
+    fn stats(&self) -> Arc<PoolStats> {
+        match *self {
+            Dispatch::Pool(ref pool) => pool.stats(),
+            Dispatch::Direct(ref stats) => Arc::clone(stats),
+        }
+    }
+}
+
+/// State shared by all connections.
+#[derive(Clone)]
+struct Shared {
+    /// Configuration active when the connection was accepted.
+    config: Arc<Config>,
+    configs: Arc<ConfigHandle>,
+    cache: Option<Arc<FileCache>>,
+    limiter: Option<Arc<RateLimiter>>,
+    access_log: Option<Arc<AccessLog>>,
+    /// Prefix making request ids unique across restarts.
+    id_prefix: String,
+    metrics: Arc<Metrics>,
+    /// Current requests and recent errors for the status page.
+    scoreboard: Arc<Scoreboard>,
+    /// Counters of the thread pool, once it is started.
+    pool: Option<Arc<PoolStats>>,
+    /// Virtual host for requests matching none, as configured for the listener which accepted the connection.
+    default_vhost: Option<String>,
+}
+
+impl Shared {
+    /// Get a copy using the currently active configuration.
+    fn reloaded(&self) -> Shared {
+        let mut shared = self.clone();
+        shared.config = self.configs.current();
+        shared
+    }
+}
+
+/// Creates a pool of the given size, bounded if a capacity is given.
+fn pool(size: usize, capacity: Option<usize>, stats: Arc<PoolStats>) -> ThreadPool {
+    match capacity {
+        Some(capacity) => ThreadPool::bounded(size, capacity, stats),
+        None => ThreadPool::with_stats(size, stats),
+    }
+}
 
-        let request = http::parse_request(request.trim());
-        debug!("Got request: {:?}", request);
+/// Creates the counters of pools with the given number of workers and total capacity.
+fn pool_stats(size: usize, capacity: Option<usize>) -> PoolStats {
+    match capacity {
+        Some(capacity) => PoolStats::new(size).with_capacity(capacity),
+        None => PoolStats::new(size),
+    }
+}
 
-        let response = build_response(config, request);
+/// Logs a connection the workers didn't take and gets the response it's answered by, none to close it.
+fn rejection(shared: &Shared, err: ExecuteError) -> Option<Response> {
+    if err == ExecuteError::Stopped {
+        error!("Can't answer connection, the workers are gone.");
+        return None;
+    }
+
+    debug!("Rejecting connection, the queue is full.");
 
-        stream.write(&response.render())
-            .expect("Can't write to TCP stream!");
-        stream.flush()
-            .expect("Can't flush TCP stream!");
+    match *shared.config.queue() {
+        Some(ref queue) if *queue.full_action() == FullAction::Reject =>
+            Some(service_unavailable(shared, *queue.retry_after())),
+        _ => None,
     }
 }
 
-fn byte_array_to_string(input: [u8; 4096]) -> String {
-    let mut output = String::new();
+/// Creates the response telling the client to retry after the given seconds.
+fn service_unavailable(shared: &Shared, retry_after: u64) -> Response {
+    let vhost = shared.config.virtual_host_or("", &shared.default_vhost);
+    let mut response = error_response(&vhost, Status::ServiceUnavailable, "Service unavailable!");
+    add_default_headers(&mut response);
+    add_vhost_headers(&vhost, &mut response);
+    response.add_header(ResponseHeader::RetryAfter(retry_after));
+    response.add_header(ResponseHeader::Custom(String::from("Connection"), String::from("close")));
+    response
+}
 
-    for i in 0..input.len() {
-        let ch = input[i];
+/// Answers a connection without waiting for it, so the acceptor isn't blocked by the client.
+fn reject_connection(mut stream: Stream, response: &Response) {
+    let mut request = [0; 4096];
 
-        if ch == 0 {
+    if let Err(err) = stream.set_nonblocking(true) {
+        debug!("Can't reject connection from {:?}: {}", stream.peer_addr(), err);
+        return;
+    }
+
+    // Reading what arrived keeps closing from resetting the connection before the client read the answer.
+    while let Ok(len) = stream.read(&mut request) {
+        if len == 0 {
             break;
         }
+    }
 
-        output.push(ch as char);
+    if let Err(err) = stream.write_all(&response.render()) {
+        debug!("Can't reject connection from {:?}: {}", stream.peer_addr(), err);
     }
+}
+
+/// Reads and parses a request from the stream. A client which sent only a part of it in time gets
+/// `408 Request Timeout`.
+///
+/// Returns nothing if no complete request arrived.
+fn read_request<S: Read + Write + Timed>(stream: &mut S, remote_addr: Option<SocketAddr>, shared: &Shared)
+    -> Option<Request> {
+    let timeouts = shared.config.timeouts();
+    let input = match timeout::read_request(stream, timeouts) {
+        Ok(ref input) if input.is_empty() => {
+            debug!("Connection from {:?} closed without request.", remote_addr);
+            return None;
+        },
+        Ok(input) => input,
+        Err(timeout::Error::TimedOut(timeout, received)) => {
+            timeout::record(&shared.metrics, timeout, remote_addr);
+
+            if received > 0 {
+                if let Err(err) = timeout::write_all(stream, &timeout::request_timeout_response(), timeouts) {
+                    connection_failed(shared, remote_addr, err);
+                }
+            }
 
-    output
+            return None;
+        },
+        Err(err) => {
+            connection_failed(shared, remote_addr, err);
+            return None;
+        },
+    };
+    let request = bytes_to_string(&input);
+    debug!("Received data: {:?}", request);
+
+    let request = http::parse_request(request.trim())
+        .with_remote_addr(remote_addr)
+        .with_id(next_request_id(&shared.id_prefix));
+    debug!("Got request: {:?}", request);
+    Some(request)
 }
 
-fn build_response(config: Config, request: Request) -> Response {
-    match request.method().as_ref() {
-        "GET" => handle_get_request(config, request),
-        "HEAD" => handle_head_request(config, request),
-        "OPTIONS" => handle_options_request(),
-        _ => handle_unsupported_request(),
+/// Counts and logs a timeout, or logs why reading or writing failed otherwise.
+fn connection_failed(shared: &Shared, remote_addr: Option<SocketAddr>, err: timeout::Error) {
+    match err {
+        timeout::Error::TimedOut(timeout, _) => timeout::record(&shared.metrics, timeout, remote_addr),
+        timeout::Error::Io(err) => debug!("Connection from {:?} failed: {}", remote_addr, err),
     }
 }
 
-fn handle_get_request(config: Config, request: Request) -> Response {
-    let mut response = match find_resource(config, request) {
-        Some(resource) => {
-            debug!("Found resource {:?}", resource);
-            let mut content = file::read_bytes(&resource);
+/// Whether the `Host` header names another host than the TLS server name of the connection.
+fn is_misdirected(request: &Request) -> bool {
+    match *request.server_name() {
+        Some(ref server_name) if !request.host().is_empty() =>
+            !vhost::strip_port(request.host()).eq_ignore_ascii_case(server_name),
+        _ => false,
+    }
+}
+
+/// Get the listeners of the configuration, and the one redirecting to HTTPS if none of them is on its port.
+fn listeners_of(config: &Config) -> Vec<ListenConfig> {
+    let mut listeners = config.listeners();
+    let redirect = config.tls().as_ref().and_then(|tls| tls.redirect().as_ref());
+
+    if let Some(redirect) = redirect {
+        if !listeners.iter().any(|listen| redirects(config, listen)) {
+            listeners.push(ListenConfig::new(config.address().clone(), *redirect.port())
+                .expect("Top level config is validated!"));
+        }
+    }
+
+    listeners
+}
+
+/// Whether a plain HTTP listener redirects to HTTPS because the redirect is configured on its port.
+fn redirects(config: &Config, listen: &ListenConfig) -> bool {
+    !listen.tls() && config.tls().as_ref()
+        .and_then(|tls| tls.redirect().as_ref())
+        .is_some_and(|redirect| redirect.port() == listen.port())
+}
+
+/// Whether the request to the redirect listener is served as usual.
+fn is_redirect_exception(shared: &Shared, request: &Request) -> bool {
+    shared.config.tls().as_ref()
+        .and_then(|tls| tls.redirect().as_ref())
+        .is_some_and(|redirect| redirect.is_exception(&request.path()))
+}
+
+/// Redirects to the HTTPS URL of the request, or rejects it if the `Host` header is missing or invalid.
+fn https_redirect(shared: &Shared, vhost: &VirtualHost, request: &Request) -> Response {
+    let (port, status) = match *shared.config.tls() {
+        Some(ref tls) => (*tls.port(), tls.redirect().as_ref().map_or(308, |redirect| *redirect.status())),
+        None => return error_response(vhost, Status::InternalServerError, "Internal server error!"),
+    };
+    let location = match tls::https_location(request.host(), request.url(), port) {
+        Some(location) => location,
+        None => {
+            let mut response = error_response(vhost, Status::BadRequest, "Host header required!");
+            add_default_headers(&mut response);
+            return response;
+        },
+    };
+    let status = if status == 301 { Status::MovedPermanently } else { Status::PermanentRedirect };
+    let mut response = Response::new(http::VERSION.to_string(), status, Vec::new());
+    response.add_header(ResponseHeader::Location(location));
+    response.add_header(ResponseHeader::ContentLength(0));
+    add_default_headers(&mut response);
+    response
+}
+
+/// Announces strict transport security on a HTTPS response, if configured.
+fn add_hsts_header(shared: &Shared, response: &mut Response) {
+    if let Some(hsts) = shared.config.tls().as_ref().and_then(|tls| tls.hsts().as_ref()) {
+        response.add_header(ResponseHeader::Custom(String::from("Strict-Transport-Security"), hsts.header_value()));
+    }
+}
+
+/// Get whether the requested path needs a client certificate.
+fn client_auth_mode(shared: &Shared, request: &Request) -> ClientAuthMode {
+    shared.config.tls().as_ref()
+        .map(|tls| tls.client_auth_mode(&request.path()))
+        .unwrap_or_default()
+}
+
+/// Whether the request asks for the metrics served on the main listener.
+fn is_metrics_request(shared: &Shared, request: &Request) -> bool {
+    match *shared.config.metrics() {
+        Some(ref metrics) => metrics.listen().is_none() && request.path() == *metrics.path(),
+        None => false,
+    }
+}
+
+fn metrics_response(shared: &Shared) -> Response {
+    let body = shared.metrics
+        .render(shared.pool.as_deref(), shared.cache.as_deref())
+        .into_bytes();
+    let content_length = body.len();
+    let mut response = Response::new(http::VERSION.to_string(), Status::Ok, body);
+    response.add_header(ResponseHeader::ContentType(String::from("text/plain; version=0.0.4; charset=utf-8")));
+    response.add_header(ResponseHeader::ContentLength(content_length));
+    add_default_headers(&mut response);
+    response
+}
+
+/// Answers a metrics request on the main listener, where the metrics path is subject to the access rules and
+/// the authentication of the virtual host like any other path.
+fn protected_metrics_response(shared: &Shared, vhost: &VirtualHost, request: &Request) -> Response {
+    match check_access(vhost, request).and_then(|_| authorize(vhost, request)) {
+        Ok(user) => metrics_response(shared).with_user(user),
+        Err(mut response) => {
+            add_vhost_headers(vhost, &mut response);
+            response
+        },
+    }
+}
+
+/// Path on the admin listener to reload the configuration with a `POST` request.
+static RELOAD_PATH: &str = "/-/reload";
+
+/// Reloads the configuration and logs the outcome.
+///
+/// Returns the changed settings which only take effect after a restart.
+fn reload_config(configs: &ConfigHandle) -> Result<Vec<&'static str>, &'static str> {
+    match configs.reload() {
+        Ok(changes) => {
+            info!("Reloaded configuration.");
+
+            for setting in changes.iter() {
+                warn!("Changed setting '{}' takes effect after a restart.", setting);
+            }
+
+            Ok(changes)
+        },
+        Err(err) => {
+            error!("Keeping current configuration: {}", err);
+            Err(err)
+        },
+    }
+}
+
+/// Reloads the configuration on a `POST` request.
+///
+/// Clients not allowed by the reload access rules of the metrics get `403 FORBIDDEN`.
+fn reload_response(shared: &Shared, request: &Request) -> Response {
+    let allowed = match *shared.config.metrics() {
+        Some(ref metrics) => metrics.is_reload_allowed(request.remote_addr()),
+        None => false,
+    };
+
+    if !allowed {
+        warn!("Denying {} to reload the configuration.", remote_ip(request));
+        let mut response = Response::new(http::VERSION.to_string(), Status::Forbidden, Vec::new());
+        response.add_header(ResponseHeader::ContentLength(0));
+        add_default_headers(&mut response);
+        return response;
+    }
+
+    if request.method() != "POST" {
+        let mut response = Response::new(http::VERSION.to_string(), Status::MethodNotAllowed, Vec::new());
+        response.add_header(ResponseHeader::ContentLength(0));
+        add_default_headers(&mut response);
+        return response;
+    }
+
+    let (status, body) = match reload_config(&shared.configs) {
+        Ok(ref changes) if changes.is_empty() => (Status::Ok, String::from("Reloaded configuration.\n")),
+        Ok(changes) => (Status::Ok, format!(
+            "Reloaded configuration. Changed settings take effect after a restart: {}\n", changes.join(", "))),
+        Err(err) => (Status::InternalServerError, format!("Keeping current configuration: {}\n", err)),
+    };
+    let body = body.into_bytes();
+    let content_length = body.len();
+    let mut response = Response::new(http::VERSION.to_string(), status, body);
+    response.add_header(ResponseHeader::ContentType(String::from("text/plain; charset=utf-8")));
+    response.add_header(ResponseHeader::ContentLength(content_length));
+    add_default_headers(&mut response);
+    response
+}
+
+/// Whether the request asks for the server status page.
+fn is_status_request(shared: &Shared, request: &Request) -> bool {
+    match *shared.config.status() {
+        Some(ref status) => request.path() == *status.path(),
+        None => false,
+    }
+}
+
+/// Renders the status page as JSON if asked for by `Accept` header or `?format=json`, else as HTML.
+///
+/// Clients not allowed by the access rules of the status page get `403 FORBIDDEN`.
+fn status_response(shared: &Shared, request: &Request) -> Response {
+    let allowed = match *shared.config.status() {
+        Some(ref status) => status.is_allowed(request.remote_addr()),
+        None => false,
+    };
+
+    if !allowed {
+        let mut response = Response::new(http::VERSION.to_string(), Status::Forbidden, Vec::new());
+        response.add_header(ResponseHeader::ContentLength(0));
+        add_default_headers(&mut response);
+        return response;
+    }
+
+    let pool = shared.pool.as_deref();
+    let as_json = request.accept().contains("application/json")
+        || request.query().split('&').any(|param| param == "format=json");
+    let (body, content_type) = if as_json {
+        (shared.scoreboard.render_json(pool, &shared.metrics), "application/json; charset=utf-8")
+    } else {
+        (shared.scoreboard.render_html(pool, &shared.metrics), "text/html; charset=utf-8")
+    };
+    let body = body.into_bytes();
+    let content_length = body.len();
+    let mut response = Response::new(http::VERSION.to_string(), Status::Ok, body);
+    response.add_header(ResponseHeader::ContentType(String::from(content_type)));
+    response.add_header(ResponseHeader::ContentLength(content_length));
+    response.add_header(ResponseHeader::Custom(String::from("Cache-Control"), String::from("no-store")));
+    add_default_headers(&mut response);
+    response
+}
+
+/// Number of requests received by this process, used for request ids.
+static REQUEST_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Get a prefix for request ids from the process start time and id.
+fn request_id_prefix() -> String {
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    format!("{:x}{:05x}", started.as_secs(), process::id())
+}
+
+/// Creates a unique id like `5b2d8e1c0a3f1-2a` for the next request.
+fn next_request_id(prefix: &str) -> String {
+    format!("{}-{:x}", prefix, REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+fn bytes_to_string(input: &[u8]) -> String {
+    input.iter()
+        .take_while(|byte| **byte != 0)
+        .map(|byte| *byte as char)
+        .collect()
+}
+
+fn build_response(vhost: &VirtualHost, request: &Request, cache: Option<Arc<FileCache>>) -> Response {
+    let mut response = if request.version() == http::VERSION && request.host().is_empty() {
+        debug!("Rejecting HTTP/{} request without Host header.", request.version());
+        let mut response = error_response(vhost, Status::BadRequest, "Host header required!");
+        add_default_headers(&mut response);
+        response
+    } else if !is_safe_path(&request.path()) {
+        debug!("Refusing path {:?} leaving the web root.", request.path());
+        let mut response = error_response(vhost, Status::NotFound, "Not found!");
+        add_default_headers(&mut response);
+        response
+    } else {
+        if let Err(mut response) = check_access(vhost, request) {
+            add_vhost_headers(vhost, &mut response);
+            return response;
+        }
+
+        let rewritten = match apply_rewrites(vhost, request) {
+            Ok(rewritten) => rewritten,
+            Err(mut response) => {
+                add_vhost_headers(vhost, &mut response);
+                return response;
+            },
+        };
+        let request = rewritten.as_ref().unwrap_or(request);
+
+        let user = match check_access(vhost, request).and_then(|_| authorize(vhost, request)) {
+            Ok(user) => user,
+            Err(mut response) => {
+                add_vhost_headers(vhost, &mut response);
+                return response;
+            },
+        };
+
+        let response = match request.method().as_ref() {
+            "GET" | "HEAD" if is_directory_without_slash(vhost, request) => handle_directory_redirect(request),
+            "GET" => handle_get_request(vhost, request, cache),
+            "HEAD" => handle_head_request(vhost, request, cache),
+            "OPTIONS" => handle_options_request(),
+            _ => handle_unsupported_request(vhost),
+        };
+        response.with_user(user)
+    };
+
+    add_vhost_headers(vhost, &mut response);
+    response
+}
+
+/// Applies the rewrite rules of the virtual host.
+///
+/// Returns the rewritten request, `None` if no rule rewrote it, or the response if the request must be
+/// redirected or the rules loop.
+fn apply_rewrites(vhost: &VirtualHost, request: &Request) -> Result<Option<Request>, Response> {
+    if vhost.rewrites().is_empty() {
+        return Ok(None);
+    }
+
+    let rewrite_request = RewriteRequest::new(
+        request.path(),
+        request.query().to_string(),
+        request.host(),
+        request.method());
+    let exists = |path: &str| rewrite::exists_in_web_root(vhost.web_dir(), path);
+    let mut trace = Vec::new();
+    let outcome = rewrite::apply(vhost.rewrites(), rewrite_request, &exists, &mut trace);
+
+    for step in trace.iter() {
+        debug!("{}", step);
+    }
+
+    match outcome {
+        Ok(Outcome::Unchanged) => Ok(None),
+        Ok(Outcome::Rewritten(url)) => Ok(Some(request.with_url(url))),
+        Ok(Outcome::Redirect(status, location)) => {
+            let status = match status {
+                301 => Status::MovedPermanently,
+                302 => Status::Found,
+                307 => Status::TemporaryRedirect,
+                _ => Status::PermanentRedirect,
+            };
+            let mut response = Response::new(http::VERSION.to_string(), status, Vec::new());
+            response.add_header(ResponseHeader::Location(location));
+            response.add_header(ResponseHeader::ContentLength(0));
+            add_default_headers(&mut response);
+            Err(response)
+        },
+        Err(err) => {
+            error!("Rewriting {} failed: {}", request.url(), err);
+            let mut response = error_response(vhost, Status::InternalServerError, "Internal server error!");
+            add_default_headers(&mut response);
+            Err(response)
+        },
+    }
+}
+
+/// Finds the location whose access rules deny the client, if any.
+fn denying_location<'a>(vhost: &'a VirtualHost, request: &Request) -> Option<&'a Location> {
+    vhost.location(&request.path())
+        .filter(|location| !location.is_allowed(request.remote_addr().map(|addr| addr.ip())))
+}
+
+/// Checks the client address against the access rules of the requested location.
+///
+/// Returns the forbidden response if the client is denied. Connections of clients denied by locations
+/// with `deny_action = 'drop'` are closed before the response is built, but if the path only leads there
+/// after rewriting, the client gets the forbidden response too.
+fn check_access(vhost: &VirtualHost, request: &Request) -> Result<(), Response> {
+    match denying_location(vhost, request) {
+        Some(location) => {
+            info!("Denying {} access to {} by location {}.", remote_ip(request), request.path(), location.path());
+            let mut response = error_response(vhost, Status::Forbidden, "Forbidden!");
+            add_default_headers(&mut response);
+            Err(response)
+        },
+        None => Ok(()),
+    }
+}
+
+/// Get the client IP for logging, or `-` if the client is not connected via IP.
+fn remote_ip(request: &Request) -> String {
+    request.remote_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| String::from("-"))
+}
+
+/// Checks the request against the rate limiter. Clients not connected via IP are not limited.
+fn check_rate_limit(limiter: &RateLimiter, request: &Request) -> Option<Decision> {
+    let client = request.remote_addr()?.ip();
+    let decision = limiter.check(client, &request.path());
+
+    if !decision.is_allowed() {
+        warn!("Rate limiting {} requesting {}.", client, request.url());
+    }
+
+    Some(decision)
+}
+
+/// Creates the response for a request exceeding the rate limit.
+fn too_many_requests(vhost: &VirtualHost, decision: &Decision) -> Response {
+    let mut response = error_response(vhost, Status::TooManyRequests, "Too many requests!");
+    response.add_header(ResponseHeader::RetryAfter(decision.retry_after()));
+    add_rate_limit_headers(&mut response, decision);
+    add_default_headers(&mut response);
+    response
+}
+
+fn add_rate_limit_headers(response: &mut Response, decision: &Decision) {
+    response.add_header(ResponseHeader::RateLimitLimit(decision.limit()));
+    response.add_header(ResponseHeader::RateLimitRemaining(decision.remaining()));
+    response.add_header(ResponseHeader::RateLimitReset(decision.reset()));
+}
+
+/// Checks the credentials of requests to paths protected by HTTP Basic authentication.
+///
+/// Returns the authenticated user, `None` if the path isn't protected, or the response challenging the client if
+/// the request is not authorized.
+fn authorize(vhost: &VirtualHost, request: &Request) -> Result<Option<String>, Response> {
+    let path = request.path();
+    let auth = match vhost.auth(&path) {
+        Some(auth) => auth,
+        None => return Ok(None),
+    };
+
+    let mut response = match auth.authenticate(request.authorization()) {
+        Ok(user) => {
+            debug!("User '{}' authenticated for realm '{}'.", user, auth.realm());
+            return Ok(Some(user));
+        },
+        Err(AuthError::MissingCredentials) => {
+            debug!("Requesting credentials for {} in realm '{}'.", path, auth.realm());
+            error_response(vhost, Status::Unauthorized, "Unauthorized!")
+        },
+        Err(AuthError::InvalidCredentials(user)) => {
+            warn!("Failed authentication of user '{}' for {} in realm '{}'.", user, path, auth.realm());
+            error_response(vhost, Status::Unauthorized, "Unauthorized!")
+        },
+        Err(AuthError::UnreadableHtpasswd(err)) => {
+            error!("Can't read htpasswd file for realm '{}': {}", auth.realm(), err);
+            error_response(vhost, Status::InternalServerError, "Internal server error!")
+        },
+    };
+
+    if response.status() == &Status::Unauthorized {
+        response.add_header(ResponseHeader::WwwAuthenticate(auth.challenge()));
+    }
+
+    add_default_headers(&mut response);
+    Err(response)
+}
+
+fn handle_get_request(vhost: &VirtualHost, request: &Request, cache: Option<Arc<FileCache>>) -> Response {
+    let mut response = match load_resource(vhost, request, cache) {
+        Ok(Some(resource)) => {
+            debug!("Found resource {:?}", resource.path());
+            let content = resource.content().clone();
             let content_length = content.len();
-            let mut response = Response::new(
+            let mut response = Response::with_shared_body(
                 http::VERSION.to_string(),
                 Status::Ok,
                 content);
             response.add_header(ResponseHeader::ContentLength(content_length));
-            response.add_header(
-                ResponseHeader::ContentType(
-                    format!("{}; charset=utf-8", determine_content_type(&resource))));
+            response.add_header(ResponseHeader::ContentType(resource.content_type().clone()));
+            response.add_header(ResponseHeader::ETag(resource.etag().clone()));
             response
         },
-        None => not_found_response(),
+        Ok(None) => directory_listing(vhost, request, true)
+            .unwrap_or_else(|| error_response(vhost, Status::NotFound, "Not found!")),
+        Err(err) => resource_error(vhost, request, err),
     };
 
     add_default_headers(&mut response);
     response
 }
 
-fn handle_head_request(config: Config, request: Request) -> Response {
-    let mut response = match find_resource(config, request) {
-        Some(resource) => {
+fn handle_head_request(vhost: &VirtualHost, request: &Request, cache: Option<Arc<FileCache>>) -> Response {
+    let mut response = match load_resource_headers(vhost, request, cache) {
+        Ok(Some((resource, content_type, etag))) => {
             debug!("Found resource {:?}", resource);
             let mut response = Response::new(
                 http::VERSION.to_string(),
                 Status::Ok,
                 Vec::new());
             response.add_header(ResponseHeader::ContentLength(0));
-            response.add_header(
-                ResponseHeader::ContentType(
-                    format!("{}; charset=utf-8", determine_content_type(&resource))));
+            response.add_header(ResponseHeader::ContentType(content_type));
+            response.add_header(ResponseHeader::ETag(etag));
             response
         },
-        None => not_found_response(),
+        Ok(None) => directory_listing(vhost, request, false)
+            .unwrap_or_else(|| error_response(vhost, Status::NotFound, "Not found!")),
+        Err(err) => resource_error(vhost, request, err),
     };
 
     add_default_headers(&mut response);
     response
 }
 
+/// Creates the response for a found file which can't be read, e.g. because it was removed meanwhile.
+fn resource_error(vhost: &VirtualHost, request: &Request, err: io::Error) -> Response {
+    if err.kind() == io::ErrorKind::NotFound {
+        debug!("Resource for {} vanished: {}", request.path(), err);
+        error_response(vhost, Status::NotFound, "Not found!")
+    } else {
+        error!("Can't read resource for {}: {}", request.path(), err);
+        error_response(vhost, Status::InternalServerError, "Internal server error!")
+    }
+}
+
 fn handle_options_request() -> Response {
     let mut response = Response::new(
         http::VERSION.to_string(),
@@ -173,21 +1439,86 @@ fn handle_options_request() -> Response {
     response
 }
 
-fn handle_unsupported_request() -> Response {
-    let mut response = Response::new(
-        String::from("1.1"),
+fn handle_unsupported_request(vhost: &VirtualHost) -> Response {
+    let mut response = error_response(
+        vhost,
         Status::MethodNotAllowed,
-        "Method not supported by this HTTP server implementation!".as_bytes().to_vec());
+        "Method not supported by this HTTP server implementation!");
     response.add_header(ResponseHeader::Allow(http::ALLOWED_METHODS.to_string()));
     response
 }
 
-fn find_resource(config: Config, request: Request) -> Option<PathBuf> {
-    let wanted_resource = create_resource_path(config.web_dir(), request.url());
+/// Get the file in the web root the request path leads to, before looking for index files.
+fn wanted_resource(vhost: &VirtualHost, request: &Request) -> Option<PathBuf> {
+    let path = request.path();
+
+    if !is_safe_path(&path) {
+        debug!("Refusing path {:?} leaving the web root.", path);
+        return None;
+    }
+
+    let wanted_resource = create_resource_path(vhost.web_dir(), &path);
     debug!("Wanted resource is {:?}", wanted_resource);
+    Some(wanted_resource)
+}
+
+fn load_resource(vhost: &VirtualHost, request: &Request, cache: Option<Arc<FileCache>>)
+    -> io::Result<Option<CachedFile>> {
+    let wanted_resource = match wanted_resource(vhost, request) {
+        Some(wanted_resource) => wanted_resource,
+        None => return Ok(None),
+    };
+
+    if let Some(ref cache) = cache {
+        if let Some(resource) = cache.get(&wanted_resource) {
+            return Ok(Some(resource));
+        }
+    }
+
+    let resource = match find_resource(wanted_resource.clone(), vhost.index_files()) {
+        Some(resource) => resource,
+        None => return Ok(None),
+    };
+    let modified = fs::metadata(&resource)?.modified()?;
+    let content = fs::read(&resource)?;
+    let content_type = format!("{}; charset=utf-8", determine_content_type(&resource));
+    let resource = CachedFile::new(resource, content, modified, content_type);
+
+    if let Some(ref cache) = cache {
+        cache.insert(wanted_resource, resource.clone());
+    }
 
+    Ok(Some(resource))
+}
+
+/// Looks up the file like `load_resource`, but only reads its metadata.
+///
+/// Returns the file, its content type and its ETag.
+fn load_resource_headers(vhost: &VirtualHost, request: &Request, cache: Option<Arc<FileCache>>)
+    -> io::Result<Option<(PathBuf, String, String)>> {
+    let wanted_resource = match wanted_resource(vhost, request) {
+        Some(wanted_resource) => wanted_resource,
+        None => return Ok(None),
+    };
+
+    if let Some(resource) = cache.as_ref().and_then(|cache| cache.get(&wanted_resource)) {
+        return Ok(Some((resource.path().clone(), resource.content_type().clone(), resource.etag().clone())));
+    }
+
+    let resource = match find_resource(wanted_resource, vhost.index_files()) {
+        Some(resource) => resource,
+        None => return Ok(None),
+    };
+    let metadata = fs::metadata(&resource)?;
+    let etag = cache::create_etag(metadata.len(), metadata.modified()?);
+    let content_type = format!("{}; charset=utf-8", determine_content_type(&resource));
+
+    Ok(Some((resource, content_type, etag)))
+}
+
+fn find_resource(wanted_resource: PathBuf, index_files: &[String]) -> Option<PathBuf> {
     if wanted_resource.is_dir() {
-        return handle_directory_resource(wanted_resource);
+        return handle_directory_resource(wanted_resource, index_files);
     }
 
     if wanted_resource.exists() {
@@ -198,29 +1529,114 @@ fn find_resource(config: Config, request: Request) -> Option<PathBuf> {
     }
 }
 
-fn handle_directory_resource(wanted_resource: PathBuf) -> Option<PathBuf> {
-    let mut wanted_resource_file = wanted_resource.join("index.html");
-    debug!("Wanted resource is a directory. Looking for {:?}", wanted_resource_file);
-
-    if !wanted_resource_file.exists() {
-        wanted_resource_file = wanted_resource.join("index.htm");
+fn handle_directory_resource(wanted_resource: PathBuf, index_files: &[String]) -> Option<PathBuf> {
+    for index_file in index_files {
+        let wanted_resource_file = wanted_resource.join(index_file);
         debug!("Wanted resource is a directory. Looking for {:?}", wanted_resource_file);
-    }
 
-    if !wanted_resource_file.exists() {
-        debug!("Nothing appropriate found!");
-        None
-    } else {
-        Some(wanted_resource_file)
+        if wanted_resource_file.is_file() {
+            return Some(wanted_resource_file);
+        }
     }
+
+    debug!("Nothing appropriate found!");
+    None
 }
 
-fn not_found_response() -> Response {
+/// Whether the request targets a directory but its path does not end with a slash.
+fn is_directory_without_slash(vhost: &VirtualHost, request: &Request) -> bool {
+    let path = request.path();
+
+    !path.ends_with('/')
+        && is_safe_path(&path)
+        && create_resource_path(vhost.web_dir(), &path).is_dir()
+}
+
+/// Redirects to the slash terminated URL of a directory, so relative links in its index page work.
+fn handle_directory_redirect(request: &Request) -> Response {
+    let url = request.url();
+    let location = match url.find('?') {
+        Some(question_mark) => format!("{}/{}", &url[..question_mark], &url[question_mark..]),
+        None => format!("{}/", url),
+    };
+    debug!("Redirecting directory {} to {}", url, location);
+
     let mut response = Response::new(
-        String::from("1.1"),
-        Status::NotFound,
-        "Not found!".as_bytes().to_vec());
-    response.add_header(ResponseHeader::ContentType(String::from("text/plain; charset=utf-8")));
+        http::VERSION.to_string(),
+        Status::MovedPermanently,
+        Vec::new());
+    response.add_header(ResponseHeader::Location(location));
+    response.add_header(ResponseHeader::ContentLength(0));
+    add_default_headers(&mut response);
+    response
+}
+
+/// Renders a listing for a requested directory without index file if the location allows it.
+///
+/// Without body, e.g. for `HEAD`, the listing is looked up the same way but not rendered.
+fn directory_listing(vhost: &VirtualHost, request: &Request, with_body: bool) -> Option<Response> {
+    let mut path = request.path();
+    let location = vhost.location(&path)?;
+
+    if !location.autoindex() || !is_safe_path(&path) {
+        return None;
+    }
+
+    let wanted_resource = create_resource_path(vhost.web_dir(), &path);
+
+    if !wanted_resource.is_dir() {
+        return None;
+    }
+
+    let mut entries = match autoindex::read_entries(&wanted_resource, location.show_hidden()) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Can't list directory {:?}: {}", wanted_resource, err);
+            return None;
+        },
+    };
+
+    if !path.ends_with('/') {
+        path.push('/');
+    }
+
+    let sort = autoindex::Sort::from_query(request.query());
+    autoindex::sort_entries(&mut entries, &sort);
+
+    let as_json = request.accept().contains("application/json");
+    let (content, content_type) = match (with_body, as_json) {
+        (false, true) => (String::new(), "application/json; charset=utf-8"),
+        (false, false) => (String::new(), "text/html; charset=utf-8"),
+        (true, true) => (autoindex::render_json(&path, &entries), "application/json; charset=utf-8"),
+        (true, false) => (autoindex::render_html(&path, &entries, &sort), "text/html; charset=utf-8"),
+    };
+
+    let content = content.into_bytes();
+    let content_length = content.len();
+    let mut response = Response::new(http::VERSION.to_string(), Status::Ok, content);
+    response.add_header(ResponseHeader::ContentLength(content_length));
+    response.add_header(ResponseHeader::ContentType(String::from(content_type)));
+    Some(response)
+}
+
+/// Creates an error response using the custom error page of the virtual host if there is one.
+fn error_response(vhost: &VirtualHost, status: Status, message: &str) -> Response {
+    let error_page = vhost.error_dir().as_ref()
+        .map(|error_dir| Path::new(error_dir).join(format!("{}.html", status.code())))
+        .filter(|error_page| error_page.is_file());
+
+    let (content, content_type) = match error_page {
+        Some(error_page) => {
+            debug!("Using error page {:?}", error_page);
+            (file::read_bytes(&error_page), "text/html; charset=utf-8")
+        },
+        None => (message.as_bytes().to_vec(), "text/plain; charset=utf-8"),
+    };
+
+    let content_length = content.len();
+    let mut response = Response::new(String::from("1.1"), status, content);
+    response.add_header(ResponseHeader::ContentType(String::from(content_type)));
+    response.add_header(ResponseHeader::ContentLength(content_length));
     response
 }
 
@@ -245,6 +1661,12 @@ fn add_default_headers(response: &mut Response) {
     response.add_header(ResponseHeader::AcceptRanges(String::from("none")));
 }
 
+fn add_vhost_headers(vhost: &VirtualHost, response: &mut Response) {
+    for (name, value) in vhost.headers() {
+        response.add_header(ResponseHeader::Custom(name.clone(), value.clone()));
+    }
+}
+
 fn formatted_now() -> String {
     // http://man7.org/linux/man-pages/man3/strftime.3.html
     // Wed, 14 Feb 2018 12:17:24 GMT
@@ -258,11 +1680,7 @@ fn create_resource_path(web_root: &String, resource_url: &String) -> PathBuf {
 }
 
 fn relativize_uri(resource_url: &String) -> String {
-    if resource_url.starts_with("/") {
-        resource_url[1..].to_string()
-    } else {
-        resource_url.clone()
-    }
+    resource_url.trim_start_matches('/').to_string()
 }
 
 #[cfg(test)]
@@ -306,12 +1724,649 @@ mod tests {
         );
     }
 
+    #[test]
+    fn error_response_uses_error_page() {
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir"))
+            .and_then(|vhost| vhost.with_error_dir(Some(String::from("test/error_dir"))))
+            .unwrap();
+
+        let response = error_response(&vhost, Status::NotFound, "Not found!");
+
+        assert_that!(response.status(), is(equal_to(&Status::NotFound)));
+        assert_that!(
+            String::from_utf8(response.render()).unwrap().contains("Content-Type: text/html; charset=utf-8"),
+            is(true));
+    }
+
+    #[test]
+    fn build_response_rejects_http_1_1_without_host() {
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir")).unwrap();
+        let request = http::parse_request("GET / HTTP/1.1\r\nAccept: */*\r\n");
+
+        assert_that!(build_response(&vhost, &request, None).status(), is(equal_to(&Status::BadRequest)));
+    }
+
+    #[test]
+    fn build_response_adds_vhost_headers() {
+        let mut headers = ::std::collections::BTreeMap::new();
+        headers.insert(String::from("X-Frame-Options"), String::from("DENY"));
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir"))
+            .and_then(|vhost| vhost.with_headers(headers))
+            .unwrap();
+        let request = http::parse_request("GET /index.html HTTP/1.1\r\nHost: localhost\r\n");
+
+        let response = String::from_utf8(build_response(&vhost, &request, None).render()).unwrap();
+
+        assert_that!(response.starts_with("HTTP/1.1 200 OK\r\n"), is(true));
+        assert_that!(response.contains("X-Frame-Options: DENY\r\n"), is(true));
+    }
+
+    #[test]
+    fn build_response_lists_directories() {
+        let location = ::location::Location::new(String::from("/"))
+            .unwrap()
+            .with_autoindex(true, false);
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/fixtures"))
+            .and_then(|vhost| vhost.with_locations(vec!(location)))
+            .unwrap();
+
+        let request = http::parse_request("GET /autoindex/ HTTP/1.1\r\nHost: localhost\r\n");
+        let html = String::from_utf8(build_response(&vhost, &request, None).render()).unwrap();
+
+        assert_that!(html.starts_with("HTTP/1.1 200 OK\r\n"), is(true));
+        assert_that!(html.contains("Content-Type: text/html; charset=utf-8"), is(true));
+        assert_that!(html.contains("<a href=\"/autoindex/a%20file.txt\">a file.txt</a>"), is(true));
+        assert_that!(html.contains(".hidden"), is(false));
+
+        let request = http::parse_request(
+            "GET /autoindex/sub/ HTTP/1.1\r\nHost: localhost\r\nAccept: application/json\r\n");
+        let json = String::from_utf8(build_response(&vhost, &request, None).render()).unwrap();
+
+        assert_that!(json.contains("Content-Type: application/json; charset=utf-8"), is(true));
+        assert_that!(json.contains("\"href\":\"/autoindex/sub/b.txt\""), is(true));
+    }
+
+    #[test]
+    fn build_response_lists_directories_for_head() {
+        let location = ::location::Location::new(String::from("/"))
+            .unwrap()
+            .with_autoindex(true, false);
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/fixtures"))
+            .and_then(|vhost| vhost.with_locations(vec!(location)))
+            .unwrap();
+
+        let request = http::parse_request("HEAD /autoindex/ HTTP/1.1\r\nHost: localhost\r\n");
+        let response = build_response(&vhost, &request, None);
+        let head = String::from_utf8(response.render()).unwrap();
+
+        assert_that!(head.starts_with("HTTP/1.1 200 OK\r\n"), is(true));
+        assert_that!(head.contains("Content-Type: text/html; charset=utf-8"), is(true));
+        assert_that!(response.body_len(), is(equal_to(0)));
+    }
+
+    #[test]
+    fn build_response_does_not_list_directories_by_default() {
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/fixtures")).unwrap();
+        let request = http::parse_request("GET /autoindex/ HTTP/1.1\r\nHost: localhost\r\n");
+
+        assert_that!(build_response(&vhost, &request, None).status(), is(equal_to(&Status::NotFound)));
+    }
+
+    #[test]
+    fn build_response_redirects_directories_without_slash() {
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir")).unwrap();
+        let request = http::parse_request("GET /css?v=1 HTTP/1.1\r\nHost: localhost\r\n");
+
+        let response = String::from_utf8(build_response(&vhost, &request, None).render()).unwrap();
+
+        assert_that!(response.starts_with("HTTP/1.1 301 MOVED PERMANENTLY\r\n"), is(true));
+        assert_that!(response.contains("Location: /css/?v=1\r\n"), is(true));
+    }
+
+    #[test]
+    fn build_response_serves_configured_index_files() {
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir"))
+            .and_then(|vhost| vhost.with_index_files(vec!(String::from("hello.html"))))
+            .unwrap();
+        let request = http::parse_request("GET / HTTP/1.1\r\nHost: localhost\r\n");
+
+        let response = String::from_utf8(build_response(&vhost, &request, None).render()).unwrap();
+
+        assert_that!(response.starts_with("HTTP/1.1 200 OK\r\n"), is(true));
+        assert_that!(response.contains(&String::from_utf8(file::read_bytes(&PathBuf::from("test/web_dir/hello.html"))).unwrap()), is(true));
+    }
+
+    #[test]
+    fn build_response_applies_rewrites() {
+        let rules = vec!(
+            ::rewrite::RewriteRule::new(String::from("^/hello$"), String::from("/hello.html")).unwrap(),
+            ::rewrite::RewriteRule::new(String::from("^/old/(.*)$"), String::from("/$1"))
+                .and_then(|rule| rule.with_redirect(Some(308)))
+                .unwrap(),
+            ::rewrite::RewriteRule::new(String::from("^/ping$"), String::from("/pong")).unwrap(),
+            ::rewrite::RewriteRule::new(String::from("^/pong$"), String::from("/ping")).unwrap());
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir"))
+            .and_then(|vhost| vhost.with_rewrites(rules))
+            .unwrap();
+
+        let request = http::parse_request("GET /hello HTTP/1.1\r\nHost: localhost\r\n");
+        assert_that!(build_response(&vhost, &request, None).status(), is(equal_to(&Status::Ok)));
+
+        let request = http::parse_request("GET /old/hello.html HTTP/1.1\r\nHost: localhost\r\n");
+        let response = String::from_utf8(build_response(&vhost, &request, None).render()).unwrap();
+        assert_that!(response.starts_with("HTTP/1.1 308 PERMANENT REDIRECT\r\n"), is(true));
+        assert_that!(response.contains("Location: /hello.html\r\n"), is(true));
+
+        let request = http::parse_request("GET /ping HTTP/1.1\r\nHost: localhost\r\n");
+        assert_that!(build_response(&vhost, &request, None).status(), is(equal_to(&Status::InternalServerError)));
+    }
+
+    #[test]
+    fn build_response_requires_basic_authentication() {
+        let auth = ::auth::AuthConfig::new(
+            String::from("/"),
+            String::from("Private"),
+            String::from("test/fixtures/htpasswd")).unwrap();
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir"))
+            .and_then(|vhost| vhost.with_auths(vec!(auth)))
+            .unwrap();
+
+        let request = http::parse_request("GET /index.html HTTP/1.1\r\nHost: localhost\r\n");
+        let response = String::from_utf8(build_response(&vhost, &request, None).render()).unwrap();
+        assert_that!(response.starts_with("HTTP/1.1 401 UNAUTHORIZED\r\n"), is(true));
+        assert_that!(
+            response.contains("WWW-Authenticate: Basic realm=\"Private\", charset=\"UTF-8\"\r\n"),
+            is(true));
+
+        // alice:wrong
+        let request = http::parse_request(
+            "GET /index.html HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic YWxpY2U6d3Jvbmc=\r\n");
+        assert_that!(build_response(&vhost, &request, None).status(), is(equal_to(&Status::Unauthorized)));
+
+        // alice:secret
+        let request = http::parse_request(
+            "GET /index.html HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic YWxpY2U6c2VjcmV0\r\n");
+        let response = build_response(&vhost, &request, None);
+        assert_that!(response.status(), is(equal_to(&Status::Ok)));
+        assert_that!(response.user(), is(equal_to(Some("alice"))));
+    }
+
+    #[test]
+    fn build_response_resolves_dot_segments_before_authentication() {
+        let auth = ::auth::AuthConfig::new(
+            String::from("/index.html"),
+            String::from("Private"),
+            String::from("test/fixtures/htpasswd")).unwrap();
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir"))
+            .and_then(|vhost| vhost.with_auths(vec!(auth)))
+            .unwrap();
+
+        for url in ["/index.html", "/./index.html", "/%2e/index.html", "//.//index.html"].iter() {
+            let request = http::parse_request(&format!("GET {} HTTP/1.1\r\nHost: localhost\r\n", url));
+            assert_that!(build_response(&vhost, &request, None).status(), is(equal_to(&Status::Unauthorized)));
+        }
+    }
+
+    #[test]
+    fn build_response_enforces_access_rules() {
+        let location = Location::new(String::from("/")).unwrap()
+            .with_access(vec!(String::from("allow 192.168.0.0/16"), String::from("deny all")), DenyAction::Forbidden)
+            .unwrap();
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir"))
+            .and_then(|vhost| vhost.with_locations(vec!(location)))
+            .unwrap();
+        let request = http::parse_request("GET /index.html HTTP/1.1\r\nHost: localhost\r\n");
+
+        let allowed = request.clone().with_remote_addr(Some("192.168.1.2:4711".parse().unwrap()));
+        assert_that!(build_response(&vhost, &allowed, None).status(), is(equal_to(&Status::Ok)));
+
+        let denied = request.clone().with_remote_addr(Some("10.0.0.1:4711".parse().unwrap()));
+        assert_that!(build_response(&vhost, &denied, None).status(), is(equal_to(&Status::Forbidden)));
+
+        assert_that!(build_response(&vhost, &request, None).status(), is(equal_to(&Status::Forbidden)));
+    }
+
+    #[test]
+    fn denying_location_matches_resolved_path() {
+        let location = Location::new(String::from("/admin/")).unwrap()
+            .with_access(vec!(String::from("deny all")), DenyAction::Drop)
+            .unwrap();
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir"))
+            .and_then(|vhost| vhost.with_locations(vec!(location)))
+            .unwrap();
+
+        for url in ["/admin/x", "/./admin/x", "/%2e/admin/x", "//admin/./x"].iter() {
+            let request = http::parse_request(&format!("GET {} HTTP/1.1\r\nHost: localhost\r\n", url))
+                .with_remote_addr(Some("10.0.0.1:4711".parse().unwrap()));
+            assert_that!(denying_location(&vhost, &request).is_some(), is(true));
+            assert_that!(build_response(&vhost, &request, None).status(), is(equal_to(&Status::Forbidden)));
+        }
+    }
+
+    #[test]
+    fn check_rate_limit_answers_too_many_requests() {
+        let limiter = RateLimiter::new(::ratelimit::RateLimitConfig::new(0.5, 1).unwrap());
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir")).unwrap();
+        let request = http::parse_request("GET / HTTP/1.1\r\nHost: localhost\r\n");
+
+        assert_that!(check_rate_limit(&limiter, &request), is(equal_to(None)));
+
+        let request = request.with_remote_addr(Some("10.0.0.1:4711".parse().unwrap()));
+        assert_that!(check_rate_limit(&limiter, &request).unwrap().is_allowed(), is(true));
+
+        let decision = check_rate_limit(&limiter, &request).unwrap();
+        let response = String::from_utf8(too_many_requests(&vhost, &decision).render()).unwrap();
+        assert_that!(decision.is_allowed(), is(false));
+        assert_that!(response.starts_with("HTTP/1.1 429 TOO MANY REQUESTS\r\n"), is(true));
+        assert_that!(response.contains("Retry-After: 2\r\n"), is(true));
+        assert_that!(response.contains("RateLimit-Limit: 1\r\n"), is(true));
+        assert_that!(response.contains("RateLimit-Remaining: 0\r\n"), is(true));
+    }
+
+    fn shared(config: Config, file: Option<PathBuf>) -> Shared {
+        let configs = Arc::new(ConfigHandle::new(config, file));
+
+        Shared {
+            config: configs.current(),
+            configs,
+            cache: None,
+            limiter: None,
+            access_log: None,
+            id_prefix: request_id_prefix(),
+            metrics: Arc::new(Metrics::new()),
+            scoreboard: Arc::new(Scoreboard::new()),
+            pool: None,
+            default_vhost: None,
+        }
+    }
+
+    #[test]
+    fn status_response_is_restricted_to_allowed_clients() {
+        let config = Config::new(String::from("127.0.0.1"), 8080, 1, String::from("test/web_dir"),
+            String::from("debug"), String::from("logs")).unwrap()
+            .with_status(Some(::status::StatusConfig::new(String::from("/server-status")).unwrap()))
+            .unwrap();
+        let shared = shared(config, None);
+        let request = http::parse_request("GET /server-status?format=json HTTP/1.1\r\nHost: localhost\r\n");
+
+        assert_that!(is_status_request(&shared, &request), is(true));
+        assert_that!(status_response(&shared, &request).status().code(), is(equal_to(403)));
+
+        let request = request.with_remote_addr(Some("127.0.0.1:4711".parse().unwrap()));
+        let response = String::from_utf8(status_response(&shared, &request).render()).unwrap();
+        assert_that!(response.starts_with("HTTP/1.1 200 OK\r\n"), is(true));
+        assert_that!(response.contains("Content-Type: application/json; charset=utf-8\r\n"), is(true));
+        assert_that!(response.contains("\"version\":\"1.0.0\""), is(true));
+    }
+
+    #[test]
+    fn rejection_answers_service_unavailable_or_closes() {
+        let config = Config::new(String::from("127.0.0.1"), 8080, 1, String::from("test/web_dir"),
+            String::from("debug"), String::from("logs")).unwrap();
+        let queue = ::threads::QueueConfig::new(10).unwrap().with_retry_after(7);
+        let shared = shared(config.clone().with_queue(Some(queue.clone())).unwrap(), None);
+
+        let response = String::from_utf8(rejection(&shared, ExecuteError::Full).unwrap().render()).unwrap();
+        assert_that!(response.starts_with("HTTP/1.1 503 SERVICE UNAVAILABLE\r\n"), is(true));
+        assert_that!(response.contains("\r\nRetry-After: 7\r\n"), is(true));
+        assert_that!(response.contains("\r\nConnection: close\r\n"), is(true));
+        assert_that!(rejection(&shared, ExecuteError::Stopped).is_none(), is(true));
+
+        let shared = self::shared(config.with_queue(Some(queue.with_full_action(FullAction::Drop))).unwrap(), None);
+        assert_that!(rejection(&shared, ExecuteError::Full).is_none(), is(true));
+    }
+
+    /// Sends a request over TLS to a server handling one connection, with the client certificate if given.
+    fn tls_request(tls_config: tls::TlsConfig, client_cert: Option<&str>, request: &[u8]) -> String {
+        use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+        use rustls::pki_types::ServerName;
+        use std::convert::TryFrom;
+
+        let config = Config::new(String::from("127.0.0.1"), 8080, 1, String::from("test/web_dir"),
+            String::from("debug"), String::from("logs")).unwrap()
+            .with_tls(Some(tls_config.clone())).unwrap();
+        let server_config = tls::server_config(&tls_config, &[]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = shared(config, None);
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            Server::handle_tls_connection(stream, server_config, shared);
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(tls::load_certs("test/fixtures/tls/localhost.pem").unwrap());
+        let builder = ClientConfig::builder_with_provider(Arc::new(::rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots);
+        let client_config = match client_cert {
+            Some(name) => builder.with_client_auth_cert(
+                tls::load_certs(&format!("test/fixtures/tls/{}.pem", name)).unwrap(),
+                tls::load_key(&format!("test/fixtures/tls/{}.key", name)).unwrap()).unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let connection = ClientConnection::new(Arc::new(client_config), ServerName::try_from("localhost").unwrap())
+            .unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+        response
+    }
+
+    fn localhost_tls() -> tls::TlsConfig {
+        tls::TlsConfig::new(8443, String::from("test/fixtures/tls/localhost.pem"),
+            String::from("test/fixtures/tls/localhost.key")).unwrap()
+    }
+
+    #[test]
+    fn handle_tls_connection_answers_over_tls() {
+        let response = tls_request(localhost_tls(), None, b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        assert_that!(response.starts_with("HTTP/1.1 200 OK\r\n"), is(true));
+        assert_that!(response.contains("<html"), is(true));
+    }
+
+    #[test]
+    fn handle_tls_connection_requires_client_certificate() {
+        let tls_config = localhost_tls()
+            .with_client_auth(
+                Some(String::from("test/fixtures/tls/client-ca.pem")),
+                vec!(tls::ClientAuthConfig::new(String::from("/hello"), ClientAuthMode::Required).unwrap()))
+            .unwrap();
+        let request = b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        assert_that!(tls_request(tls_config.clone(), None, request).starts_with("HTTP/1.1 403 FORBIDDEN\r\n"), is(true));
+        assert_that!(tls_request(tls_config, Some("client"), request).starts_with("HTTP/1.1 200 OK\r\n"), is(true));
+    }
+
+    #[test]
+    fn handle_tls_connection_requires_client_certificate_for_dot_segments() {
+        let tls_config = localhost_tls()
+            .with_client_auth(
+                Some(String::from("test/fixtures/tls/client-ca.pem")),
+                vec!(tls::ClientAuthConfig::new(String::from("/hello"), ClientAuthMode::Required).unwrap()))
+            .unwrap();
+        let request = b"GET /./hello.html HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        assert_that!(tls_request(tls_config.clone(), None, request).starts_with("HTTP/1.1 403 FORBIDDEN\r\n"), is(true));
+        assert_that!(tls_request(tls_config, Some("client"), request).starts_with("HTTP/1.1 200 OK\r\n"), is(true));
+    }
+
+    #[test]
+    fn handle_tls_connection_adds_hsts_header() {
+        let tls_config = localhost_tls().with_hsts(Some(tls::HstsConfig::new(600))).unwrap();
+        let response = tls_request(tls_config, None, b"GET /hello.html HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        assert_that!(response.contains("\r\nStrict-Transport-Security: max-age=600\r\n"), is(true));
+    }
+
+    #[test]
+    fn https_redirect_keeps_host_and_target() {
+        let redirect = tls::RedirectConfig::new(8080).unwrap()
+            .with_status(301).unwrap()
+            .with_exceptions(vec!(String::from("/.well-known/"))).unwrap();
+        let config = Config::new(String::from("127.0.0.1"), 8080, 1, String::from("test/web_dir"),
+            String::from("debug"), String::from("logs")).unwrap()
+            .with_tls(Some(localhost_tls().with_redirect(Some(redirect)).unwrap())).unwrap();
+        let shared = shared(config, None);
+        let vhost = shared.config.virtual_host("");
+        let request = http::parse_request("GET /a/b.html?x=1 HTTP/1.1\r\nHost: localhost:8080\r\n");
+        let response = String::from_utf8(https_redirect(&shared, &vhost, &request).render()).unwrap();
+
+        assert_that!(redirects(&shared.config, &shared.config.listeners()[0]), is(true));
+        assert_that!(redirects(&shared.config, &shared.config.listeners()[1]), is(false));
+        assert_that!(response.starts_with("HTTP/1.1 301 MOVED PERMANENTLY\r\n"), is(true));
+        assert_that!(response.contains("\r\nLocation: https://localhost:8443/a/b.html?x=1\r\n"), is(true));
+        assert_that!(is_redirect_exception(&shared, &request), is(false));
+
+        let request = http::parse_request("GET /.well-known/acme-challenge/token HTTP/1.1\r\n");
+        assert_that!(is_redirect_exception(&shared, &request), is(true));
+        assert_that!(https_redirect(&shared, &vhost, &request).status().code(), is(equal_to(400)));
+    }
+
+    #[test]
+    fn handle_buffered_request_keeps_connection_alive_if_client_wants() {
+        let config = Config::new(String::from("127.0.0.1"), 8080, 1, String::from("test/web_dir"),
+            String::from("debug"), String::from("logs")).unwrap();
+        let shared = shared(config, None);
+        let request = b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec();
+
+        let (response, keep_alive) = Server::handle_buffered_request(
+            request.clone(), None, true, Transport::Plain, shared.clone());
+        assert_that!(keep_alive, is(true));
+        assert_that!(String::from_utf8_lossy(&response).contains("\r\nConnection: keep-alive\r\n"), is(true));
+
+        let (response, keep_alive) = Server::handle_buffered_request(request, None, false, Transport::Plain, shared.clone());
+        assert_that!(keep_alive, is(false));
+        assert_that!(String::from_utf8_lossy(&response).contains("\r\nConnection: close\r\n"), is(true));
+
+        let request = b"GET /index.html HTTP/1.0\r\nHost: localhost\r\n\r\n".to_vec();
+        let (response, keep_alive) = Server::handle_buffered_request(request, None, true, Transport::Plain, shared);
+        assert_that!(keep_alive, is(false));
+        assert_that!(String::from_utf8_lossy(&response).contains("Connection:"), is(false));
+    }
+
+    #[test]
+    fn handle_buffered_request_applies_access_rules_to_metrics() {
+        let location = Location::new(String::from("/metrics")).unwrap()
+            .with_access(vec!(String::from("allow 127.0.0.1"), String::from("deny all")), DenyAction::Forbidden)
+            .unwrap();
+        let config = Config::new(String::from("127.0.0.1"), 8080, 1, String::from("test/web_dir"),
+            String::from("debug"), String::from("logs")).unwrap()
+            .with_metrics(Some(::metrics::MetricsConfig::new(String::from("/metrics"), None).unwrap()))
+            .and_then(|config| config.with_locations(vec!(location)))
+            .unwrap();
+        let shared = shared(config, None);
+        let request = b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec();
+
+        let denied = Some("10.0.0.1:4711".parse().unwrap());
+        let (response, _) = Server::handle_buffered_request(
+            request.clone(), denied, false, Transport::Plain, shared.clone());
+        assert_that!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 403 FORBIDDEN\r\n"), is(true));
+
+        let allowed = Some("127.0.0.1:4711".parse().unwrap());
+        let (response, _) = Server::handle_buffered_request(request, allowed, false, Transport::Plain, shared);
+        let response = String::from_utf8_lossy(&response);
+        assert_that!(response.starts_with("HTTP/1.1 200 OK\r\n"), is(true));
+        assert_that!(response.contains("webserver_"), is(true));
+    }
+
+    #[test]
+    fn open_listeners_takes_passed_sockets() {
+        use std::os::unix::io::OwnedFd;
+
+        let main = listen::bind("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let extra = listen::bind("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let port = main.local_addr().unwrap().port();
+        let extra_port = extra.local_addr().unwrap().port();
+        let config = Config::new(String::from("127.0.0.1"), port, 1, String::from("test/web_dir"),
+            String::from("debug"), String::from("logs")).unwrap();
+        let sut = Server::new(config).with_listen_fds(ListenFds::new(vec!(
+            (OwnedFd::from(extra), String::from("extra")),
+            (OwnedFd::from(main), String::from("unknown")))));
+
+        let listeners = sut.open_listeners().unwrap();
+
+        assert_that!(listeners.len(), is(equal_to(2)));
+        assert_that!(*listeners[0].0.port(), is(equal_to(port)));
+        assert_that!(listeners[0].2, is(false));
+        assert_that!(listeners[1].0.url(), is(equal_to(format!("http://127.0.0.1:{}/", extra_port))));
+        assert_that!(listeners[1].2, is(false));
+    }
+
+    #[test]
+    fn dispatches_split_threads_among_acceptors() {
+        use listen::AcceptorsConfig;
+
+        let config = Config::new(String::from("127.0.0.1"), 8080, 8, String::from("test/web_dir"),
+            String::from("debug"), String::from("logs")).unwrap();
+        let queue = Server::new(config.clone().with_acceptors(Some(AcceptorsConfig::new(3).unwrap())).unwrap());
+        let bounded = Server::new(config.clone().with_acceptors(Some(AcceptorsConfig::new(3).unwrap())).unwrap()
+            .with_queue(Some(::threads::QueueConfig::new(10).unwrap())).unwrap());
+        let direct = Server::new(config.clone()
+            .with_acceptors(Some(AcceptorsConfig::new(3).unwrap().with_mode(AcceptMode::Direct))).unwrap());
+
+        assert_that!(Server::new(config).dispatches(1).len(), is(equal_to(1)));
+
+        let pools = queue.dispatches(3);
+        assert_that!(pools.len(), is(equal_to(3)));
+        assert_that!(pools[0].stats().size(), is(equal_to(6)));
+        assert_that!(pools[0].is_bounded(), is(false));
+
+        let bounded = bounded.dispatches(3);
+        assert_that!(bounded[0].stats().capacity(), is(equal_to(Some(9))));
+        assert_that!(bounded[0].is_bounded(), is(true));
+
+        let acceptors = direct.dispatches(6);
+        assert_that!(acceptors.len(), is(equal_to(1)));
+        assert_that!(acceptors[0].stats().size(), is(equal_to(6)));
+        assert_that!(acceptors[0].execute(|| ()), is(equal_to(Ok(()))));
+        assert_that!(acceptors[0].stats().busy_workers(), is(equal_to(0)));
+    }
+
+    #[test]
+    fn is_misdirected_compares_host_with_server_name() {
+        let request = http::parse_request("GET / HTTP/1.1\r\nHost: WWW.example.com:8443\r\n");
+
+        assert_that!(is_misdirected(&request), is(false));
+        assert_that!(is_misdirected(&request.clone().with_server_name(Some(String::from("www.example.com")))), is(false));
+        assert_that!(is_misdirected(&request.with_server_name(Some(String::from("api.example.com")))), is(true));
+    }
+
+    #[test]
+    fn admin_listener_answers_while_a_client_sends_nothing() {
+        let config = Config::new(String::from("127.0.0.1"), 8080, 1, String::from("test/web_dir"),
+            String::from("debug"), String::from("logs")).unwrap()
+            .with_metrics(Some(::metrics::MetricsConfig::new(String::from("/metrics"), None).unwrap()))
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        Server::start_admin_listener(Some(listener), &shared(config, None));
+
+        let _idle = TcpStream::connect(addr).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert_that!(response.starts_with("HTTP/1.1 200 OK\r\n"), is(true));
+    }
+
+    #[test]
+    fn reload_response_swaps_config() {
+        let config = Config::new(String::from("127.0.0.1"), 9090, 1, String::from("test/web_dir"),
+            String::from("debug"), String::from("logs")).unwrap()
+            .with_metrics(Some(::metrics::MetricsConfig::new(String::from("/metrics"), None).unwrap()))
+            .unwrap();
+        let shared = shared(config, Some(PathBuf::from("test/fixtures/config.toml")));
+        let localhost = Some("127.0.0.1:4711".parse().unwrap());
+        let request = http::parse_request("GET /-/reload HTTP/1.1\r\nHost: localhost\r\n")
+            .with_remote_addr(localhost);
+
+        assert_that!(reload_response(&shared, &request).status().code(), is(equal_to(405)));
+        assert_that!(shared.reloaded().config.web_dir(), is(equal_to("test/web_dir")));
+
+        let request = http::parse_request("POST /-/reload HTTP/1.1\r\nHost: localhost\r\n");
+        assert_that!(reload_response(&shared, &request).status().code(), is(equal_to(403)));
+
+        let remote = request.clone().with_remote_addr(Some("10.0.0.1:4711".parse().unwrap()));
+        assert_that!(reload_response(&shared, &remote).status().code(), is(equal_to(403)));
+        assert_that!(shared.reloaded().config.web_dir(), is(equal_to("test/web_dir")));
+
+        let request = request.with_remote_addr(localhost);
+        let response = String::from_utf8(reload_response(&shared, &request).render()).unwrap();
+        assert_that!(response.starts_with("HTTP/1.1 200 OK\r\n"), is(true));
+        assert_that!(response.ends_with("take effect after a restart: port, threads, metrics.listen\n"), is(true));
+        assert_that!(shared.config.web_dir(), is(equal_to("test/web_dir")));
+        assert_that!(shared.reloaded().config.web_dir(), is(equal_to("web_dir")));
+    }
+
+    #[test]
+    fn reload_keeps_metrics_on_admin_listener() {
+        let config = Config::new(String::from("127.0.0.1"), 9090, 1, String::from("test/web_dir"),
+            String::from("debug"), String::from("logs")).unwrap()
+            .with_metrics(Some(::metrics::MetricsConfig::new(
+                String::from("/metrics"), Some(String::from("127.0.0.1:9091"))).unwrap()))
+            .unwrap();
+        let shared = shared(config, Some(PathBuf::from("test/fixtures/config.toml")));
+        let request = http::parse_request("GET /metrics HTTP/1.1\r\nHost: localhost\r\n");
+        assert_that!(is_metrics_request(&shared, &request), is(false));
+
+        assert_that!(reload_config(&shared.configs).unwrap().contains(&"metrics.listen"), is(true));
+
+        let reloaded = shared.reloaded();
+        assert_that!(reloaded.config.web_dir(), is(equal_to("web_dir")));
+        assert_that!(is_metrics_request(&reloaded, &request), is(false));
+    }
+
+    #[test]
+    fn next_request_id_is_unique() {
+        let prefix = request_id_prefix();
+        let first = next_request_id(&prefix);
+        let second = next_request_id(&prefix);
+
+        assert_that!(first.starts_with(&format!("{}-", prefix)), is(true));
+        assert_that!(first, is(not(equal_to(second))));
+    }
+
+    #[test]
+    fn head_request_reads_metadata_only() {
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir")).unwrap();
+        let cache = Arc::new(FileCache::new(1024 * 1024));
+        let head = http::parse_request("HEAD /hello.html HTTP/1.1\r\nHost: localhost\r\n");
+        let get = http::parse_request("GET /hello.html HTTP/1.1\r\nHost: localhost\r\n");
+
+        let response = String::from_utf8(handle_head_request(&vhost, &head, Some(cache.clone())).render()).unwrap();
+        assert_that!(response.starts_with("HTTP/1.1 200 OK\r\n"), is(true));
+        assert_that!(cache.is_empty(), is(true));
+
+        let etag = load_resource(&vhost, &get, Some(cache.clone())).unwrap().unwrap().etag().clone();
+        assert_that!(response.contains(&format!("ETag: {}\r\n", etag)), is(true));
+    }
+
+    #[test]
+    fn get_request_shares_cached_content() {
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir")).unwrap();
+        let cache = Arc::new(FileCache::new(1024 * 1024));
+        let request = http::parse_request("GET /hello.html HTTP/1.1\r\nHost: localhost\r\n");
+
+        let cached = load_resource(&vhost, &request, Some(cache.clone())).unwrap().unwrap();
+        let response = handle_get_request(&vhost, &request, Some(cache));
+
+        assert_that!(response.body().as_ptr(), is(equal_to(cached.content().as_ptr())));
+    }
+
+    #[test]
+    fn resource_error_answers_not_found_or_internal_server_error() {
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir")).unwrap();
+        let request = http::parse_request("GET /hello.html HTTP/1.1\r\nHost: localhost\r\n");
+
+        let vanished = io::Error::from(io::ErrorKind::NotFound);
+        assert_that!(resource_error(&vhost, &request, vanished).status(), is(equal_to(&Status::NotFound)));
+
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_that!(resource_error(&vhost, &request, denied).status(), is(equal_to(&Status::InternalServerError)));
+    }
+
+    #[test]
+    fn build_response_refuses_paths_leaving_web_root() {
+        let vhost = VirtualHost::new(Vec::new(), String::from("test/web_dir")).unwrap();
+        let request = http::parse_request("GET /%2e%2e/fixtures/hello.txt HTTP/1.1\r\nHost: localhost\r\n");
+
+        assert_that!(build_response(&vhost, &request, None).status(), is(equal_to(&Status::NotFound)));
+    }
+
     #[test]
     fn test_relativize_uri() {
         assert_that!(relativize_uri( & String::from("foo/bar/bax.html")),
             is(equal_to(String::from("foo/bar/bax.html"))));
         assert_that!(relativize_uri( & String::from("/foo/bar/bax.html")),
             is(equal_to(String::from("foo/bar/bax.html"))));
+        assert_that!(relativize_uri( & String::from("//etc/passwd")),
+            is(equal_to(String::from("etc/passwd"))));
     }
 
     #[test]
@@ -0,0 +1,395 @@
+///! This module provides the server status page, like Apache's `mod_status`.
+///!
+///! The page shows uptime, version, the workers of the thread pool with the request each one is
+///! currently answering, request totals and the errors of the last minutes. It is rendered as HTML or,
+///! if the client accepts `application/json`, as JSON. By default only clients on localhost may see it.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use acl;
+use acl::AccessRule;
+use escape;
+use metrics::Metrics;
+use threads::PoolStats;
+use http::Request;
+use time;
+
+/// Time span of the recent error counts.
+pub static RECENT_ERRORS_WINDOW: Duration = Duration::from_secs(15 * 60);
+/// Maximum number of errors remembered for the recent error counts.
+static MAX_RECENT_ERRORS: usize = 10_000;
+
+fn default_path() -> String {
+    String::from("/server-status")
+}
+
+fn default_access() -> Vec<String> {
+    vec!(String::from("allow 127.0.0.1"), String::from("allow ::1"), String::from("deny all"))
+}
+
+/// Configuration of the status page.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct StatusConfig {
+    /// Path the status page is served at.
+    #[serde(default = "default_path")]
+    path: String,
+    /// Allow and deny rules for client addresses. Only localhost by default.
+    #[serde(default = "default_access")]
+    access: Vec<String>,
+    #[serde(skip)]
+    access_rules: Vec<AccessRule>,
+}
+
+impl StatusConfig {
+    /// Creates a new status page configuration accessible from localhost only.
+    pub fn new(path: String) -> Result<StatusConfig, &'static str> {
+        if !path.starts_with('/') {
+            return Err("Config value 'status.path' must start with '/'!");
+        }
+
+        StatusConfig { path, access: Vec::new(), access_rules: Vec::new() }
+            .with_access(default_access())
+    }
+
+    /// Sets the allow and deny rules for client addresses.
+    pub fn with_access(mut self, access: Vec<String>) -> Result<StatusConfig, &'static str> {
+        let mut access_rules = Vec::with_capacity(access.len());
+
+        for rule in access.iter() {
+            access_rules.push(AccessRule::parse(rule)
+                .map_err(|_| "Config value 'status.access' must contain rules like 'allow 127.0.0.1' or 'deny all'!")?);
+        }
+
+        self.access = access;
+        self.access_rules = access_rules;
+        Ok(self)
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<StatusConfig, &'static str> {
+        StatusConfig::new(self.path)?.with_access(self.access)
+    }
+
+    /// Get the path the status page is served at.
+    pub fn path(&self) -> &String {
+        &self.path
+    }
+
+    /// Whether the client may see the status page.
+    pub fn is_allowed(&self, client: Option<SocketAddr>) -> bool {
+        acl::is_allowed(&self.access_rules, client.map(|addr| addr.ip()))
+    }
+}
+
+/// A request currently answered by a worker.
+#[derive(Debug, Clone, PartialEq)]
+struct Activity {
+    method: String,
+    path: String,
+    client: Option<SocketAddr>,
+    started: Instant,
+}
+
+/// Tracks what the workers are doing and the recent errors.
+#[derive(Debug)]
+pub struct Scoreboard {
+    started: SystemTime,
+    started_instant: Instant,
+    /// Current request by worker thread name.
+    workers: Mutex<BTreeMap<String, Activity>>,
+    /// Time and status of recent error responses, oldest first.
+    errors: Mutex<VecDeque<(Instant, u16)>>,
+}
+
+impl Scoreboard {
+    /// Creates a scoreboard for a server started now.
+    pub fn new() -> Scoreboard {
+        Scoreboard {
+            started: SystemTime::now(),
+            started_instant: Instant::now(),
+            workers: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Records that the current thread answers the request until the returned guard is dropped.
+    pub fn begin(&self, request: &Request) -> Busy<'_> {
+        let worker = thread::current().name().unwrap_or("unnamed").to_string();
+        let activity = Activity {
+            method: request.method().clone(),
+            path: request.path(),
+            client: request.remote_addr(),
+            started: Instant::now(),
+        };
+
+        self.workers.lock().unwrap().insert(worker.clone(), activity);
+        Busy { scoreboard: self, worker }
+    }
+
+    /// Records the status of an answered request.
+    pub fn record_status(&self, status: u16) {
+        if status < 400 {
+            return;
+        }
+
+        let mut errors = self.errors.lock().unwrap();
+
+        if errors.len() >= MAX_RECENT_ERRORS {
+            errors.pop_front();
+        }
+
+        errors.push_back((Instant::now(), status));
+    }
+
+    /// Get the number of client (4xx) and server (5xx) errors within `RECENT_ERRORS_WINDOW`.
+    fn recent_errors(&self, now: Instant) -> (usize, usize) {
+        let mut errors = self.errors.lock().unwrap();
+
+        while errors.front().is_some_and(|&(at, _)| now.duration_since(at) > RECENT_ERRORS_WINDOW) {
+            errors.pop_front();
+        }
+
+        let server_errors = errors.iter().filter(|&&(_, status)| status >= 500).count();
+        (errors.len() - server_errors, server_errors)
+    }
+
+    fn snapshot(&self, pool: Option<&PoolStats>, metrics: &Metrics) -> Snapshot {
+        let now = Instant::now();
+        let workers = self.workers.lock().unwrap().clone();
+        let (client_errors, server_errors) = self.recent_errors(now);
+
+        Snapshot {
+            started: self.started,
+            uptime: now.duration_since(self.started_instant),
+            workers: pool.map(|pool| pool.size()).unwrap_or(0),
+            busy_workers: pool.map(|pool| pool.busy_workers()).unwrap_or(0),
+            queue_depth: pool.map(|pool| pool.queue_depth()).unwrap_or(0),
+            queue_capacity: pool.and_then(|pool| pool.capacity()),
+            rejected: pool.map(|pool| pool.rejected()).unwrap_or(0),
+            activities: workers.into_iter()
+                .map(|(worker, activity)| {
+                    let elapsed = now.duration_since(activity.started);
+                    (worker, activity, elapsed)
+                })
+                .collect(),
+            requests_total: metrics.requests_total(),
+            bytes_sent: metrics.bytes_sent(),
+            requests_by_status: metrics.requests_by_status(),
+            client_errors,
+            server_errors,
+        }
+    }
+
+    /// Renders the status page as HTML.
+    pub fn render_html(&self, pool: Option<&PoolStats>, metrics: &Metrics) -> String {
+        self.snapshot(pool, metrics).to_html()
+    }
+
+    /// Renders the status page as JSON.
+    pub fn render_json(&self, pool: Option<&PoolStats>, metrics: &Metrics) -> String {
+        self.snapshot(pool, metrics).to_json()
+    }
+}
+
+impl Default for Scoreboard {
+    fn default() -> Scoreboard {
+        Scoreboard::new()
+    }
+}
+
+/// Removes the activity of a worker from the scoreboard when dropped.
+#[derive(Debug)]
+pub struct Busy<'a> {
+    scoreboard: &'a Scoreboard,
+    worker: String,
+}
+
+impl<'a> Drop for Busy<'a> {
+    fn drop(&mut self) {
+        self.scoreboard.workers.lock().unwrap().remove(&self.worker);
+    }
+}
+
+struct Snapshot {
+    started: SystemTime,
+    uptime: Duration,
+    workers: usize,
+    busy_workers: usize,
+    queue_depth: usize,
+    queue_capacity: Option<usize>,
+    rejected: usize,
+    activities: Vec<(String, Activity, Duration)>,
+    requests_total: u64,
+    bytes_sent: u64,
+    requests_by_status: BTreeMap<u16, u64>,
+    client_errors: usize,
+    server_errors: usize,
+}
+
+impl Snapshot {
+    fn started(&self) -> String {
+        let since_epoch = self.started.duration_since(::std::time::UNIX_EPOCH).unwrap_or_default();
+        let tm = time::at_utc(time::Timespec::new(since_epoch.as_secs() as i64, 0));
+
+        time::strftime("%Y-%m-%dT%H:%M:%SZ", &tm).unwrap_or_default()
+    }
+
+    fn to_html(&self) -> String {
+        let mut html = String::new();
+
+        html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+        html.push_str("<title>Server Status</title>\n</head>\n<body>\n<h1>Server Status</h1>\n<dl>\n");
+        html.push_str(&format!("<dt>Version</dt><dd>{} {}</dd>\n",
+            escape::html(super::APPLICATION_DESCRIPTION), escape::html(super::APPLICATION_VERSION)));
+        html.push_str(&format!("<dt>Started</dt><dd>{}</dd>\n", self.started()));
+        html.push_str(&format!("<dt>Uptime</dt><dd>{} seconds</dd>\n", self.uptime.as_secs()));
+        html.push_str(&format!("<dt>Workers</dt><dd>{} busy of {}, {} queued</dd>\n",
+            self.busy_workers, self.workers, self.queue_depth));
+
+        if let Some(capacity) = self.queue_capacity {
+            html.push_str(&format!("<dt>Queue</dt><dd>{} of {}, {} rejected</dd>\n",
+                self.queue_depth, capacity, self.rejected));
+        }
+
+        html.push_str(&format!("<dt>Requests</dt><dd>{}</dd>\n", self.requests_total));
+        html.push_str(&format!("<dt>Bytes sent</dt><dd>{}</dd>\n", self.bytes_sent));
+        html.push_str(&format!("<dt>Errors in the last {} minutes</dt><dd>{} client, {} server</dd>\n",
+            RECENT_ERRORS_WINDOW.as_secs() / 60, self.client_errors, self.server_errors));
+        html.push_str("</dl>\n<h2>Requests by status</h2>\n<table>\n<tr><th>Status</th><th>Requests</th></tr>\n");
+
+        for (status, count) in self.requests_by_status.iter() {
+            html.push_str(&format!("<tr><td>{}</td><td>{}</td></tr>\n", status, count));
+        }
+
+        html.push_str("</table>\n<h2>Current requests</h2>\n<table>\n");
+        html.push_str("<tr><th>Worker</th><th>Client</th><th>Method</th><th>Path</th><th>Elapsed ms</th></tr>\n");
+
+        for &(ref worker, ref activity, elapsed) in self.activities.iter() {
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                escape::html(worker),
+                activity.client.map(|addr| addr.ip().to_string()).unwrap_or_else(|| String::from("-")),
+                escape::html(&activity.method),
+                escape::html(&activity.path),
+                elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())));
+        }
+
+        html.push_str("</table>\n</body>\n</html>\n");
+        html
+    }
+
+    fn to_json(&self) -> String {
+        let by_status: Vec<String> = self.requests_by_status.iter()
+            .map(|(status, count)| format!("\"{}\":{}", status, count))
+            .collect();
+        let activities: Vec<String> = self.activities.iter()
+            .map(|&(ref worker, ref activity, elapsed)| format!(
+                "{{\"worker\":{},\"client\":{},\"method\":{},\"path\":{},\"elapsed_ms\":{}}}",
+                escape::json(worker),
+                activity.client
+                    .map(|addr| escape::json(&addr.ip().to_string()))
+                    .unwrap_or_else(|| String::from("null")),
+                escape::json(&activity.method),
+                escape::json(&activity.path),
+                elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())))
+            .collect();
+
+        format!(
+            "{{\"version\":{},\"started\":{},\"uptime_seconds\":{},\"workers\":{},\"busy_workers\":{},\
+             \"queue_depth\":{},\"queue_capacity\":{},\"rejected\":{},\"requests_total\":{},\"bytes_sent\":{},\
+             \"requests_by_status\":{{{}}},\
+             \"recent_errors\":{{\"window_seconds\":{},\"client\":{},\"server\":{}}},\"requests\":[{}]}}",
+            escape::json(super::APPLICATION_VERSION),
+            escape::json(&self.started()),
+            self.uptime.as_secs(),
+            self.workers,
+            self.busy_workers,
+            self.queue_depth,
+            self.queue_capacity.map(|capacity| capacity.to_string()).unwrap_or_else(|| String::from("null")),
+            self.rejected,
+            self.requests_total,
+            self.bytes_sent,
+            by_status.join(","),
+            RECENT_ERRORS_WINDOW.as_secs(),
+            self.client_errors,
+            self.server_errors,
+            activities.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+    use http;
+
+    #[test]
+    fn new_allows_localhost_only() {
+        let sut = StatusConfig::new(String::from("/server-status")).unwrap();
+
+        assert_that!(sut.is_allowed(Some("127.0.0.1:4711".parse().unwrap())), is(true));
+        assert_that!(sut.is_allowed(Some("[::1]:4711".parse().unwrap())), is(true));
+        assert_that!(sut.is_allowed(Some("10.0.0.1:4711".parse().unwrap())), is(false));
+        assert_that!(sut.is_allowed(None), is(false));
+    }
+
+    #[test]
+    fn with_access_validates_rules() {
+        assert_that!(
+            StatusConfig::new(String::from("/status")).unwrap().with_access(vec!(String::from("allow everybody"))),
+            is(equal_to(Err("Config value 'status.access' must contain rules like 'allow 127.0.0.1' or 'deny all'!"))));
+    }
+
+    #[test]
+    fn scoreboard_tracks_current_requests() {
+        let sut = Scoreboard::new();
+        let request = http::parse_request("GET /slow<b> HTTP/1.1\r\nHost: localhost\r\n")
+            .with_remote_addr(Some("10.0.0.1:4711".parse().unwrap()));
+        let metrics = Metrics::new();
+
+        {
+            let _busy = sut.begin(&request);
+            let html = sut.render_html(None, &metrics);
+            let json = sut.render_json(None, &metrics);
+
+            assert_that!(html.contains("<td>10.0.0.1</td><td>GET</td><td>/slow&lt;b&gt;</td>"), is(true));
+            assert_that!(json.contains("\"client\":\"10.0.0.1\",\"method\":\"GET\",\"path\":\"/slow<b>\""), is(true));
+        }
+
+        assert_that!(sut.render_json(None, &metrics).contains("\"requests\":[]"), is(true));
+    }
+
+    #[test]
+    fn scoreboard_counts_recent_errors() {
+        let sut = Scoreboard::new();
+        let metrics = Metrics::new();
+
+        sut.record_status(200);
+        sut.record_status(404);
+        sut.record_status(403);
+        sut.record_status(500);
+        metrics.observe("GET", 404, "default", 10, Duration::from_millis(1));
+
+        let json = sut.render_json(None, &metrics);
+
+        assert_that!(json.contains("\"client\":2,\"server\":1"), is(true));
+        assert_that!(json.contains("\"requests_by_status\":{\"404\":1}"), is(true));
+        assert_that!(json.contains("\"version\":\"1.0.0\""), is(true));
+        assert_that!(json.contains("\"queue_capacity\":null,\"rejected\":0"), is(true));
+        assert_that!(sut.recent_errors(Instant::now() + RECENT_ERRORS_WINDOW * 2), is(equal_to((0, 0))));
+    }
+
+    #[test]
+    fn status_shows_bounded_queue() {
+        let sut = Scoreboard::new();
+        let metrics = Metrics::new();
+        let pool = PoolStats::new(4).with_capacity(100);
+
+        assert_that!(sut.render_html(Some(&pool), &metrics).contains("<dt>Queue</dt><dd>0 of 100, 0 rejected</dd>"),
+            is(true));
+        assert_that!(sut.render_json(Some(&pool), &metrics).contains("\"queue_capacity\":100,\"rejected\":0"), is(true));
+    }
+}
@@ -0,0 +1,284 @@
+///! This module provides systemd socket activation and readiness notification.
+///!
+///! Started by a socket unit, systemd passes the listening sockets as file descriptors from 3 on and sets
+///! `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`. The server takes them over instead of binding, which
+///! allows privileged ports without root and keeps connections queued while the server restarts. A socket
+///! is used for the `[[listen]]` entry whose `name` equals its `FileDescriptorName=`, otherwise for the
+///! listener with the same address and port. Sockets matching no listener serve plain HTTP, listeners
+///! without a passed socket are bound as usual.
+///!
+///! Once all listeners accept connections `READY=1` is sent to `NOTIFY_SOCKET`, so services of
+///! `Type=notify` are only reported as started when they answer requests.
+///!
+///! The same variables hand the sockets over to a new binary on upgrade, see the `upgrade` module.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::net::TcpListener;
+use std::path::Path;
+use libc;
+use socket2::{SockAddr, SockRef, Type};
+use listen::{ListenConfig, Listener};
+use upgrade;
+
+/// First file descriptor passed by systemd.
+pub static LISTEN_FDS_START: RawFd = 3;
+
+/// Name of sockets without `FileDescriptorName=`.
+static UNKNOWN_NAME: &'static str = "unknown";
+
+/// Listening sockets passed by systemd or an upgrading server, not yet taken by a listener.
+#[derive(Debug, Default)]
+pub struct ListenFds {
+    sockets: Vec<(OwnedFd, String)>,
+    /// Socket of the upgrading server waiting for this one to accept connections.
+    upgrade_socket: Option<OsString>,
+}
+
+impl ListenFds {
+    /// Creates the sockets to take over from open file descriptors and their names.
+    pub fn new(sockets: Vec<(OwnedFd, String)>) -> ListenFds {
+        ListenFds { sockets, upgrade_socket: None }
+    }
+
+    /// Takes the sockets passed by systemd to this process and removes the variables from the environment,
+    /// so child processes don't take them too.
+    ///
+    /// Started by an upgrading server, the sockets are passed by the parent process instead.
+    pub fn from_env() -> Result<ListenFds, &'static str> {
+        let listen_pid = env::var("LISTEN_PID").ok();
+        let listen_fds = env::var("LISTEN_FDS").ok();
+        let listen_fdnames = env::var("LISTEN_FDNAMES").ok();
+        let upgrade_socket = env::var_os(upgrade::SOCKET_VAR);
+
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        env::remove_var(upgrade::SOCKET_VAR);
+
+        let pid = match upgrade_socket {
+            Some(_) => ::std::os::unix::process::parent_id(),
+            None => ::std::process::id(),
+        };
+        let passed = parse(listen_pid.as_deref(), listen_fds.as_deref(), listen_fdnames.as_deref(), pid)?;
+        let mut sockets = Vec::with_capacity(passed.len());
+
+        for (fd, name) in passed {
+            // Only descriptors systemd passed to this process are taken, each one once.
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+                return Err("LISTEN_FDS names a file descriptor which is not open!");
+            }
+
+            sockets.push((unsafe { OwnedFd::from_raw_fd(fd) }, name));
+        }
+
+        Ok(ListenFds { sockets, upgrade_socket })
+    }
+
+    /// Get the socket of the upgrading server to report readiness to, if started by one.
+    pub fn upgrade_socket(&self) -> &Option<OsString> {
+        &self.upgrade_socket
+    }
+
+    /// Whether no sockets are left.
+    pub fn is_empty(&self) -> bool {
+        self.sockets.is_empty()
+    }
+
+    /// Get the number of sockets left.
+    pub fn len(&self) -> usize {
+        self.sockets.len()
+    }
+
+    /// Takes the socket for the listener, matched by name or else by address.
+    pub fn take(&mut self, listen: &ListenConfig) -> io::Result<Option<Listener>> {
+        let position = self.sockets.iter()
+            .position(|(fd, name)| matches(listen, name, &SockRef::from(fd)));
+
+        match position {
+            Some(position) => into_listener(self.sockets.remove(position).0).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Takes all sockets left, with their names.
+    pub fn take_all(&mut self) -> io::Result<Vec<(String, Listener)>> {
+        self.sockets.drain(..)
+            .map(|(fd, name)| into_listener(fd).map(|listener| (name, listener)))
+            .collect()
+    }
+}
+
+/// Parses the variables systemd sets for socket activation into file descriptors and their names.
+///
+/// Returns no descriptors if the variables are missing or meant for another process.
+pub fn parse(listen_pid: Option<&str>, listen_fds: Option<&str>, listen_fdnames: Option<&str>, pid: u32)
+    -> Result<Vec<(RawFd, String)>, &'static str> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(Vec::new()),
+    };
+
+    if listen_pid.parse::<u32>().map_err(|_| "LISTEN_PID must be a process id!")? != pid {
+        return Ok(Vec::new());
+    }
+
+    let count: RawFd = listen_fds.parse().map_err(|_| "LISTEN_FDS must be a number!")?;
+    let names: Vec<&str> = listen_fdnames.map(|names| names.split(':').collect()).unwrap_or_default();
+
+    Ok((0..count)
+        .map(|index| {
+            let name = if names.len() == count as usize { names[index as usize] } else { UNKNOWN_NAME };
+            (LISTEN_FDS_START + index, name.to_string())
+        })
+        .collect())
+}
+
+/// Whether the socket is the one for the listener: by name if the listener has one, else by address.
+fn matches(listen: &ListenConfig, name: &str, socket: &SockRef) -> bool {
+    if let Some(ref wanted) = *listen.name() {
+        return wanted == name;
+    }
+
+    let local = match socket.local_addr() {
+        Ok(local) => local,
+        Err(_) => return false,
+    };
+
+    match listen.unix_path() {
+        Some(path) => local.as_pathname() == Some(Path::new(path)),
+        None => matches_address(listen, &local),
+    }
+}
+
+/// Whether the TCP socket is bound to the port of the listener and the same or the unspecified address.
+fn matches_address(listen: &ListenConfig, local: &SockAddr) -> bool {
+    let local = match local.as_socket() {
+        Some(local) if local.port() == *listen.port() => local,
+        _ => return false,
+    };
+
+    if local.ip().is_unspecified() || listen.address().parse().is_ok_and(|ip: IpAddr| ip.is_unspecified()) {
+        return true;
+    }
+
+    listen.socket_address().to_socket_addrs()
+        .map(|mut addrs| addrs.any(|addr| addr.ip() == local.ip()))
+        .unwrap_or(false)
+}
+
+/// Turns a passed stream socket into a TCP or Unix listener, closed on exec like the ones bound by the server.
+fn into_listener(fd: OwnedFd) -> io::Result<Listener> {
+    let (is_stream, is_unix) = {
+        let socket = SockRef::from(&fd);
+        (socket.r#type()? == Type::STREAM, socket.local_addr()?.is_unix())
+    };
+
+    if !is_stream {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "passed socket is no stream socket"));
+    }
+
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(if is_unix { Listener::Unix(UnixListener::from(fd)) } else { Listener::Tcp(TcpListener::from(fd)) })
+}
+
+/// Sends a state like `READY=1` to the service manager, if started with `NOTIFY_SOCKET`.
+pub fn notify(state: &str) -> io::Result<()> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => notify_socket(&socket, state),
+        None => Ok(()),
+    }
+}
+
+/// Sends a state to the given notification socket. Names starting with `@` are abstract socket addresses.
+pub fn notify_socket(socket: &OsStr, state: &str) -> io::Result<()> {
+    let sender = UnixDatagram::unbound()?;
+
+    if socket.as_bytes().starts_with(b"@") {
+        send_to_abstract(&sender, &socket.as_bytes()[1..], state)
+    } else {
+        sender.send_to(state.as_bytes(), socket).map(|_| ())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_to_abstract(sender: &UnixDatagram, name: &[u8], state: &str) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+
+    let addr = ::std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    sender.send_to_addr(state.as_bytes(), &addr).map(|_| ())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_to_abstract(_sender: &UnixDatagram, _name: &[u8], _state: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "abstract socket addresses are only supported on Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use listen;
+
+    #[test]
+    fn parse_listen_fds() {
+        assert_that!(parse(None, None, None, 42), is(equal_to(Ok(Vec::new()))));
+        assert_that!(parse(Some("41"), Some("2"), None, 42), is(equal_to(Ok(Vec::new()))));
+        assert_that!(parse(Some("42"), Some("two"), None, 42), is(equal_to(Err("LISTEN_FDS must be a number!"))));
+        assert_that!(
+            parse(Some("42"), Some("2"), Some("web:tls"), 42),
+            is(equal_to(Ok(vec!((3, String::from("web")), (4, String::from("tls")))))));
+        assert_that!(
+            parse(Some("42"), Some("2"), Some("web"), 42),
+            is(equal_to(Ok(vec!((3, String::from("unknown")), (4, String::from("unknown")))))));
+    }
+
+    #[test]
+    fn take_matches_sockets_by_name_and_address() {
+        let web = listen::bind("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let other = listen::bind("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let port = web.local_addr().unwrap().port();
+        let mut sut = ListenFds::new(vec!(
+            (OwnedFd::from(other), String::from("admin")),
+            (OwnedFd::from(web), String::from("unknown"))));
+        let by_address = ListenConfig::new(String::from("localhost"), port).unwrap();
+        let by_name = ListenConfig::new(String::from("127.0.0.1"), 1).unwrap()
+            .with_name(Some(String::from("admin"))).unwrap();
+
+        assert_that!(sut.take(&ListenConfig::new(String::from("127.0.0.1"), 1).unwrap()).unwrap().is_none(), is(true));
+
+        let listener = sut.take(&by_address).unwrap().unwrap();
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(b"GET").unwrap();
+        let mut buf = [0; 3];
+        listener.accept().unwrap().read_exact(&mut buf).unwrap();
+        assert_that!(&buf, is(equal_to(b"GET")));
+
+        assert_that!(sut.take(&by_name).unwrap().is_some(), is(true));
+        assert_that!(sut.is_empty(), is(true));
+    }
+
+    #[test]
+    fn notify_sends_state_to_socket() {
+        let path = env::temp_dir().join(format!("webserver-notify-{}.sock", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        notify_socket(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = receiver.recv(&mut buf).unwrap();
+
+        assert_that!(&buf[..len], is(equal_to(&b"READY=1"[..])));
+        ::std::fs::remove_file(&path).unwrap();
+    }
+}
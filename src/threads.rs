@@ -1,9 +1,16 @@
 ///! This module provides a thread pool.
+///!
+///! By default the queue of a pool is unbounded. With a `[queue]` section it holds at most `capacity` jobs
+///! waiting for a free worker, further ones are rejected, so an overloaded server answers new connections
+///! right away by `503 Service Unavailable` or closes them, instead of piling them up in memory.
 
+use std::panic;
+use std::panic::AssertUnwindSafe;
 use std::thread;
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 trait FnBox {
     fn call_box(self: Box<Self>);
@@ -22,12 +29,183 @@ enum Message {
     Terminate,
 }
 
+fn default_retry_after() -> u64 {
+    5
+}
+
+/// What happens to a connection when the queue is full.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FullAction {
+    /// Answer with `503 SERVICE UNAVAILABLE` and `Retry-After`.
+    #[default]
+    Reject,
+    /// Close the connection without answering.
+    Drop,
+}
+
+/// Configuration of the queue of jobs waiting for a free worker.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct QueueConfig {
+    /// Maximum number of waiting jobs, shared out among the pools of the acceptors.
+    capacity: usize,
+    /// Seconds a rejected client is told to wait before retrying.
+    #[serde(default = "default_retry_after")]
+    retry_after: u64,
+    /// Whether a connection is answered or closed when the queue is full.
+    #[serde(default)]
+    full_action: FullAction,
+}
+
+impl QueueConfig {
+    /// Creates a new configuration with the given capacity, rejecting connections when it's reached.
+    pub fn new(capacity: usize) -> Result<QueueConfig, &'static str> {
+        if capacity < 1 {
+            return Err("Config value 'queue.capacity' must be greater than 0!");
+        }
+
+        Ok(QueueConfig { capacity, retry_after: default_retry_after(), full_action: FullAction::default() })
+    }
+
+    /// Sets the seconds a rejected client is told to wait before retrying.
+    pub fn with_retry_after(mut self, retry_after: u64) -> QueueConfig {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// Sets what happens to a connection when the queue is full.
+    pub fn with_full_action(mut self, full_action: FullAction) -> QueueConfig {
+        self.full_action = full_action;
+        self
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<QueueConfig, &'static str> {
+        Ok(QueueConfig::new(self.capacity)?.with_retry_after(self.retry_after).with_full_action(self.full_action))
+    }
+
+    /// Get the maximum number of waiting jobs.
+    pub fn capacity(&self) -> &usize {
+        &self.capacity
+    }
+
+    /// Get the seconds a rejected client is told to wait before retrying.
+    pub fn retry_after(&self) -> &u64 {
+        &self.retry_after
+    }
+
+    /// Get what happens to a connection when the queue is full.
+    pub fn full_action(&self) -> &FullAction {
+        &self.full_action
+    }
+}
+
+/// Why a job wasn't executed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExecuteError {
+    /// The queue holds as many jobs as it may.
+    Full,
+    /// The workers are gone.
+    Stopped,
+}
+
+/// Where the jobs wait for a free worker.
+enum Queue {
+    Unbounded(mpsc::Sender<Message>),
+    Bounded(mpsc::SyncSender<Message>),
+}
+
+impl Queue {
+    /// Adds the message unless the queue is full.
+    fn try_send(&self, message: Message) -> Result<(), ExecuteError> {
+        match *self {
+            Queue::Unbounded(ref sender) => sender.send(message).map_err(|_| ExecuteError::Stopped),
+            Queue::Bounded(ref sender) => sender.try_send(message).map_err(|err| match err {
+                mpsc::TrySendError::Full(_) => ExecuteError::Full,
+                mpsc::TrySendError::Disconnected(_) => ExecuteError::Stopped,
+            }),
+        }
+    }
+
+    /// Adds the message, waiting for room if the queue is full.
+    fn send(&self, message: Message) -> Result<(), ExecuteError> {
+        match *self {
+            Queue::Unbounded(ref sender) => sender.send(message),
+            Queue::Bounded(ref sender) => sender.send(message),
+        }.map_err(|_| ExecuteError::Stopped)
+    }
+}
+
 /// Represents a thread pool.
 pub struct ThreadPool {
     /// A vector of workers. They are simply a wrapper of the threads working in the background.
     workers: Vec<Worker>,
-    /// The sender to receive work to be done from.
-    sender: mpsc::Sender<Message>,
+    /// The queue the workers receive work to be done from.
+    queue: Queue,
+    /// Counters shared with the workers.
+    stats: Arc<PoolStats>,
+}
+
+/// Live counters of a thread pool, e.g. for metrics.
+#[derive(Debug)]
+pub struct PoolStats {
+    /// Number of workers.
+    size: usize,
+    /// Jobs waiting for a free worker.
+    queued: AtomicUsize,
+    /// Workers executing a job.
+    busy: AtomicUsize,
+    /// Workers started so far, numbering the workers of all pools sharing the counters.
+    started: AtomicUsize,
+    /// Maximum number of waiting jobs of all pools sharing the counters, if bounded.
+    capacity: Option<usize>,
+    /// Jobs rejected because the queue was full.
+    rejected: AtomicUsize,
+}
+
+impl PoolStats {
+    /// Creates counters for the given number of workers, e.g. shared by several pools.
+    pub fn new(size: usize) -> PoolStats {
+        PoolStats {
+            size,
+            queued: AtomicUsize::new(0),
+            busy: AtomicUsize::new(0),
+            started: AtomicUsize::new(0),
+            capacity: None,
+            rejected: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the maximum number of waiting jobs of the pools sharing the counters.
+    pub fn with_capacity(mut self, capacity: usize) -> PoolStats {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Get the number of workers.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Get the number of jobs waiting for a free worker.
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Get the number of workers executing a job.
+    pub fn busy_workers(&self) -> usize {
+        self.busy.load(Ordering::SeqCst)
+    }
+
+    /// Get the maximum number of waiting jobs, if bounded.
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Get the number of jobs rejected because the queue was full.
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::SeqCst)
+    }
 }
 
 impl ThreadPool {
@@ -39,30 +217,71 @@ impl ThreadPool {
     ///
     /// The `new` function will panic if the size is zero.
     pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
+        ThreadPool::with_stats(size, Arc::new(PoolStats::new(size)))
+    }
 
+    /// Create a new ThreadPool counting its jobs in the given counters, which other pools may share.
+    ///
+    /// # Panics
+    ///
+    /// The `with_stats` function will panic if the size is zero.
+    pub fn with_stats(size: usize, stats: Arc<PoolStats>) -> ThreadPool {
         let (sender, receiver) = mpsc::channel();
+        ThreadPool::start(size, Queue::Unbounded(sender), receiver, stats)
+    }
+
+    /// Create a new ThreadPool holding at most `capacity` jobs waiting for a free worker.
+    ///
+    /// # Panics
+    ///
+    /// The `bounded` function will panic if the size or capacity is zero.
+    pub fn bounded(size: usize, capacity: usize, stats: Arc<PoolStats>) -> ThreadPool {
+        assert!(capacity > 0);
+
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        ThreadPool::start(size, Queue::Bounded(sender), receiver, stats)
+    }
+
+    fn start(size: usize, queue: Queue, receiver: mpsc::Receiver<Message>, stats: Arc<PoolStats>) -> ThreadPool {
+        assert!(size > 0);
+
         let receiver = Arc::new(Mutex::new(receiver));
         let mut workers = Vec::with_capacity(size);
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        for _ in 0..size {
+            let id = stats.started.fetch_add(1, Ordering::SeqCst);
+            workers.push(Worker::new(id, Arc::clone(&receiver), Arc::clone(&stats)));
         }
 
         ThreadPool {
             workers,
-            sender,
+            queue,
+            stats,
         }
     }
 
+    /// Get the live counters of this pool.
+    pub fn stats(&self) -> Arc<PoolStats> {
+        Arc::clone(&self.stats)
+    }
+
     /// Executes the given closure in the background by one of the thread pools workers.
-    pub fn execute<F>(&self, f: F)
+    ///
+    /// Fails if the queue is full or the workers are gone, the closure is dropped then.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
         where
             F: FnOnce() + Send + 'static
     {
         let job = Box::new(f);
 
-        self.sender.send(Message::NewJob(job)).unwrap();
+        self.stats.queued.fetch_add(1, Ordering::SeqCst);
+        self.queue.try_send(Message::NewJob(job)).inspect_err(|err| {
+            self.stats.queued.fetch_sub(1, Ordering::SeqCst);
+
+            if *err == ExecuteError::Full {
+                self.stats.rejected.fetch_add(1, Ordering::SeqCst);
+            }
+        })
     }
 }
 
@@ -71,7 +290,10 @@ impl Drop for ThreadPool {
         info!("Sending terminate message to all workers.");
 
         for _ in &mut self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+            if self.queue.send(Message::Terminate).is_err() {
+                warn!("Workers are gone already.");
+                break;
+            }
         }
 
         info!("Shutting down all workers.");
@@ -80,27 +302,54 @@ impl Drop for ThreadPool {
             info!("Shutting down worker {}", worker.id);
 
             if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+                if thread.join().is_err() {
+                    error!("Worker {} panicked.", worker.id);
+                }
             }
         }
     }
 }
 
+/// Marks a worker as busy until dropped, even if the job panics.
+pub struct Busy<'a> {
+    stats: &'a PoolStats,
+}
+
+impl<'a> Busy<'a> {
+    /// Counts a busy worker, e.g. a thread answering connections outside of a pool.
+    pub fn new(stats: &'a PoolStats) -> Busy<'a> {
+        stats.busy.fetch_add(1, Ordering::SeqCst);
+        Busy { stats }
+    }
+}
+
+impl<'a> Drop for Busy<'a> {
+    fn drop(&mut self) {
+        self.stats.busy.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>, stats: Arc<PoolStats>) -> Worker {
+        let thread = thread::Builder::new().name(format!("worker-{}", id)).spawn(move || {
             loop {
                 let message = receiver.lock().unwrap().recv().unwrap();
 
                 match message {
                     Message::NewJob(job) => {
                         info!("Worker {} got a job; executing.", id);
-                        job.call_box();
+                        stats.queued.fetch_sub(1, Ordering::SeqCst);
+                        let _busy = Busy::new(&stats);
+
+                        // The worker must survive a panicking job, else the pool shrinks with every one.
+                        if panic::catch_unwind(AssertUnwindSafe(|| job.call_box())).is_err() {
+                            error!("Worker {} panicked executing a job.", id);
+                        }
                     },
                     Message::Terminate => {
                         info!("Worker {} got a job; executing.", id);
@@ -108,7 +357,7 @@ impl Worker {
                     }
                 }
             }
-        });
+        }).expect("Can't spawn worker thread!");
 
         Worker {
             id,
@@ -116,3 +365,93 @@ impl Worker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn stats_count_busy_workers_and_queued_jobs() {
+        let sut = ThreadPool::new(1);
+        let stats = sut.stats();
+        let (started_sender, started) = channel();
+        let (release, released) = channel::<()>();
+
+        sut.execute(move || {
+            started_sender.send(()).unwrap();
+            released.recv().unwrap();
+        }).unwrap();
+        started.recv().unwrap();
+        sut.execute(|| ()).unwrap();
+
+        assert_that!(stats.size(), is(equal_to(1)));
+        assert_that!(stats.busy_workers(), is(equal_to(1)));
+        assert_that!(stats.queue_depth(), is(equal_to(1)));
+
+        release.send(()).unwrap();
+        drop(sut);
+
+        assert_that!(stats.busy_workers(), is(equal_to(0)));
+        assert_that!(stats.queue_depth(), is(equal_to(0)));
+    }
+
+    #[test]
+    fn pools_share_stats_and_number_workers_across_pools() {
+        let stats = Arc::new(PoolStats::new(2));
+        let first = ThreadPool::with_stats(1, Arc::clone(&stats));
+        let second = ThreadPool::with_stats(1, Arc::clone(&stats));
+        let (names_sender, names) = channel();
+        let other_sender = names_sender.clone();
+
+        first.execute(move || names_sender.send(thread::current().name().map(String::from)).unwrap()).unwrap();
+        second.execute(move || other_sender.send(thread::current().name().map(String::from)).unwrap()).unwrap();
+        let mut workers = vec!(names.recv().unwrap().unwrap(), names.recv().unwrap().unwrap());
+        workers.sort();
+
+        assert_that!(stats.size(), is(equal_to(2)));
+        assert_that!(workers, is(equal_to(vec!(String::from("worker-0"), String::from("worker-1")))));
+    }
+
+    #[test]
+    fn bounded_pool_rejects_jobs_when_queue_is_full() {
+        let stats = Arc::new(PoolStats::new(1).with_capacity(1));
+        let sut = ThreadPool::bounded(1, 1, Arc::clone(&stats));
+        let (started_sender, started) = channel();
+        let (release, released) = channel::<()>();
+
+        sut.execute(move || {
+            started_sender.send(()).unwrap();
+            released.recv().unwrap();
+        }).unwrap();
+        started.recv().unwrap();
+
+        assert_that!(sut.execute(|| ()), is(equal_to(Ok(()))));
+        assert_that!(sut.execute(|| ()), is(equal_to(Err(ExecuteError::Full))));
+        assert_that!(stats.queue_depth(), is(equal_to(1)));
+        assert_that!(stats.rejected(), is(equal_to(1)));
+        assert_that!(stats.capacity(), is(equal_to(Some(1))));
+
+        release.send(()).unwrap();
+        drop(sut);
+        assert_that!(stats.queue_depth(), is(equal_to(0)));
+    }
+
+    #[test]
+    fn worker_survives_panicking_job() {
+        let sut = ThreadPool::new(1);
+        let (done_sender, done) = channel();
+
+        sut.execute(|| panic!("Job failed!")).unwrap();
+        sut.execute(move || done_sender.send(()).unwrap()).unwrap();
+        done.recv().unwrap();
+        drop(sut);
+    }
+
+    #[test]
+    fn queue_config_validates_capacity() {
+        assert_that!(QueueConfig::new(0), is(equal_to(Err("Config value 'queue.capacity' must be greater than 0!"))));
+        assert_that!(*QueueConfig::new(10).unwrap().full_action(), is(equal_to(FullAction::Reject)));
+    }
+}
@@ -0,0 +1,492 @@
+///! This module provides the timeouts protecting the workers from slow and idle clients.
+///!
+///! A client must send the complete request header within `header` seconds after connecting, or after the first
+///! byte of a further request on a kept connection. Between two reads of the body at most `body` seconds may
+///! pass, between two writes of the response at most `write` seconds. A kept connection waits `keep_alive`
+///! seconds for the next request. With `min_rate` bytes per second, a body or response transferred slower than
+///! that on average is dropped once it took longer than `body` or `write` seconds, so trickling clients can't
+///! hold a connection by sending or taking a byte now and then.
+///!
+///! A client running out of time after it sent a part of a request gets `408 Request Timeout`, otherwise the
+///! connection is closed. All timeouts are logged and counted by the metrics.
+
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+use event_loop;
+use http;
+use http::{Response, ResponseHeader, Status};
+use metrics::Metrics;
+
+fn default_header() -> u64 {
+    20
+}
+
+fn default_body() -> u64 {
+    20
+}
+
+fn default_keep_alive() -> u64 {
+    5
+}
+
+fn default_write() -> u64 {
+    30
+}
+
+fn default_min_rate() -> u64 {
+    500
+}
+
+/// Configuration of the timeouts, all in seconds.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct TimeoutsConfig {
+    /// Time to receive the request header.
+    #[serde(default = "default_header")]
+    header: u64,
+    /// Time between two reads of the request body.
+    #[serde(default = "default_body")]
+    body: u64,
+    /// Time a kept connection waits for the next request.
+    #[serde(default = "default_keep_alive")]
+    keep_alive: u64,
+    /// Time between two writes of the response.
+    #[serde(default = "default_write")]
+    write: u64,
+    /// Bytes per second a body or response must be transferred with on average, 0 disables the check.
+    #[serde(default = "default_min_rate")]
+    min_rate: u64,
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> TimeoutsConfig {
+        TimeoutsConfig {
+            header: default_header(),
+            body: default_body(),
+            keep_alive: default_keep_alive(),
+            write: default_write(),
+            min_rate: default_min_rate(),
+        }
+    }
+}
+
+impl TimeoutsConfig {
+    /// Creates a new configuration with the given timeouts in seconds and the default minimum rate.
+    pub fn new(header: u64, body: u64, keep_alive: u64, write: u64) -> Result<TimeoutsConfig, &'static str> {
+        if header < 1 {
+            return Err("Config value 'timeouts.header' must be greater than 0!");
+        }
+
+        if body < 1 {
+            return Err("Config value 'timeouts.body' must be greater than 0!");
+        }
+
+        if keep_alive < 1 {
+            return Err("Config value 'timeouts.keep_alive' must be greater than 0!");
+        }
+
+        if write < 1 {
+            return Err("Config value 'timeouts.write' must be greater than 0!");
+        }
+
+        Ok(TimeoutsConfig { header, body, keep_alive, write, min_rate: default_min_rate() })
+    }
+
+    /// Sets the minimum transfer rate in bytes per second, 0 disables the check.
+    pub fn with_min_rate(mut self, min_rate: u64) -> TimeoutsConfig {
+        self.min_rate = min_rate;
+        self
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<TimeoutsConfig, &'static str> {
+        Ok(TimeoutsConfig::new(self.header, self.body, self.keep_alive, self.write)?.with_min_rate(self.min_rate))
+    }
+
+    /// Get the seconds to receive the request header.
+    pub fn header(&self) -> &u64 {
+        &self.header
+    }
+
+    /// Get the seconds between two reads of the request body.
+    pub fn body(&self) -> &u64 {
+        &self.body
+    }
+
+    /// Get the seconds a kept connection waits for the next request.
+    pub fn keep_alive(&self) -> &u64 {
+        &self.keep_alive
+    }
+
+    /// Get the seconds between two writes of the response.
+    pub fn write(&self) -> &u64 {
+        &self.write
+    }
+
+    /// Get the minimum transfer rate in bytes per second.
+    pub fn min_rate(&self) -> &u64 {
+        &self.min_rate
+    }
+}
+
+/// The timeouts a connection may run into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Timeout {
+    /// The request header wasn't received in time.
+    Header,
+    /// The request body stalled.
+    Body,
+    /// A kept connection got no further request.
+    KeepAlive,
+    /// The client stopped taking the response.
+    Write,
+    /// The body or response was transferred too slowly.
+    MinRate,
+}
+
+impl Timeout {
+    /// Get the name of the timeout like in the configuration.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Timeout::Header => "header",
+            Timeout::Body => "body",
+            Timeout::KeepAlive => "keep_alive",
+            Timeout::Write => "write",
+            Timeout::MinRate => "min_rate",
+        }
+    }
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Tracks what the server waits for on a connection and when it gives up.
+#[derive(Debug, Clone)]
+pub struct Timer {
+    timeout: Timeout,
+    limit: Duration,
+    min_rate: u64,
+    started: Instant,
+    last: Instant,
+    bytes: usize,
+}
+
+impl Timer {
+    /// Waits for the next request on a kept connection.
+    pub fn keep_alive(config: &TimeoutsConfig, now: Instant) -> Timer {
+        Timer::new(Timeout::KeepAlive, config.keep_alive, 0, now)
+    }
+
+    /// Waits for the complete request header.
+    pub fn header(config: &TimeoutsConfig, now: Instant) -> Timer {
+        Timer::new(Timeout::Header, config.header, 0, now)
+    }
+
+    /// Waits for the request body.
+    pub fn body(config: &TimeoutsConfig, now: Instant) -> Timer {
+        Timer::new(Timeout::Body, config.body, config.min_rate, now)
+    }
+
+    /// Waits for the client to take the response.
+    pub fn write(config: &TimeoutsConfig, now: Instant) -> Timer {
+        Timer::new(Timeout::Write, config.write, config.min_rate, now)
+    }
+
+    fn new(timeout: Timeout, seconds: u64, min_rate: u64, now: Instant) -> Timer {
+        Timer { timeout, limit: Duration::from_secs(seconds), min_rate, started: now, last: now, bytes: 0 }
+    }
+
+    /// Records transferred bytes.
+    pub fn progress(&mut self, bytes: usize, now: Instant) {
+        self.bytes += bytes;
+        self.last = now;
+    }
+
+    /// Get what the timer waits for.
+    pub fn timeout(&self) -> Timeout {
+        self.timeout
+    }
+
+    /// Get when the timer expires and which timeout it is then.
+    ///
+    /// The header and keep-alive timeouts count from the start, the body and write timeouts from the last
+    /// progress. After the limit, the average rate since the start must be at least the minimum rate.
+    pub fn deadline(&self) -> (Instant, Timeout) {
+        let deadline = match self.timeout {
+            Timeout::Body | Timeout::Write => self.last + self.limit,
+            _ => self.started + self.limit,
+        };
+
+        if self.min_rate == 0 {
+            return (deadline, self.timeout);
+        }
+
+        let rated = Duration::from_secs_f64(self.bytes as f64 / self.min_rate as f64);
+        let rate_deadline = self.started + cmp::max(self.limit, rated);
+
+        if rate_deadline < deadline {
+            (rate_deadline, Timeout::MinRate)
+        } else {
+            (deadline, self.timeout)
+        }
+    }
+
+    /// Get the time left until the timer expires, or the timeout if it has expired.
+    fn left(&self, now: Instant) -> Result<Duration, Timeout> {
+        let (deadline, timeout) = self.deadline();
+
+        match deadline.checked_duration_since(now) {
+            Some(left) if left > Duration::from_millis(0) => Ok(left),
+            _ => Err(timeout),
+        }
+    }
+}
+
+/// A connection whose blocking reads and writes give up after a timeout.
+pub trait Timed {
+    /// Sets how long a read blocks at most.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Sets how long a write blocks at most.
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Timed for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// Why reading a request or writing a response failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The timeout expired after the given number of bytes were transferred.
+    TimedOut(Timeout, usize),
+    /// The connection failed.
+    Io(io::Error),
+}
+
+/// Reads a request until it's complete, the client closed the connection or it's as long as a request may be.
+pub fn read_request<S: Read + Timed>(stream: &mut S, config: &TimeoutsConfig) -> Result<Vec<u8>, Error> {
+    let mut input = Vec::new();
+    let mut chunk = [0; 4096];
+    let mut timer = Timer::header(config, Instant::now());
+
+    loop {
+        match event_loop::request_len(&input) {
+            Some(len) if len <= input.len() => break,
+            Some(_) if timer.timeout() == Timeout::Header => timer = Timer::body(config, Instant::now()),
+            _ => (),
+        }
+
+        if input.len() >= event_loop::MAX_REQUEST {
+            break;
+        }
+
+        let left = timer.left(Instant::now()).map_err(|timeout| Error::TimedOut(timeout, input.len()))?;
+        stream.set_read_timeout(Some(left)).map_err(Error::Io)?;
+        let max = cmp::min(chunk.len(), event_loop::MAX_REQUEST - input.len());
+
+        match stream.read(&mut chunk[..max]) {
+            Ok(0) => break,
+            Ok(len) => {
+                input.extend_from_slice(&chunk[..len]);
+                timer.progress(len, Instant::now());
+            },
+            Err(ref err) if is_timeout(err) || err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(Error::Io(err)),
+        }
+    }
+
+    Ok(input)
+}
+
+/// Writes the whole response, giving up if the client doesn't take it in time.
+pub fn write_all<S: Write + Timed>(stream: &mut S, output: &[u8], config: &TimeoutsConfig) -> Result<(), Error> {
+    write_parts(stream, &[output], config)
+}
+
+/// Writes the head and then the body of the response, without copying the body.
+pub fn write_response<S: Write + Timed>(stream: &mut S, response: &Response, config: &TimeoutsConfig)
+    -> Result<(), Error> {
+    write_parts(stream, &[&response.render_head(), response.body()], config)
+}
+
+/// Writes the parts one after the other, all within the same write timeout.
+fn write_parts<S: Write + Timed>(stream: &mut S, parts: &[&[u8]], config: &TimeoutsConfig) -> Result<(), Error> {
+    let mut timer = Timer::write(config, Instant::now());
+    let mut written = 0;
+
+    for part in parts.iter() {
+        let mut offset = 0;
+
+        while offset < part.len() {
+            let left = timer.left(Instant::now()).map_err(|timeout| Error::TimedOut(timeout, written))?;
+            stream.set_write_timeout(Some(left)).map_err(Error::Io)?;
+
+            match stream.write(&part[offset..]) {
+                Ok(0) => return Err(Error::Io(io::Error::new(io::ErrorKind::WriteZero, "connection closed"))),
+                Ok(len) => {
+                    offset += len;
+                    written += len;
+                    timer.progress(len, Instant::now());
+                },
+                Err(ref err) if is_timeout(err) || err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(Error::Io(err)),
+            }
+        }
+    }
+
+    stream.flush().map_err(Error::Io)
+}
+
+/// Whether a blocking read or write failed because its timeout expired.
+pub fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+/// Counts and logs that a connection ran into the timeout. Idle connections closing are logged only for debugging.
+pub fn record(metrics: &Metrics, timeout: Timeout, remote_addr: Option<SocketAddr>) {
+    metrics.timeout(timeout);
+
+    if timeout == Timeout::KeepAlive {
+        debug!("Closing idle connection from {:?}.", remote_addr);
+    } else {
+        info!("Connection from {:?} ran into the {} timeout.", remote_addr, timeout);
+    }
+}
+
+/// Renders the response telling the client it didn't send its request in time.
+pub fn request_timeout_response() -> Vec<u8> {
+    let body = b"Request timeout!".to_vec();
+    let content_length = body.len();
+    let mut response = Response::new(String::from(http::VERSION), Status::RequestTimeout, body);
+    response.add_header(ResponseHeader::ContentType(String::from("text/plain; charset=utf-8")));
+    response.add_header(ResponseHeader::ContentLength(content_length));
+    response.add_header(ResponseHeader::Custom(String::from("Connection"), String::from("close")));
+    response.render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use listen::Stream;
+
+    fn secs(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    fn connected() -> (TcpStream, Stream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (client, Stream::Tcp(listener.accept().unwrap().0))
+    }
+
+    #[test]
+    fn config_validates_timeouts() {
+        assert_that!(TimeoutsConfig::new(0, 1, 1, 1),
+            is(equal_to(Err("Config value 'timeouts.header' must be greater than 0!"))));
+        assert_that!(TimeoutsConfig::new(1, 0, 1, 1),
+            is(equal_to(Err("Config value 'timeouts.body' must be greater than 0!"))));
+        assert_that!(TimeoutsConfig::new(1, 1, 0, 1),
+            is(equal_to(Err("Config value 'timeouts.keep_alive' must be greater than 0!"))));
+        assert_that!(TimeoutsConfig::new(1, 1, 1, 0),
+            is(equal_to(Err("Config value 'timeouts.write' must be greater than 0!"))));
+        assert_that!(TimeoutsConfig::new(20, 20, 5, 30).unwrap(), is(equal_to(TimeoutsConfig::default())));
+    }
+
+    #[test]
+    fn header_timer_counts_from_start() {
+        let config = TimeoutsConfig::new(10, 5, 3, 5).unwrap();
+        let now = Instant::now();
+        let mut sut = Timer::header(&config, now);
+
+        sut.progress(100, now + secs(8));
+
+        assert_that!(sut.deadline(), is(equal_to((now + secs(10), Timeout::Header))));
+        assert_that!(Timer::keep_alive(&config, now).deadline(), is(equal_to((now + secs(3), Timeout::KeepAlive))));
+    }
+
+    #[test]
+    fn body_timer_counts_from_last_progress_while_rate_is_met() {
+        let config = TimeoutsConfig::new(10, 5, 3, 5).unwrap().with_min_rate(100);
+        let now = Instant::now();
+        let mut sut = Timer::body(&config, now);
+
+        sut.progress(1000, now + secs(4));
+        assert_that!(sut.deadline(), is(equal_to((now + secs(9), Timeout::Body))));
+
+        sut.progress(1000, now + secs(7));
+        assert_that!(sut.deadline(), is(equal_to((now + secs(12), Timeout::Body))));
+    }
+
+    #[test]
+    fn body_timer_expires_when_transferred_too_slowly() {
+        let config = TimeoutsConfig::new(10, 5, 3, 5).unwrap().with_min_rate(100);
+        let now = Instant::now();
+        let mut sut = Timer::write(&config, now);
+
+        sut.progress(100, now + secs(4));
+        assert_that!(sut.deadline(), is(equal_to((now + secs(5), Timeout::MinRate))));
+
+        sut.progress(500, now + secs(5));
+        assert_that!(sut.deadline(), is(equal_to((now + secs(6), Timeout::MinRate))));
+
+        let sut = Timer::write(&config.with_min_rate(0), now);
+        assert_that!(sut.deadline(), is(equal_to((now + secs(5), Timeout::Write))));
+    }
+
+    #[test]
+    fn read_request_reads_until_body_is_complete() {
+        let (mut client, mut stream) = connected();
+        let config = TimeoutsConfig::default();
+        let sender = thread::spawn(move || {
+            client.write_all(b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nab").unwrap();
+            thread::sleep(Duration::from_millis(50));
+            client.write_all(b"cd").unwrap();
+            client
+        });
+
+        let request = read_request(&mut stream, &config).unwrap();
+
+        assert_that!(request, is(equal_to(b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcd".to_vec())));
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn read_request_gives_up_on_incomplete_header() {
+        let (mut client, mut stream) = connected();
+        let config = TimeoutsConfig::new(1, 1, 1, 1).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+
+        match read_request(&mut stream, &config) {
+            Err(Error::TimedOut(timeout, received)) => {
+                assert_that!(timeout, is(equal_to(Timeout::Header)));
+                assert_that!(received, is(equal_to(25)));
+            },
+            other => panic!("Expected header timeout, got {:?}!", other),
+        }
+    }
+
+    #[test]
+    fn request_timeout_response_closes_connection() {
+        let response = String::from_utf8(request_timeout_response()).unwrap();
+
+        assert_that!(response.starts_with("HTTP/1.1 408 REQUEST TIMEOUT\r\n"), is(true));
+        assert_that!(response.contains("\r\nConnection: close\r\n"), is(true));
+        assert_that!(response.ends_with("\r\n\r\nRequest timeout!"), is(true));
+    }
+}
@@ -0,0 +1,974 @@
+///! This module provides HTTPS with [rustls](https://github.com/rustls/rustls).
+///!
+///! TLS is enabled by a `[tls]` section with the port to serve HTTPS on, the certificate chain and the
+///! private key as PEM files. The minimum protocol version and the cipher suites can be restricted,
+///! ciphers are then preferred in the configured order. Requests are answered by the same handling as
+///! plain HTTP, only the stream is wrapped.
+///!
+///! Several host names can be served from one port: the certificate is selected by the server name the
+///! client sends via SNI. Certificates are configured by `[[tls.certificate]]` sections or with `tls_cert`
+///! and `tls_key` of a virtual host. Exact names win over wildcards like `*.example.com`, which match a
+///! single label only. Clients asking for an unknown name or none get the default certificate.
+///!
+///! Client certificates are requested if `client_ca` names a PEM bundle of certificate authorities.
+///! Whether one is needed is decided per path prefix by `[[tls.client_auth]]` sections with the mode
+///! `required`, `optional` or `off`, the longest matching prefix wins and other paths are `off`.
+///! Certificates not issued by one of the authorities abort the handshake, requests to `required`
+///! paths without a certificate are answered with `403 FORBIDDEN`. The subject and the alternative
+///! names of a verified certificate are passed on with the request.
+///!
+///! A `[tls.redirect]` section starts a plain HTTP listener answering every request with a redirect to
+///! the `https://` URL of the same host and target, except paths below the configured prefixes like
+///! `/.well-known/`, which are served as usual. If its port is the one of the main listener, that
+///! listener redirects. A `[tls.hsts]` section adds `Strict-Transport-Security` to HTTPS responses.
+
+use std::io;
+use std::io::{Read, Write};
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, Ipv6Addr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+use rustls;
+use rustls::RootCertStore;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned, SupportedCipherSuite, SupportedProtocolVersion};
+use rustls::crypto::CryptoProvider;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use timeout::Timed;
+use vhost::{strip_port, VirtualHost};
+use x509_parser::parse_x509_certificate;
+use x509_parser::extensions::GeneralName;
+
+fn default_min_version() -> String {
+    String::from("1.2")
+}
+
+/// Configuration of the HTTPS listener.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct TlsConfig {
+    /// Port to serve HTTPS on, at the same address as plain HTTP.
+    port: u16,
+    /// PEM file with the default certificate chain, starting with the server certificate.
+    cert: String,
+    /// PEM file with the private key of the default certificate.
+    key: String,
+    /// Minimum protocol version: `1.2` or `1.3`.
+    #[serde(default = "default_min_version")]
+    min_version: String,
+    /// Cipher suites like `TLS13_AES_256_GCM_SHA384` in order of preference. All supported if empty.
+    #[serde(default)]
+    ciphers: Vec<String>,
+    /// Certificates selected by server name.
+    #[serde(default, rename = "certificate")]
+    certificates: Vec<CertificateConfig>,
+    /// PEM file with the certificate authorities client certificates are verified against.
+    client_ca: Option<String>,
+    /// Client certificate modes by path prefix.
+    #[serde(default)]
+    client_auth: Vec<ClientAuthConfig>,
+    /// Plain HTTP listener redirecting to HTTPS.
+    redirect: Option<RedirectConfig>,
+    /// Strict transport security announced on HTTPS responses.
+    hsts: Option<HstsConfig>,
+}
+
+impl TlsConfig {
+    /// Creates a new TLS configuration accepting TLS 1.2 and 1.3 with all supported cipher suites.
+    pub fn new(port: u16, cert: String, key: String) -> Result<TlsConfig, &'static str> {
+        if port == 0 {
+            return Err("Config value 'tls.port' must not be 0!");
+        }
+
+        if cert.is_empty() {
+            return Err("Config value 'tls.cert' must not be empty!");
+        }
+
+        if key.is_empty() {
+            return Err("Config value 'tls.key' must not be empty!");
+        }
+
+        Ok(TlsConfig {
+            port,
+            cert,
+            key,
+            min_version: default_min_version(),
+            ciphers: Vec::new(),
+            certificates: Vec::new(),
+            client_ca: None,
+            client_auth: Vec::new(),
+            redirect: None,
+            hsts: None,
+        })
+    }
+
+    /// Restricts the protocol versions and cipher suites.
+    pub fn with_protocol(mut self, min_version: String, ciphers: Vec<String>) -> Result<TlsConfig, &'static str> {
+        let versions = protocol_versions(&min_version)?;
+        let suites = cipher_suites(&ciphers)?;
+
+        if !suites.iter().any(|suite| versions.iter().any(|version| suite.version() == *version)) {
+            return Err("Config value 'tls.ciphers' must contain a cipher suite for the allowed protocol versions!");
+        }
+
+        self.min_version = min_version;
+        self.ciphers = ciphers;
+        Ok(self)
+    }
+
+    /// Sets the certificates selected by server name.
+    pub fn with_certificates(mut self, certificates: Vec<CertificateConfig>) -> Result<TlsConfig, &'static str> {
+        let mut validated = Vec::with_capacity(certificates.len());
+
+        for certificate in certificates {
+            validated.push(certificate.validate()?);
+        }
+
+        self.certificates = validated;
+        Ok(self)
+    }
+
+    /// Requests client certificates issued by the given authorities, needed as configured by path prefix.
+    pub fn with_client_auth(mut self, client_ca: Option<String>, client_auth: Vec<ClientAuthConfig>)
+        -> Result<TlsConfig, &'static str> {
+        if client_ca.as_ref().is_some_and(|client_ca| client_ca.is_empty()) {
+            return Err("Config value 'tls.client_ca' must not be empty!");
+        }
+
+        if client_ca.is_none() && client_auth.iter().any(|auth| auth.mode != ClientAuthMode::Off) {
+            return Err("Config value 'tls.client_auth' requires 'tls.client_ca'!");
+        }
+
+        let mut validated = Vec::with_capacity(client_auth.len());
+
+        for auth in client_auth {
+            validated.push(auth.validate()?);
+        }
+
+        self.client_ca = client_ca;
+        self.client_auth = validated;
+        Ok(self)
+    }
+
+    /// Starts a plain HTTP listener redirecting to HTTPS.
+    pub fn with_redirect(mut self, redirect: Option<RedirectConfig>) -> Result<TlsConfig, &'static str> {
+        self.redirect = match redirect {
+            Some(ref redirect) if redirect.port == self.port =>
+                return Err("Config value 'tls.redirect.port' must differ from 'tls.port'!"),
+            Some(redirect) => Some(redirect.validate()?),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    /// Announces strict transport security on HTTPS responses.
+    pub fn with_hsts(mut self, hsts: Option<HstsConfig>) -> Result<TlsConfig, &'static str> {
+        self.hsts = match hsts {
+            Some(hsts) => Some(hsts.validate()?),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<TlsConfig, &'static str> {
+        TlsConfig::new(self.port, self.cert, self.key)?
+            .with_protocol(self.min_version, self.ciphers)?
+            .with_certificates(self.certificates)?
+            .with_client_auth(self.client_ca, self.client_auth)?
+            .with_redirect(self.redirect)?
+            .with_hsts(self.hsts)
+    }
+
+    /// Get the port to serve HTTPS on.
+    pub fn port(&self) -> &u16 {
+        &self.port
+    }
+
+    /// Get the PEM file with the certificate chain.
+    pub fn cert(&self) -> &String {
+        &self.cert
+    }
+
+    /// Get the PEM file with the private key.
+    pub fn key(&self) -> &String {
+        &self.key
+    }
+
+    /// Get the minimum protocol version.
+    pub fn min_version(&self) -> &String {
+        &self.min_version
+    }
+
+    /// Get the cipher suites in order of preference.
+    pub fn ciphers(&self) -> &Vec<String> {
+        &self.ciphers
+    }
+
+    /// Get the certificates selected by server name.
+    pub fn certificates(&self) -> &Vec<CertificateConfig> {
+        &self.certificates
+    }
+
+    /// Get the PEM file with the certificate authorities for client certificates.
+    pub fn client_ca(&self) -> &Option<String> {
+        &self.client_ca
+    }
+
+    /// Get the client certificate modes by path prefix.
+    pub fn client_auth(&self) -> &Vec<ClientAuthConfig> {
+        &self.client_auth
+    }
+
+    /// Get the plain HTTP listener redirecting to HTTPS, if any.
+    pub fn redirect(&self) -> &Option<RedirectConfig> {
+        &self.redirect
+    }
+
+    /// Get the strict transport security announced on HTTPS responses, if any.
+    pub fn hsts(&self) -> &Option<HstsConfig> {
+        &self.hsts
+    }
+
+    /// Get the client certificate mode of the path prefix with the longest match for the request path.
+    pub fn client_auth_mode(&self, path: &str) -> ClientAuthMode {
+        self.client_auth.iter()
+            .filter(|auth| path.starts_with(auth.path.as_str()))
+            .max_by_key(|auth| auth.path.len())
+            .map(|auth| auth.mode)
+            .unwrap_or_default()
+    }
+}
+
+/// Whether requests need a client certificate.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode {
+    /// Answer requests without a verified client certificate with `403 FORBIDDEN`.
+    Required,
+    /// Pass on a verified client certificate if the client sent one.
+    Optional,
+    /// Ignore client certificates.
+    #[default]
+    Off,
+}
+
+/// The client certificate mode of a path prefix.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ClientAuthConfig {
+    /// Path prefix like `/admin/`.
+    path: String,
+    /// Whether requests below the path need a client certificate.
+    mode: ClientAuthMode,
+}
+
+impl ClientAuthConfig {
+    /// Creates a new client certificate mode for a path prefix.
+    pub fn new(path: String, mode: ClientAuthMode) -> Result<ClientAuthConfig, &'static str> {
+        if !path.starts_with('/') {
+            return Err("Config value 'tls.client_auth.path' must start with '/'!");
+        }
+
+        Ok(ClientAuthConfig { path, mode })
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<ClientAuthConfig, &'static str> {
+        ClientAuthConfig::new(self.path, self.mode)
+    }
+
+    /// Get the path prefix.
+    pub fn path(&self) -> &String {
+        &self.path
+    }
+
+    /// Get the client certificate mode.
+    pub fn mode(&self) -> &ClientAuthMode {
+        &self.mode
+    }
+}
+
+fn default_redirect_status() -> u16 {
+    308
+}
+
+/// Configuration of the plain HTTP listener redirecting to HTTPS.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct RedirectConfig {
+    /// Port to listen on, at the same address as plain HTTP.
+    port: u16,
+    /// Status of the redirect: `301` or `308`.
+    #[serde(default = "default_redirect_status")]
+    status: u16,
+    /// Path prefixes like `/.well-known/` served as usual instead of redirected.
+    #[serde(default)]
+    exceptions: Vec<String>,
+}
+
+impl RedirectConfig {
+    /// Creates a new redirect listener answering with `308 PERMANENT REDIRECT`.
+    pub fn new(port: u16) -> Result<RedirectConfig, &'static str> {
+        if port == 0 {
+            return Err("Config value 'tls.redirect.port' must not be 0!");
+        }
+
+        Ok(RedirectConfig { port, status: default_redirect_status(), exceptions: Vec::new() })
+    }
+
+    /// Sets the status of the redirect.
+    pub fn with_status(mut self, status: u16) -> Result<RedirectConfig, &'static str> {
+        if status != 301 && status != 308 {
+            return Err("Config value 'tls.redirect.status' must be 301 or 308!");
+        }
+
+        self.status = status;
+        Ok(self)
+    }
+
+    /// Sets the path prefixes which are not redirected.
+    pub fn with_exceptions(mut self, exceptions: Vec<String>) -> Result<RedirectConfig, &'static str> {
+        if exceptions.iter().any(|prefix| !prefix.starts_with('/')) {
+            return Err("Config value 'tls.redirect.exceptions' must contain paths starting with '/'!");
+        }
+
+        self.exceptions = exceptions;
+        Ok(self)
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<RedirectConfig, &'static str> {
+        RedirectConfig::new(self.port)?
+            .with_status(self.status)?
+            .with_exceptions(self.exceptions)
+    }
+
+    /// Get the port to listen on.
+    pub fn port(&self) -> &u16 {
+        &self.port
+    }
+
+    /// Get the status of the redirect.
+    pub fn status(&self) -> &u16 {
+        &self.status
+    }
+
+    /// Get the path prefixes which are not redirected.
+    pub fn exceptions(&self) -> &Vec<String> {
+        &self.exceptions
+    }
+
+    /// Whether the request path is served as usual instead of redirected.
+    pub fn is_exception(&self, path: &str) -> bool {
+        self.exceptions.iter().any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+/// Builds the `https://` URL for the host of a `Host` header and a request target.
+///
+/// Returns `None` if the host is empty or contains characters not allowed in host names.
+pub fn https_location(host: &str, url: &str, port: u16) -> Option<String> {
+    let host = strip_port(host);
+
+    if host.is_empty() || !host.chars().all(|ch| ch.is_ascii_alphanumeric() || "-.[]:".contains(ch)) {
+        return None;
+    }
+
+    let port = if port == 443 { String::new() } else { format!(":{}", port) };
+    let url = if url.starts_with('/') { url } else { "/" };
+
+    Some(format!("https://{}{}{}", host, port, url))
+}
+
+/// Configuration of the `Strict-Transport-Security` header.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct HstsConfig {
+    /// Seconds browsers only use HTTPS for the host.
+    max_age: u64,
+    /// Whether subdomains are included.
+    #[serde(default)]
+    include_subdomains: bool,
+    /// Whether the host may be included in the preload lists of browsers.
+    #[serde(default)]
+    preload: bool,
+}
+
+impl HstsConfig {
+    /// Creates a new configuration for the host only.
+    pub fn new(max_age: u64) -> HstsConfig {
+        HstsConfig { max_age, include_subdomains: false, preload: false }
+    }
+
+    /// Includes subdomains and allows preloading.
+    pub fn with_scope(mut self, include_subdomains: bool, preload: bool) -> Result<HstsConfig, &'static str> {
+        if preload && (!include_subdomains || self.max_age < 31_536_000) {
+            return Err("Config value 'tls.hsts.preload' requires 'include_subdomains' and a 'max_age' of at least one year!");
+        }
+
+        self.include_subdomains = include_subdomains;
+        self.preload = preload;
+        Ok(self)
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<HstsConfig, &'static str> {
+        HstsConfig::new(self.max_age).with_scope(self.include_subdomains, self.preload)
+    }
+
+    /// Get the value of the `Strict-Transport-Security` header.
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age);
+
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+
+        if self.preload {
+            value.push_str("; preload");
+        }
+
+        value
+    }
+}
+
+/// The verified certificate a client authenticated with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientCertificate {
+    /// Distinguished name of the subject like `O=Example, CN=alice`.
+    subject: String,
+    /// Subject alternative names like `DNS:alice.example.com` or `email:alice@example.com`.
+    sans: Vec<String>,
+}
+
+impl ClientCertificate {
+    /// Creates a client certificate from its subject and alternative names.
+    pub fn new(subject: String, sans: Vec<String>) -> ClientCertificate {
+        ClientCertificate { subject, sans }
+    }
+
+    /// Reads subject and alternative names from a DER encoded certificate.
+    pub fn parse(der: &[u8]) -> Option<ClientCertificate> {
+        let (_, certificate) = parse_x509_certificate(der).ok()?;
+        let sans = match certificate.subject_alternative_name() {
+            Ok(Some(extension)) => extension.value.general_names.iter().filter_map(general_name).collect(),
+            _ => Vec::new(),
+        };
+
+        Some(ClientCertificate { subject: certificate.subject().to_string(), sans })
+    }
+
+    /// Get the distinguished name of the subject.
+    pub fn subject(&self) -> &String {
+        &self.subject
+    }
+
+    /// Get the subject alternative names.
+    pub fn sans(&self) -> &Vec<String> {
+        &self.sans
+    }
+}
+
+fn general_name(name: &GeneralName) -> Option<String> {
+    match name {
+        GeneralName::DNSName(name) => Some(format!("DNS:{}", name)),
+        GeneralName::RFC822Name(name) => Some(format!("email:{}", name)),
+        GeneralName::URI(name) => Some(format!("URI:{}", name)),
+        GeneralName::IPAddress(octets) => match octets.len() {
+            4 => Some(format!("IP:{}", Ipv4Addr::from(<[u8; 4]>::try_from(*octets).ok()?))),
+            16 => Some(format!("IP:{}", Ipv6Addr::from(<[u8; 16]>::try_from(*octets).ok()?))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A certificate presented to clients asking for one of its server names.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct CertificateConfig {
+    /// Server names like `api.example.com` or `*.example.com`.
+    server_names: Vec<String>,
+    /// PEM file with the certificate chain.
+    cert: String,
+    /// PEM file with the private key.
+    key: String,
+}
+
+impl CertificateConfig {
+    /// Creates a new certificate configuration.
+    pub fn new(server_names: Vec<String>, cert: String, key: String) -> Result<CertificateConfig, &'static str> {
+        if server_names.is_empty() || server_names.iter().any(|name| !is_valid_server_name(name)) {
+            return Err("Config value 'tls.certificate.server_names' must contain host names or wildcards like '*.example.com'!");
+        }
+
+        if cert.is_empty() || key.is_empty() {
+            return Err("Config value 'tls.certificate.cert' and 'tls.certificate.key' must not be empty!");
+        }
+
+        Ok(CertificateConfig {
+            server_names: server_names.iter().map(|name| name.to_lowercase()).collect(),
+            cert,
+            key,
+        })
+    }
+
+    /// Validates a deserialized configuration by running it through the constructor.
+    pub fn validate(self) -> Result<CertificateConfig, &'static str> {
+        CertificateConfig::new(self.server_names, self.cert, self.key)
+    }
+
+    /// Get the server names.
+    pub fn server_names(&self) -> &Vec<String> {
+        &self.server_names
+    }
+
+    /// Get the PEM file with the certificate chain.
+    pub fn cert(&self) -> &String {
+        &self.cert
+    }
+
+    /// Get the PEM file with the private key.
+    pub fn key(&self) -> &String {
+        &self.key
+    }
+}
+
+fn is_valid_server_name(name: &str) -> bool {
+    !name.is_empty() && !name.chars().skip(1).any(|c| c == '*') && (!name.starts_with('*') || name.starts_with("*."))
+}
+
+/// Whether the server name matches a certificate name. Wildcards match a single label only.
+fn matches_server_name(name: &str, server_name: &str) -> bool {
+    if name.starts_with("*.") {
+        let suffix = &name[1..];
+
+        server_name.len() > suffix.len()
+            && server_name.ends_with(suffix)
+            && !server_name[..server_name.len() - suffix.len()].contains('.')
+    } else {
+        name == server_name
+    }
+}
+
+/// Selects the certificate by the server name the client sent via SNI.
+#[derive(Debug)]
+pub struct SniResolver {
+    /// Certificates by server name, exact names and wildcards.
+    certificates: Vec<(String, Arc<CertifiedKey>)>,
+    /// Certificate for clients asking for an unknown name or none.
+    default: Arc<CertifiedKey>,
+}
+
+impl SniResolver {
+    /// Get the certificate for the server name.
+    pub fn select(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let server_name = match server_name {
+            Some(server_name) => server_name.to_lowercase(),
+            None => return self.default.clone(),
+        };
+        let exact = self.certificates.iter()
+            .find(|(name, _)| *name == server_name);
+        let wildcard = || self.certificates.iter()
+            .filter(|(name, _)| name.starts_with("*.") && matches_server_name(name, &server_name))
+            .max_by_key(|(name, _)| name.len());
+
+        exact.or_else(wildcard)
+            .map(|(_, certificate)| certificate.clone())
+            .unwrap_or_else(|| self.default.clone())
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.select(client_hello.server_name()))
+    }
+}
+
+fn protocol_versions(min_version: &str) -> Result<Vec<&'static SupportedProtocolVersion>, &'static str> {
+    match min_version {
+        "1.2" => Ok(vec!(&rustls::version::TLS13, &rustls::version::TLS12)),
+        "1.3" => Ok(vec!(&rustls::version::TLS13)),
+        _ => Err("Config value 'tls.min_version' must be '1.2' or '1.3'!"),
+    }
+}
+
+/// Get the cipher suites by name in the given order, or all supported ones if no names are given.
+fn cipher_suites(names: &[String]) -> Result<Vec<SupportedCipherSuite>, &'static str> {
+    if names.is_empty() {
+        return Ok(ring::DEFAULT_CIPHER_SUITES.to_vec());
+    }
+
+    names.iter()
+        .map(|name| ring::ALL_CIPHER_SUITES.iter()
+            .find(|suite| suite.suite().as_str() == Some(name.as_str()))
+            .cloned()
+            .ok_or("Config value 'tls.ciphers' must contain supported cipher suites like 'TLS13_AES_256_GCM_SHA384'!"))
+        .collect()
+}
+
+/// Reads the certificate chain from a PEM file.
+pub fn load_certs(file: &str) -> Result<Vec<CertificateDer<'static>>, &'static str> {
+    let certs = CertificateDer::pem_file_iter(file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| {
+            error!("Can't read certificates from {}: {}", file, err);
+            "Can't read TLS certificate file!"
+        })?;
+
+    if certs.is_empty() {
+        return Err("TLS certificate file contains no certificate!");
+    }
+
+    Ok(certs)
+}
+
+/// Reads the private key from a PEM file.
+pub fn load_key(file: &str) -> Result<PrivateKeyDer<'static>, &'static str> {
+    PrivateKeyDer::from_pem_file(file).map_err(|err| {
+        error!("Can't read private key from {}: {}", file, err);
+        "Can't read TLS private key file!"
+    })
+}
+
+/// Reads a certificate chain and its private key.
+fn load_certified_key(cert: &str, key: &str, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>, &'static str> {
+    CertifiedKey::from_der(load_certs(cert)?, load_key(key)?, provider)
+        .map(Arc::new)
+        .map_err(|err| {
+            error!("Can't use TLS certificate {}: {}", cert, err);
+            "TLS certificate and private key don't match!"
+        })
+}
+
+/// Loads the default certificate and the certificates by server name of the TLS config and virtual hosts.
+pub fn resolver(config: &TlsConfig, vhosts: &[VirtualHost], provider: &CryptoProvider) -> Result<SniResolver, &'static str> {
+    let default = load_certified_key(&config.cert, &config.key, provider)?;
+    let mut certificates = Vec::new();
+
+    for certificate in config.certificates.iter() {
+        let certified_key = load_certified_key(&certificate.cert, &certificate.key, provider)?;
+
+        for name in certificate.server_names.iter() {
+            certificates.push((name.clone(), certified_key.clone()));
+        }
+    }
+
+    for vhost in vhosts.iter() {
+        if let (Some(cert), Some(key)) = (vhost.tls_cert(), vhost.tls_key()) {
+            let certified_key = load_certified_key(cert, key, provider)?;
+
+            for name in vhost.server_names().iter() {
+                certificates.push((name.clone(), certified_key.clone()));
+            }
+        }
+    }
+
+    Ok(SniResolver { certificates, default })
+}
+
+/// Get the crypto provider restricted to the configured cipher suites.
+fn provider(config: &TlsConfig) -> Result<CryptoProvider, &'static str> {
+    Ok(CryptoProvider {
+        cipher_suites: cipher_suites(&config.ciphers)?,
+        ..ring::default_provider()
+    })
+}
+
+/// Reads the certificate authorities for client certificates from a PEM file.
+fn load_client_roots(file: &str) -> Result<RootCertStore, &'static str> {
+    let mut roots = RootCertStore::empty();
+
+    for cert in load_certs(file)? {
+        roots.add(cert).map_err(|err| {
+            error!("Can't use client CA certificate from {}: {}", file, err);
+            "Can't use TLS client CA certificate!"
+        })?;
+    }
+
+    Ok(roots)
+}
+
+/// Builds the rustls server configuration by loading the certificates and keys.
+pub fn server_config(config: &TlsConfig, vhosts: &[VirtualHost]) -> Result<Arc<ServerConfig>, &'static str> {
+    let provider = Arc::new(provider(config)?);
+    let resolver = resolver(config, vhosts, &provider)?;
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&protocol_versions(&config.min_version)?)
+        .map_err(|_| "Config value 'tls.ciphers' must contain a cipher suite for the allowed protocol versions!")?;
+    let builder = match config.client_ca {
+        Some(ref client_ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_client_roots(client_ca)?), provider)
+                .allow_unauthenticated()
+                .build()
+                .map_err(|err| {
+                    error!("Can't verify client certificates with {}: {}", client_ca, err);
+                    "Can't use TLS client CA certificate!"
+                })?;
+
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_cert_resolver(Arc::new(resolver));
+
+    server_config.ignore_client_order = !config.ciphers.is_empty();
+    server_config.alpn_protocols = vec!(b"http/1.1".to_vec());
+    Ok(Arc::new(server_config))
+}
+
+/// A TLS stream on a TCP connection.
+pub struct TlsStream {
+    stream: StreamOwned<ServerConnection, TcpStream>,
+}
+
+impl TlsStream {
+    /// Performs the TLS handshake on the accepted connection.
+    pub fn accept(config: Arc<ServerConfig>, mut stream: TcpStream) -> io::Result<TlsStream> {
+        let mut connection = ServerConnection::new(config)
+            .map_err(io::Error::other)?;
+
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+
+        Ok(TlsStream { stream: StreamOwned::new(connection, stream) })
+    }
+
+    /// Get the server name the client asked for by SNI, if any.
+    pub fn server_name(&self) -> Option<&str> {
+        self.stream.conn.server_name()
+    }
+
+    /// Get the verified certificate the client authenticated with, if any.
+    pub fn client_certificate(&self) -> Option<ClientCertificate> {
+        self.stream.conn.peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| ClientCertificate::parse(cert))
+    }
+
+    /// Notifies the client that no more data will be sent and flushes the stream.
+    pub fn close(&mut self) -> io::Result<()> {
+        self.stream.conn.send_close_notify();
+        self.stream.flush()
+    }
+}
+
+impl Timed for TlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.sock.set_write_timeout(timeout)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+
+    fn localhost() -> TlsConfig {
+        TlsConfig::new(8443, String::from("test/fixtures/tls/localhost.pem"), String::from("test/fixtures/tls/localhost.key"))
+            .unwrap()
+    }
+
+    #[test]
+    fn with_protocol_validates_version_and_ciphers() {
+        assert_that!(
+            localhost().with_protocol(String::from("1.1"), Vec::new()),
+            is(equal_to(Err("Config value 'tls.min_version' must be '1.2' or '1.3'!"))));
+        assert_that!(
+            localhost().with_protocol(String::from("1.2"), vec!(String::from("RC4_MD5"))),
+            is(equal_to(Err("Config value 'tls.ciphers' must contain supported cipher suites like 'TLS13_AES_256_GCM_SHA384'!"))));
+        assert_that!(
+            localhost().with_protocol(String::from("1.3"), vec!(String::from("TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256"))),
+            is(equal_to(Err("Config value 'tls.ciphers' must contain a cipher suite for the allowed protocol versions!"))));
+        assert_that!(
+            localhost().with_protocol(String::from("1.3"), vec!(String::from("TLS13_CHACHA20_POLY1305_SHA256"))).is_ok(),
+            is(true));
+    }
+
+    #[test]
+    fn certificate_config_validates_server_names() {
+        let expected = "Config value 'tls.certificate.server_names' must contain host names or wildcards like '*.example.com'!";
+
+        assert_that!(CertificateConfig::new(Vec::new(), String::from("c"), String::from("k")), is(equal_to(Err(expected))));
+        assert_that!(
+            CertificateConfig::new(vec!(String::from("foo.*.com")), String::from("c"), String::from("k")),
+            is(equal_to(Err(expected))));
+        assert_that!(
+            CertificateConfig::new(vec!(String::from("é*.example")), String::from("c"), String::from("k")),
+            is(equal_to(Err(expected))));
+        assert_that!(
+            CertificateConfig::new(vec!(String::from("*.Example.com")), String::from("c"), String::from("k"))
+                .unwrap().server_names().clone(),
+            is(equal_to(vec!(String::from("*.example.com")))));
+    }
+
+    #[test]
+    fn wildcards_match_a_single_label() {
+        assert_that!(matches_server_name("*.example.com", "www.example.com"), is(true));
+        assert_that!(matches_server_name("*.example.com", "a.b.example.com"), is(false));
+        assert_that!(matches_server_name("*.example.com", "example.com"), is(false));
+        assert_that!(matches_server_name("example.com", "example.com"), is(true));
+    }
+
+    #[test]
+    fn resolver_selects_certificate_by_server_name() {
+        let config = localhost()
+            .with_certificates(vec!(CertificateConfig::new(
+                vec!(String::from("*.example.com")),
+                String::from("test/fixtures/tls/example.com.pem"),
+                String::from("test/fixtures/tls/example.com.key")).unwrap()))
+            .unwrap();
+        let vhost = VirtualHost::new(vec!(String::from("www.example.org")), String::from("dir")).unwrap()
+            .with_tls_certificate(
+                Some(String::from("test/fixtures/tls/example.org.pem")),
+                Some(String::from("test/fixtures/tls/example.org.key")))
+            .unwrap();
+        let sut = resolver(&config, &[vhost], &ring::default_provider()).unwrap();
+        let cert = |server_name| sut.select(server_name).cert[0].clone();
+        let localhost = load_certs("test/fixtures/tls/localhost.pem").unwrap()[0].clone();
+        let example_com = load_certs("test/fixtures/tls/example.com.pem").unwrap()[0].clone();
+        let example_org = load_certs("test/fixtures/tls/example.org.pem").unwrap()[0].clone();
+
+        assert_that!(cert(Some("api.example.com")), is(equal_to(example_com)));
+        assert_that!(cert(Some("WWW.example.org")), is(equal_to(example_org)));
+        assert_that!(cert(Some("a.b.example.com")), is(equal_to(localhost.clone())));
+        assert_that!(cert(None), is(equal_to(localhost)));
+    }
+
+    fn client_auth(path: &str, mode: ClientAuthMode) -> ClientAuthConfig {
+        ClientAuthConfig::new(String::from(path), mode).unwrap()
+    }
+
+    #[test]
+    fn with_client_auth_requires_client_ca() {
+        assert_that!(
+            localhost().with_client_auth(None, vec!(client_auth("/admin/", ClientAuthMode::Required))),
+            is(equal_to(Err("Config value 'tls.client_auth' requires 'tls.client_ca'!"))));
+        assert_that!(
+            localhost().with_client_auth(Some(String::new()), Vec::new()),
+            is(equal_to(Err("Config value 'tls.client_ca' must not be empty!"))));
+        assert_that!(
+            ClientAuthConfig::new(String::from("admin"), ClientAuthMode::Required),
+            is(equal_to(Err("Config value 'tls.client_auth.path' must start with '/'!"))));
+        assert_that!(localhost().with_client_auth(None, vec!(client_auth("/", ClientAuthMode::Off))).is_ok(), is(true));
+    }
+
+    #[test]
+    fn client_auth_mode_selects_longest_path_prefix() {
+        let sut = localhost()
+            .with_client_auth(
+                Some(String::from("test/fixtures/tls/client-ca.pem")),
+                vec!(
+                    client_auth("/admin/", ClientAuthMode::Required),
+                    client_auth("/admin/public/", ClientAuthMode::Off),
+                    client_auth("/api/", ClientAuthMode::Optional)))
+            .unwrap();
+
+        assert_that!(sut.client_auth_mode("/admin/users"), is(equal_to(ClientAuthMode::Required)));
+        assert_that!(sut.client_auth_mode("/admin/public/logo.png"), is(equal_to(ClientAuthMode::Off)));
+        assert_that!(sut.client_auth_mode("/api/items"), is(equal_to(ClientAuthMode::Optional)));
+        assert_that!(sut.client_auth_mode("/index.html"), is(equal_to(ClientAuthMode::Off)));
+    }
+
+    #[test]
+    fn client_certificate_parses_subject_and_sans() {
+        let cert = load_certs("test/fixtures/tls/client.pem").unwrap()[0].clone();
+
+        assert_that!(
+            ClientCertificate::parse(&cert),
+            is(equal_to(Some(ClientCertificate::new(
+                String::from("O=Example, CN=alice"),
+                vec!(String::from("email:alice@example.com"), String::from("DNS:alice.example.com")))))));
+        assert_that!(ClientCertificate::parse(b"no certificate"), is(equal_to(None)));
+    }
+
+    #[test]
+    fn server_config_loads_client_ca() {
+        let config = localhost()
+            .with_client_auth(Some(String::from("test/fixtures/tls/client-ca.pem")), Vec::new())
+            .unwrap();
+
+        assert_that!(server_config(&config, &[]).is_ok(), is(true));
+
+        let config = localhost()
+            .with_client_auth(Some(String::from("test/fixtures/tls/missing.pem")), Vec::new())
+            .unwrap();
+
+        assert_that!(server_config(&config, &[]).err(), is(equal_to(Some("Can't read TLS certificate file!"))));
+    }
+
+    #[test]
+    fn with_redirect_validates_port_and_status() {
+        assert_that!(
+            localhost().with_redirect(Some(RedirectConfig::new(8443).unwrap())),
+            is(equal_to(Err("Config value 'tls.redirect.port' must differ from 'tls.port'!"))));
+        assert_that!(
+            RedirectConfig::new(80).unwrap().with_status(302),
+            is(equal_to(Err("Config value 'tls.redirect.status' must be 301 or 308!"))));
+        assert_that!(
+            RedirectConfig::new(80).unwrap().with_exceptions(vec!(String::from(".well-known"))),
+            is(equal_to(Err("Config value 'tls.redirect.exceptions' must contain paths starting with '/'!"))));
+        assert_that!(
+            RedirectConfig::new(80).unwrap().with_exceptions(vec!(String::from("/.well-known/"))).unwrap()
+                .is_exception("/.well-known/acme-challenge/token"),
+            is(true));
+    }
+
+    #[test]
+    fn https_location_keeps_host_and_target() {
+        assert_that!(
+            https_location("example.com:8080", "/a%20b.html?x=1", 8443),
+            is(equal_to(Some(String::from("https://example.com:8443/a%20b.html?x=1")))));
+        assert_that!(https_location("[::1]:80", "/", 443), is(equal_to(Some(String::from("https://[::1]/")))));
+        assert_that!(https_location("example.com", "*", 443), is(equal_to(Some(String::from("https://example.com/")))));
+        assert_that!(https_location("", "/", 443), is(equal_to(None)));
+        assert_that!(https_location("evil.com/x?", "/", 443), is(equal_to(None)));
+    }
+
+    #[test]
+    fn hsts_header_value() {
+        assert_that!(HstsConfig::new(600).header_value(), is(equal_to(String::from("max-age=600"))));
+        assert_that!(
+            HstsConfig::new(31_536_000).with_scope(true, true).unwrap().header_value(),
+            is(equal_to(String::from("max-age=31536000; includeSubDomains; preload"))));
+        assert_that!(
+            HstsConfig::new(600).with_scope(true, true),
+            is(equal_to(Err("Config value 'tls.hsts.preload' requires 'include_subdomains' and a 'max_age' of at least one year!"))));
+    }
+
+    #[test]
+    fn server_config_loads_cert_and_key() {
+        let config = server_config(&localhost(), &[]).unwrap();
+
+        assert_that!(config.alpn_protocols.clone(), is(equal_to(vec!(b"http/1.1".to_vec()))));
+        assert_that!(config.ignore_client_order, is(false));
+    }
+
+    #[test]
+    fn server_config_fails_without_cert() {
+        let config = TlsConfig::new(8443, String::from("test/fixtures/tls/missing.pem"), String::from("test/fixtures/tls/localhost.key"))
+            .unwrap();
+
+        assert_that!(server_config(&config, &[]).err(), is(equal_to(Some("Can't read TLS certificate file!"))));
+    }
+}
@@ -0,0 +1,209 @@
+///! This module provides upgrading the server binary without dropping connections.
+///!
+///! On `SIGUSR2` the server starts its binary again with the same arguments and hands its listening sockets
+///! over the way systemd passes them: as file descriptors from 3 on, described by `LISTEN_FDS` and
+///! `LISTEN_FDNAMES`. `LISTEN_PID` names the old process, which the new one accepts as its parent because
+///! `WEBSERVER_UPGRADE_SOCKET` is set. Once the new process accepts connections, it sends `READY=1` to that
+///! socket. The old process then stops accepting, lets its workers finish the connections in flight and exits,
+///! while the new one serves all new connections from the same sockets.
+///!
+///! If the new process exits or isn't ready in time, it's killed and the old process keeps serving.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+use libc;
+use systemd;
+
+/// Environment variable naming the socket the new process reports its readiness to.
+pub static SOCKET_VAR: &'static str = "WEBSERVER_UPGRADE_SOCKET";
+
+/// Seconds to wait for the new process to accept connections.
+static READY_TIMEOUT: u64 = 30;
+
+/// Starts the binary of this process again, passing the given listening sockets with their names, and waits
+/// until it accepts connections.
+///
+/// Returns the process id of the new process.
+pub fn upgrade(listeners: &[(RawFd, String)]) -> Result<u32, &'static str> {
+    let mut args = env::args_os();
+    let program = args.next().ok_or("Can't find the binary to start for the upgrade!")?;
+    let path = env::temp_dir().join(format!("webserver-upgrade-{}.sock", process::id()));
+    let _ = fs::remove_file(&path);
+    let ready = UnixDatagram::bind(&path).map_err(|err| {
+        error!("Can't bind socket {} for the upgrade: {}", path.display(), err);
+        "Can't create socket for the upgrade!"
+    })?;
+
+    let result = spawn(&program, args.collect(), listeners, &path)
+        .and_then(|child| wait_ready(&ready, child, Duration::from_secs(READY_TIMEOUT)));
+    let _ = fs::remove_file(&path);
+    result
+}
+
+/// Starts the program with the listening sockets passed from file descriptor 3 on, reporting its readiness to
+/// the socket at the given path.
+fn spawn(program: &OsStr, args: Vec<OsString>, listeners: &[(RawFd, String)], ready_socket: &Path)
+    -> Result<Child, &'static str> {
+    let fds: Vec<RawFd> = listeners.iter().map(|(fd, _)| *fd).collect();
+    let names: Vec<&str> = listeners.iter().map(|(_, name)| name.as_str()).collect();
+    let mut moved = fds.clone();
+    let mut command = Command::new(program);
+    command.args(args)
+        .env("LISTEN_PID", process::id().to_string())
+        .env("LISTEN_FDS", fds.len().to_string())
+        .env("LISTEN_FDNAMES", names.join(":"))
+        .env(SOCKET_VAR, ready_socket);
+
+    // Runs in the forked child, so it must neither allocate nor lock.
+    unsafe {
+        command.pre_exec(move || pass_fds(&fds, &mut moved));
+    }
+
+    command.spawn().map_err(|err| {
+        error!("Can't start {} for the upgrade: {}", program.to_string_lossy(), err);
+        "Can't start the new process!"
+    })
+}
+
+/// Duplicates the descriptors to 3, 4, ... without close on exec. They are moved above that range first, so
+/// none is overwritten before it's passed.
+fn pass_fds(fds: &[RawFd], moved: &mut [RawFd]) -> io::Result<()> {
+    let count = fds.len() as RawFd;
+
+    for (fd, target) in fds.iter().zip(moved.iter_mut()) {
+        *target = check(unsafe { libc::fcntl(*fd, libc::F_DUPFD, systemd::LISTEN_FDS_START + count) })?;
+    }
+
+    for (index, fd) in moved.iter().enumerate() {
+        check(unsafe { libc::dup2(*fd, systemd::LISTEN_FDS_START + index as RawFd) })?;
+        unsafe { libc::close(*fd) };
+    }
+
+    Ok(())
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 { Err(io::Error::last_os_error()) } else { Ok(result) }
+}
+
+/// Waits until the child sends `READY=1` to the socket. The child is killed if it's not ready in time.
+fn wait_ready(ready: &UnixDatagram, mut child: Child, timeout: Duration) -> Result<u32, &'static str> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0; 256];
+
+    ready.set_read_timeout(Some(Duration::from_millis(100)))
+        .map_err(|_| "Can't wait for the new process!")?;
+
+    loop {
+        match ready.recv(&mut buf) {
+            Ok(len) if is_ready(&buf[..len]) => return Ok(child.id()),
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => (),
+            Err(err) => warn!("Can't receive state of the new process: {}", err),
+        }
+
+        if let Ok(Some(status)) = child.try_wait() {
+            error!("New process {} exited with {} before it was ready.", child.id(), status);
+            return Err("The new process exited before it was ready!");
+        }
+
+        if Instant::now() >= deadline {
+            error!("New process {} wasn't ready within {:?}, killing it.", child.id(), timeout);
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err("The new process wasn't ready in time!");
+        }
+    }
+}
+
+/// Whether the notification contains the line `READY=1`.
+fn is_ready(state: &[u8]) -> bool {
+    state.split(|byte| *byte == b'\n').any(|line| line == b"READY=1")
+}
+
+/// Reports to the upgrading process that this one accepts connections.
+pub fn notify_ready(socket: &OsStr) -> io::Result<()> {
+    systemd::notify_socket(socket, "READY=1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+    use std::os::unix::io::AsRawFd;
+    use listen;
+
+    fn ready_socket(name: &str) -> (UnixDatagram, ::std::path::PathBuf) {
+        let path = env::temp_dir().join(format!("webserver-upgrade-{}-{}.sock", name, process::id()));
+        let _ = fs::remove_file(&path);
+        (UnixDatagram::bind(&path).unwrap(), path)
+    }
+
+    #[test]
+    fn is_ready_finds_ready_line() {
+        assert_that!(is_ready(b"READY=1"), is(true));
+        assert_that!(is_ready(b"STATUS=starting\nREADY=1\n"), is(true));
+        assert_that!(is_ready(b"READY=0"), is(false));
+        assert_that!(is_ready(b"STATUS=READY=1"), is(false));
+    }
+
+    #[test]
+    fn spawn_passes_listening_sockets() {
+        let web = listen::bind("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let admin = listen::bind("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let (_ready, path) = ready_socket("spawn");
+        let script = "[ \"$LISTEN_FDS\" = 2 ] && [ \"$LISTEN_FDNAMES\" = web:admin ] && [ -S /dev/fd/3 ] \
+                      && [ -S /dev/fd/4 ] && [ -n \"$WEBSERVER_UPGRADE_SOCKET\" ]";
+        let listeners = vec!((web.as_raw_fd(), String::from("web")), (admin.as_raw_fd(), String::from("admin")));
+
+        let mut child = spawn(OsStr::new("sh"), vec!(OsString::from("-c"), OsString::from(script)), &listeners, &path)
+            .unwrap();
+
+        assert_that!(child.wait().unwrap().success(), is(true));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wait_ready_returns_pid_of_ready_child() {
+        let (ready, path) = ready_socket("ready");
+        let child = spawn(OsStr::new("sleep"), vec!(OsString::from("10")), &[], &path).unwrap();
+        let pid = child.id();
+
+        notify_ready(path.as_os_str()).unwrap();
+
+        assert_that!(wait_ready(&ready, child, Duration::from_secs(5)), is(equal_to(Ok(pid))));
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wait_ready_fails_if_child_exits() {
+        let (ready, path) = ready_socket("exit");
+        let child = spawn(OsStr::new("true"), Vec::new(), &[], &path).unwrap();
+
+        assert_that!(
+            wait_ready(&ready, child, Duration::from_secs(5)),
+            is(equal_to(Err("The new process exited before it was ready!"))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wait_ready_kills_child_not_ready_in_time() {
+        let (ready, path) = ready_socket("timeout");
+        let child = spawn(OsStr::new("sleep"), vec!(OsString::from("10")), &[], &path).unwrap();
+
+        assert_that!(
+            wait_ready(&ready, child, Duration::from_millis(200)),
+            is(equal_to(Err("The new process wasn't ready in time!"))));
+        fs::remove_file(&path).unwrap();
+    }
+}
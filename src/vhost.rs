@@ -0,0 +1,449 @@
+///! This module provides name based virtual hosts.
+///!
+///! A virtual host is selected by the `Host` header of the request. Server names may start with
+///! a wildcard label (`*.example.com`) which matches any subdomain. Exact names win over
+///! wildcards, and longer wildcards win over shorter ones.
+
+use std::collections::BTreeMap;
+use log::Level;
+use location;
+use location::Location;
+use rewrite::RewriteRule;
+use auth;
+use auth::AuthConfig;
+
+/// Configuration of a virtual host.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct VirtualHost {
+    /// Host names served by this virtual host, e.g. `www.example.com` or `*.example.com`.
+    #[serde(default)]
+    server_names: Vec<String>,
+    /// Directory with the content to serve.
+    web_dir: String,
+    /// Directory with custom error pages named by status code, e.g. `404.html`.
+    #[serde(default)]
+    error_dir: Option<String>,
+    /// Additional headers added to every response.
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// Level used to log the requests of this virtual host.
+    #[serde(default)]
+    log_level: Option<String>,
+    /// Whether this virtual host serves requests matching no other virtual host.
+    #[serde(default)]
+    default: bool,
+    /// Per location settings.
+    #[serde(default, rename = "location")]
+    locations: Vec<Location>,
+    /// Files served for requested directories, in order of preference.
+    /// Inherited from the top level config if empty.
+    #[serde(default)]
+    index_files: Vec<String>,
+    /// URL rewrite and redirect rules.
+    #[serde(default, rename = "rewrite")]
+    rewrites: Vec<RewriteRule>,
+    /// Path prefixes protected by HTTP Basic authentication.
+    #[serde(default, rename = "auth")]
+    auths: Vec<AuthConfig>,
+    /// PEM file with the certificate chain presented for the server names of this virtual host via TLS SNI.
+    #[serde(default)]
+    tls_cert: Option<String>,
+    /// PEM file with the private key of `tls_cert`.
+    #[serde(default)]
+    tls_key: Option<String>,
+}
+
+impl VirtualHost {
+    /// Creates a new virtual host.
+    pub fn new(server_names: Vec<String>, web_dir: String) -> Result<VirtualHost, &'static str> {
+        if web_dir.is_empty() {
+            return Err("Config value 'vhost.web_dir' must not be empty!");
+        }
+
+        for name in server_names.iter() {
+            if name.is_empty() || name.chars().skip(1).any(|c| c == '*') || (name.starts_with('*') && !name.starts_with("*.")) {
+                return Err("Config value 'vhost.server_names' must contain host names or wildcards like '*.example.com'!");
+            }
+        }
+
+        Ok(VirtualHost {
+            server_names: server_names.iter().map(|name| name.to_lowercase()).collect(),
+            web_dir,
+            error_dir: None,
+            headers: BTreeMap::new(),
+            log_level: None,
+            default: false,
+            locations: Vec::new(),
+            index_files: Vec::new(),
+            rewrites: Vec::new(),
+            auths: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+        })
+    }
+
+    /// Sets the directory with custom error pages.
+    pub fn with_error_dir(mut self, error_dir: Option<String>) -> Result<VirtualHost, &'static str> {
+        if let Some(ref error_dir) = error_dir {
+            if error_dir.is_empty() {
+                return Err("Config value 'error_dir' must not be empty!");
+            }
+        }
+
+        self.error_dir = error_dir;
+        Ok(self)
+    }
+
+    /// Sets additional headers added to every response.
+    pub fn with_headers(mut self, headers: BTreeMap<String, String>) -> Result<VirtualHost, &'static str> {
+        for name in headers.keys() {
+            if name.is_empty() || name.contains(|c: char| c == ':' || c.is_whitespace()) {
+                return Err("Config value 'vhost.headers' contains an invalid header name!");
+            }
+        }
+
+        self.headers = headers;
+        Ok(self)
+    }
+
+    /// Sets the level used to log the requests of this virtual host.
+    pub fn with_log_level(mut self, log_level: Option<String>) -> Result<VirtualHost, &'static str> {
+        if let Some(ref log_level) = log_level {
+            if log_level.parse::<Level>().is_err() {
+                return Err("Config value 'vhost.log_level' must be one of error, warn, info, debug or trace!");
+            }
+        }
+
+        self.log_level = log_level;
+        Ok(self)
+    }
+
+    /// Sets the per location settings.
+    pub fn with_locations(mut self, locations: Vec<Location>) -> Result<VirtualHost, &'static str> {
+        let mut validated = Vec::with_capacity(locations.len());
+
+        for location in locations {
+            validated.push(location.validate()?);
+        }
+
+        self.locations = validated;
+        Ok(self)
+    }
+
+    /// Sets the files served for requested directories, in order of preference.
+    pub fn with_index_files(mut self, index_files: Vec<String>) -> Result<VirtualHost, &'static str> {
+        validate_index_files(&index_files)?;
+        self.index_files = index_files;
+        Ok(self)
+    }
+
+    /// Sets the URL rewrite and redirect rules.
+    pub fn with_rewrites(mut self, rewrites: Vec<RewriteRule>) -> Result<VirtualHost, &'static str> {
+        let mut validated = Vec::with_capacity(rewrites.len());
+
+        for rule in rewrites {
+            validated.push(rule.validate()?);
+        }
+
+        self.rewrites = validated;
+        Ok(self)
+    }
+
+    /// Sets the path prefixes protected by HTTP Basic authentication.
+    pub fn with_auths(mut self, auths: Vec<AuthConfig>) -> Result<VirtualHost, &'static str> {
+        let mut validated = Vec::with_capacity(auths.len());
+
+        for auth in auths {
+            validated.push(auth.validate()?);
+        }
+
+        self.auths = validated;
+        Ok(self)
+    }
+
+    /// Sets the TLS certificate chain and private key presented for the server names of this virtual host.
+    pub fn with_tls_certificate(mut self, tls_cert: Option<String>, tls_key: Option<String>) -> Result<VirtualHost, &'static str> {
+        match (&tls_cert, &tls_key) {
+            (Some(cert), Some(key)) if cert.is_empty() || key.is_empty() =>
+                return Err("Config value 'vhost.tls_cert' and 'vhost.tls_key' must not be empty!"),
+            (Some(_), Some(_)) if self.server_names.is_empty() =>
+                return Err("Config value 'vhost.tls_cert' requires 'vhost.server_names'!"),
+            (Some(_), None) | (None, Some(_)) =>
+                return Err("Config value 'vhost.tls_cert' and 'vhost.tls_key' must be set together!"),
+            _ => (),
+        }
+
+        self.tls_cert = tls_cert;
+        self.tls_key = tls_key;
+        Ok(self)
+    }
+
+    /// Marks this virtual host as default.
+    pub fn with_default(mut self, default: bool) -> VirtualHost {
+        self.default = default;
+        self
+    }
+
+    /// Validates a deserialized virtual host by running it through the constructor.
+    pub fn validate(self) -> Result<VirtualHost, &'static str> {
+        let default = self.default;
+
+        VirtualHost::new(self.server_names, self.web_dir)?
+            .with_error_dir(self.error_dir)?
+            .with_headers(self.headers)?
+            .with_log_level(self.log_level)?
+            .with_locations(self.locations)?
+            .with_index_files(self.index_files)?
+            .with_rewrites(self.rewrites)?
+            .with_auths(self.auths)?
+            .with_tls_certificate(self.tls_cert, self.tls_key)
+            .map(|vhost| vhost.with_default(default))
+    }
+
+    /// Get the host names served by this virtual host.
+    pub fn server_names(&self) -> &Vec<String> {
+        &self.server_names
+    }
+
+    /// Get the name used to identify this virtual host in logs.
+    pub fn name(&self) -> &str {
+        match self.server_names.first() {
+            Some(name) => name,
+            None => "default",
+        }
+    }
+
+    /// Get the web root directory.
+    pub fn web_dir(&self) -> &String {
+        &self.web_dir
+    }
+
+    /// Get the directory with custom error pages.
+    pub fn error_dir(&self) -> &Option<String> {
+        &self.error_dir
+    }
+
+    /// Get the additional response headers.
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    /// Get the level used to log requests. Defaults to `info`.
+    pub fn log_level(&self) -> Level {
+        self.log_level.as_ref()
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(Level::Info)
+    }
+
+    /// Get the location settings applying to the given request path, if any.
+    pub fn location(&self, path: &str) -> Option<&Location> {
+        location::select(&self.locations, path)
+    }
+
+    /// Get the files served for requested directories, in order of preference.
+    pub fn index_files(&self) -> &Vec<String> {
+        &self.index_files
+    }
+
+    /// Get the URL rewrite and redirect rules.
+    pub fn rewrites(&self) -> &Vec<RewriteRule> {
+        &self.rewrites
+    }
+
+    /// Get the path prefixes protected by HTTP Basic authentication.
+    pub fn auths(&self) -> &Vec<AuthConfig> {
+        &self.auths
+    }
+
+    /// Get the authentication settings applying to the given request path, if any.
+    pub fn auth(&self, path: &str) -> Option<&AuthConfig> {
+        auth::select(&self.auths, path)
+    }
+
+    /// Get the PEM file with the TLS certificate chain of this virtual host, if any.
+    pub fn tls_cert(&self) -> &Option<String> {
+        &self.tls_cert
+    }
+
+    /// Get the PEM file with the TLS private key of this virtual host, if any.
+    pub fn tls_key(&self) -> &Option<String> {
+        &self.tls_key
+    }
+
+    /// Whether this virtual host is the default.
+    pub fn is_default(&self) -> bool {
+        self.default
+    }
+
+    /// Returns how well the given host name matches this virtual host or `None` if it does not match.
+    ///
+    /// Bigger values mean better matches.
+    fn match_score(&self, host: &str) -> Option<usize> {
+        self.server_names.iter()
+            .filter_map(|name| {
+                if name == host {
+                    Some(usize::MAX)
+                } else if name.starts_with("*.") && host.ends_with(&name[1..]) && host.len() > name.len() - 1 {
+                    Some(name.len())
+                } else {
+                    None
+                }
+            })
+            .max()
+    }
+}
+
+/// Validates that index files are plain file names.
+pub fn validate_index_files(index_files: &[String]) -> Result<(), &'static str> {
+    for index_file in index_files {
+        if index_file.is_empty() || index_file.contains('/') || index_file.contains('\\') || index_file == ".." {
+            return Err("Config value 'index_files' must contain file names only!");
+        }
+    }
+
+    Ok(())
+}
+
+/// Selects the virtual host for the given value of the `Host` header.
+///
+/// Returns `None` if no virtual host matches. The port part of the header is ignored.
+pub fn select<'a>(vhosts: &'a [VirtualHost], host: &str) -> Option<&'a VirtualHost> {
+    let host = strip_port(host).to_lowercase();
+
+    vhosts.iter()
+        .filter_map(|vhost| vhost.match_score(&host).map(|score| (score, vhost)))
+        .fold(None, |best: Option<(usize, &VirtualHost)>, candidate| match best {
+            Some(best) if best.0 >= candidate.0 => Some(best),
+            _ => Some(candidate),
+        })
+        .map(|(_, vhost)| vhost)
+}
+
+/// Removes the port from the value of a `Host` header, e.g. `localhost:8080` or `[::1]:8080`.
+pub fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        match host.find(']') {
+            Some(end) => &host[..end + 1],
+            None => host,
+        }
+    } else {
+        match host.rfind(':') {
+            Some(colon) => &host[..colon],
+            None => host,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hamcrest::prelude::*;
+
+    fn vhost(names: Vec<&str>) -> VirtualHost {
+        VirtualHost::new(
+            names.iter().map(|name| name.to_string()).collect(),
+            names[0].to_string()
+        ).unwrap()
+    }
+
+    #[test]
+    fn new_validates_web_dir_not_empty() {
+        assert_that!(
+            VirtualHost::new(vec!(String::from("example.com")), String::from("")),
+            is(equal_to(Err("Config value 'vhost.web_dir' must not be empty!"))));
+    }
+
+    #[test]
+    fn new_validates_wildcards() {
+        let expected = "Config value 'vhost.server_names' must contain host names or wildcards like '*.example.com'!";
+
+        assert_that!(VirtualHost::new(vec!(String::from("*example.com")), String::from("dir")), is(equal_to(Err(expected))));
+        assert_that!(VirtualHost::new(vec!(String::from("www.*.com")), String::from("dir")), is(equal_to(Err(expected))));
+        assert_that!(VirtualHost::new(vec!(String::from("")), String::from("dir")), is(equal_to(Err(expected))));
+        assert_that!(VirtualHost::new(vec!(String::from("é*.example")), String::from("dir")), is(equal_to(Err(expected))));
+        assert_that!(VirtualHost::new(vec!(String::from("é.example")), String::from("dir")).is_ok(), is(true));
+    }
+
+    #[test]
+    fn with_log_level_validates_level() {
+        assert_that!(
+            vhost(vec!("example.com")).with_log_level(Some(String::from("loud"))),
+            is(equal_to(Err("Config value 'vhost.log_level' must be one of error, warn, info, debug or trace!"))));
+    }
+
+    #[test]
+    fn with_index_files_validates_file_names() {
+        let expected = "Config value 'index_files' must contain file names only!";
+
+        assert_that!(
+            vhost(vec!("example.com")).with_index_files(vec!(String::from("../index.html"))),
+            is(equal_to(Err(expected))));
+        assert_that!(
+            vhost(vec!("example.com")).with_index_files(vec!(String::from(""))),
+            is(equal_to(Err(expected))));
+    }
+
+    #[test]
+    fn with_headers_validates_names() {
+        let mut headers = BTreeMap::new();
+        headers.insert(String::from("X-Foo:"), String::from("bar"));
+
+        assert_that!(
+            vhost(vec!("example.com")).with_headers(headers),
+            is(equal_to(Err("Config value 'vhost.headers' contains an invalid header name!"))));
+    }
+
+    #[test]
+    fn select_prefers_exact_names() {
+        let vhosts = vec!(vhost(vec!("*.example.com")), vhost(vec!("www.example.com")));
+
+        assert_that!(select(&vhosts, "www.example.com").unwrap().name(), is(equal_to("www.example.com")));
+        assert_that!(select(&vhosts, "foo.example.com").unwrap().name(), is(equal_to("*.example.com")));
+    }
+
+    #[test]
+    fn select_prefers_longest_wildcard() {
+        let vhosts = vec!(vhost(vec!("*.example.com")), vhost(vec!("*.foo.example.com")));
+
+        assert_that!(select(&vhosts, "bar.foo.example.com").unwrap().name(), is(equal_to("*.foo.example.com")));
+        assert_that!(select(&vhosts, "bar.example.com").unwrap().name(), is(equal_to("*.example.com")));
+    }
+
+    #[test]
+    fn select_ignores_port_and_case() {
+        let vhosts = vec!(vhost(vec!("example.com", "www.example.com")));
+
+        assert_that!(select(&vhosts, "WWW.Example.com:8080").is_some(), is(true));
+    }
+
+    #[test]
+    fn select_returns_none_if_nothing_matches() {
+        let vhosts = vec!(vhost(vec!("*.example.com")));
+
+        assert_that!(select(&vhosts, "example.com"), is(equal_to(None)));
+        assert_that!(select(&vhosts, "example.org"), is(equal_to(None)));
+        assert_that!(select(&vhosts, ".example.com"), is(equal_to(None)));
+    }
+
+    #[test]
+    fn with_tls_certificate_validates_pair() {
+        assert_that!(
+            vhost(vec!("example.com")).with_tls_certificate(Some(String::from("cert.pem")), None),
+            is(equal_to(Err("Config value 'vhost.tls_cert' and 'vhost.tls_key' must be set together!"))));
+        assert_that!(
+            VirtualHost::new(Vec::new(), String::from("dir")).unwrap().with_tls_certificate(Some(String::from("cert.pem")), Some(String::from("key.pem"))),
+            is(equal_to(Err("Config value 'vhost.tls_cert' requires 'vhost.server_names'!"))));
+        assert_that!(
+            vhost(vec!("example.com")).with_tls_certificate(Some(String::from("cert.pem")), Some(String::from("key.pem")))
+                .is_ok(),
+            is(true));
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_that!(strip_port("localhost"), is(equal_to("localhost")));
+        assert_that!(strip_port("localhost:8080"), is(equal_to("localhost")));
+        assert_that!(strip_port("[::1]:8080"), is(equal_to("[::1]")));
+        assert_that!(strip_port("[::1]"), is(equal_to("[::1]")));
+    }
+}
@@ -0,0 +1,42 @@
+#!/usr/bin/env bash
+#
+# Generates the self-signed certificates used by the TLS tests.
+#
+# Run it from this directory: ./generate.sh
+#
+
+set -euo pipefail
+
+DAYS=36500
+
+# Creates a self-signed server certificate: server_cert NAME SUBJECT_ALT_NAMES
+server_cert() {
+    openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+        -days "${DAYS}" -subj "/CN=${1}" \
+        -addext "subjectAltName=${2}" \
+        -addext "basicConstraints=critical,CA:FALSE" \
+        -addext "keyUsage=critical,digitalSignature" \
+        -addext "extendedKeyUsage=serverAuth" \
+        -keyout "${1#\*.}.key" -out "${1#\*.}.pem"
+}
+
+server_cert localhost "DNS:localhost,IP:127.0.0.1"
+# Wildcard certificate stored as example.com.pem.
+server_cert "*.example.com" "DNS:*.example.com"
+server_cert example.org "DNS:example.org,DNS:www.example.org"
+
+# Certificate authority for client certificates and a client certificate issued by it.
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+    -days "${DAYS}" -subj "/O=Example/CN=Example Client CA" \
+    -addext "basicConstraints=critical,CA:TRUE" \
+    -addext "keyUsage=critical,keyCertSign,cRLSign" \
+    -keyout client-ca.key -out client-ca.pem
+openssl req -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+    -subj "/O=Example/CN=alice" -keyout client.key -out client.csr
+openssl x509 -req -in client.csr -CA client-ca.pem -CAkey client-ca.key -CAcreateserial \
+    -days "${DAYS}" -out client.pem -extfile <(printf '%s\n' \
+        "basicConstraints=critical,CA:FALSE" \
+        "keyUsage=critical,digitalSignature" \
+        "extendedKeyUsage=clientAuth" \
+        "subjectAltName=email:alice@example.com,DNS:alice.example.com")
+rm -f client.csr client-ca.srl